@@ -0,0 +1,176 @@
+//! `user-activity`。アーカイブを読み、ユーザーがどのチャンネルにどれだけ書いたかを集計する。
+//!
+//! ファイルは1行ずつ読み、対象のユーザーのノートだけを覚える。
+
+use std::collections::{BTreeMap, HashSet};
+use std::error::Error;
+use std::fmt::Write as _;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use chrono::{DateTime, Utc};
+use serde_json::Value;
+
+use crate::model::{ChannelId, Note, NoteId, UserId};
+use crate::reader;
+
+/// ディレクトリを渡されたときに読むファイルの拡張子
+const ARCHIVE_EXTENSIONS: [&str; 2] = ["jsonl", "ndjson"];
+
+/// あるユーザーの、あるチャンネルでのノート
+struct ChannelActivity {
+    notes: usize,
+    first_note_at: DateTime<Utc>,
+    last_note_at: DateTime<Utc>,
+    /// 受け取ったリアクションの数の合計
+    reactions: usize,
+}
+
+/// ユーザーとチャンネルの組ごとの集計
+pub struct Activity {
+    users: HashSet<UserId>,
+    /// `None`はチャンネルに属さないノート
+    per_channel: BTreeMap<(UserId, Option<ChannelId>), ChannelActivity>,
+    /// 返信やピン留め、別のファイルに同じノートが現れても1度だけ数える
+    seen: HashSet<NoteId>,
+}
+
+impl Activity {
+    pub fn new(users: impl IntoIterator<Item = UserId>) -> Self {
+        Self { users: users.into_iter().collect(), per_channel: BTreeMap::new(), seen: HashSet::new() }
+    }
+
+    /// 1つのアーカイブを読んで数える。対象のユーザーでないノートは読み捨てる。
+    pub fn read(&mut self, path: &Path) -> Result<(), Box<dyn Error + Send + Sync>> {
+        reader::for_each_note_value(path, |at, value| {
+            let Some(user_id) = value["user"]["id"].as_str() else {
+                return Ok(())
+            };
+            if !self.users.contains(&UserId(user_id.to_owned())) {
+                return Ok(())
+            }
+            let note: Note = serde_json::from_value(value).map_err(|e| format!("{at}: {e}"))?;
+            if self.seen.insert(note.id.clone()) {
+                self.add(note);
+            }
+
+            Ok(())
+        })
+    }
+
+    fn add(&mut self, note: Note) {
+        let channel_id = note.channel_id.or_else(|| note.channel.map(|x| x.id));
+        let reactions = note.reactions.total() + note.other_reactions_count.unwrap_or(0);
+        self.per_channel.entry((note.user.id, channel_id))
+            .and_modify(|x| {
+                x.notes += 1;
+                x.first_note_at = x.first_note_at.min(note.created_at);
+                x.last_note_at = x.last_note_at.max(note.created_at);
+                x.reactions += reactions;
+            })
+            .or_insert(ChannelActivity { notes: 1, first_note_at: note.created_at, last_note_at: note.created_at, reactions });
+    }
+
+    /// ユーザーとチャンネルの組ごとに、`{"kind": "user-activity"}`を1つ返す。
+    pub fn records(&self) -> impl Iterator<Item = Value> + '_ {
+        self.per_channel.iter().map(|((user_id, channel_id), x)| serde_json::json!({
+            "kind": "user-activity",
+            "user_id": user_id,
+            "channel_id": channel_id,
+            "notes": x.notes,
+            "first_note_at": x.first_note_at,
+            "last_note_at": x.last_note_at,
+            "reactions": x.reactions,
+        }))
+    }
+
+    /// 表で読めるようにしたもの
+    pub fn to_markdown(&self) -> String {
+        let mut md = String::from("| User | Channel | Notes | First | Last | Reactions |\n| --- | --- | ---: | --- | --- | ---: |\n");
+        for ((user_id, channel_id), x) in &self.per_channel {
+            let channel = channel_id.as_ref().map_or("(none)", |x| x.0.as_str());
+            let _ = writeln!(md, "| {} | {channel} | {} | {} | {} | {} |", user_id.0, x.notes, x.first_note_at.to_rfc3339(), x.last_note_at.to_rfc3339(), x.reactions);
+        }
+
+        md
+    }
+}
+
+/// `--input`を、読むファイルの並びにする。ディレクトリなら、その直下にある`.jsonl`と`.ndjson`を名前の順に読む。
+pub fn expand_inputs(paths: &[PathBuf]) -> io::Result<Vec<PathBuf>> {
+    let mut files = vec![];
+    for path in paths {
+        if !path.is_dir() {
+            files.push(path.clone());
+            continue
+        }
+        let mut found: Vec<_> = fs::read_dir(path)?
+            .map(|x| x.map(|x| x.path()))
+            .filter(|x| x.as_ref().map_or(true, |x| x.is_file() && x.extension().is_some_and(|x| ARCHIVE_EXTENSIONS.iter().any(|e| x == *e))))
+            .collect::<io::Result<_>>()?;
+        found.sort();
+        files.extend(found);
+    }
+
+    Ok(files)
+}
+
+/// `--users-from`のファイルを読む。空行と`#`から始まる行は無視する。
+pub fn read_user_list(path: &Path) -> io::Result<Vec<UserId>> {
+    Ok(fs::read_to_string(path)?
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| UserId(line.to_owned()))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use serde_json::json;
+
+    use crate::activity::{expand_inputs, Activity};
+    use crate::model::UserId;
+    use crate::testing::{capture_dir, note_json};
+
+    fn note(id: &str, user: &str, channel: Option<&str>, created_at: &str) -> serde_json::Value {
+        let mut note = note_json(id, created_at);
+        note["user"] = json!({ "id": user });
+        note["channelId"] = json!(channel);
+        note
+    }
+
+    #[test]
+    fn notes_are_counted_per_user_and_channel_once() {
+        let dir = capture_dir("user-activity", &[]);
+        let archives = dir.join("archives");
+        fs::create_dir(&archives).unwrap();
+        let first = json!([
+            note("n3", "u1", Some("ch1"), "2024-01-03T00:00:00.000Z"),
+            note("n2", "u2", Some("ch1"), "2024-01-02T00:00:00.000Z"),
+            note("n1", "u1", Some("ch1"), "2024-01-01T00:00:00.000Z"),
+        ]);
+        let replies = json!({ "kind": "replies", "parent_id": "n1", "notes": [note("n4", "u1", None, "2024-01-04T00:00:00.000Z")] });
+        fs::write(archives.join("ch1.jsonl"), format!("{first}\n{replies}\n")).unwrap();
+        // 同じノートは、別のファイルにあっても1度だけ数える
+        let second = json!([note("n5", "u1", Some("ch2"), "2024-02-01T00:00:00.000Z"), note("n3", "u1", Some("ch1"), "2024-01-03T00:00:00.000Z")]);
+        fs::write(archives.join("ch2.jsonl"), format!("{second}\n")).unwrap();
+        fs::write(archives.join("notes.txt"), "not an archive").unwrap();
+
+        let mut activity = Activity::new([UserId("u1".to_owned())]);
+        for path in expand_inputs(&[archives]).unwrap() {
+            activity.read(&path).unwrap();
+        }
+
+        let records: Vec<_> = activity.records().collect();
+        assert_eq!(records, [
+            json!({ "kind": "user-activity", "user_id": "u1", "channel_id": null, "notes": 1, "first_note_at": "2024-01-04T00:00:00Z", "last_note_at": "2024-01-04T00:00:00Z", "reactions": 3 }),
+            json!({ "kind": "user-activity", "user_id": "u1", "channel_id": "ch1", "notes": 2, "first_note_at": "2024-01-01T00:00:00Z", "last_note_at": "2024-01-03T00:00:00Z", "reactions": 6 }),
+            json!({ "kind": "user-activity", "user_id": "u1", "channel_id": "ch2", "notes": 1, "first_note_at": "2024-02-01T00:00:00Z", "last_note_at": "2024-02-01T00:00:00Z", "reactions": 3 }),
+        ]);
+        assert!(activity.to_markdown().contains("| u1 | ch1 | 2 | 2024-01-01T00:00:00+00:00 | 2024-01-03T00:00:00+00:00 | 6 |"));
+    }
+}
@@ -0,0 +1,811 @@
+use std::convert::Infallible;
+use std::error::Error;
+use std::fmt::{Debug, Display, Formatter};
+use std::future::Future;
+use std::num::NonZeroUsize;
+use std::str::FromStr;
+use std::sync::RwLock;
+
+use reqwest::header::{HeaderName, HeaderValue, CONTENT_TYPE};
+use reqwest::{Client, Method};
+use serde::de::{self, DeserializeOwned, IgnoredAny, MapAccess, SeqAccess, Visitor};
+use serde::{Deserialize, Deserializer, Serialize};
+
+use crate::host::Host;
+use crate::i18n::{Lang, Message};
+use crate::log::debug;
+use crate::model::{Account, Channel, ChannelId, DetailedUser, Following, FollowingId, Hiding, HidingId, ListId, Note, NoteId, NoteLocation, Reaction, ReactionId, Renote, TrendingTag, UnixDateTime, UserId, UserList};
+
+/// 捨てるときに中身を0で上書きする。複製したものも同じく上書きされる。
+/// 生の値はリクエストの本文を組み立てるときと、[`Self::expose`]を呼んだ所にだけ現れる。
+#[derive(Eq, PartialEq, Clone)]
+pub struct MisskeyAuthorizationToken(String);
+
+impl MisskeyAuthorizationToken {
+    /// 前後の空白は取り除く。元の`String`も上書きしてから捨てる。
+    pub fn new(value: String) -> Self {
+        let trimmed = value.trim();
+        if trimmed.len() == value.len() {
+            return Self(value)
+        }
+
+        let token = Self(trimmed.to_owned());
+        drop(Self(value));
+        token
+    }
+
+    /// 表示や保存のために生の値を使う。
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+impl Drop for MisskeyAuthorizationToken {
+    fn drop(&mut self) {
+        let mut bytes = std::mem::take(&mut self.0).into_bytes();
+        bytes.resize(bytes.capacity(), 0);
+        bytes.fill(0);
+        // 解放される直前の書き込みとして消されないように
+        std::hint::black_box(&bytes);
+    }
+}
+
+impl Serialize for MisskeyAuthorizationToken {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.0)
+    }
+}
+
+impl Debug for MisskeyAuthorizationToken {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("MisskeyAuthorizationToken").field("value", &"*****").finish()
+    }
+}
+
+impl FromStr for MisskeyAuthorizationToken {
+    type Err = Infallible;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(Self::new(s.to_owned()))
+    }
+}
+
+/// `--header`で指定した、全てのリクエストに付けるヘッダー。
+/// 認証プロキシのトークンなどを含みうるので、値は表示しない。
+#[derive(Eq, PartialEq, Clone)]
+pub struct ExtraHeader {
+    pub name: HeaderName,
+    pub value: HeaderValue,
+}
+
+impl Debug for ExtraHeader {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: *****", self.name)
+    }
+}
+
+impl FromStr for ExtraHeader {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, value) = s.split_once(':').ok_or("expected \"Name: value\"")?;
+        let name = HeaderName::from_str(name.trim()).map_err(|_| format!("invalid header name {:?}", name.trim()))?;
+        let mut value = HeaderValue::from_str(value.trim()).map_err(|_| format!("invalid value for header {name}"))?;
+        value.set_sensitive(true);
+
+        Ok(Self { name, value })
+    }
+}
+
+#[derive(Serialize)]
+struct WithTokenRef<'a, T> {
+    #[serde(rename = "i", skip_serializing_if = "Option::is_none")]
+    token: Option<&'a MisskeyAuthorizationToken>,
+    #[serde(flatten)]
+    body: T,
+}
+
+/// APIが返したレスポンスそのもの。解釈は呼び出し側が行う。
+pub struct RawResponse {
+    pub status: u16,
+    pub body: String,
+    pub headers: ResponseHeaders,
+}
+
+/// レスポンスのヘッダーのうち、Misskeyではなくプロキシが返したものを見分けるのに使うもの
+#[derive(Clone, Default, Debug, Serialize, Deserialize)]
+pub struct ResponseHeaders {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub content_type: Option<String>,
+    /// Cloudflareがブラウザでの確認を求めたときに付ける`cf-mitigated`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cf_mitigated: Option<String>,
+}
+
+/// Misskey APIへの送信手段を抽象化したもの。
+/// `endpoint`は`channels/timeline`のように`/api/`以下のパスで、`body`にはトークンを含めない。
+pub trait ApiClient: Sync {
+    fn call(&self, endpoint: &str, body: serde_json::Value) -> impl Future<Output = Result<RawResponse, Box<dyn Error + Send + Sync>>> + Send;
+}
+
+/// 実際にネットワーク越しにリクエストを送るクライアント。
+pub struct HttpApiClient {
+    http: Client,
+    host: Host,
+    /// 無ければ認証せずに送る。`--reauth-command`で差し替えることがある
+    token: RwLock<Option<MisskeyAuthorizationToken>>,
+}
+
+impl HttpApiClient {
+    pub const fn new(http: Client, host: Host, token: Option<MisskeyAuthorizationToken>) -> Self {
+        Self { http, host, token: RwLock::new(token) }
+    }
+}
+
+impl ApiClient for HttpApiClient {
+    async fn call(&self, endpoint: &str, body: serde_json::Value) -> Result<RawResponse, Box<dyn Error + Send + Sync>> {
+        // 本文はここで組み立てられるので、トークンを読むのもその間だけ。`serde_json::Value`を挟むと、消されない写しが残る
+        let request = {
+            let token = self.token.read().expect("not poisoned");
+            self.http.request(Method::POST, self.host.endpoint(endpoint))
+                .json(&WithTokenRef { token: token.as_ref(), body: &body })
+        };
+        let x = request.send().await?;
+        let status = x.status().as_u16();
+        let header = |name| x.headers().get(name).and_then(|x: &HeaderValue| x.to_str().ok()).map(str::to_owned);
+        let headers = ResponseHeaders { content_type: header(CONTENT_TYPE.as_str()), cf_mitigated: header("cf-mitigated") };
+        let body = x.text().await?;
+
+        Ok(RawResponse { status, body, headers })
+    }
+}
+
+/// 実行の途中でトークンを差し替えられるクライアント
+pub trait ReplaceToken {
+    fn replace_token(&self, token: MisskeyAuthorizationToken);
+}
+
+impl ReplaceToken for HttpApiClient {
+    fn replace_token(&self, token: MisskeyAuthorizationToken) {
+        *self.token.write().expect("not poisoned") = Some(token);
+    }
+}
+
+/// `endpoint`を呼ぶのにトークンに必要な権限。このツールが使う他のエンドポイントは誰でも読めるので、どのトークンでも通る
+pub fn required_permission(endpoint: &str) -> Option<&'static str> {
+    match endpoint {
+        "i" | "notes/translate" | "notes/user-list-timeline" | "users/lists/show" => Some("read:account"),
+        "mute/list" => Some("read:mutes"),
+        "blocking/list" => Some("read:blocks"),
+        "channels/owned" | "channels/followed" => Some("read:channels"),
+        _ => None,
+    }
+}
+
+#[derive(Deserialize)]
+struct ErrorBody {
+    error: ErrorDetail,
+}
+
+#[derive(Deserialize)]
+struct ErrorDetail {
+    code: String,
+    message: String,
+}
+
+/// Misskeyがエラーとして返したレスポンス
+#[derive(Debug)]
+pub struct ApiError {
+    pub endpoint: String,
+    pub status: u16,
+    pub code: String,
+    pub message: String,
+}
+
+impl Display for ApiError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match required_permission(&self.endpoint) {
+            Some(permission) if self.code == "PERMISSION_DENIED" => write!(
+                f,
+                "{} was denied: the token lacks the \"{permission}\" permission. \
+                Enable it when issuing the token in Settings > API (設定 > API > アクセストークンの発行)",
+                self.endpoint,
+            ),
+            _ if self.code.is_empty() => write!(f, "{} failed with {}", self.endpoint, self.status),
+            _ => write!(f, "{} failed with {} {}: {}", self.endpoint, self.status, self.code, self.message),
+        }
+    }
+}
+
+impl Error for ApiError {}
+
+/// トークンが失効したか取り消されたことを示すレスポンスか。
+/// スコープが足りないだけの`PERMISSION_DENIED`は、新しいトークンでも変わらないので含めない。
+pub fn is_credential_failure(status: u16, code: &str) -> bool {
+    matches!(code, "CREDENTIAL_REQUIRED" | "AUTHENTICATION_FAILED") || (matches!(status, 401 | 403) && code != "PERMISSION_DENIED")
+}
+
+/// 実行の途中でトークンが通らなくなったか。残りのリクエストも通らないので、それ以上続けない
+pub fn is_token_rejected(e: &(dyn Error + Send + Sync + 'static)) -> bool {
+    e.downcast_ref::<ApiError>().is_some_and(|e| is_credential_failure(e.status, &e.code))
+}
+
+/// 時間を置いたり、小さなページで頼み直せば通るかもしれない失敗か
+pub fn is_transient(e: &(dyn Error + Send + Sync + 'static)) -> bool {
+    if e.is::<ProxyError>() {
+        return true
+    }
+
+    if let Some(e) = e.downcast_ref::<ApiError>() {
+        return e.status >= 500
+    }
+
+    e.downcast_ref::<reqwest::Error>().is_some_and(reqwest::Error::is_timeout)
+}
+
+/// ゲートウェイのタイムアウトなどで、Misskeyではなく前に立つプロキシが返したJSONでないページ
+#[derive(Debug)]
+pub struct ProxyError {
+    pub endpoint: String,
+    pub status: u16,
+    /// `<title>`か、本文の始めの[`SUMMARY_LENGTH`]文字
+    pub summary: String,
+}
+
+impl Display for ProxyError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} failed with {} from a proxy in front of the server: {}", self.endpoint, self.status, self.summary)
+    }
+}
+
+impl Error for ProxyError {}
+
+/// Cloudflareがブラウザでの確認を求めた。頼み直しても通らない
+#[derive(Debug)]
+pub struct Challenged {
+    pub endpoint: String,
+}
+
+impl Challenged {
+    pub fn message(&self) -> Message {
+        Message::new("cloudflare-challenge", &[("endpoint", &self.endpoint)])
+    }
+}
+
+impl Display for Challenged {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.message().render(Lang::En))
+    }
+}
+
+impl Error for Challenged {}
+
+/// [`ProxyError::summary`]に残す本文の長さ
+const SUMMARY_LENGTH: usize = 120;
+
+/// `Content-Type`があればそれで、無ければ本文の始まりで決める。
+fn is_json(headers: &ResponseHeaders, body: &str) -> bool {
+    headers.content_type.as_ref().map_or_else(
+        || !body.trim_start().starts_with('<'),
+        |x| x.split(';').next().is_some_and(|x| x.trim().ends_with("json")),
+    )
+}
+
+/// HTMLなら`<title>`の中身、無ければ空白をまとめた本文の始め
+fn summarize(body: &str) -> String {
+    let lower = body.to_ascii_lowercase();
+    let title = lower.find("<title>")
+        .map(|start| start + "<title>".len())
+        .and_then(|start| Some(&body[start..start + lower[start..].find("</title>")?]));
+    let text = title.filter(|x| !x.trim().is_empty()).unwrap_or(body);
+
+    text.split_whitespace().collect::<Vec<_>>().join(" ").chars().take(SUMMARY_LENGTH).collect()
+}
+
+async fn request<C: ApiClient, B: Serialize + Sync, R: DeserializeOwned>(client: &C, endpoint: &str, body: &B) -> Result<R, Box<dyn Error + Send + Sync>> {
+    let body = serde_json::to_value(body)?;
+    debug!("{endpoint} {body}");
+    let RawResponse { status, body: mut text, headers } = client.call(endpoint, body).await?;
+    // 204 No Contentは`null`として読む
+    if status == 204 {
+        "null".clone_into(&mut text);
+    }
+
+    if status == 403 && headers.cf_mitigated.is_some() {
+        return Err(Box::new(Challenged { endpoint: endpoint.to_owned() }))
+    }
+
+    if status != 204 && !is_json(&headers, &text) {
+        return Err(Box::new(ProxyError { endpoint: endpoint.to_owned(), status, summary: summarize(&text) }))
+    }
+
+    if !(200..300).contains(&status) {
+        if let Ok(ErrorBody { error }) = serde_json::from_str(&text) {
+            return Err(Box::new(ApiError {
+                endpoint: endpoint.to_owned(),
+                status,
+                code: error.code,
+                message: error.message,
+            }))
+        }
+
+        // JSONではあるが、Misskeyのエラーの形をしていないもの
+        return Err(Box::new(ApiError {
+            endpoint: endpoint.to_owned(),
+            status,
+            code: String::new(),
+            message: String::new(),
+        }))
+    }
+
+    serde_path_to_error::deserialize(&mut serde_json::de::Deserializer::from_str(&text)).map_err(|e| {
+        debug!("raw: {text}");
+        format!("failed to read the response of {endpoint} (status {status}): {e}").into()
+    })
+}
+
+/// どのタイムラインを辿るか
+#[derive(Eq, PartialEq, Clone, Debug, Serialize)]
+pub enum Timeline {
+    #[serde(rename = "channelId")]
+    Channel(ChannelId),
+    #[serde(rename = "listId")]
+    List(ListId),
+}
+
+impl Timeline {
+    const fn endpoint(&self) -> &'static str {
+        match self {
+            Self::Channel(_) => "channels/timeline",
+            Self::List(_) => "notes/user-list-timeline",
+        }
+    }
+
+    /// 記録に書くときの名前とID
+    pub fn field(&self) -> (&'static str, &str) {
+        match self {
+            Self::Channel(id) => ("channel_id", &id.0),
+            Self::List(id) => ("list_id", &id.0),
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct TimelineCommand {
+    #[serde(flatten)]
+    pub timeline: Timeline,
+    pub limit: NonZeroUsize,
+    #[serde(skip_serializing_if = "Option::is_none", rename = "sinceId")]
+    pub note_after: Option<NoteId>,
+    #[serde(skip_serializing_if = "Option::is_none", rename = "untilId")]
+    pub note_before: Option<NoteId>,
+    #[serde(skip_serializing_if = "Option::is_none", rename = "sinceDate")]
+    pub date_after: Option<UnixDateTime>,
+    #[serde(skip_serializing_if = "Option::is_none", rename = "untilDate")]
+    pub date_before: Option<UnixDateTime>,
+}
+
+/// タイムラインの1ページ
+pub struct TimelinePage {
+    pub notes: Vec<Note>,
+    /// サーバーが、負荷のために一部のノートを返せなかったかもしれないと示した
+    pub partial: bool,
+}
+
+/// 新しいMisskeyは、結果が欠けているかもしれないときに配列ではなく`{"notes": [...], "partial": true}`を返す。
+/// 応答の文字列から直接ノートにし、捨てるフィールドを含めた全体を[`serde_json::Value`]として持たない。
+impl<'de> Deserialize<'de> for TimelinePage {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct PageVisitor;
+
+        impl<'de> Visitor<'de> for PageVisitor {
+            type Value = TimelinePage;
+
+            fn expecting(&self, formatter: &mut Formatter) -> std::fmt::Result {
+                formatter.write_str("an array of notes or an object with notes")
+            }
+
+            fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+                let mut notes = Vec::with_capacity(seq.size_hint().unwrap_or(0));
+                while let Some(note) = seq.next_element()? {
+                    notes.push(note);
+                }
+
+                Ok(TimelinePage { notes, partial: false })
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Self::Value, A::Error> {
+                let mut notes = None;
+                let mut partial = false;
+                while let Some(key) = map.next_key::<String>()? {
+                    match key.as_str() {
+                        "notes" => notes = Some(map.next_value()?),
+                        "partial" => partial = map.next_value::<serde_json::Value>()? == true,
+                        _ => { map.next_value::<IgnoredAny>()?; }
+                    }
+                }
+
+                Ok(TimelinePage { notes: notes.ok_or_else(|| de::Error::missing_field("notes"))?, partial })
+            }
+        }
+
+        deserializer.deserialize_any(PageVisitor)
+    }
+}
+
+impl TimelineCommand {
+    /// 送った後も、`--emit-page-markers`で書くために持っておける
+    pub async fn send(&self, client: &impl ApiClient) -> Result<TimelinePage, Box<dyn Error + Send + Sync>> {
+        request(client, self.timeline.endpoint(), self).await
+    }
+}
+
+#[derive(Eq, PartialEq, Serialize)]
+pub struct NoteChildrenCommand {
+    #[serde(rename = "noteId")]
+    pub note_id: NoteId,
+    pub limit: NonZeroUsize,
+    #[serde(skip_serializing_if = "Option::is_none", rename = "untilId")]
+    pub note_before: Option<NoteId>,
+}
+
+impl NoteChildrenCommand {
+    /// 返信と引用を返す。
+    pub async fn send(self, client: &impl ApiClient) -> Result<Vec<Note>, Box<dyn Error + Send + Sync>> {
+        request(client, "notes/children", &self).await
+    }
+}
+
+#[derive(Eq, PartialEq, Serialize)]
+pub struct NoteShowCommand {
+    #[serde(rename = "noteId")]
+    pub note_id: NoteId,
+}
+
+impl NoteShowCommand {
+    pub async fn send(self, client: &impl ApiClient) -> Result<Note, Box<dyn Error + Send + Sync>> {
+        request(client, "notes/show", &self).await
+    }
+
+    /// 日時とチャンネルだけを読む。
+    pub async fn locate(self, client: &impl ApiClient) -> Result<NoteLocation, Box<dyn Error + Send + Sync>> {
+        request(client, "notes/show", &self).await
+    }
+}
+
+#[derive(Eq, PartialEq, Serialize)]
+pub struct NoteTranslateCommand {
+    #[serde(rename = "noteId")]
+    pub note_id: NoteId,
+    #[serde(rename = "targetLang")]
+    pub target_lang: String,
+}
+
+#[derive(Deserialize)]
+pub struct NoteTranslateResponse {
+    pub text: String,
+}
+
+impl NoteTranslateCommand {
+    /// 訳すものが無ければ[`None`]
+    pub async fn send(self, client: &impl ApiClient) -> Result<Option<NoteTranslateResponse>, Box<dyn Error + Send + Sync>> {
+        request(client, "notes/translate", &self).await
+    }
+}
+
+#[derive(Eq, PartialEq, Serialize)]
+pub struct UserDetailCommand {
+    #[serde(rename = "userId")]
+    pub id: UserId,
+}
+
+impl UserDetailCommand {
+    pub async fn send(self, client: &impl ApiClient) -> Result<DetailedUser, Box<dyn Error + Send + Sync>> {
+        request(client, "users/show", &self).await
+    }
+}
+
+/// `@alice@misskey.example`のような名前からユーザーを引く
+#[derive(Eq, PartialEq, Serialize)]
+pub struct UserByNameCommand {
+    pub username: String,
+    /// 無ければこのサーバーのユーザー
+    pub host: Option<String>,
+}
+
+impl UserByNameCommand {
+    pub async fn send(self, client: &impl ApiClient) -> Result<DetailedUser, Box<dyn Error + Send + Sync>> {
+        request(client, "users/show", &self).await
+    }
+}
+
+/// `users/followers`か`users/following`の1ページ
+#[derive(Eq, PartialEq, Serialize)]
+pub struct FollowListCommand {
+    #[serde(skip)]
+    pub endpoint: &'static str,
+    #[serde(rename = "userId")]
+    pub user_id: UserId,
+    pub limit: NonZeroUsize,
+    #[serde(skip_serializing_if = "Option::is_none", rename = "untilId")]
+    pub until_id: Option<FollowingId>,
+}
+
+impl FollowListCommand {
+    pub async fn send(self, client: &impl ApiClient) -> Result<Vec<Following>, Box<dyn Error + Send + Sync>> {
+        request(client, self.endpoint, &self).await
+    }
+}
+
+/// `channels/owned`か`channels/followed`の1ページ
+#[derive(Eq, PartialEq, Serialize)]
+pub struct ChannelListCommand {
+    #[serde(skip)]
+    pub endpoint: &'static str,
+    pub limit: NonZeroUsize,
+}
+
+impl ChannelListCommand {
+    pub async fn send(self, client: &impl ApiClient) -> Result<Vec<Channel>, Box<dyn Error + Send + Sync>> {
+        request(client, self.endpoint, &self).await
+    }
+}
+
+/// `mute/list`か`blocking/list`の1ページ。トークンのアカウントのものを新しい順に返す
+#[derive(Eq, PartialEq, Serialize)]
+pub struct HidingListCommand {
+    #[serde(skip)]
+    pub endpoint: &'static str,
+    pub limit: NonZeroUsize,
+    #[serde(skip_serializing_if = "Option::is_none", rename = "untilId")]
+    pub until_id: Option<HidingId>,
+}
+
+impl HidingListCommand {
+    pub async fn send(self, client: &impl ApiClient) -> Result<Vec<Hiding>, Box<dyn Error + Send + Sync>> {
+        request(client, self.endpoint, &self).await
+    }
+}
+
+#[derive(Serialize)]
+pub struct MeCommand {}
+
+impl MeCommand {
+    pub async fn send(self, client: &impl ApiClient) -> Result<Account, Box<dyn Error + Send + Sync>> {
+        request(client, "i", &self).await
+    }
+}
+
+#[derive(Serialize)]
+pub struct ChannelShowCommand {
+    #[serde(rename = "channelId")]
+    pub channel_id: ChannelId,
+}
+
+impl ChannelShowCommand {
+    pub async fn send(self, client: &impl ApiClient) -> Result<Channel, Box<dyn Error + Send + Sync>> {
+        request(client, "channels/show", &self).await
+    }
+}
+
+/// `notes/reactions`の1ページ。新しい順に返ってくる
+#[derive(Eq, PartialEq, Serialize)]
+pub struct NoteReactionsCommand {
+    #[serde(rename = "noteId")]
+    pub note_id: NoteId,
+    pub limit: NonZeroUsize,
+    #[serde(skip_serializing_if = "Option::is_none", rename = "untilId")]
+    pub until_id: Option<ReactionId>,
+}
+
+impl NoteReactionsCommand {
+    pub async fn send(self, client: &impl ApiClient) -> Result<Vec<Reaction>, Box<dyn Error + Send + Sync>> {
+        request(client, "notes/reactions", &self).await
+    }
+}
+
+/// `notes/renotes`の1ページ。新しい順に返ってくる
+#[derive(Eq, PartialEq, Serialize)]
+pub struct NoteRenotesCommand {
+    #[serde(rename = "noteId")]
+    pub note_id: NoteId,
+    pub limit: NonZeroUsize,
+    #[serde(skip_serializing_if = "Option::is_none", rename = "untilId")]
+    pub until_id: Option<NoteId>,
+}
+
+impl NoteRenotesCommand {
+    pub async fn send(self, client: &impl ApiClient) -> Result<Vec<Renote>, Box<dyn Error + Send + Sync>> {
+        request(client, "notes/renotes", &self).await
+    }
+}
+
+/// 権限があるかを確かめるため、`endpoint`を1件だけ頼む。返ってきたものは読まない
+pub struct ProbeCommand<'a> {
+    pub endpoint: &'a str,
+}
+
+impl ProbeCommand<'_> {
+    pub async fn send(&self, client: &impl ApiClient) -> Result<(), Box<dyn Error + Send + Sync>> {
+        request::<_, _, serde::de::IgnoredAny>(client, self.endpoint, &serde_json::json!({ "limit": 1 })).await.map(|_| ())
+    }
+}
+
+#[derive(Serialize)]
+pub struct UserListShowCommand {
+    #[serde(rename = "listId")]
+    pub list_id: ListId,
+}
+
+impl UserListShowCommand {
+    pub async fn send(self, client: &impl ApiClient) -> Result<UserList, Box<dyn Error + Send + Sync>> {
+        request(client, "users/lists/show", &self).await
+    }
+}
+
+/// `channels/featured`。最近よく書かれているチャンネル
+#[derive(Serialize)]
+pub struct FeaturedChannelsCommand {}
+
+impl FeaturedChannelsCommand {
+    pub async fn send(self, client: &impl ApiClient) -> Result<Vec<Channel>, Box<dyn Error + Send + Sync>> {
+        request(client, "channels/featured", &self).await
+    }
+}
+
+/// `hashtags/trend`。普段より多く使われているハッシュタグ
+#[derive(Serialize)]
+pub struct TrendingTagsCommand {}
+
+impl TrendingTagsCommand {
+    pub async fn send(self, client: &impl ApiClient) -> Result<Vec<TrendingTag>, Box<dyn Error + Send + Sync>> {
+        request(client, "hashtags/trend", &self).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use crate::api::{is_transient, request, required_permission, Challenged, ExtraHeader, MisskeyAuthorizationToken, ProxyError, ResponseHeaders, TimelinePage, WithTokenRef};
+    use crate::capture::{Exchange, ReplayClient};
+    use crate::miauth::PERMISSIONS;
+    use crate::model::Note;
+    use crate::status::Outcome;
+    use crate::testing::{capture_dir, note_json, peak_allocation};
+
+    /// nginxが上流に繋がらなかったときの既定のページ
+    const NGINX_502: &str = "<html>\r\n<head><title>502 Bad Gateway</title></head>\r\n<body>\r\n<center><h1>502 Bad Gateway</h1></center>\r\n\
+        <hr><center>nginx/1.24.0</center>\r\n</body>\r\n</html>\r\n";
+
+    /// Cloudflareがオリジンに繋がらなかったときのページの始め
+    const CLOUDFLARE_522: &str = r#"<!DOCTYPE html>
+<!--[if lt IE 7]> <html class="no-js ie6 oldie" lang="en-US"> <![endif]-->
+<!--[if gt IE 8]><!--> <html class="no-js" lang="en-US"> <!--<![endif]-->
+<head>
+<title>misskey.example | 522: Connection timed out</title>
+<meta charset="UTF-8" />
+<meta http-equiv="Content-Type" content="text/html; charset=UTF-8" />
+</head>
+<body><div id="cf-wrapper"><h1><span class="inline-block">Connection timed out</span> <span class="code-label">Error code 522</span></h1></div></body>
+</html>"#;
+
+    /// Cloudflareのブラウザの確認。`cf-mitigated: challenge`が付く
+    const CLOUDFLARE_CHALLENGE: &str = r#"<!DOCTYPE html><html lang="en-US"><head><title>Just a moment...</title>
+<meta http-equiv="Content-Type" content="text/html; charset=UTF-8"><noscript><div class="h2"><span id="challenge-error-text">Enable JavaScript and cookies to continue</span></div></noscript>
+</head><body><div class="main-wrapper" role="main"><div class="main-content"></div></div></body></html>"#;
+
+    fn page(status: u16, content_type: Option<&str>, cf_mitigated: Option<&str>, response: &str) -> Exchange {
+        Exchange {
+            endpoint: "i".to_owned(),
+            request: json!({}),
+            status,
+            response: response.to_owned(),
+            headers: ResponseHeaders { content_type: content_type.map(str::to_owned), cf_mitigated: cf_mitigated.map(str::to_owned) },
+        }
+    }
+
+    #[tokio::test]
+    async fn proxy_error_pages_are_summarized_and_retried() {
+        let dir = capture_dir("proxy-pages", &[
+            page(502, Some("text/html"), None, NGINX_502),
+            page(522, Some("text/html; charset=UTF-8"), None, CLOUDFLARE_522),
+            // ヘッダーが無くても、本文がHTMLなら同じに扱う
+            page(200, None, None, "<html><body>Service temporarily down for maintenance. We will be back soon, please check the status page for updates on the progress of the work</body></html>"),
+            page(200, Some("application/json; charset=utf-8"), None, "{}"),
+        ]);
+        let client = ReplayClient::open(&dir).unwrap();
+
+        let mut summaries = vec![];
+        for _ in 0..3 {
+            let e = request::<_, _, serde_json::Value>(&client, "i", &json!({})).await.unwrap_err();
+            assert!(is_transient(&*e), "{e}");
+            assert_eq!(Outcome::classify(&*e), Outcome::Network);
+            summaries.push(e.downcast::<ProxyError>().unwrap().summary);
+        }
+        assert_eq!(summaries[0], "502 Bad Gateway");
+        assert_eq!(summaries[1], "misskey.example | 522: Connection timed out");
+        assert_eq!(summaries[2].chars().count(), 120);
+        assert!(summaries[2].starts_with("<html><body>Service temporarily down"));
+        assert_eq!(request::<_, _, serde_json::Value>(&client, "i", &json!({})).await.unwrap(), json!({}));
+    }
+
+    #[tokio::test]
+    async fn cloudflare_challenge_is_not_retried() {
+        let dir = capture_dir("cloudflare-challenge", &[page(403, Some("text/html; charset=UTF-8"), Some("challenge"), CLOUDFLARE_CHALLENGE)]);
+        let client = ReplayClient::open(&dir).unwrap();
+
+        let e = request::<_, _, serde_json::Value>(&client, "i", &json!({})).await.unwrap_err();
+
+        assert!(e.is::<Challenged>());
+        assert!(!is_transient(&*e));
+        assert!(e.to_string().contains("blocks clients that are not browsers"), "{e}");
+    }
+
+    #[test]
+    fn large_pages_are_read_without_an_intermediate_value() {
+        let notes: Vec<_> = (0..60).map(|i| {
+            let mut note = note_json(&format!("n{i:02}"), "2024-01-01T00:00:00.000Z");
+            note["reactions"] = (0..300).map(|x| (format!(":emoji{x}@.:"), json!(x + 1))).collect();
+            // `properties`は書き出さない
+            note["files"] = (0..20).map(|x| json!({ "id": format!("f{x}"), "url": format!("https://misskey.example/files/{x}"), "properties": { "width": 1920, "height": 1080 } })).collect();
+            note
+        }).collect();
+        let body = json!(notes).to_string();
+
+        let (page, streamed) = peak_allocation(|| serde_json::from_str::<TimelinePage>(&body).unwrap());
+        let (through_value, buffered) = peak_allocation(|| {
+            let value: serde_json::Value = serde_json::from_str(&body).unwrap();
+            serde_json::from_value::<Vec<Note>>(value).unwrap()
+        });
+
+        assert_eq!(page.notes.len(), 60);
+        assert!(!page.partial);
+        assert_eq!(serde_json::to_string(&page.notes).unwrap(), serde_json::to_string(&through_value).unwrap());
+        assert!(streamed * 2 < buffered, "{streamed} bytes at peak, {buffered} through Value");
+
+        let wrapped = json!({ "partial": true, "notes": [note_json("n1", "2024-01-01T00:00:00.000Z")], "extra": [1] }).to_string();
+        let page: TimelinePage = serde_json::from_str(&wrapped).unwrap();
+        assert_eq!((page.notes.len(), page.partial), (1, true));
+        assert!(serde_json::from_str::<TimelinePage>(r#"{"partial": true}"#).is_err());
+    }
+
+    #[test]
+    fn raw_token_appears_only_in_request_body() {
+        const TOKEN: &str = "sometokenhere";
+        let token: MisskeyAuthorizationToken = format!(" {TOKEN}\n").parse().unwrap();
+        let body = json!({ "channelId": "ch" });
+        let wtr = WithTokenRef { token: Some(&token), body: &body };
+
+        assert_eq!(serde_json::to_string(&wtr).unwrap().matches(TOKEN).count(), 1);
+        assert!(!format!("{token:?}").contains(TOKEN));
+        assert!(!body.to_string().contains(TOKEN));
+    }
+
+    #[test]
+    fn extra_header_is_parsed_and_redacted() {
+        let header: ExtraHeader = "Authorization: Bearer proxysecret".parse().unwrap();
+        assert_eq!(header.name, "authorization");
+        assert_eq!(header.value, "Bearer proxysecret");
+        assert_eq!(format!("{header:?}"), "authorization: *****");
+
+        assert!("no separator".parse::<ExtraHeader>().is_err());
+        assert!("Bad Name: x".parse::<ExtraHeader>().is_err());
+        assert!("X-Waf: line\nbreak".parse::<ExtraHeader>().is_err());
+    }
+
+    #[test]
+    fn every_permission_is_requested_by_miauth() {
+        let public = [
+            "channels/show", "channels/timeline", "channels/featured", "hashtags/trend",
+            "notes/show", "notes/children", "notes/renotes", "notes/reactions",
+            "users/show", "users/followers", "users/following",
+        ];
+        for endpoint in public {
+            assert_eq!(required_permission(endpoint), None);
+        }
+        for endpoint in ["i", "notes/translate", "notes/user-list-timeline", "users/lists/show", "mute/list", "blocking/list", "channels/owned", "channels/followed"] {
+            assert!(required_permission(endpoint).is_some_and(|x| PERMISSIONS.contains(&x)), "{endpoint}");
+        }
+    }
+}
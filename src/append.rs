@@ -0,0 +1,91 @@
+//! `archive --append`。前の実行で書いたファイルに書き足し、既に書いたノートは書かない。
+//!
+//! 始める前にファイルを1行ずつ読み、ノートのIDだけを覚える。`--append-index`があれば、ファイルの代わりにそこからIDを読み、
+//! 終わったときに書き直すので、次の実行では大きなファイルを読み直さずに済む。索引を消せば、次の実行でファイルから作り直す。
+
+use std::collections::HashSet;
+use std::fs;
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+
+use serde_json::Value;
+
+use crate::model::Note;
+use crate::output::temporary_path;
+use crate::reader;
+
+pub struct Seen {
+    ids: HashSet<String>,
+    index: Option<PathBuf>,
+    /// 既に書いてあったので書かなかったノートの数
+    skipped: usize,
+}
+
+impl Seen {
+    /// `index`があればそこから、無ければ`output`から読む。どちらも無ければ、何も書いていないものとして始める。
+    pub fn open(output: Option<&Path>, index: Option<&Path>) -> io::Result<Self> {
+        let ids = match (index, output) {
+            (Some(index), _) if index.exists() => read_index(index)?,
+            (_, Some(output)) if output.exists() => scan(output)?,
+            _ => HashSet::new(),
+        };
+
+        Ok(Self { ids, index: index.map(Path::to_path_buf), skipped: 0 })
+    }
+
+    /// まだ書いていないノートだけを返し、書いたものとして覚える。
+    pub fn retain<'a>(&mut self, notes: &'a [Note]) -> Vec<&'a Note> {
+        let fresh: Vec<_> = notes.iter().filter(|x| self.ids.insert(x.id.0.clone())).collect();
+        self.skipped += notes.len() - fresh.len();
+
+        fresh
+    }
+
+    pub const fn skipped(&self) -> usize {
+        self.skipped
+    }
+
+    /// `--append-index`があれば、この実行で書いたものも含めて書き直す。途中で落ちても前の索引は壊さない。
+    pub fn finish(&self) -> io::Result<()> {
+        let Some(index) = &self.index else {
+            return Ok(())
+        };
+        let mut ids: Vec<_> = self.ids.iter().collect();
+        ids.sort_unstable();
+        let mut file = io::BufWriter::new(fs::File::create(temporary_path(index))?);
+        for id in ids {
+            writeln!(file, "{id}")?;
+        }
+        file.into_inner().map_err(io::IntoInnerError::into_error)?.sync_all()?;
+
+        fs::rename(temporary_path(index), index)
+    }
+}
+
+/// 1行に1つのID
+fn read_index(path: &Path) -> io::Result<HashSet<String>> {
+    let file = BufReader::new(fs::File::open(path)?);
+    let mut ids = HashSet::new();
+    for line in file.lines() {
+        let line = line?;
+        let id = line.trim();
+        if !id.is_empty() {
+            ids.insert(id.to_owned());
+        }
+    }
+
+    Ok(ids)
+}
+
+/// `--field-map`で変えた名前も戻して読む
+fn scan(path: &Path) -> io::Result<HashSet<String>> {
+    let mut ids = HashSet::new();
+    reader::for_each_note_value(path, |_, note| {
+        if let Some(id) = note.get("id").and_then(Value::as_str) {
+            ids.insert(id.to_owned());
+        }
+        Ok(())
+    }).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("failed to read the notes already in {}: {e}", path.display())))?;
+
+    Ok(ids)
+}
@@ -0,0 +1,2704 @@
+//! `archive`サブコマンドの本体。
+
+use std::collections::HashSet;
+use std::error::Error;
+use std::fs;
+use std::io::{self, Write};
+use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use tokio::sync::Semaphore;
+use tokio::task::JoinSet;
+
+use crate::api::{self, is_transient, ApiClient, ApiError, ChannelShowCommand, NoteChildrenCommand, NoteShowCommand, Timeline, TimelineCommand, UserListShowCommand};
+use crate::clock;
+use crate::content_hash::{self, HashLog};
+use crate::deletion::DeletionCheck;
+use crate::filename::UniqueNames;
+use crate::host::Host;
+use crate::i18n::{self, msg, Lang, Message};
+use crate::language;
+use crate::log::{info, progress};
+use crate::model::{Account, Channel, ChannelId, ListId, Note, NoteId};
+use crate::note_set::NoteSet;
+use crate::manifest;
+use crate::metrics::{self, Metrics};
+use crate::output::{self, Destination, Layout, OutputOptions};
+use crate::sink::PipeClosed;
+use crate::pagination::{self, Cursor, Direction, Pagination};
+use crate::pacer::Pacer;
+use crate::renotes::Checkpoint;
+use crate::preflight::{self, authenticate, check_permissions, check_range, estimate_requests, estimate_run_time, HiddenUsers};
+use crate::timestamp::Formatted;
+use crate::translate::Translator;
+
+pub const PAGE_SIZE: NonZeroUsize = NonZeroUsize::new(60).unwrap();
+
+/// 小さくしたページの大きさを元に戻すまでに、続けて成功する回数
+const RESTORE_AFTER: usize = 3;
+
+/// サーバーが大きなページを返しきれないときに、`limit`を一時的に小さくする。
+struct AdaptivePageSize {
+    configured: NonZeroUsize,
+    current: NonZeroUsize,
+    successes: usize,
+}
+
+impl AdaptivePageSize {
+    const fn new(configured: NonZeroUsize) -> Self {
+        Self { configured, current: configured, successes: 0 }
+    }
+
+    /// 半分にする。既に1なら`None`。
+    fn shrink(&mut self) -> Option<NonZeroUsize> {
+        self.successes = 0;
+        self.current = NonZeroUsize::new(self.current.get() / 2)?;
+
+        Some(self.current)
+    }
+
+    /// 元の大きさに戻したら`true`
+    fn succeeded(&mut self) -> bool {
+        if self.current == self.configured {
+            return false
+        }
+
+        self.successes += 1;
+        if self.successes < RESTORE_AFTER {
+            return false
+        }
+
+        self.current = self.configured;
+        self.successes = 0;
+        true
+    }
+}
+
+/// 全てのチャンネルで共通の設定
+#[derive(Clone)]
+#[allow(clippy::struct_excessive_bools)]
+pub struct ArchiveOptions {
+    pub before: Option<NoteId>,
+    pub after: Option<NoteId>,
+    pub cool_down: Duration,
+    pub dry_run: bool,
+    pub fail_fast: bool,
+    pub parallel_channels: NonZeroUsize,
+    pub output: OutputOptions,
+    /// 書き終えたファイルのハッシュをここに書き出す
+    pub manifest: Option<PathBuf>,
+    /// あれば、遡ったノートへの返信をこの段数まで辿る
+    pub reply_depth: Option<NonZeroUsize>,
+    /// あれば、返信を全て書き出した親をここに書き足し、次の実行では飛ばす
+    pub reply_checkpoint: Option<PathBuf>,
+    /// チャンネルの説明やピン留めされたノートも書き出す
+    pub with_channel_info: bool,
+    /// あれば、このホストから見たノートとユーザーのURLを書き出す
+    pub note_urls: Option<Host>,
+    /// ノートの作者の名前やアイコンも書き出す
+    pub inline_user_detail: bool,
+    /// 頼んだ範囲の外のノートを取り除く
+    pub range_filter: bool,
+    pub direction: Direction,
+    /// あれば、本文のあるノートをこの言語に訳す
+    pub translate: Option<Translator>,
+    /// あれば、書き出したノートの数を足していく
+    pub metrics: Option<Arc<Metrics>>,
+    /// あれば、`notesCount`よりこれを超えて少なかったチャンネルを欠けたものとして扱う
+    pub max_missing_notes: Option<usize>,
+    /// `--before`と`--after`の前後を`notes/show`で確かめない
+    pub force_range: bool,
+    /// あれば、それぞれのノートのハッシュを埋め込み、ここにも書き出す
+    pub hash_notes: Option<Arc<HashLog>>,
+    /// 結果が欠けているかもしれないページを頼み直す回数
+    pub partial_retries: u32,
+    /// 頼み直すたびに、これだけ長く待つ
+    pub partial_backoff: Duration,
+    /// あれば、それぞれのノートのリアクションを数の多いこの種類だけにする
+    pub max_reactions: Option<NonZeroUsize>,
+    /// あれば、前の実行のノートのうち今回見えなかったものを消えたものとして記録する
+    pub detect_deletions: Option<Arc<DeletionCheck>>,
+    /// 遡り始める前に、トークンのアカウントがミュートかブロックしているユーザーを引く
+    pub check_hidden_users: bool,
+    /// `check_hidden_users`で引いたユーザー。そのユーザーのノートには`hidden_author`を付ける
+    pub hidden_users: Option<Arc<HiddenUsers>>,
+    /// それぞれのページの前に、それを取ったリクエストを`page`の記録として書く
+    pub page_markers: bool,
+}
+
+/// 1チャンネル分の結果
+pub struct ChannelSummary {
+    pub notes: usize,
+    pub pages: usize,
+    /// チャンネルの外から取ってきた返信の数
+    pub replies: usize,
+    /// `--detect-deletions`で消えたものとして記録したノートの数
+    pub tombstones: usize,
+    /// 全てを遡ったときだけ比べる
+    pub notes_count: Option<NotesCount>,
+    /// 最初のページが空だったときの理由
+    pub empty_reason: Option<EmptyReason>,
+}
+
+/// 1つもノートを書き出さなかった理由
+#[derive(Eq, PartialEq, Copy, Clone, Debug)]
+pub enum EmptyReason {
+    /// 範囲の中には無いが、範囲の外には見えるノートがある
+    NoNewNotes,
+    /// 範囲を付けなくても1つも見えない
+    ChannelEmpty,
+    /// 確かめるリクエストが失敗した
+    Unknown,
+}
+
+impl EmptyReason {
+    const fn name(self) -> &'static str {
+        match self {
+            Self::NoNewNotes => "no-new-notes",
+            Self::ChannelEmpty => "channel-empty",
+            Self::Unknown => "unknown",
+        }
+    }
+}
+
+/// `channels/show`の`notesCount`。始める前と、終わった後に取得する
+pub struct NotesCount {
+    pub before: usize,
+    /// 取得できなければ無い
+    pub after: Option<usize>,
+}
+
+impl ChannelSummary {
+    /// 始める前の`notesCount`より少なかった数
+    fn missing(&self) -> Option<usize> {
+        self.notes_count.as_ref().map(|x| x.before.saturating_sub(self.notes))
+    }
+
+    /// 書き出した数から始める前の`notesCount`を引いたもの。負なら、その数だけ足りない
+    fn delta(&self) -> Option<i64> {
+        let signed = |n: usize| i64::try_from(n).unwrap_or(i64::MAX);
+        self.notes_count.as_ref().map(|x| signed(self.notes).saturating_sub(signed(x.before)))
+    }
+}
+
+/// `channels/show`で得たチャンネルを遡って、ノートを`out`に書き出す。
+pub async fn archive_channel(
+    client: &impl ApiClient,
+    pacer: &Pacer,
+    out: &mut Destination,
+    channel: Channel,
+    account: &Account,
+    options: &ArchiveOptions,
+) -> Result<ChannelSummary, Box<dyn Error + Send + Sync>> {
+    let channel_id = &channel.id;
+    writeln!(out, "{}", serde_json::json!({
+        "kind": "log",
+        "message": format!("archiving channel {}", channel.name),
+    }))?;
+    writeln!(out, "{}", serde_json::json!({
+        "kind": "relationship",
+        "channel_id": channel_id,
+        "account_id": account.id,
+        "following": channel.is_following,
+        "owner": channel.user_id.as_ref().map(|owner| *owner == account.id),
+    }))?;
+
+    if options.with_channel_info {
+        writeln!(out, "{}", serde_json::json!({
+            "kind": "channel",
+            "channel": channel,
+        }))?;
+
+        for note_id in &channel.pinned_note_ids {
+            pacer.wait().await;
+            // 消されたか見えなくなったピン留めのために、チャンネル全体は止めない
+            let mut note = match (NoteShowCommand { note_id: note_id.clone() }).send(client).await {
+                Ok(note) => note,
+                Err(e) if metrics::is_exhausted(&*e) || api::is_token_rejected(&*e) => return Err(e),
+                Err(e) => {
+                    writeln!(out, "{}", serde_json::json!({
+                        "kind": "warning",
+                        "note_id": note_id,
+                        "message": format!("failed to fetch a pinned note: {e}"),
+                    }))?;
+                    continue
+                }
+            };
+            note.channel_id = Some(channel_id.clone());
+            fill_optional_fields(client, pacer, out, std::slice::from_mut(&mut note), options).await?;
+            out.write_note("pinned-note", channel_id, &note)?;
+        }
+    }
+
+    let timeline = Timeline::Channel(channel_id.clone());
+    let mut summary = archive_timeline(client, pacer, out, &timeline, &channel.name, channel.notes_count, options).await?;
+
+    if !options.dry_run && options.before.is_none() && options.after.is_none() {
+        compare_notes_count(client, pacer, out, &channel, &mut summary).await?;
+    }
+
+    Ok(summary)
+}
+
+/// `timeline`を辿って、ノートを`out`に書き出す。求められていれば返信も辿る。
+/// `notes_count`は、dry-runで所要時間を見積もるのに使う。
+async fn archive_timeline(
+    client: &impl ApiClient,
+    pacer: &Pacer,
+    out: &mut Destination,
+    timeline: &Timeline,
+    label: &str,
+    notes_count: Option<usize>,
+    options: &ArchiveOptions,
+) -> Result<ChannelSummary, Box<dyn Error + Send + Sync>> {
+    let mut summary = ChannelSummary { notes: 0, pages: 0, replies: 0, tombstones: 0, notes_count: None, empty_reason: None };
+    let mut seen = NoteSet::new();
+    let mut parents = vec![];
+    let mut walk = pagination::new(options.direction, options.before.clone(), options.after.clone());
+    let mut page_size = AdaptivePageSize::new(PAGE_SIZE);
+    // 前のページを書き出す間に頼んでおいた次のページと、その間に書くはずだった記録
+    let mut prefetched = None;
+
+    loop {
+        // ここまでのページは書き終えている
+        out.set_resume(Some(gap_record(&walk.command(timeline, page_size.current), walk.cursor(), options.direction, &PipeClosed)));
+        let (log, fetched) = if let Some(prefetched) = prefetched.take() {
+            prefetched
+        } else {
+            let mut log = vec![];
+            let fetched = fetch_page(client, pacer, &mut log, &mut page_size, options, |limit| walk.command(timeline, limit)).await;
+            (log, fetched)
+        };
+        out.write_all(&log)?;
+        let Fetched { notes: mut result, params } = match fetched {
+            Ok(fetched) => fetched,
+            Err(e) => {
+                writeln!(out, "{}", gap_record(&walk.command(timeline, page_size.current), walk.cursor(), options.direction, &*e))?;
+                return Err(became_unavailable(timeline, &summary, e))
+            }
+        };
+
+        if options.dry_run {
+            writeln!(out, "{}", dry_run_record(&result, timeline, notes_count, options.cool_down))?;
+            break
+        }
+
+        if result.is_empty() {
+            if summary.pages == 0 {
+                summary.empty_reason = Some(explain_empty(client, pacer, timeline, options).await);
+            }
+            break
+        }
+
+        let returned = result.len();
+        let duplicates;
+        (result, duplicates) = drop_duplicates(out, result, &seen)?;
+        if result.is_empty() {
+            // 同じページを返し続けるサーバーもあるので、先へ進めなければ止める
+            if walk.skip(&duplicates) {
+                continue
+            }
+            let message = "every note on the page was already archived and the page did not advance; stopping";
+            writeln!(out, "{}", serde_json::json!({ "kind": "log", "message": message }))?;
+            break
+        }
+        if options.range_filter {
+            result = drop_out_of_range(out, result, &*walk, &mut seen)?;
+            if result.is_empty() {
+                writeln!(out, "{}", serde_json::json!({
+                    "kind": "log",
+                    "message": "every note on the page was outside the requested range; stopping",
+                }))?;
+                break
+            }
+        }
+
+        check_page(out, &mut result, timeline, options.hidden_users.as_deref())?;
+        fill_optional_fields(client, pacer, out, &mut result, options).await?;
+
+        seen.extend(result.iter().map(|x| x.id.clone()));
+        if options.reply_depth.is_some() {
+            parents.extend(result.iter().filter(|x| x.reply_count > 0).map(|x| x.id.clone()));
+        }
+
+        summary.notes += result.len();
+        summary.pages += 1;
+        if let Some(metrics) = &options.metrics {
+            metrics.record_notes(result.len());
+        }
+        walk.advance(&mut result);
+        if options.output.canonical {
+            // APIの返す順序には頼らない
+            match options.direction {
+                Direction::Backward => result.sort_by(|a, b| b.id.0.cmp(&a.id.0)),
+                Direction::Forward => result.sort_by(|a, b| a.id.0.cmp(&b.id.0)),
+            }
+        }
+
+        // 次のページの`untilId`はもう決まっているので、このページを書き出す間に頼んでおく。
+        // 最後のページでも、次の空のページは頼むことになるので、余分なリクエストは増えない
+        let mut log = vec![];
+        let next = fetch_page(client, pacer, &mut log, &mut page_size, options, |limit| walk.command(timeline, limit));
+        let write = async {
+            let cursor = walk.cursor().expect("advanced on a non-empty page");
+            writeln!(out, "{}", serde_json::json!({ "kind": "log", "message": format!("proceeded by {}", cursor.id.0), "cursor": cursor }))?;
+            if options.page_markers {
+                writeln!(out, "{}", serde_json::json!({ "kind": "page", "params": params, "returned": returned, "new": result.len() }))?;
+            }
+            out.write_page(&result)?;
+            out.flush()
+        };
+        let (next, written) = tokio::join!(next, write);
+        written?;
+        prefetched = Some((log, next));
+        progress!("{label}: {} notes", summary.notes);
+    }
+    out.set_resume(None);
+
+    if let (Some(check), Timeline::Channel(channel_id)) = (options.detect_deletions.as_deref().filter(|_| !options.dry_run), timeline) {
+        let format = options.output.timestamp_format;
+        summary.tombstones = check.detect(client, pacer, out, channel_id, options.before.as_ref(), options.after.as_ref(), &seen, format).await?;
+    }
+
+    if let Some(depth) = options.reply_depth.filter(|_| !options.dry_run) {
+        summary.replies = archive_replies(client, pacer, out, parents, &mut seen, depth, options).await?;
+    }
+
+    Ok(summary)
+}
+
+/// 最初のページから、全てを遡るのにかかる時間を見積もる
+fn dry_run_record(notes: &[Note], timeline: &Timeline, notes_count: Option<usize>, cool_down: Duration) -> serde_json::Value {
+    let oldest = notes.iter().map(|x| x.created_at).min();
+    let estimate = notes_count
+        .map(|n| estimate_run_time(n, PAGE_SIZE, cool_down).as_secs());
+    let (field, id) = timeline.field();
+    let mut record = serde_json::json!({
+        "kind": "dry-run",
+        "notes": notes.len(),
+        "oldest": oldest,
+        "channel_notes_count": notes_count,
+        "estimated_seconds": estimate,
+        "estimated_requests": notes_count.map(|n| estimate_requests(n, PAGE_SIZE)),
+    });
+    record[field] = id.into();
+
+    record
+}
+
+/// 取得できなかった範囲。`backfill`がこれだけを読んで、同じ範囲を頼み直せるようにする。
+/// `cursor`はそれまでに進んだ位置で、`until_id`か`since_id`のノートの日時を読む人のために添える。
+fn gap_record(command: &TimelineCommand, cursor: Option<&Cursor>, direction: Direction, error: &(dyn Error + Send + Sync)) -> serde_json::Value {
+    let (field, id) = command.timeline.field();
+    let mut record = serde_json::json!({
+        "kind": "gap",
+        "direction": direction.name(),
+        "until_id": command.note_before,
+        "since_id": command.note_after,
+        "cursor": cursor,
+        "limit": command.limit,
+        "error": error.to_string(),
+    });
+    record[field] = id.into();
+
+    record
+}
+
+/// 最初のページが空なら、範囲を付けずに1つだけ頼み、そもそもノートが見えるのかを確かめる。
+/// 権限が足りずに空が返ってきたのと、新しいノートが無いのとを分けるため。
+async fn explain_empty(client: &impl ApiClient, pacer: &Pacer, timeline: &Timeline, options: &ArchiveOptions) -> EmptyReason {
+    if options.before.is_none() && options.after.is_none() {
+        return EmptyReason::ChannelEmpty
+    }
+
+    pacer.wait().await;
+    let probe = TimelineCommand {
+        timeline: timeline.clone(),
+        limit: NonZeroUsize::MIN,
+        note_after: None,
+        note_before: None,
+        date_after: None,
+        date_before: None,
+    };
+    match probe.send(client).await {
+        Ok(page) if page.notes.is_empty() => EmptyReason::ChannelEmpty,
+        Ok(_) => EmptyReason::NoNewNotes,
+        Err(_) => EmptyReason::Unknown,
+    }
+}
+
+/// 全てを遡ったときに、書き出した数を`notesCount`と比べる。足りなければ`warning`として記録する。
+async fn compare_notes_count(
+    client: &impl ApiClient,
+    pacer: &Pacer,
+    out: &mut Destination,
+    channel: &Channel,
+    summary: &mut ChannelSummary,
+) -> io::Result<()> {
+    let Some(before) = channel.notes_count else {
+        return Ok(())
+    };
+    // 取れなくても、始める前の数で比べる
+    pacer.wait().await;
+    let after = ChannelShowCommand { channel_id: channel.id.clone() }.send(client).await.ok().and_then(|x| x.notes_count);
+    summary.notes_count = Some(NotesCount { before, after });
+
+    if let Some(missing) = summary.missing().filter(|n| *n > 0) {
+        let message = Message::new("notes-count-shortfall", &[("notes", &summary.notes), ("missing", &missing)]);
+        info!("{}: {message}", channel.name);
+        writeln!(out, "{}", serde_json::json!({
+            "kind": "warning",
+            "channel_id": channel.id,
+            "message": message.render(Lang::En),
+        }))?;
+    }
+
+    Ok(())
+}
+
+/// `notesCount`より`--max-missing-notes`を超えて少なかった
+#[derive(Debug)]
+pub struct Incomplete {
+    pub channel_id: ChannelId,
+    pub missing: usize,
+}
+
+impl Incomplete {
+    pub fn message(&self) -> Message {
+        Message::new("incomplete", &[("channel", &self.channel_id.0), ("missing", &self.missing)])
+    }
+}
+
+impl std::fmt::Display for Incomplete {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.message().render(Lang::En))
+    }
+}
+
+impl Error for Incomplete {}
+
+/// 遡っている途中で、チャンネルが消えたかアーカイブされた。それまでに書いたノートと、続きを頼むための`gap`は残してある
+#[derive(Debug)]
+pub struct ChannelUnavailable {
+    pub channel_id: ChannelId,
+    /// Misskeyが返したエラーコード
+    pub code: String,
+    pub notes: usize,
+    pub pages: usize,
+}
+
+impl ChannelUnavailable {
+    /// Misskeyは、消えたチャンネルにもアーカイブされたチャンネルにもこれを返す
+    const CODES: [&str; 1] = ["NO_SUCH_CHANNEL"];
+
+    pub fn message(&self) -> Message {
+        Message::new("channel-unavailable", &[("channel", &self.channel_id.0), ("pages", &self.pages), ("code", &self.code)])
+    }
+}
+
+impl std::fmt::Display for ChannelUnavailable {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.message().render(Lang::En))
+    }
+}
+
+impl Error for ChannelUnavailable {}
+
+/// チャンネルが無くなったことを示すエラーなら、[`ChannelUnavailable`]にする。他はそのまま返す
+fn became_unavailable(timeline: &Timeline, summary: &ChannelSummary, e: Box<dyn Error + Send + Sync>) -> Box<dyn Error + Send + Sync> {
+    match (timeline, e.downcast_ref::<ApiError>()) {
+        (Timeline::Channel(channel_id), Some(api)) if ChannelUnavailable::CODES.contains(&api.code.as_str()) => Box::new(ChannelUnavailable {
+            channel_id: channel_id.clone(),
+            code: api.code.clone(),
+            notes: summary.notes,
+            pages: summary.pages,
+        }),
+        _ => e,
+    }
+}
+
+fn check_completeness(channel_id: &ChannelId, summary: &ChannelSummary, options: &ArchiveOptions) -> Result<(), Box<dyn Error + Send + Sync>> {
+    match (summary.missing(), options.max_missing_notes) {
+        (Some(missing), Some(max)) if missing > max => Err(Box::new(Incomplete { channel_id: channel_id.clone(), missing })),
+        _ => Ok(()),
+    }
+}
+
+/// 1ページを取得する。失敗したら、`page_size`を小さくして同じ`untilId`のまま頼み直す。
+/// 結果が欠けているかもしれないと返ってきたら、時間を置いて`options.partial_retries`回まで頼み直し、
+/// それでも欠けていればそのまま使って`warning`を記録する。
+/// 取れたページと、それを取ったリクエスト
+struct Fetched {
+    notes: Vec<Note>,
+    params: PageParams,
+}
+
+/// `--emit-page-markers`で`page`の記録に書く。トークンはリクエストの本文に足すので、ここには無い
+#[derive(Serialize)]
+struct PageParams {
+    #[serde(flatten)]
+    command: TimelineCommand,
+    /// 一部しか返せなかったときや、`limit`を減らしての頼み直しも数える。1から
+    attempt: u32,
+}
+
+async fn fetch_page(
+    client: &impl ApiClient,
+    pacer: &Pacer,
+    out: &mut (impl Write + Send),
+    page_size: &mut AdaptivePageSize,
+    options: &ArchiveOptions,
+    command: impl Fn(NonZeroUsize) -> TimelineCommand,
+) -> Result<Fetched, Box<dyn Error + Send + Sync>> {
+    let (mut attempt, mut partial_attempts) = (0, 0);
+    loop {
+        attempt += 1;
+        pacer.wait().await;
+        let sent = command(page_size.current);
+        match sent.send(client).await {
+            Ok(page) if page.partial && partial_attempts < options.partial_retries => {
+                partial_attempts += 1;
+                let backoff = options.partial_backoff * partial_attempts;
+                writeln!(out, "{}", serde_json::json!({
+                    "kind": "log",
+                    "message": format!("the server returned a partial page; retrying in {}s ({partial_attempts}/{})", backoff.as_secs(), options.partial_retries),
+                }))?;
+                tokio::time::sleep(backoff).await;
+            }
+            Ok(page) => {
+                if page.partial {
+                    writeln!(out, "{}", serde_json::json!({
+                        "kind": "warning",
+                        "until_id": sent.note_before,
+                        "since_id": sent.note_after,
+                        "message": format!("accepted a partial page after {partial_attempts} retries; notes may be missing"),
+                    }))?;
+                }
+                if page_size.succeeded() {
+                    writeln!(out, "{}", serde_json::json!({
+                        "kind": "log",
+                        "message": format!("restored limit {}", page_size.current),
+                    }))?;
+                }
+                return Ok(Fetched { notes: page.notes, params: PageParams { command: sent, attempt } })
+            }
+            Err(e) if is_transient(&*e) => {
+                let Some(limit) = page_size.shrink() else {
+                    return Err(e)
+                };
+                writeln!(out, "{}", serde_json::json!({
+                    "kind": "log",
+                    "message": format!("{e}; retrying with limit {limit}"),
+                }))?;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// 書き出す前にページのノートを確かめ、おかしなものを`warning`として記録する。
+fn check_page(out: &mut Destination, notes: &mut [Note], timeline: &Timeline, hidden: Option<&HiddenUsers>) -> io::Result<()> {
+    if let Timeline::Channel(channel_id) = timeline {
+        check_channels(out, notes, channel_id)?;
+    }
+    if let Some(hidden) = hidden {
+        // 隠していても、リノートや自分へのリプライなどで返ってくることがある
+        for note in notes.iter_mut() {
+            note.hidden_author = hidden.of(&note.user.id);
+        }
+    }
+
+    check_clock(out, notes, Utc::now())
+}
+
+/// APIが他のチャンネルのノートを返したら、`warning`として記録する。そのノートはAPIが示したチャンネルのまま書き出す。
+fn check_channels(out: &mut Destination, notes: &mut [Note], requested: &ChannelId) -> io::Result<()> {
+    for note in notes {
+        match &note.channel_id {
+            Some(actual) if actual != requested => writeln!(out, "{}", serde_json::json!({
+                "kind": "warning",
+                "note_id": note.id,
+                "channel_id": requested,
+                "message": format!("the API returned a note of channel {} for channel {}", actual.0, requested.0),
+            }))?,
+            Some(_) => {}
+            None => note.channel_id = Some(requested.clone()),
+        }
+    }
+
+    Ok(())
+}
+
+/// `createdAt`が取得した時刻より先にあるノートを、まとめて`warning`として記録する。ノートはそのまま書き出す。
+fn check_clock(out: &mut Destination, notes: &[Note], fetched_at: DateTime<Utc>) -> io::Result<()> {
+    let future: Vec<_> = notes.iter().filter(|x| clock::is_future(x.created_at, fetched_at)).map(|x| &x.id).collect();
+    if future.is_empty() {
+        return Ok(())
+    }
+
+    writeln!(out, "{}", serde_json::json!({
+        "kind": "warning",
+        "note_ids": future,
+        "fetched_at": fetched_at,
+        "message": format!("{} note(s) are dated after the fetch time; they are kept as is, but --split-by puts them in the bucket of the fetch time", future.len()),
+    }))
+}
+
+/// 前のページで見たノートを取り除き、`warning`として記録する。`--no-range-filter`でも行う。
+/// 取り除いたノートも返すので、それだけのページなら、その分だけ進める。
+fn drop_duplicates(out: &mut Destination, notes: Vec<Note>, seen: &NoteSet) -> std::io::Result<(Vec<Note>, Vec<Note>)> {
+    let (duplicates, kept): (Vec<_>, _) = notes.into_iter().partition(|x| seen.contains(&x.id));
+    for note in &duplicates {
+        writeln!(out, "{}", serde_json::json!({
+            "kind": "warning",
+            "note_id": note.id,
+            "created_at": note.created_at,
+            "message": "dropped a note already seen on an earlier page",
+        }))?;
+    }
+
+    Ok((kept, duplicates))
+}
+
+/// `walk`が範囲の外とするノートを取り除き、`warning`として記録する。
+/// 取り除いたノートも`seen`に入れるので、後のページに現れても書き出さない。
+fn drop_out_of_range(
+    out: &mut Destination,
+    notes: Vec<Note>,
+    walk: &dyn Pagination,
+    seen: &mut NoteSet,
+) -> std::io::Result<Vec<Note>> {
+    let mut kept = Vec::with_capacity(notes.len());
+    for note in notes {
+        let Some(reason) = walk.out_of_range(&note) else {
+            kept.push(note);
+            continue
+        };
+
+        writeln!(out, "{}", serde_json::json!({
+            "kind": "warning",
+            "note_id": note.id,
+            "created_at": note.created_at,
+            "message": format!("dropped a note outside the requested range: {reason}"),
+        }))?;
+        seen.insert(&note.id);
+    }
+
+    Ok(kept)
+}
+
+/// 返信を辿っている1つの親
+struct ReplyFrame {
+    parent: NoteId,
+    depth: usize,
+    /// まだ辿っていない、返信のある返信。親への返信を取るまでは`None`
+    children: Option<Vec<NoteId>>,
+    /// これかその下の返信を取れなかった
+    failed: bool,
+}
+
+/// `parents`への返信を`max_depth`段まで深さ優先で辿って書き出す。既に書き出したノートは`seen`で除く。
+/// `--reply-checkpoint`にある親は飛ばし、その下の返信を全て書き出して`out`を流した親を書き足すので、止まっても続きから辿れる。
+/// 書き出した返信の数を返す。
+async fn archive_replies(
+    client: &impl ApiClient,
+    pacer: &Pacer,
+    out: &mut Destination,
+    parents: Vec<NoteId>,
+    seen: &mut NoteSet,
+    max_depth: NonZeroUsize,
+    options: &ArchiveOptions,
+) -> Result<usize, Box<dyn Error + Send + Sync>> {
+    let path = options.reply_checkpoint.as_deref();
+    let mut checkpoint = path.map(|x| Checkpoint::open(x).map_err(|e| format!("failed to open {}: {e}", x.display()))).transpose()?;
+    let mut replies = 0;
+    let mut stack: Vec<_> = parents.into_iter().rev().map(|parent| ReplyFrame { parent, depth: 1, children: None, failed: false }).collect();
+
+    while let Some(frame) = stack.last_mut() {
+        if frame.children.is_none() {
+            if checkpoint.as_ref().is_some_and(|x| x.contains(&frame.parent)) {
+                stack.pop();
+                continue
+            }
+            let (written, mut children) = fetch_replies(client, pacer, out, &frame.parent, seen, options).await?.unwrap_or_else(|| {
+                frame.failed = true;
+                (0, vec![])
+            });
+            replies += written;
+            children.reverse();
+            frame.children = Some(if frame.depth < max_depth.get() { children } else { vec![] });
+        }
+
+        let depth = frame.depth + 1;
+        if let Some(parent) = frame.children.as_mut().and_then(Vec::pop) {
+            stack.push(ReplyFrame { parent, depth, children: None, failed: false });
+            continue
+        }
+        let done = stack.pop().expect("not empty");
+        if done.failed {
+            // 次の実行で、取れなかった返信まで辿り直す。積んであるのは親か、最初の段の兄弟
+            if let Some(up) = stack.last_mut().filter(|x| x.depth < done.depth) {
+                up.failed = true;
+            }
+        } else if let Some(checkpoint) = &mut checkpoint {
+            checkpoint.mark(&done.parent)?;
+        }
+    }
+
+    Ok(replies)
+}
+
+/// `parent`への返信を全て書き出し、その数と、返信のある返信を返す。
+/// `notes/children`が失敗したら`reply-gap`として記録し、`None`を返す。予算が尽きたかトークンが拒まれたときは止める。
+async fn fetch_replies(
+    client: &impl ApiClient,
+    pacer: &Pacer,
+    out: &mut Destination,
+    parent: &NoteId,
+    seen: &mut NoteSet,
+    options: &ArchiveOptions,
+) -> Result<Option<(usize, Vec<NoteId>)>, Box<dyn Error + Send + Sync>> {
+    let (mut replies, mut children) = (0, vec![]);
+    let mut last_note = None;
+    loop {
+        let send = NoteChildrenCommand {
+            note_id: parent.clone(),
+            limit: PAGE_SIZE,
+            note_before: last_note.clone(),
+        };
+
+        pacer.wait().await;
+        let result = match send.send(client).await {
+            Ok(result) => result,
+            Err(e) if metrics::is_exhausted(&*e) || api::is_token_rejected(&*e) => return Err(e),
+            Err(e) => {
+                writeln!(out, "{}", serde_json::json!({ "kind": "reply-gap", "parent_id": parent, "until_id": last_note, "error": e.to_string() }))?;
+                return Ok(None)
+            }
+        };
+        let Some(oldest) = result.iter().min_by(|a, b| a.id.cmp(&b.id)) else {
+            break
+        };
+        last_note = Some(oldest.id.clone());
+
+        let mut fresh: Vec<Note> = result.into_iter().filter(|x| seen.insert(&x.id)).collect();
+        if fresh.is_empty() {
+            continue
+        }
+        fill_optional_fields(client, pacer, out, &mut fresh, options).await?;
+
+        children.extend(fresh.iter().filter(|x| x.reply_count > 0).map(|x| x.id.clone()));
+        replies += fresh.len();
+        if let Some(metrics) = &options.metrics {
+            metrics.record_notes(fresh.len());
+        }
+        out.write_replies(parent, &fresh)?;
+        out.flush()?;
+    }
+
+    Ok(Some((replies, children)))
+}
+
+/// 取得したノートに、オプションで求められたものを書き足す。ハッシュは他の全てを書き足した後に求める。
+async fn fill_optional_fields(
+    client: &impl ApiClient,
+    pacer: &Pacer,
+    out: &mut Destination,
+    notes: &mut [Note],
+    options: &ArchiveOptions,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    for note in &mut *notes {
+        if let Some(host) = &options.note_urls {
+            note.fill_urls(host);
+        }
+        if options.inline_user_detail {
+            note.user.inline_detail();
+        }
+        if let Some(max) = options.max_reactions {
+            let other = note.reactions.keep_top(max.get());
+            note.other_reactions_count = (other > 0).then_some(other);
+        }
+        if options.output.detect_language {
+            note.detected_language = language::detect_note(note).map(str::to_owned);
+        }
+    }
+    if let Some(translator) = &options.translate {
+        translator.translate(client, pacer, out, notes).await?;
+    }
+
+    if let Some(log) = &options.hash_notes {
+        let archived_at = chrono::Utc::now();
+        for note in notes {
+            let hash = content_hash::hash_note(&serde_json::to_value(Formatted(&*note, options.output.timestamp_format))?, options.output.timestamp_format);
+            log.record(&note.id, &hash, archived_at)?;
+            note.content_hash = Some(hash);
+        }
+    }
+
+    Ok(())
+}
+
+fn summary_record(timeline: &Timeline, result: &Result<ChannelSummary, Box<dyn Error + Send + Sync>>) -> serde_json::Value {
+    let mut record = match result {
+        Ok(summary) => serde_json::json!({
+            "kind": "summary",
+            "outcome": "ok",
+            "notes": summary.notes,
+            "pages": summary.pages,
+            "replies": summary.replies,
+            "tombstones": summary.tombstones,
+            "channel_notes_count_before": summary.notes_count.as_ref().map(|x| x.before),
+            "channel_notes_count_after": summary.notes_count.as_ref().and_then(|x| x.after),
+            "notes_count_delta": summary.delta(),
+            "empty_reason": summary.empty_reason.map(EmptyReason::name),
+        }),
+        Err(e) => e.downcast_ref::<ChannelUnavailable>().map_or_else(
+            || serde_json::json!({
+                "kind": "summary",
+                "outcome": "failed",
+                "error": e.to_string(),
+            }),
+            |unavailable| serde_json::json!({
+                "kind": "summary",
+                "outcome": "channel-became-unavailable",
+                "notes": unavailable.notes,
+                "pages": unavailable.pages,
+                "error_code": unavailable.code,
+                "error": e.to_string(),
+            }),
+        ),
+    };
+    let (field, id) = timeline.field();
+    record[field] = id.into();
+
+    record
+}
+
+/// 複数のチャンネルを遡る。あるチャンネルで失敗しても、`fail_fast`でなければ他のチャンネルは続ける。
+/// `parallel_channels`が2以上なら、その数までのチャンネルを同時に遡る。その場合でも`pacer`は共有する。
+pub async fn archive<C: ApiClient + Send + 'static>(
+    client: &Arc<C>,
+    pacer: &Arc<Pacer>,
+    output: Option<&Path>,
+    channels: &[ChannelId],
+    options: &ArchiveOptions,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    // dry-runでは何もファイルに書かない
+    let output = if options.dry_run { None } else { output };
+    let per_channel = output.is_some_and(output::is_per_channel);
+    if options.parallel_channels.get() > 1 && !per_channel && !options.dry_run {
+        return Err(format!("--parallel-channels requires --output containing {}", output::CHANNEL_PLACEHOLDER).into());
+    }
+    check_options(output, options)?;
+
+    pacer.wait().await;
+    let account = authenticate(&**client).await?;
+    check_permissions(&**client, pacer, "archive", &endpoints(options, &["channels/show", "channels/timeline"])).await?;
+    check_bounds(&**client, pacer, options, Some(channels)).await?;
+    let hidden_users = if options.check_hidden_users { list_hidden_users(&**client, pacer).await.map(Arc::new) } else { None };
+    let (shown, possibly_incomplete) = show_channels(&**client, pacer, channels, &account, hidden_users.as_deref()).await;
+    let options = &ArchiveOptions { output: OutputOptions { possibly_incomplete, ..options.output.clone() }, hidden_users, ..options.clone() };
+
+    let mut files = vec![];
+    let mut errors = if per_channel && options.parallel_channels.get() > 1 {
+        let template = output.expect("per_channel implies Some");
+        archive_parallel(client, pacer, template, shown, options, &account, &mut files).await?
+    } else {
+        archive_sequential(&**client, pacer, output, shown, options, &account, &mut files).await?
+    };
+
+    write_manifest(options, &account, ("channels", serde_json::json!(channels)), &files)?;
+
+    if let Some(i) = errors.iter().position(|e| metrics::is_exhausted(&**e) || api::is_token_rejected(&**e)) {
+        return Err(errors.swap_remove(i))
+    }
+
+    let failed = errors.len();
+    errors.pop().map_or(Ok(()), |last| Err(ChannelsFailed { failed, total: channels.len(), last }.into()))
+}
+
+/// ユーザーリストのタイムラインを遡る。先頭に`users/lists/show`で得たリストの名前とメンバーを書く。
+pub async fn archive_list(
+    client: &impl ApiClient,
+    pacer: &Pacer,
+    output: Option<&Path>,
+    list_id: &ListId,
+    options: &ArchiveOptions,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let output = if options.dry_run { None } else { output };
+    check_options(output, options)?;
+
+    pacer.wait().await;
+    let account = authenticate(client).await?;
+    check_permissions(client, pacer, "archive-list", &endpoints(options, &["users/lists/show", "notes/user-list-timeline"])).await?;
+    check_bounds(client, pacer, options, None).await?;
+    let mut out = Destination::open(output, &options.output)?;
+    write_header(&mut out, &account)?;
+
+    pacer.wait().await;
+    let list = UserListShowCommand { list_id: list_id.clone() }.send(client).await?;
+    writeln!(out, "{}", serde_json::json!({
+        "kind": "list",
+        "list": list,
+    }))?;
+
+    let timeline = Timeline::List(list_id.clone());
+    let result = archive_timeline(client, pacer, &mut out, &timeline, &list.name, None, options).await;
+    writeln!(out, "{}", summary_record(&timeline, &result))?;
+    let files = out.finish()?;
+    write_manifest(options, &account, ("list", serde_json::json!(list_id)), &files)?;
+
+    result.map(|_| ())
+}
+
+/// `gap`の記録の、頼み直すのに要るところ
+#[derive(Deserialize)]
+struct GapRecord {
+    channel_id: Option<ChannelId>,
+    list_id: Option<ListId>,
+    direction: String,
+    until_id: Option<NoteId>,
+    since_id: Option<NoteId>,
+}
+
+/// もう一度遡る範囲
+pub struct Gap {
+    pub timeline: Timeline,
+    pub direction: Direction,
+    pub before: Option<NoteId>,
+    pub after: Option<NoteId>,
+}
+
+/// `archive`や`backfill`の出力から`gap`の記録を集める。
+pub fn read_gaps(path: &Path) -> Result<Vec<Gap>, Box<dyn Error + Send + Sync>> {
+    let text = fs::read_to_string(path).map_err(|e| format!("failed to open {}: {e}", path.display()))?;
+    let mut gaps = vec![];
+    for (i, line) in text.lines().enumerate() {
+        let at = || format!("{}:{}", path.display(), i + 1);
+        // 書き方に依らず、`kind`が`gap`のものだけを選ぶ
+        let record: serde_json::Value = serde_json::from_str(line).map_err(|e| format!("{}: {e}", at()))?;
+        if record["kind"] != "gap" {
+            continue
+        }
+        let record: GapRecord = serde_json::from_value(record).map_err(|e| format!("{}: {e}", at()))?;
+        let timeline = match (record.channel_id, record.list_id) {
+            (Some(id), _) => Timeline::Channel(id),
+            (None, Some(id)) => Timeline::List(id),
+            (None, None) => return Err(format!("{}: the gap has neither channel_id nor list_id", at()).into()),
+        };
+        let direction = Direction::from_str(&record.direction, false).map_err(|e| format!("{}: {e}", at()))?;
+
+        gaps.push(Gap { timeline, direction, before: record.until_id, after: record.since_id });
+    }
+
+    Ok(gaps)
+}
+
+/// `gaps`の範囲だけをもう一度遡る。また失敗した範囲は、新しい`gap`として書き出す。
+pub async fn backfill(
+    client: &impl ApiClient,
+    pacer: &Pacer,
+    output: Option<&Path>,
+    gaps: &[Gap],
+    options: &ArchiveOptions,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    pacer.wait().await;
+    let account = authenticate(client).await?;
+    let mut out = Destination::open(output, &options.output)?;
+    write_header(&mut out, &account)?;
+
+    let mut errors = vec![];
+    for gap in gaps {
+        let options = ArchiveOptions { before: gap.before.clone(), after: gap.after.clone(), direction: gap.direction, ..options.clone() };
+        let (_, label) = gap.timeline.field();
+        let result = archive_timeline(client, pacer, &mut out, &gap.timeline, label, None, &options).await;
+        writeln!(out, "{}", summary_record(&gap.timeline, &result))?;
+        out.flush()?;
+        if let Err(e) = result {
+            errors.push(e);
+        }
+    }
+    out.finish()?;
+
+    let failed = errors.len();
+    errors.pop().map_or(Ok(()), |last| Err(ChannelsFailed { failed, total: gaps.len(), last }.into()))
+}
+
+/// `ids`のノートを`notes/show`で1つずつ取り、与えられた順に書き出す。同じIDは1度だけ取る。
+/// 消えたノートは`{"kind": "tombstone"}`に、他の理由で取れなかったノートは`{"kind": "note-error"}`にして続ける。
+pub async fn fetch_notes(
+    client: &impl ApiClient,
+    pacer: &Pacer,
+    output: Option<&Path>,
+    ids: &[NoteId],
+    options: &ArchiveOptions,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    pacer.wait().await;
+    let account = authenticate(client).await?;
+    let mut out = Destination::open(output, &options.output)?;
+    write_header(&mut out, &account)?;
+
+    let mut seen = HashSet::new();
+    let unique: Vec<&NoteId> = ids.iter().filter(|x| seen.insert(*x)).collect();
+    let mut batch = vec![];
+    let (mut errors, mut stopped) = (vec![], None);
+    for (i, note_id) in unique.iter().enumerate() {
+        pacer.wait().await;
+        let e = match (NoteShowCommand { note_id: (*note_id).clone() }).send(client).await {
+            Ok(note) => {
+                batch.push(note);
+                if batch.len() == PAGE_SIZE.get() || i + 1 == unique.len() {
+                    write_batch(client, pacer, &mut out, &mut batch, options).await?;
+                }
+                continue
+            }
+            Err(e) => e,
+        };
+
+        // 入力の順を保つため、先に取れた分を書いてから記録する
+        write_batch(client, pacer, &mut out, &mut batch, options).await?;
+        // 残りのノートも送れないので止める
+        if metrics::is_exhausted(&*e) || api::is_token_rejected(&*e) {
+            stopped = Some(e);
+            break
+        }
+        if e.downcast_ref::<ApiError>().is_some_and(|x| x.code == "NO_SUCH_NOTE") {
+            writeln!(out, "{}", serde_json::json!({ "kind": "tombstone", "note_id": note_id }))?;
+        } else {
+            writeln!(out, "{}", serde_json::json!({ "kind": "note-error", "note_id": note_id, "error": e.to_string() }))?;
+            errors.push(e);
+        }
+    }
+    let files = out.finish()?;
+    write_manifest(options, &account, ("note_ids", serde_json::json!(unique)), &files)?;
+    if let Some(e) = stopped {
+        return Err(e)
+    }
+
+    let failed = errors.len();
+    errors.pop().map_or(Ok(()), |last| Err(NotesFailed { failed, total: unique.len(), last }.into()))
+}
+
+async fn write_batch(
+    client: &impl ApiClient,
+    pacer: &Pacer,
+    out: &mut Destination,
+    batch: &mut Vec<Note>,
+    options: &ArchiveOptions,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    if batch.is_empty() {
+        return Ok(())
+    }
+    fill_optional_fields(client, pacer, out, batch, options).await?;
+    if let Some(metrics) = &options.metrics {
+        metrics.record_notes(batch.len());
+    }
+    out.write_page(batch)?;
+    out.flush()?;
+    batch.clear();
+
+    Ok(())
+}
+
+/// チャンネルでもリストでも共通の、組み合わせられないオプションを弾く。
+fn check_options(output: Option<&Path>, options: &ArchiveOptions) -> Result<(), Box<dyn Error + Send + Sync>> {
+    if options.output.split_by.is_some() && output.is_none() && !options.dry_run {
+        return Err("--split-by requires --output".into());
+    }
+    if options.direction == Direction::Forward && options.before.is_some() {
+        return Err("--before cannot be used with --direction forward".into());
+    }
+    if options.output.layout != Layout::Lines && output.is_none() && !options.dry_run {
+        return Err("--output-layout tree and chunked require --output".into());
+    }
+
+    Ok(())
+}
+
+/// 両方あれば、矛盾した`sinceId`と`untilId`を送る前に確かめる。
+async fn check_bounds(client: &impl ApiClient, pacer: &Pacer, options: &ArchiveOptions, channels: Option<&[ChannelId]>) -> Result<(), Box<dyn Error + Send + Sync>> {
+    match (&options.before, &options.after) {
+        (Some(before), Some(after)) if !options.force_range => check_range(client, pacer, before, after, channels).await,
+        _ => Ok(()),
+    }
+}
+
+/// `--manifest`があれば、何を遡ったかと共に書き出す。
+fn write_manifest(options: &ArchiveOptions, account: &Account, (field, target): (&str, serde_json::Value), files: &[PathBuf]) -> io::Result<()> {
+    let Some(path) = options.manifest.as_deref().filter(|_| !options.dry_run) else {
+        return Ok(())
+    };
+    let mut meta = serde_json::json!({
+        "kind": "meta",
+        "version": env!("CARGO_PKG_VERSION"),
+        "account": account.username,
+        "generated_at": chrono::Utc::now(),
+    });
+    meta[field] = target;
+
+    manifest::write(path, meta, files)
+}
+
+/// いくつかのチャンネルで失敗した
+#[derive(Debug)]
+pub struct ChannelsFailed {
+    pub failed: usize,
+    pub total: usize,
+    /// 最後に失敗したチャンネルのエラー
+    pub last: Box<dyn Error + Send + Sync>,
+}
+
+/// `fetch-notes`で、いくつかのノートを取れなかった
+#[derive(Debug)]
+pub struct NotesFailed {
+    pub failed: usize,
+    pub total: usize,
+    /// 最後に取れなかったノートのエラー
+    pub last: Box<dyn Error + Send + Sync>,
+}
+
+impl NotesFailed {
+    pub fn message(&self) -> Message {
+        Message::new("notes-failed", &[("failed", &self.failed), ("total", &self.total)])
+    }
+}
+
+impl std::fmt::Display for NotesFailed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.message().render(Lang::En))
+    }
+}
+
+impl Error for NotesFailed {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        Some(&*self.last)
+    }
+}
+
+impl ChannelsFailed {
+    pub fn message(&self) -> Message {
+        Message::new("channels-failed", &[("failed", &self.failed), ("total", &self.total)])
+    }
+}
+
+impl std::fmt::Display for ChannelsFailed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.message().render(Lang::En))
+    }
+}
+
+impl Error for ChannelsFailed {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        Some(&*self.last)
+    }
+}
+
+/// どのアカウントで取得したかを、それぞれの出力の先頭に書く。トークンは含めない。
+pub fn write_header(out: &mut Destination, account: &Account) -> io::Result<()> {
+    writeln!(out, "{}", serde_json::json!({
+        "kind": "log",
+        "message": format!("authenticated as @{}", account.username),
+    }))?;
+    writeln!(out, "{}", serde_json::json!({
+        "kind": "account",
+        "account": account,
+    }))
+}
+
+/// `options`で使うエンドポイント。`timeline`はタイムラインを引くのに使うもの。
+/// ミュートとブロックは引けなくても進めるので、`check_hidden_users`でも含めない
+fn endpoints(options: &ArchiveOptions, timeline: &[&'static str]) -> Vec<&'static str> {
+    let mut endpoints = timeline.to_vec();
+    endpoints.push("notes/show");
+    if options.reply_depth.is_some() {
+        endpoints.push("notes/children");
+    }
+    if options.translate.is_some() {
+        endpoints.push("notes/translate");
+    }
+
+    endpoints
+}
+
+/// 引けなければ、警告せずに進める。
+async fn list_hidden_users(client: &impl ApiClient, pacer: &Pacer) -> Option<HiddenUsers> {
+    preflight::list_hidden_users(client, pacer).await
+        .inspect_err(|e| info!("{}", msg("hidden-users-unknown", &[("error", e)])))
+        .ok()
+}
+
+/// チャンネルと`channels/show`の結果。引けなかったチャンネルは、そのチャンネルの失敗として後で扱う
+type Shown = (ChannelId, Result<Channel, Box<dyn Error + Send + Sync>>);
+
+/// 遡り始める前に、全てのチャンネルを`channels/show`で引き、ノートの一部しか見えないかもしれないものを探す。
+/// `hidden`がユーザーを隠していれば、全てのチャンネルをそうだとする。
+async fn show_channels(
+    client: &impl ApiClient,
+    pacer: &Pacer,
+    channels: &[ChannelId],
+    account: &Account,
+    hidden: Option<&HiddenUsers>,
+) -> (Vec<Shown>, Vec<(ChannelId, Vec<String>)>) {
+    let mut shown = Vec::with_capacity(channels.len());
+    let mut possibly_incomplete = vec![];
+    for channel_id in channels {
+        pacer.wait().await;
+        let channel = ChannelShowCommand { channel_id: channel_id.clone() }.send(client).await;
+        if let Ok(channel) = &channel {
+            let mut reasons = preflight::check_visibility(client, pacer, channel, account).await;
+            reasons.extend(hidden.and_then(HiddenUsers::reason));
+            if !reasons.is_empty() {
+                let render = |lang| reasons.iter().map(|x| x.render(lang)).collect::<Vec<_>>();
+                info!("{}", msg("possibly-incomplete", &[("channel", &channel.name), ("reasons", &render(i18n::current()).join("; "))]));
+                possibly_incomplete.push((channel_id.clone(), render(Lang::En)));
+            }
+        }
+        shown.push((channel_id.clone(), channel));
+    }
+
+    (shown, possibly_incomplete)
+}
+
+/// チャンネルごとのファイルには、そのチャンネルについての警告だけを書く。
+fn for_channel(options: &OutputOptions, channel_id: &ChannelId) -> OutputOptions {
+    OutputOptions {
+        possibly_incomplete: options.possibly_incomplete.iter().filter(|(x, _)| x == channel_id).cloned().collect(),
+        ..options.clone()
+    }
+}
+
+/// 失敗したチャンネルのエラーを返す。書き終えたファイルは`files`に足す。
+async fn archive_sequential(
+    client: &impl ApiClient,
+    pacer: &Pacer,
+    output: Option<&Path>,
+    shown: Vec<Shown>,
+    options: &ArchiveOptions,
+    account: &Account,
+    files: &mut Vec<PathBuf>,
+) -> Result<Vec<Box<dyn Error + Send + Sync>>, Box<dyn Error + Send + Sync>> {
+    let per_channel = output.is_some_and(output::is_per_channel);
+    let mut shared = if per_channel { None } else { Some(Destination::open(output, &options.output)?) };
+    if let Some(out) = &mut shared {
+        write_header(out, account)?;
+    }
+
+    let mut names = UniqueNames::default();
+    let mut errors = vec![];
+    for (channel_id, channel) in shown {
+        let mut own = if per_channel {
+            let path = output::for_channel(output.expect("per_channel implies Some"), &channel_id, &mut names);
+            let mut own = Destination::open(Some(&path), &for_channel(&options.output, &channel_id))?;
+            write_header(&mut own, account)?;
+            Some(own)
+        } else {
+            None
+        };
+        let out = own.as_mut().or(shared.as_mut()).expect("either is Some");
+
+        let result = match channel {
+            Ok(channel) => archive_channel(client, pacer, out, channel, account, options).await,
+            Err(e) => Err(e),
+        };
+        writeln!(out, "{}", summary_record(&Timeline::Channel(channel_id.clone()), &result))?;
+        out.flush()?;
+        let result = result.and_then(|summary| check_completeness(&channel_id, &summary, options));
+        if let Some(own) = own {
+            files.extend(own.finish()?);
+        }
+
+        if let Err(e) = result {
+            if options.fail_fast {
+                return Err(e);
+            }
+            // 残りのチャンネルも送れないので止める
+            let exhausted = metrics::is_exhausted(&*e) || api::is_token_rejected(&*e);
+            errors.push(e);
+            if exhausted {
+                break
+            }
+        }
+    }
+
+    if let Some(shared) = shared {
+        files.extend(shared.finish()?);
+    }
+
+    Ok(errors)
+}
+
+/// 失敗したチャンネルのエラーを返す。書き終えたファイルは`files`に足す。
+/// 出力が混ざらないよう、チャンネルごとに別のファイルへ書く。
+async fn archive_parallel<C: ApiClient + Send + 'static>(
+    client: &Arc<C>,
+    pacer: &Arc<Pacer>,
+    template: &Path,
+    shown: Vec<Shown>,
+    options: &ArchiveOptions,
+    account: &Account,
+    files: &mut Vec<PathBuf>,
+) -> Result<Vec<Box<dyn Error + Send + Sync>>, Box<dyn Error + Send + Sync>> {
+    let slots = Arc::new(Semaphore::new(options.parallel_channels.get()));
+    let mut tasks = JoinSet::new();
+    let mut names = UniqueNames::default();
+
+    for (channel_id, channel) in shown {
+        let client = Arc::clone(client);
+        let pacer = Arc::clone(pacer);
+        let slots = Arc::clone(&slots);
+        let options = options.clone();
+        let account = account.clone();
+        let path = output::for_channel(template, &channel_id, &mut names);
+
+        tasks.spawn(async move {
+            let _slot = slots.acquire_owned().await.expect("never closed");
+            let mut out = Destination::open(Some(&path), &for_channel(&options.output, &channel_id))?;
+            write_header(&mut out, &account)?;
+
+            let result = match channel {
+                Ok(channel) => archive_channel(&*client, &pacer, &mut out, channel, &account, &options).await,
+                Err(e) => Err(e),
+            };
+            writeln!(out, "{}", summary_record(&Timeline::Channel(channel_id.clone()), &result))?;
+            let written = out.finish()?;
+
+            Ok::<_, Box<dyn Error + Send + Sync>>((written, result.and_then(|summary| check_completeness(&channel_id, &summary, &options))))
+        });
+    }
+
+    let mut errors = vec![];
+    while let Some(joined) = tasks.join_next().await {
+        let (written, result) = joined??;
+        files.extend(written);
+        if let Err(e) = result {
+            if options.fail_fast {
+                tasks.shutdown().await;
+                return Err(e);
+            }
+            errors.push(e);
+        }
+    }
+
+    Ok(errors)
+}
+
+/// `--ids-from`のファイルを読む。空行と`#`から始まる行は無視する。
+pub fn read_note_list(path: &Path) -> std::io::Result<Vec<NoteId>> {
+    Ok(fs::read_to_string(path)?
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| NoteId(line.to_owned()))
+        .collect())
+}
+
+/// `--channels-from`のファイルを読む。空行と`#`から始まる行は無視する。
+pub fn read_channel_list(path: &Path) -> std::io::Result<Vec<ChannelId>> {
+    Ok(fs::read_to_string(path)?
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| ChannelId(line.to_owned()))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+    use std::fs;
+    use std::num::{NonZeroU32, NonZeroU64, NonZeroUsize};
+    use std::path::PathBuf;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    use chrono::FixedOffset;
+    use serde_json::json;
+
+    use std::error::Error;
+
+    use tokio::time::{sleep, Instant};
+
+    use crate::api::{ApiClient, RawResponse, ResponseHeaders, Timeline};
+    use crate::archive::{archive, archive_list, backfill, fetch_notes, read_gaps, ArchiveOptions};
+    use crate::capture::{Exchange, ReplayClient};
+    use crate::log::{self, Level, LogFormat};
+    use crate::metrics::{self, MeteredClient, Metrics};
+    use crate::model::{ChannelId, ListId, NoteId};
+    use crate::pacer::Pacer;
+    use crate::field_map::FieldMap;
+    use crate::testing::{capture_dir, channel, exchange, me, note_json, probe, RUN};
+    use crate::chunk;
+    use crate::deletion::DeletionCheck;
+    use crate::output::{self, Layout, OutputOptions};
+    use crate::page_markers;
+    use crate::pagination::Direction;
+    use crate::reader;
+    use crate::timestamp::TimestampFormat;
+    use crate::timezone::Timezone;
+    use crate::schema;
+    use crate::status::{self, Outcome};
+    use crate::translate::Translator;
+
+    const OPTIONS: ArchiveOptions = ArchiveOptions {
+        before: None,
+        after: None,
+        cool_down: Duration::ZERO,
+        dry_run: false,
+        fail_fast: false,
+        parallel_channels: NonZeroUsize::MIN,
+        output: OutputOptions {
+            split_by: None,
+            timezone: Timezone::Fixed(FixedOffset::east_opt(0).unwrap()),
+            atomic: true,
+            timestamp_format: TimestampFormat::Rfc3339,
+            layout: Layout::Lines,
+            chunk_size: chunk::DEFAULT_CHUNK_SIZE,
+            sink: None,
+            canonical: false,
+            possibly_incomplete: vec![],
+            run: RUN,
+            field_map: FieldMap::EMPTY,
+            detect_language: false,
+            append: false,
+            append_index: None,
+        },
+        manifest: None,
+        reply_depth: None,
+        reply_checkpoint: None,
+        with_channel_info: false,
+        note_urls: None,
+        inline_user_detail: false,
+        range_filter: true,
+        direction: Direction::Backward,
+        translate: None,
+        metrics: None,
+        max_missing_notes: None,
+        force_range: false,
+        hash_notes: None,
+        partial_retries: 2,
+        partial_backoff: Duration::ZERO,
+        max_reactions: None,
+        detect_deletions: None,
+        check_hidden_users: false,
+        hidden_users: None,
+        page_markers: false,
+    };
+
+    fn pacer() -> Arc<Pacer> {
+        Arc::new(Pacer::with_burst(Duration::ZERO, NonZeroU32::MIN))
+    }
+
+    #[tokio::test]
+    async fn archive_from_replay() {
+        let dir = capture_dir("archive", &[
+            me(),
+            channel("ch"),
+            probe("ch"),
+            exchange("channels/timeline", json!({ "channelId": "ch", "limit": 60 }), &json!([note_json("9xyz", "2024-01-01T00:00:00.000Z")])),
+            exchange("channels/timeline", json!({ "channelId": "ch", "limit": 60, "untilId": "9xyz" }), &json!([])),
+        ]);
+        let client = Arc::new(ReplayClient::open(&dir).unwrap());
+        let output = dir.join("out.jsonl");
+
+        archive(&client, &pacer(), Some(&output), &[ChannelId("ch".to_owned())], &OPTIONS).await.unwrap();
+
+        let out = fs::read_to_string(output).unwrap();
+        assert!(out.contains("proceeded by 9xyz"));
+        assert!(out.contains(r#""id":"9xyz""#));
+        assert!(out.contains(r#""channel_id":"ch""#));
+    }
+
+    #[tokio::test]
+    async fn notes_sharing_a_timestamp_continue_from_the_smallest_id() {
+        let at = "2024-01-01T00:00:00.000Z";
+        // サーバーが同じ日時のノートをIDの順に並べるとは限らない
+        let dir = capture_dir("archive-tie", &[
+            me(),
+            channel("ch"),
+            probe("ch"),
+            exchange("channels/timeline", json!({ "channelId": "ch", "limit": 60 }), &json!([note_json("9xk2b", at), note_json("9xk2c", at), note_json("9xk2a", at)])),
+            exchange("channels/timeline", json!({ "channelId": "ch", "limit": 60, "untilId": "9xk2a" }), &json!([note_json("9xk1z", at)])),
+            exchange("channels/timeline", json!({ "channelId": "ch", "limit": 60, "untilId": "9xk1z" }), &json!([])),
+        ]);
+        let client = Arc::new(ReplayClient::open(&dir).unwrap());
+        let output = dir.join("out.jsonl");
+
+        archive(&client, &pacer(), Some(&output), &[ChannelId("ch".to_owned())], &OPTIONS).await.unwrap();
+
+        let records: Vec<serde_json::Value> = fs::read_to_string(output).unwrap().lines().map(|x| serde_json::from_str(x).unwrap()).collect();
+        let ids: Vec<_> = records.iter().filter_map(serde_json::Value::as_array).flatten().map(|x| x["id"].as_str().unwrap()).collect();
+        assert_eq!(ids, ["9xk2b", "9xk2c", "9xk2a", "9xk1z"]);
+        let cursor = records.iter().find(|x| x["kind"] == "log" && x.get("cursor").is_some()).unwrap();
+        assert_eq!(cursor["cursor"], json!({ "id": "9xk2a", "created_at": "2024-01-01T00:00:00Z" }));
+    }
+
+    #[tokio::test]
+    async fn list_timeline_starts_with_the_list_record() {
+        let dir = capture_dir("archive-list", &[
+            me(),
+            exchange("users/lists/show", json!({ "listId": "l1" }), &json!({
+                "id": "l1", "name": "friends", "userIds": ["u1", "u2"], "createdAt": "2024-01-01T00:00:00.000Z",
+            })),
+            exchange("notes/user-list-timeline", json!({ "listId": "l1", "limit": 60 }), &json!([note_json("n1", "2024-01-01T00:00:00.000Z")])),
+            exchange("notes/user-list-timeline", json!({ "listId": "l1", "limit": 60, "untilId": "n1" }), &json!([])),
+        ]);
+        let client = ReplayClient::open(&dir).unwrap();
+        let output = dir.join("out.jsonl");
+
+        archive_list(&client, &pacer(), Some(&output), &ListId("l1".to_owned()), &OPTIONS).await.unwrap();
+
+        let records: Vec<serde_json::Value> = fs::read_to_string(output).unwrap().lines().map(|x| serde_json::from_str(x).unwrap()).collect();
+        let list = records.iter().position(|x| x["kind"] == "list").unwrap();
+        assert_eq!(records[list], json!({ "kind": "list", "list": { "id": "l1", "name": "friends", "user_ids": ["u1", "u2"] } }));
+        let page = records.iter().position(|x| x[0]["id"] == "n1").unwrap();
+        assert!(list < page && records[page][0].get("channel_id").is_none());
+        let summary = records.last().unwrap();
+        assert_eq!((&summary["kind"], &summary["list_id"], &summary["notes"]), (&json!("summary"), &json!("l1"), &json!(1)));
+    }
+
+    #[tokio::test]
+    async fn empty_first_page_is_explained_by_a_probe() {
+        let dir = capture_dir("empty-reason", &[
+            me(),
+            channel("ch"),
+            probe("ch"),
+            exchange("channels/timeline", json!({ "channelId": "ch", "limit": 60, "sinceId": "n9" }), &json!([])),
+            exchange("channels/timeline", json!({ "channelId": "ch", "limit": 1 }), &json!([note_json("n9", "2024-01-01T00:00:00.000Z")])),
+        ]);
+        let client = Arc::new(ReplayClient::open(&dir).unwrap());
+        let output = dir.join("out.jsonl");
+        let options = ArchiveOptions {
+            after: Some(NoteId("n9".to_owned())),
+            ..OPTIONS
+        };
+
+        archive(&client, &pacer(), Some(&output), &[ChannelId("ch".to_owned())], &options).await.unwrap();
+
+        let out = fs::read_to_string(output).unwrap();
+        let summary: serde_json::Value = serde_json::from_str(out.lines().last().unwrap()).unwrap();
+        assert_eq!((&summary["notes"], &summary["empty_reason"]), (&json!(0), &json!("no-new-notes")));
+    }
+
+    #[tokio::test]
+    async fn failed_pages_are_recorded_as_gaps_and_backfilled() {
+        let dir = capture_dir("gap", &[
+            me(),
+            channel("ch"),
+            probe("ch"),
+            exchange("channels/timeline", json!({ "channelId": "ch", "limit": 60 }), &json!([note_json("n2", "2024-01-02T00:00:00.000Z")])),
+            Exchange {
+                endpoint: "channels/timeline".to_owned(),
+                request: json!({ "channelId": "ch", "limit": 60, "untilId": "n2" }),
+                status: 400,
+                response: json!({ "error": { "message": "Invalid param.", "code": "INVALID_PARAM", "id": "3d81ceae-475f-4600-b2a8-2bc116157532" } }).to_string(),
+                headers: ResponseHeaders::default(),
+            },
+        ]);
+        let client = Arc::new(ReplayClient::open(&dir).unwrap());
+        let output = dir.join("out.jsonl");
+
+        assert!(archive(&client, &pacer(), Some(&output), &[ChannelId("ch".to_owned())], &OPTIONS).await.is_err());
+        let gaps = read_gaps(&output).unwrap();
+        assert_eq!(gaps.len(), 1);
+        assert_eq!(gaps[0].before, Some(NoteId("n2".to_owned())));
+
+        let retry = capture_dir("backfill", &[
+            me(),
+            exchange("channels/timeline", json!({ "channelId": "ch", "limit": 60, "untilId": "n2" }), &json!([note_json("n1", "2024-01-01T00:00:00.000Z")])),
+            exchange("channels/timeline", json!({ "channelId": "ch", "limit": 60, "untilId": "n1" }), &json!([])),
+        ]);
+        let client = ReplayClient::open(&retry).unwrap();
+        let output = retry.join("out.jsonl");
+
+        backfill(&client, &pacer(), Some(&output), &gaps, &OPTIONS).await.unwrap();
+
+        let out = fs::read_to_string(output).unwrap();
+        assert!(out.contains(r#""id":"n1""#));
+        assert!(!out.contains(r#""kind":"gap""#));
+    }
+
+    #[test]
+    fn gaps_are_read_by_their_kind_however_they_are_formatted() {
+        let dir = capture_dir("read-gaps", &[]);
+        let input = dir.join("in.jsonl");
+        fs::write(&input, [
+            json!([note_json("n3", "2024-01-03T00:00:00.000Z")]).to_string(),
+            r#"{"kind": "gap", "channel_id": "ch", "direction": "backward", "until_id": "n2"}"#.to_owned(),
+            json!({ "kind": "reply-gap", "parent_id": "n3", "until_id": null, "error": "gap" }).to_string(),
+            json!({ "kind": "warning", "message": r#""kind":"gap""# }).to_string(),
+        ].join("\n")).unwrap();
+
+        let gaps = read_gaps(&input).unwrap();
+        assert_eq!(gaps.len(), 1);
+        assert!(matches!(&gaps[0].timeline, Timeline::Channel(id) if id.0 == "ch"));
+        assert_eq!((&gaps[0].before, &gaps[0].after), (&Some(NoteId("n2".to_owned())), &None));
+    }
+
+    #[tokio::test]
+    async fn partial_pages_are_retried_then_accepted_with_a_warning() {
+        let partial = |notes: serde_json::Value| json!({ "notes": notes, "partial": true });
+        let n2 = note_json("n2", "2024-01-02T00:00:00.000Z");
+        let dir = capture_dir("partial", &[
+            me(),
+            channel("ch"),
+            probe("ch"),
+            exchange("channels/timeline", json!({ "channelId": "ch", "limit": 60 }), &partial(json!([n2]))),
+            exchange("channels/timeline", json!({ "channelId": "ch", "limit": 60 }), &json!([note_json("n3", "2024-01-03T00:00:00.000Z"), n2])),
+            exchange("channels/timeline", json!({ "channelId": "ch", "limit": 60, "untilId": "n2" }), &partial(json!([]))),
+            exchange("channels/timeline", json!({ "channelId": "ch", "limit": 60, "untilId": "n2" }), &partial(json!([]))),
+            exchange("channels/timeline", json!({ "channelId": "ch", "limit": 60, "untilId": "n2" }), &partial(json!([]))),
+        ]);
+        let client = Arc::new(ReplayClient::open(&dir).unwrap());
+        let output = dir.join("out.jsonl");
+
+        archive(&client, &pacer(), Some(&output), &[ChannelId("ch".to_owned())], &OPTIONS).await.unwrap();
+
+        let out = fs::read_to_string(output).unwrap();
+        assert!(out.contains(r#""id":"n3""#));
+        let warnings: Vec<serde_json::Value> = out.lines()
+            .map(|x| serde_json::from_str(x).unwrap())
+            .filter(|x: &serde_json::Value| x["kind"] == "warning")
+            .collect();
+        assert_eq!(warnings.len(), 1);
+        assert_eq!(warnings[0]["until_id"], "n2");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn max_duration_stops_before_the_next_page_and_leaves_a_gap() {
+        let dir = capture_dir("max-duration", &[
+            me(),
+            channel("ch"),
+            probe("ch"),
+            exchange("channels/timeline", json!({ "channelId": "ch", "limit": 60 }), &json!([note_json("n3", "2024-01-03T00:00:00.000Z"), note_json("n2", "2024-01-02T00:00:00.000Z")])),
+            exchange("channels/timeline", json!({ "channelId": "ch", "limit": 60, "untilId": "n2" }), &json!([note_json("n1", "2024-01-01T00:00:00.000Z")])),
+        ]);
+        // 300msおきに送るので、4つ目の最初のページは900ms、次のページは1200msになる
+        let client = ReplayClient::open(&dir).unwrap();
+        let client = Arc::new(MeteredClient::new(client, Arc::new(Metrics::default())).with_deadline(Some(Duration::from_millis(1050))));
+        let pacer = Arc::new(Pacer::with_burst(Duration::from_millis(300), NonZeroU32::MIN));
+        let output = dir.join("out.jsonl");
+
+        let result = archive(&client, &pacer, Some(&output), &[ChannelId("ch".to_owned())], &OPTIONS).await;
+
+        let (outcome, status) = status::record(&result);
+        assert_eq!((outcome, &status["outcome"], &status["exit_code"]), (Outcome::TimeBudgetExhausted, &json!("time-budget-exhausted"), &json!(6)));
+        let lines: Vec<serde_json::Value> = fs::read_to_string(output).unwrap().lines().map(|x| serde_json::from_str(x).unwrap()).collect();
+        assert_eq!(lines.iter().filter(|x| x.is_array()).count(), 1);
+        let gap = lines.iter().find(|x| x["kind"] == "gap").unwrap();
+        assert_eq!((&gap["until_id"], &gap["cursor"]["id"], &gap["channel_id"]), (&json!("n2"), &json!("n2"), &json!("ch")));
+        assert!(gap["error"].as_str().unwrap().contains("--max-duration 1s"), "{gap}");
+    }
+
+    #[tokio::test]
+    async fn canonical_runs_are_byte_identical() {
+        let mut n1 = note_json("n1", "2024-01-01T00:00:00.000Z");
+        n1["reactions"] = json!({ "👍": 1, "🎉": 2, "❤": 3, "😇": 4 });
+        let dir = capture_dir("canonical", &[
+            me(),
+            channel("ch"),
+            probe("ch"),
+            exchange("channels/timeline", json!({ "channelId": "ch", "limit": 60 }), &json!([n1, note_json("n2", "2024-01-02T00:00:00.000Z")])),
+            exchange("channels/timeline", json!({ "channelId": "ch", "limit": 60, "untilId": "n1" }), &json!([])),
+        ]);
+        let options = ArchiveOptions {
+            output: OutputOptions { canonical: true, ..OPTIONS.output },
+            ..OPTIONS
+        };
+
+        let mut runs = vec![];
+        for run in ["a.jsonl", "b.jsonl"] {
+            let client = Arc::new(ReplayClient::open(&dir).unwrap());
+            let output = dir.join(run);
+            archive(&client, &pacer(), Some(&output), &[ChannelId("ch".to_owned())], &options).await.unwrap();
+            runs.push(fs::read(output).unwrap());
+        }
+
+        assert_eq!(runs[0], runs[1]);
+        let out = String::from_utf8(runs.swap_remove(0)).unwrap();
+        assert!(!out.contains(r#""kind":"log""#));
+        let records: Vec<serde_json::Value> = out.lines().map(|x| serde_json::from_str(x).unwrap()).collect();
+        let ids: Vec<_> = records.iter().filter(|x| x["kind"] == "note").map(|x| &x["note"]["id"]).collect();
+        assert_eq!(ids, [&json!("n2"), &json!("n1")]);
+    }
+
+    #[tokio::test]
+    async fn fetch_notes_keeps_input_order_and_tombstones_deleted_notes() {
+        let dir = capture_dir("fetch-notes", &[
+            me(),
+            exchange("notes/show", json!({ "noteId": "n2" }), &note_json("n2", "2024-01-02T00:00:00.000Z")),
+            Exchange {
+                endpoint: "notes/show".to_owned(),
+                request: json!({ "noteId": "gone" }),
+                status: 400,
+                response: json!({ "error": { "message": "No such note.", "code": "NO_SUCH_NOTE", "id": "24fcbfc6-2e37-42b6-8388-c29b3861a08d" } }).to_string(),
+                headers: ResponseHeaders::default(),
+            },
+            exchange("notes/show", json!({ "noteId": "n1" }), &note_json("n1", "2024-01-01T00:00:00.000Z")),
+        ]);
+        let client = ReplayClient::open(&dir).unwrap();
+        let output = dir.join("out.jsonl");
+        let ids = ["n2", "gone", "n1", "n2"].map(|x| NoteId(x.to_owned()));
+
+        fetch_notes(&client, &pacer(), Some(&output), &ids, &OPTIONS).await.unwrap();
+
+        let records: Vec<serde_json::Value> = fs::read_to_string(output).unwrap().lines().map(|x| serde_json::from_str(x).unwrap()).collect();
+        let tail: Vec<_> = records.iter().skip_while(|x| x["kind"] != "account").skip(1).collect();
+        assert_eq!(tail.len(), 3);
+        assert_eq!(tail[0][0]["id"], "n2");
+        assert_eq!(tail[1], &json!({ "kind": "tombstone", "note_id": "gone" }));
+        assert_eq!(tail[2][0]["id"], "n1");
+    }
+
+    #[tokio::test]
+    async fn fetch_notes_stops_when_the_budget_runs_out() {
+        let dir = capture_dir("fetch-notes-budget", &[
+            me(),
+            exchange("notes/show", json!({ "noteId": "n1" }), &note_json("n1", "2024-01-01T00:00:00.000Z")),
+            exchange("notes/show", json!({ "noteId": "n2" }), &note_json("n2", "2024-01-02T00:00:00.000Z")),
+        ]);
+        let client = MeteredClient::new(ReplayClient::open(&dir).unwrap(), Arc::new(Metrics::default())).with_budget(NonZeroU64::new(3));
+        let output = dir.join("out.jsonl");
+        let ids = ["n1", "n2", "n3", "n4"].map(|x| NoteId(x.to_owned()));
+
+        let e = fetch_notes(&client, &pacer(), Some(&output), &ids, &OPTIONS).await.unwrap_err();
+
+        assert!(metrics::is_exhausted(&*e), "{e}");
+        let out = fs::read_to_string(output).unwrap();
+        assert!(out.contains(r#""id":"n2""#) && !out.contains("note-error"));
+    }
+
+    #[tokio::test]
+    async fn notes_of_another_channel_are_kept_and_warned_about() {
+        let mut stray = note_json("n2", "2024-01-02T00:00:00.000Z");
+        stray["channelId"] = json!("other");
+        stray["channel"] = json!({ "id": "other", "name": "Other", "color": "#86b300" });
+        let dir = capture_dir("channel-mismatch", &[
+            me(),
+            channel("ch"),
+            probe("ch"),
+            exchange("channels/timeline", json!({ "channelId": "ch", "limit": 60 }), &json!([stray, note_json("n1", "2024-01-01T00:00:00.000Z")])),
+            exchange("channels/timeline", json!({ "channelId": "ch", "limit": 60, "untilId": "n1" }), &json!([])),
+        ]);
+        let client = Arc::new(ReplayClient::open(&dir).unwrap());
+        let output = dir.join("out.jsonl");
+
+        archive(&client, &pacer(), Some(&output), &[ChannelId("ch".to_owned())], &OPTIONS).await.unwrap();
+
+        let records: Vec<serde_json::Value> = fs::read_to_string(output).unwrap().lines().map(|x| serde_json::from_str(x).unwrap()).collect();
+        let warning = records.iter().find(|x| x["kind"] == "warning").unwrap();
+        assert_eq!((&warning["note_id"], &warning["channel_id"]), (&json!("n2"), &json!("ch")));
+        let page = records.iter().find(|x| x.is_array()).unwrap();
+        assert_eq!(page[0]["channel_id"], "other");
+        assert_eq!(page[0]["channel"], json!({ "id": "other", "name": "Other" }));
+        assert_eq!(page[1]["channel_id"], "ch");
+    }
+
+    #[tokio::test]
+    async fn future_notes_are_kept_as_is_and_warned_about() {
+        let dir = capture_dir("future-notes", &[
+            me(),
+            channel("ch"),
+            probe("ch"),
+            exchange("channels/timeline", json!({ "channelId": "ch", "limit": 60 }), &json!([
+                note_json("n2", "2106-02-07T06:28:16.000Z"),
+                note_json("n1", "2024-01-01T00:00:00.000Z"),
+            ])),
+            exchange("channels/timeline", json!({ "channelId": "ch", "limit": 60, "untilId": "n1" }), &json!([])),
+        ]);
+        let client = Arc::new(ReplayClient::open(&dir).unwrap());
+        let output = dir.join("out.jsonl");
+
+        archive(&client, &pacer(), Some(&output), &[ChannelId("ch".to_owned())], &OPTIONS).await.unwrap();
+
+        let records: Vec<serde_json::Value> = fs::read_to_string(output).unwrap().lines().map(|x| serde_json::from_str(x).unwrap()).collect();
+        let warning = records.iter().find(|x| x["kind"] == "warning").unwrap();
+        assert_eq!(warning["note_ids"], json!(["n2"]));
+        let page = records.iter().find(|x| x.is_array()).unwrap();
+        assert_eq!(page[0]["createdAt"], "2106-02-07T06:28:16Z");
+    }
+
+    #[tokio::test]
+    async fn replies_outside_the_channel_are_pulled_in_once() {
+        let mut parent = note_json("n1", "2024-01-01T00:00:00.000Z");
+        parent["repliesCount"] = json!(2);
+        let mut in_channel = note_json("n2", "2024-01-02T00:00:00.000Z");
+        in_channel["replyId"] = json!("n1");
+        let mut outside = note_json("r1", "2024-01-03T00:00:00.000Z");
+        outside["replyId"] = json!("n1");
+        outside["repliesCount"] = json!(1);
+        let dir = capture_dir("replies", &[
+            me(),
+            channel("ch"),
+            probe("ch"),
+            exchange("channels/timeline", json!({ "channelId": "ch", "limit": 60 }), &json!([in_channel, parent])),
+            exchange("channels/timeline", json!({ "channelId": "ch", "limit": 60, "untilId": "n1" }), &json!([])),
+            exchange("notes/children", json!({ "noteId": "n1", "limit": 60 }), &json!([outside, in_channel])),
+            exchange("notes/children", json!({ "noteId": "n1", "limit": 60, "untilId": "n2" }), &json!([])),
+        ]);
+        let client = Arc::new(ReplayClient::open(&dir).unwrap());
+        let output = dir.join("out.jsonl");
+        let options = ArchiveOptions {
+            reply_depth: Some(NonZeroUsize::MIN),
+            ..OPTIONS
+        };
+
+        archive(&client, &pacer(), Some(&output), &[ChannelId("ch".to_owned())], &options).await.unwrap();
+
+        let out = fs::read_to_string(output).unwrap();
+        let replies: Vec<_> = out.lines().filter(|l| l.contains(r#""kind":"replies""#)).collect();
+        assert_eq!(replies.len(), 1);
+        assert!(replies[0].contains(r#""parent_id":"n1""#) && replies[0].contains(r#""id":"r1""#));
+        assert!(!replies[0].contains(r#""id":"n2""#));
+        assert!(out.contains(r#""replies":1"#));
+    }
+
+    #[tokio::test]
+    async fn reply_walk_resumes_from_the_checkpoint() {
+        let parent = |id: &str, day: u8| {
+            let mut note = note_json(id, &format!("2024-01-0{day}T00:00:00.000Z"));
+            note["repliesCount"] = json!(1);
+            note
+        };
+        let reply = |id: &str, to: &str| {
+            let mut note = note_json(id, "2024-01-05T00:00:00.000Z");
+            note["replyId"] = json!(to);
+            note
+        };
+        let timeline = || vec![
+            me(),
+            channel("ch"),
+            probe("ch"),
+            exchange("channels/timeline", json!({ "channelId": "ch", "limit": 60 }), &json!([parent("n2", 2), parent("n1", 1)])),
+            exchange("channels/timeline", json!({ "channelId": "ch", "limit": 60, "untilId": "n1" }), &json!([])),
+        ];
+        // 1回目はn2への返信を取れない
+        let mut first = timeline();
+        first.extend([
+            Exchange {
+                endpoint: "notes/children".to_owned(),
+                request: json!({ "noteId": "n2", "limit": 60 }),
+                status: 400,
+                response: json!({ "error": { "message": "Invalid param.", "code": "INVALID_PARAM", "id": "3d81ceae-475f-4600-b2a8-2bc116157532" } }).to_string(),
+                headers: ResponseHeaders::default(),
+            },
+            exchange("notes/children", json!({ "noteId": "n1", "limit": 60 }), &json!([reply("r1", "n1")])),
+            exchange("notes/children", json!({ "noteId": "n1", "limit": 60, "untilId": "r1" }), &json!([])),
+        ]);
+        let first = capture_dir("reply-checkpoint-first", &first);
+        // 2回目はn1を飛ばす
+        let mut second = timeline();
+        second.extend([
+            exchange("notes/children", json!({ "noteId": "n2", "limit": 60 }), &json!([reply("r2", "n2")])),
+            exchange("notes/children", json!({ "noteId": "n2", "limit": 60, "untilId": "r2" }), &json!([])),
+        ]);
+        let second = capture_dir("reply-checkpoint-second", &second);
+        let output = first.join("out.jsonl");
+        let options = ArchiveOptions {
+            reply_depth: Some(NonZeroUsize::MIN),
+            reply_checkpoint: Some(first.join("replies.checkpoint")),
+            output: OutputOptions { append: true, ..OPTIONS.output },
+            ..OPTIONS
+        };
+
+        let client = Arc::new(ReplayClient::open(&first).unwrap());
+        archive(&client, &pacer(), Some(&output), &[ChannelId("ch".to_owned())], &options).await.unwrap();
+        let out = fs::read_to_string(&output).unwrap();
+        assert!(out.contains(r#""kind":"reply-gap""#) && out.contains(r#""parent_id":"n2""#));
+        assert_eq!(fs::read_to_string(first.join("replies.checkpoint")).unwrap(), "n1\n");
+
+        let client = Arc::new(ReplayClient::open(&second).unwrap());
+        archive(&client, &pacer(), Some(&output), &[ChannelId("ch".to_owned())], &options).await.unwrap();
+        let out = fs::read_to_string(&output).unwrap();
+        assert_eq!(out.matches(r#""id":"r1""#).count(), 1);
+        assert_eq!(out.matches(r#""id":"r2""#).count(), 1);
+        assert_eq!(fs::read_to_string(first.join("replies.checkpoint")).unwrap(), "n1\nn2\n");
+    }
+
+    #[tokio::test]
+    async fn notes_outside_the_requested_range_are_dropped() {
+        let dir = capture_dir("range-filter", &[
+            me(),
+            channel("ch"),
+            probe("ch"),
+            exchange("channels/timeline", json!({ "channelId": "ch", "limit": 60 }), &json!([
+                note_json("n3", "2024-01-03T00:00:00.000Z"),
+                note_json("n2", "2024-01-02T00:00:00.000Z"),
+            ])),
+            // n4はuntilIdより新しく、n2は前のページと重なっている
+            exchange("channels/timeline", json!({ "channelId": "ch", "limit": 60, "untilId": "n2" }), &json!([
+                note_json("n4", "2024-01-04T00:00:00.000Z"),
+                note_json("n2", "2024-01-02T00:00:00.000Z"),
+                note_json("n1", "2024-01-01T00:00:00.000Z"),
+            ])),
+            exchange("channels/timeline", json!({ "channelId": "ch", "limit": 60, "untilId": "n1" }), &json!([
+                note_json("n4", "2024-01-04T00:00:00.000Z"),
+            ])),
+        ]);
+        let run = |range_filter: bool, name: &'static str| {
+            let dir = dir.clone();
+            async move {
+                let client = Arc::new(ReplayClient::open(&dir).unwrap());
+                let output = dir.join(name);
+                let options = ArchiveOptions { range_filter, ..OPTIONS };
+                let _ = archive(&client, &pacer(), Some(&output), &[ChannelId("ch".to_owned())], &options).await;
+                fs::read_to_string(output).unwrap()
+            }
+        };
+
+        let filtered = run(true, "filtered.jsonl").await;
+        let pages: Vec<_> = filtered.lines().filter(|l| l.starts_with('[')).collect();
+        assert_eq!(pages.len(), 2);
+        assert!(pages[1].contains(r#""id":"n1""#) && !pages[1].contains(r#""id":"n2""#) && !pages[1].contains(r#""id":"n4""#));
+        assert!(filtered.contains(r#""kind":"warning""#) && filtered.contains("newer than untilId"));
+        assert!(filtered.contains("already seen on an earlier page"));
+        assert_eq!(filtered.matches(r#""id":"n4""#).count(), 0);
+
+        // フィルターしなくても、重なったノートは書き出さない
+        let unfiltered = run(false, "unfiltered.jsonl").await;
+        let pages: Vec<_> = unfiltered.lines().filter(|l| l.starts_with('[')).collect();
+        assert_eq!(pages.len(), 2);
+        assert!(pages[1].contains(r#""id":"n4""#) && !pages[1].contains(r#""id":"n2""#));
+        assert!(!unfiltered.contains("newer than untilId"));
+        assert!(unfiltered.contains("did not advance; stopping") && !unfiltered.contains("outside the requested range; stopping"));
+    }
+
+    #[tokio::test]
+    async fn a_page_of_only_duplicates_is_skipped() {
+        let dir = capture_dir("duplicate-page", &[
+            me(),
+            channel("ch"),
+            probe("ch"),
+            exchange("channels/timeline", json!({ "channelId": "ch", "limit": 60, "sinceId": "n1" }), &json!([
+                note_json("n4", "2024-01-04T00:00:00.000Z"),
+                note_json("n3", "2024-01-03T00:00:00.000Z"),
+                note_json("n1", "2024-01-01T00:00:00.000Z"),
+            ])),
+            // 前のページで範囲の外として除いたn1しか無い
+            exchange("channels/timeline", json!({ "channelId": "ch", "limit": 60, "sinceId": "n1", "untilId": "n3" }), &json!([
+                note_json("n1", "2024-01-01T00:00:00.000Z"),
+            ])),
+            exchange("channels/timeline", json!({ "channelId": "ch", "limit": 60, "sinceId": "n1", "untilId": "n1" }), &json!([])),
+        ]);
+        let client = Arc::new(ReplayClient::open(&dir).unwrap());
+        let output = dir.join("out.jsonl");
+        let options = ArchiveOptions { after: Some(NoteId("n1".to_owned())), ..OPTIONS };
+
+        archive(&client, &pacer(), Some(&output), &[ChannelId("ch".to_owned())], &options).await.unwrap();
+
+        let out = fs::read_to_string(output).unwrap();
+        assert!(out.contains("older than sinceId") && out.contains("already seen on an earlier page"));
+        assert!(!out.contains("stopping"));
+    }
+
+    #[tokio::test]
+    async fn channel_info_includes_pinned_notes() {
+        let dir = capture_dir("channel-info", &[
+            me(),
+            exchange("channels/show", json!({ "channelId": "ch" }), &json!({
+                "id": "ch", "name": "test", "description": "about", "bannerUrl": null, "pinnedNoteIds": ["p1"],
+            })),
+            exchange("notes/show", json!({ "noteId": "p1" }), &note_json("p1", "2024-01-01T00:00:00.000Z")),
+            exchange("channels/timeline", json!({ "channelId": "ch", "limit": 60 }), &json!([])),
+        ]);
+        let client = Arc::new(ReplayClient::open(&dir).unwrap());
+        let output = dir.join("out.jsonl");
+        let options = ArchiveOptions {
+            with_channel_info: true,
+            ..OPTIONS
+        };
+
+        archive(&client, &pacer(), Some(&output), &[ChannelId("ch".to_owned())], &options).await.unwrap();
+
+        let out = fs::read_to_string(output).unwrap();
+        assert!(out.contains(r#""description":"about""#));
+        assert!(out.lines().any(|l| l.contains(r#""kind":"pinned-note""#) && l.contains(r#""id":"p1""#)));
+    }
+
+    #[tokio::test]
+    async fn deleted_pinned_notes_are_warned_about() {
+        let dir = capture_dir("deleted-pinned-note", &[
+            me(),
+            exchange("channels/show", json!({ "channelId": "ch" }), &json!({
+                "id": "ch", "name": "test", "description": "about", "bannerUrl": null, "pinnedNoteIds": ["p1", "p2"],
+            })),
+            Exchange {
+                endpoint: "notes/show".to_owned(),
+                request: json!({ "noteId": "p1" }),
+                status: 400,
+                response: json!({ "error": { "message": "No such note.", "code": "NO_SUCH_NOTE", "id": "24fcbfc6-2e37-42b6-8388-c29b3861a08d" } }).to_string(),
+                headers: ResponseHeaders::default(),
+            },
+            exchange("notes/show", json!({ "noteId": "p2" }), &note_json("p2", "2024-01-01T00:00:00.000Z")),
+            exchange("channels/timeline", json!({ "channelId": "ch", "limit": 60 }), &json!([])),
+        ]);
+        let client = Arc::new(ReplayClient::open(&dir).unwrap());
+        let output = dir.join("out.jsonl");
+        let options = ArchiveOptions {
+            with_channel_info: true,
+            ..OPTIONS
+        };
+
+        archive(&client, &pacer(), Some(&output), &[ChannelId("ch".to_owned())], &options).await.unwrap();
+
+        let out = fs::read_to_string(output).unwrap();
+        assert!(out.lines().any(|l| l.contains(r#""kind":"warning""#) && l.contains(r#""note_id":"p1""#) && l.contains("NO_SUCH_NOTE")));
+        assert!(out.lines().any(|l| l.contains(r#""kind":"pinned-note""#) && l.contains(r#""id":"p2""#)));
+    }
+
+    #[tokio::test]
+    async fn archived_records_match_the_schema() {
+        let mut reply = note_json("r1", "2024-01-01T01:00:00.000Z");
+        reply["replyId"] = json!("n1");
+        let mut note = note_json("n1", "2024-01-01T00:00:00.000Z");
+        note["repliesCount"] = json!(1);
+        let dir = capture_dir("schema", &[
+            me(),
+            exchange("channels/show", json!({ "channelId": "ch" }), &json!({
+                "id": "ch", "name": "test", "notesCount": 2, "description": null, "bannerUrl": null, "pinnedNoteIds": ["p1"], "isFollowing": true,
+            })),
+            probe("ch"),
+            exchange("notes/show", json!({ "noteId": "p1" }), &note_json("p1", "2024-01-01T00:00:00.000Z")),
+            exchange("channels/timeline", json!({ "channelId": "ch", "limit": 60 }), &json!([note])),
+            exchange("channels/timeline", json!({ "channelId": "ch", "limit": 60, "untilId": "n1" }), &json!([])),
+            exchange("notes/children", json!({ "noteId": "n1", "limit": 60 }), &json!([reply])),
+            exchange("notes/children", json!({ "noteId": "n1", "limit": 60, "untilId": "r1" }), &json!([])),
+            // 2つ目のチャンネルは失敗させ、`gap`も書かせる
+            channel("ch2"),
+            probe("ch2"),
+            Exchange {
+                endpoint: "channels/timeline".to_owned(),
+                request: json!({ "channelId": "ch2", "limit": 60 }),
+                status: 400,
+                response: json!({ "error": { "message": "Invalid param.", "code": "INVALID_PARAM", "id": "3d81ceae-475f-4600-b2a8-2bc116157532" } }).to_string(),
+                headers: ResponseHeaders::default(),
+            },
+        ]);
+        let client = Arc::new(ReplayClient::open(&dir).unwrap());
+        let output = dir.join("out.jsonl");
+        let options = ArchiveOptions {
+            with_channel_info: true,
+            reply_depth: Some(NonZeroUsize::MIN),
+            inline_user_detail: true,
+            ..OPTIONS
+        };
+
+        let result = archive(&client, &pacer(), Some(&output), &[ChannelId("ch".to_owned()), ChannelId("ch2".to_owned())], &options).await;
+
+        let schema = schema::document(None);
+        let mut lines: Vec<serde_json::Value> = fs::read_to_string(output).unwrap().lines().map(|x| serde_json::from_str(x).unwrap()).collect();
+        lines.push(status::record(&result).1);
+        for (i, line) in lines.iter().enumerate() {
+            schema::validate(&schema, &schema, line, &format!("line {}", i + 1)).unwrap();
+        }
+        let kinds: HashSet<_> = lines.iter().map(|x| x["kind"].as_str().unwrap_or("page")).collect();
+        assert_eq!(kinds, HashSet::from([
+            "meta", "warning", "log", "account", "relationship", "channel", "pinned-note", "page", "replies", "gap", "summary", "status",
+        ]));
+    }
+
+    #[tokio::test]
+    async fn note_urls_prefer_the_remote_url() {
+        let mut local = note_json("n2", "2024-01-02T00:00:00.000Z");
+        local["user"] = json!({ "id": "u1", "username": "alice", "host": null });
+        let mut remote = note_json("n1", "2024-01-01T00:00:00.000Z");
+        remote["user"] = json!({ "id": "u2", "username": "bob", "host": "remote.example" });
+        remote["url"] = json!("https://remote.example/@bob/1");
+        let dir = capture_dir("note-urls", &[
+            me(),
+            channel("ch"),
+            probe("ch"),
+            exchange("channels/timeline", json!({ "channelId": "ch", "limit": 60 }), &json!([local, remote])),
+            exchange("channels/timeline", json!({ "channelId": "ch", "limit": 60, "untilId": "n1" }), &json!([])),
+        ]);
+        let client = Arc::new(ReplayClient::open(&dir).unwrap());
+        let output = dir.join("out.jsonl");
+        let options = ArchiveOptions {
+            note_urls: Some("misskey.example".parse().unwrap()),
+            ..OPTIONS
+        };
+
+        archive(&client, &pacer(), Some(&output), &[ChannelId("ch".to_owned())], &options).await.unwrap();
+
+        let out = fs::read_to_string(output).unwrap();
+        assert!(out.contains(r#""local_url":"https://misskey.example/notes/n2""#));
+        assert!(out.contains(r#""local_url":"https://misskey.example/@alice""#));
+        assert!(out.contains(r#""local_url":"https://remote.example/@bob/1""#));
+        assert!(out.contains(r#""local_url":"https://misskey.example/@bob@remote.example""#));
+        // 作者のusernameはURLを組み立てるためだけに読む
+        assert!(!out.contains(r#""username":"alice""#) && !out.contains(r#""username":"bob""#));
+    }
+
+    #[tokio::test]
+    async fn inline_user_detail_is_opt_in() {
+        let mut note = note_json("n1", "2024-01-01T00:00:00.000Z");
+        note["user"] = json!({
+            "id": "u1", "username": "alice", "name": "Alice", "host": null,
+            "avatarUrl": "https://misskey.example/avatar.webp", "isBot": false,
+        });
+        let dir = capture_dir("inline-user-detail", &[
+            me(),
+            channel("ch"),
+            probe("ch"),
+            exchange("channels/timeline", json!({ "channelId": "ch", "limit": 60 }), &json!([note])),
+            exchange("channels/timeline", json!({ "channelId": "ch", "limit": 60, "untilId": "n1" }), &json!([])),
+        ]);
+        let run = |options: ArchiveOptions, name: &'static str| {
+            let dir = dir.clone();
+            async move {
+                let client = Arc::new(ReplayClient::open(&dir).unwrap());
+                let output = dir.join(name);
+                archive(&client, &pacer(), Some(&output), &[ChannelId("ch".to_owned())], &options).await.unwrap();
+                fs::read_to_string(output).unwrap()
+            }
+        };
+
+        let lean = run(OPTIONS, "lean.jsonl").await;
+        assert!(lean.contains(r#""user":{"id":"u1"}"#));
+
+        let inline = run(ArchiveOptions { inline_user_detail: true, ..OPTIONS }, "inline.jsonl").await;
+        assert!(inline.contains(
+            r#""user":{"avatarUrl":"https://misskey.example/avatar.webp","host":null,"id":"u1","name":"Alice","username":"alice"}"#
+        ));
+        assert!(!inline.contains("isBot"));
+    }
+
+    #[tokio::test]
+    async fn failed_channel_does_not_stop_the_others() {
+        let dir = capture_dir("multi-channel", &[
+            me(),
+            channel("ok"),
+            probe("ok"),
+            exchange("channels/timeline", json!({ "channelId": "ok", "limit": 60 }), &json!([note_json("9xyz", "2024-01-01T00:00:00.000Z")])),
+            exchange("channels/timeline", json!({ "channelId": "ok", "limit": 60, "untilId": "9xyz" }), &json!([])),
+        ]);
+        let client = Arc::new(ReplayClient::open(&dir).unwrap());
+        let template = dir.join("{channel}.jsonl");
+        let channels = [ChannelId("broken".to_owned()), ChannelId("ok".to_owned())];
+
+        let result = archive(&client, &pacer(), Some(&template), &channels, &OPTIONS).await;
+
+        assert!(result.is_err());
+        assert!(fs::read_to_string(dir.join("broken.jsonl")).unwrap().contains(r#""outcome":"failed""#));
+        let ok = fs::read_to_string(dir.join("ok.jsonl")).unwrap();
+        assert!(ok.contains(r#""outcome":"ok""#));
+        assert!(ok.contains(r#""id":"9xyz""#));
+    }
+
+    #[tokio::test]
+    async fn shortfall_against_notes_count_is_reported_as_a_gap() {
+        let show = |count: usize| exchange("channels/show", json!({ "channelId": "ch" }), &json!({ "id": "ch", "name": "test", "notesCount": count }));
+        let dir = capture_dir("notes-count", &[
+            me(),
+            show(3),
+            exchange("channels/timeline", json!({ "channelId": "ch", "limit": 60 }), &json!([note_json("9xyz", "2024-01-01T00:00:00.000Z")])),
+            exchange("channels/timeline", json!({ "channelId": "ch", "limit": 60, "untilId": "9xyz" }), &json!([])),
+            show(4),
+        ]);
+        let client = Arc::new(ReplayClient::open(&dir).unwrap());
+        let output = dir.join("out.jsonl");
+        let options = ArchiveOptions { max_missing_notes: Some(1), ..OPTIONS };
+
+        let result = archive(&client, &pacer(), Some(&output), &[ChannelId("ch".to_owned())], &options).await;
+
+        let e = result.unwrap_err();
+        assert_eq!(Outcome::classify(&*e), Outcome::CompletedWithGaps);
+        let written = fs::read_to_string(&output).unwrap();
+        let summary: serde_json::Value = written.lines().map(|x| serde_json::from_str(x).unwrap())
+            .find(|x: &serde_json::Value| x["kind"] == "summary").unwrap();
+        assert_eq!(summary["channel_notes_count_before"], 3);
+        assert_eq!(summary["channel_notes_count_after"], 4);
+        assert_eq!(summary["notes_count_delta"], -2);
+        assert!(written.contains("the channel reported 2 more"));
+    }
+
+    #[tokio::test]
+    async fn notes_missing_since_the_previous_run_become_tombstones() {
+        let show = || exchange("channels/show", json!({ "channelId": "ch" }), &json!({ "id": "ch", "name": "test", "notesCount": 2 }));
+        let no_such_note = Exchange {
+            endpoint: "notes/show".to_owned(),
+            request: json!({ "noteId": "9c" }),
+            status: 400,
+            response: json!({ "error": { "message": "No such note.", "code": "NO_SUCH_NOTE", "id": "24fcbfc6-2e37-42b6-8388-c29b3861a08d" } }).to_string(),
+            headers: ResponseHeaders::default(),
+        };
+        let dir = capture_dir("detect-deletions", &[
+            me(),
+            show(),
+            exchange("channels/timeline", json!({ "channelId": "ch", "limit": 60 }), &json!([note_json("9e", "2024-01-05T00:00:00.000Z"), note_json("9a", "2024-01-01T00:00:00.000Z")])),
+            exchange("channels/timeline", json!({ "channelId": "ch", "limit": 60, "untilId": "9a" }), &json!([])),
+            // 9dは見えなかったが消えてはいない
+            exchange("notes/show", json!({ "noteId": "9d" }), &note_json("9d", "2024-01-04T00:00:00.000Z")),
+            no_such_note,
+            show(),
+        ]);
+        let previous = dir.join("previous.jsonl");
+        let in_channel = |id: &str, day: u32| {
+            let mut note = note_json(id, &format!("2024-01-0{day}T00:00:00.000Z"));
+            note["channelId"] = json!(if id == "9x" { "other" } else { "ch" });
+            note
+        };
+        let notes = json!([in_channel("9e", 5), in_channel("9d", 4), in_channel("9c", 3), in_channel("9b", 2), in_channel("9a", 1), in_channel("9x", 3)]);
+        fs::write(&previous, format!("{notes}\n")).unwrap();
+        let client = Arc::new(ReplayClient::open(&dir).unwrap());
+        let output = dir.join("out.jsonl");
+        let check = DeletionCheck::read(&previous, NonZeroUsize::new(2)).unwrap();
+        let options = ArchiveOptions { detect_deletions: Some(Arc::new(check)), ..OPTIONS };
+
+        archive(&client, &pacer(), Some(&output), &[ChannelId("ch".to_owned())], &options).await.unwrap();
+
+        let records: Vec<serde_json::Value> = fs::read_to_string(&output).unwrap().lines().map(|x| serde_json::from_str(x).unwrap()).collect();
+        let tombstones: Vec<_> = records.iter().filter(|x| x["kind"] == "tombstone").collect();
+        assert_eq!(tombstones.len(), 2);
+        assert_eq!((&tombstones[0]["note_id"], &tombstones[0]["confirmed"]), (&json!("9c"), &json!(true)));
+        assert!(tombstones[0]["checked_at"].is_string());
+        assert_eq!(tombstones[0]["last_known"]["createdAt"], "2024-01-03T00:00:00Z");
+        // 確かめる数を超えた分は、確かめずに書く
+        let mut unconfirmed = tombstones[1].clone();
+        assert_eq!(unconfirmed["last_known"]["text"], "hello");
+        unconfirmed.as_object_mut().unwrap().remove("last_known");
+        assert_eq!(unconfirmed, json!({ "kind": "tombstone", "note_id": "9b", "channel_id": "ch", "confirmed": false, "checked_at": null }));
+        assert_eq!(records.iter().find(|x| x["kind"] == "summary").unwrap()["tombstones"], 2);
+    }
+
+    fn gateway_timeout(request: serde_json::Value) -> Exchange {
+        Exchange {
+            endpoint: "channels/timeline".to_owned(),
+            request,
+            status: 504,
+            response: "<html>504 Gateway Time-out</html>".to_owned(),
+            headers: ResponseHeaders::default(),
+        }
+    }
+
+    #[tokio::test]
+    async fn quiet_run_writes_nothing_to_stderr() {
+        let dir = capture_dir("quiet", &[
+            me(),
+            channel("ch"),
+            probe("ch"),
+            exchange("channels/timeline", json!({ "channelId": "ch", "limit": 60 }), &json!([note_json("9xyz", "2024-01-01T00:00:00.000Z")])),
+            exchange("channels/timeline", json!({ "channelId": "ch", "limit": 60, "untilId": "9xyz" }), &json!([])),
+        ]);
+        let client = Arc::new(ReplayClient::open(&dir).unwrap());
+        let output = dir.join("out.jsonl");
+
+        log::init(Level::Quiet, true, LogFormat::Text);
+        log::start_capture();
+        let result = archive(&client, &pacer(), Some(&output), &[ChannelId("ch".to_owned())], &OPTIONS).await;
+        let stderr = log::take_captured();
+        log::init(Level::Normal, true, LogFormat::Text);
+
+        result.unwrap();
+        assert_eq!(stderr, "");
+    }
+
+    #[tokio::test]
+    async fn timeouts_shrink_the_page_until_it_succeeds() {
+        let timeline = |limit: usize, until: Option<&str>, notes: &[&str]| {
+            let mut request = json!({ "channelId": "ch", "limit": limit });
+            if let Some(until) = until {
+                request["untilId"] = json!(until);
+            }
+            let notes: Vec<_> = notes.iter().map(|id| note_json(id, &format!("2024-01-01T00:00:0{}.000Z", &id[1..]))).collect();
+            exchange("channels/timeline", request, &json!(notes))
+        };
+        let dir = capture_dir("adaptive-page-size", &[
+            me(),
+            channel("ch"),
+            probe("ch"),
+            gateway_timeout(json!({ "channelId": "ch", "limit": 60 })),
+            timeline(30, None, &["n6", "n5"]),
+            timeline(30, Some("n5"), &["n4"]),
+            timeline(30, Some("n4"), &["n3"]),
+            // 3回続けて成功したので元に戻る
+            timeline(60, Some("n3"), &["n2", "n1"]),
+            timeline(60, Some("n1"), &[]),
+        ]);
+        let client = Arc::new(ReplayClient::open(&dir).unwrap());
+        let output = dir.join("out.jsonl");
+
+        archive(&client, &pacer(), Some(&output), &[ChannelId("ch".to_owned())], &OPTIONS).await.unwrap();
+
+        let out = fs::read_to_string(&output).unwrap();
+        let pages: String = out.lines().filter(|l| l.starts_with('[')).collect();
+        for id in ["n6", "n5", "n4", "n3", "n2", "n1"] {
+            assert_eq!(pages.matches(&format!(r#""id":"{id}""#)).count(), 1, "{id}");
+        }
+        assert!(out.contains("retrying with limit 30"));
+        assert!(out.contains("restored limit 60"));
+    }
+
+    #[tokio::test]
+    async fn gives_up_when_a_single_note_times_out() {
+        let mut exchanges = vec![me(), channel("ch"), probe("ch")];
+        exchanges.extend([60, 30, 15, 7, 3, 1].map(|limit| gateway_timeout(json!({ "channelId": "ch", "limit": limit }))));
+        let dir = capture_dir("adaptive-page-size-floor", &exchanges);
+        let client = Arc::new(ReplayClient::open(&dir).unwrap());
+        let output = dir.join("out.jsonl");
+
+        assert!(archive(&client, &pacer(), Some(&output), &[ChannelId("ch".to_owned())], &OPTIONS).await.is_err());
+        assert!(fs::read_to_string(&output).unwrap().contains("retrying with limit 1"));
+    }
+
+    /// 呼ばれた時刻を記録する
+    struct RecordingClient {
+        inner: ReplayClient,
+        calls: Mutex<Vec<Instant>>,
+    }
+
+    impl ApiClient for RecordingClient {
+        async fn call(&self, endpoint: &str, body: serde_json::Value) -> Result<RawResponse, Box<dyn Error + Send + Sync>> {
+            self.calls.lock().unwrap().push(Instant::now());
+            // サーバーの応答に時間がかかる間に、他のチャンネルのリクエストが割り込めるようにする
+            sleep(Duration::from_millis(300)).await;
+            self.inner.call(endpoint, body).await
+        }
+    }
+
+    /// 次のページを頼んだ時点で、出力に書き終えていたページの数を記録する
+    struct PeekingClient {
+        inner: ReplayClient,
+        output: PathBuf,
+        written: Mutex<Vec<usize>>,
+    }
+
+    impl ApiClient for PeekingClient {
+        async fn call(&self, endpoint: &str, body: serde_json::Value) -> Result<RawResponse, Box<dyn Error + Send + Sync>> {
+            if body.get("untilId").is_some() {
+                let pages = fs::read_to_string(&self.output).unwrap().lines().filter(|l| l.starts_with('[')).count();
+                self.written.lock().unwrap().push(pages);
+            }
+            self.inner.call(endpoint, body).await
+        }
+    }
+
+    #[tokio::test]
+    async fn next_page_is_requested_before_the_current_one_is_written() {
+        let dir = capture_dir("prefetch", &[
+            me(),
+            channel("ch"),
+            probe("ch"),
+            exchange("channels/timeline", json!({ "channelId": "ch", "limit": 60 }), &json!([note_json("n3", "2024-01-03T00:00:00.000Z")])),
+            exchange("channels/timeline", json!({ "channelId": "ch", "limit": 60, "untilId": "n3" }), &json!([note_json("n2", "2024-01-02T00:00:00.000Z")])),
+            exchange("channels/timeline", json!({ "channelId": "ch", "limit": 60, "untilId": "n2" }), &json!([note_json("n1", "2024-01-01T00:00:00.000Z")])),
+            exchange("channels/timeline", json!({ "channelId": "ch", "limit": 60, "untilId": "n1" }), &json!([])),
+        ]);
+        let output = dir.join("out.jsonl");
+        let client = Arc::new(PeekingClient { inner: ReplayClient::open(&dir).unwrap(), output: output::temporary_path(&output), written: Mutex::new(vec![]) });
+
+        archive(&client, &pacer(), Some(&output), &[ChannelId("ch".to_owned())], &OPTIONS).await.unwrap();
+
+        assert_eq!(*client.written.lock().unwrap(), [0, 1, 2]);
+        let out = fs::read_to_string(output).unwrap();
+        let order: Vec<_> = ["n3", "n2", "n1"].iter().map(|id| out.find(&format!(r#""id":"{id}""#)).unwrap()).collect();
+        assert!(order.is_sorted());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn parallel_channels_share_the_rate_budget() {
+        let mut exchanges = vec![me()];
+        for ch in ["a", "b", "c"] {
+            exchanges.push(channel(ch));
+            exchanges.push(probe(ch));
+            for (until, page) in [(None, "3"), (Some("3"), "2"), (Some("2"), "1")] {
+                let mut request = json!({ "channelId": ch, "limit": 60 });
+                if let Some(until) = until {
+                    request["untilId"] = json!(format!("{ch}{until}"));
+                }
+                exchanges.push(exchange("channels/timeline", request, &json!([note_json(&format!("{ch}{page}"), &format!("2024-01-0{page}T00:00:00.000Z"))])));
+            }
+            exchanges.push(exchange("channels/timeline", json!({ "channelId": ch, "limit": 60, "untilId": format!("{ch}1") }), &json!([])));
+            exchanges.push(channel(ch));
+        }
+        let dir = capture_dir("parallel", &exchanges);
+        let client = Arc::new(RecordingClient {
+            inner: ReplayClient::open(&dir).unwrap(),
+            calls: Mutex::new(vec![]),
+        });
+        let interval = Duration::from_secs(1);
+        let pacer = Arc::new(Pacer::with_burst(interval, NonZeroU32::MIN));
+        let options = ArchiveOptions {
+            parallel_channels: NonZeroUsize::new(3).unwrap(),
+            ..OPTIONS
+        };
+        let channels = ["a", "b", "c"].map(|c| ChannelId(c.to_owned()));
+
+        archive(&client, &pacer, Some(&dir.join("{channel}.jsonl")), &channels, &options).await.unwrap();
+
+        let calls = client.calls.lock().unwrap().clone();
+        assert_eq!(calls.len(), exchanges.len());
+        for pair in calls.windows(2) {
+            assert!(pair[1] - pair[0] >= interval);
+        }
+        for ch in ["a", "b", "c"] {
+            assert!(fs::read_to_string(dir.join(format!("{ch}.jsonl"))).unwrap().contains(r#""notes":3"#));
+        }
+    }
+
+    #[tokio::test]
+    async fn translations_are_attached_and_unavailable_servers_warn_once() {
+        let mut silent = note_json("n4", "2024-01-03T00:00:00.000Z");
+        silent["text"] = json!(null);
+        let dir = capture_dir("translate", &[
+            me(),
+            channel("ch"),
+            probe("ch"),
+            exchange("channels/timeline", json!({ "channelId": "ch", "limit": 60 }), &json!([silent, note_json("n3", "2024-01-02T00:00:00.000Z"), note_json("n2", "2024-01-01T00:00:00.000Z")])),
+            exchange("notes/translate", json!({ "noteId": "n3", "targetLang": "en" }), &json!({ "sourceLang": "ja", "text": "hello" })),
+            Exchange {
+                endpoint: "notes/translate".to_owned(),
+                request: json!({ "noteId": "n2", "targetLang": "en" }),
+                status: 400,
+                response: json!({ "error": { "code": "UNAVAILABLE", "message": "Unavailable." } }).to_string(),
+                headers: ResponseHeaders::default(),
+            },
+            exchange("channels/timeline", json!({ "channelId": "ch", "limit": 60, "untilId": "n2" }), &json!([note_json("n1", "2023-12-31T00:00:00.000Z")])),
+            exchange("channels/timeline", json!({ "channelId": "ch", "limit": 60, "untilId": "n1" }), &json!([])),
+        ]);
+        let client = Arc::new(ReplayClient::open(&dir).unwrap());
+        let output = dir.join("out.jsonl");
+        let options = ArchiveOptions {
+            translate: Some(Translator::new("en".to_owned())),
+            ..OPTIONS
+        };
+
+        archive(&client, &pacer(), Some(&output), &[ChannelId("ch".to_owned())], &options).await.unwrap();
+
+        let out = fs::read_to_string(output).unwrap();
+        assert!(out.contains(r#""translation":{"lang":"en","text":"hello"}"#));
+        assert_eq!(out.matches("translation is not configured").count(), 1);
+        assert_eq!(out.matches(r#""translation""#).count(), 1);
+    }
+
+    #[tokio::test]
+    async fn records_the_archiving_account_and_its_relationship() {
+        let mut reacted = note_json("n1", "2024-01-01T00:00:00.000Z");
+        reacted["myReaction"] = json!("👍");
+        let dir = capture_dir("relationship", &[
+            me(),
+            exchange("channels/show", json!({ "channelId": "ch" }), &json!({ "id": "ch", "name": "test", "userId": "me", "isFollowing": false })),
+            exchange("channels/timeline", json!({ "channelId": "ch", "limit": 60 }), &json!([reacted])),
+            exchange("channels/timeline", json!({ "channelId": "ch", "limit": 60, "untilId": "n1" }), &json!([])),
+        ]);
+        let client = Arc::new(ReplayClient::open(&dir).unwrap());
+        let output = dir.join("out.jsonl");
+
+        archive(&client, &pacer(), Some(&output), &[ChannelId("ch".to_owned())], &OPTIONS).await.unwrap();
+
+        let out = fs::read_to_string(output).unwrap();
+        assert!(out.contains(r#"{"account":{"id":"me","username":"archiver"},"kind":"account"}"#));
+        assert!(out.contains(r#"{"account_id":"me","channel_id":"ch","following":false,"kind":"relationship","owner":true}"#));
+        assert!(out.contains(r#""myReaction":"👍""#));
+    }
+
+    #[tokio::test]
+    async fn channels_with_hidden_notes_are_flagged_as_possibly_incomplete() {
+        let shown = json!({ "id": "ch", "name": "test", "userId": "owner", "notesCount": 3, "isFollowing": false });
+        let dir = capture_dir("possibly-incomplete", &[
+            me(),
+            exchange("channels/show", json!({ "channelId": "ch" }), &shown),
+            probe("ch"),
+            exchange("channels/timeline", json!({ "channelId": "ch", "limit": 60 }), &json!([note_json("probe", "2024-01-01T00:00:00.000Z")])),
+            exchange("channels/timeline", json!({ "channelId": "ch", "limit": 60, "untilId": "probe" }), &json!([])),
+            exchange("channels/show", json!({ "channelId": "ch" }), &shown),
+        ]);
+        let client = Arc::new(ReplayClient::open(&dir).unwrap());
+        let output = dir.join("out.jsonl");
+
+        archive(&client, &pacer(), Some(&output), &[ChannelId("ch".to_owned())], &OPTIONS).await.unwrap();
+
+        let out = fs::read_to_string(output).unwrap();
+        let meta: serde_json::Value = serde_json::from_str(out.lines().next().unwrap()).unwrap();
+        assert_eq!(meta["possibly_incomplete"], json!(true));
+        let warning: serde_json::Value = serde_json::from_str(out.lines().find(|l| l.contains(r#""kind":"warning""#) && l.contains(r#""possibly_incomplete":true"#)).unwrap()).unwrap();
+        assert_eq!(warning["channel_id"], json!("ch"));
+        let message = warning["message"].as_str().unwrap();
+        assert!(message.contains("only 1 of the newest 3 note(s)") && message.contains("neither follows nor owns"));
+    }
+    #[tokio::test]
+    async fn archive_proceeds_without_the_permission_to_list_mutes() {
+        // `read:channels`はあるが、`read:mutes`の無いトークン
+        let dir = capture_dir("hidden-users-denied", &[
+            me(),
+            Exchange {
+                endpoint: "mute/list".to_owned(),
+                request: json!({ "limit": 100 }),
+                status: 403,
+                response: json!({ "error": {
+                    "message": "Your app does not have the necessary permissions to use this endpoint.",
+                    "code": "PERMISSION_DENIED",
+                    "id": "1370e5b7-d4eb-4566-bb1d-7748ee6a1838",
+                }}).to_string(),
+                headers: ResponseHeaders::default(),
+            },
+            channel("ch"),
+            probe("ch"),
+            exchange("channels/timeline", json!({ "channelId": "ch", "limit": 60 }), &json!([note_json("n1", "2024-01-01T00:00:00.000Z")])),
+            exchange("channels/timeline", json!({ "channelId": "ch", "limit": 60, "untilId": "n1" }), &json!([])),
+            channel("ch"),
+        ]);
+        let client = Arc::new(ReplayClient::open(&dir).unwrap());
+        let output = dir.join("out.jsonl");
+        let options = ArchiveOptions { check_hidden_users: true, ..OPTIONS };
+
+        archive(&client, &pacer(), Some(&output), &[ChannelId("ch".to_owned())], &options).await.unwrap();
+
+        let out = fs::read_to_string(output).unwrap();
+        assert!(out.lines().any(|l| l.starts_with('[') && l.contains(r#""id":"n1""#)));
+    }
+
+    #[tokio::test]
+    async fn notes_of_muted_users_are_tagged_and_the_archive_flagged() {
+        let shown = json!({ "id": "ch", "name": "test", "userId": "me", "notesCount": 2 });
+        let mut muted = note_json("n2", "2024-01-02T00:00:00.000Z");
+        muted["user"]["id"] = json!("troll");
+        let dir = capture_dir("hidden-users-archive", &[
+            me(),
+            exchange("mute/list", json!({ "limit": 100 }), &json!([{ "id": "m1", "muteeId": "troll" }])),
+            exchange("mute/list", json!({ "limit": 100, "untilId": "m1" }), &json!([])),
+            exchange("blocking/list", json!({ "limit": 100 }), &json!([])),
+            exchange("channels/show", json!({ "channelId": "ch" }), &shown),
+            exchange("channels/timeline", json!({ "channelId": "ch", "limit": 100 }), &json!([muted, note_json("n1", "2024-01-01T00:00:00.000Z")])),
+            exchange("channels/timeline", json!({ "channelId": "ch", "limit": 60 }), &json!([muted, note_json("n1", "2024-01-01T00:00:00.000Z")])),
+            exchange("channels/timeline", json!({ "channelId": "ch", "limit": 60, "untilId": "n1" }), &json!([])),
+            exchange("channels/show", json!({ "channelId": "ch" }), &shown),
+        ]);
+        let client = Arc::new(ReplayClient::open(&dir).unwrap());
+        let output = dir.join("out.jsonl");
+        let options = ArchiveOptions { check_hidden_users: true, ..OPTIONS };
+
+        archive(&client, &pacer(), Some(&output), &[ChannelId("ch".to_owned())], &options).await.unwrap();
+
+        let out = fs::read_to_string(output).unwrap();
+        let meta: serde_json::Value = serde_json::from_str(out.lines().next().unwrap()).unwrap();
+        assert_eq!(meta["possibly_incomplete"], json!(true));
+        let warning = out.lines().find(|l| l.contains(r#""kind":"warning""#) && l.contains(r#""possibly_incomplete":true"#)).unwrap();
+        assert!(warning.contains("mutes 1 and blocks 0 user(s)") && !warning.contains("troll"));
+        let page: serde_json::Value = serde_json::from_str(out.lines().find(|l| l.starts_with('[')).unwrap()).unwrap();
+        assert_eq!(page.as_array().unwrap().iter().map(|x| (&x["id"], x.get("hidden_author"))).collect::<Vec<_>>(), [(&json!("n2"), Some(&json!("muted"))), (&json!("n1"), None)]);
+    }
+    #[tokio::test]
+    async fn channel_deleted_mid_run_ends_with_gaps_and_a_checkpoint() {
+        let dir = capture_dir("channel-unavailable", &[
+            me(),
+            channel("ch"),
+            probe("ch"),
+            exchange("channels/timeline", json!({ "channelId": "ch", "limit": 60 }), &json!([note_json("n3", "2024-01-03T00:00:00.000Z"), note_json("n2", "2024-01-02T00:00:00.000Z")])),
+            exchange("channels/timeline", json!({ "channelId": "ch", "limit": 60, "untilId": "n2" }), &json!([note_json("n1", "2024-01-01T00:00:00.000Z")])),
+            Exchange {
+                endpoint: "channels/timeline".to_owned(),
+                request: json!({ "channelId": "ch", "limit": 60, "untilId": "n1" }),
+                status: 400,
+                response: json!({ "error": { "message": "No such channel.", "code": "NO_SUCH_CHANNEL", "id": "4d0eeeba-a02c-4c3c-9966-ef60d1a0502f" } }).to_string(),
+                headers: ResponseHeaders::default(),
+            },
+        ]);
+        let client = Arc::new(ReplayClient::open(&dir).unwrap());
+        let output = dir.join("out.jsonl");
+
+        let result = archive(&client, &pacer(), Some(&output), &[ChannelId("ch".to_owned())], &OPTIONS).await;
+
+        let (outcome, status) = status::record(&result);
+        assert_eq!((outcome, &status["exit_code"]), (Outcome::CompletedWithGaps, &json!(2)));
+        let out = fs::read_to_string(&output).unwrap();
+        let summary: serde_json::Value = serde_json::from_str(out.lines().find(|l| l.contains(r#""kind":"summary""#)).unwrap()).unwrap();
+        assert_eq!(
+            (&summary["outcome"], &summary["error_code"], &summary["notes"], &summary["pages"]),
+            (&json!("channel-became-unavailable"), &json!("NO_SUCH_CHANNEL"), &json!(3), &json!(2)),
+        );
+        // 戻ってきたら、backfillで続きから遡れる
+        let gaps = read_gaps(&output).unwrap();
+        assert_eq!(gaps.len(), 1);
+        assert!(out.contains(r#""kind":"gap""#) && out.contains(r#""until_id":"n1""#));
+    }
+
+    #[tokio::test]
+    async fn detected_languages_are_recorded_with_the_detector() {
+        let mut note = note_json("n1", "2024-01-01T00:00:00.000Z");
+        note["text"] = json!("今日はいい天気ですね。散歩に行ってきます");
+        let dir = capture_dir("detect-language", &[
+            me(),
+            channel("ch"),
+            probe("ch"),
+            exchange("channels/timeline", json!({ "channelId": "ch", "limit": 60 }), &json!([note, note_json("n0", "2023-12-31T00:00:00.000Z")])),
+            exchange("channels/timeline", json!({ "channelId": "ch", "limit": 60, "untilId": "n0" }), &json!([])),
+        ]);
+        let client = Arc::new(ReplayClient::open(&dir).unwrap());
+        let output = dir.join("out.jsonl");
+        let options = ArchiveOptions { output: OutputOptions { detect_language: true, ..OPTIONS.output }, ..OPTIONS };
+
+        archive(&client, &pacer(), Some(&output), &[ChannelId("ch".to_owned())], &options).await.unwrap();
+
+        let out = fs::read_to_string(output).unwrap();
+        assert!(out.lines().next().unwrap().contains(r#""language_detector":"script-stopwords/1""#));
+        let page: serde_json::Value = serde_json::from_str(out.lines().find(|l| l.starts_with('[')).unwrap()).unwrap();
+        // 「hello」は短すぎる
+        assert_eq!((&page[0]["detected_language"], page[1].get("detected_language")), (&json!("ja"), None));
+    }
+
+    #[tokio::test]
+    async fn appending_overlapping_ranges_writes_each_note_once() {
+        let dir = capture_dir("append", &[]);
+        let (output, index) = (dir.join("out.jsonl"), dir.join("ids.txt"));
+        let runs = [(&["n2", "n1"][..], None), (&["n3", "n2", "n1"], Some(&index)), (&["n4", "n3", "n2"], Some(&index))];
+        for (i, (ids, index)) in runs.into_iter().enumerate() {
+            let notes: Vec<_> = ids.iter().map(|x| note_json(x, &format!("2024-01-0{}T00:00:00.000Z", &x[1..]))).collect();
+            let last = ids[ids.len() - 1];
+            let run = capture_dir(&format!("append-{i}"), &[
+                me(),
+                channel("ch"),
+                probe("ch"),
+                exchange("channels/timeline", json!({ "channelId": "ch", "limit": 60 }), &json!(notes)),
+                exchange("channels/timeline", json!({ "channelId": "ch", "limit": 60, "untilId": last }), &json!([])),
+            ]);
+            let client = Arc::new(ReplayClient::open(&run).unwrap());
+            let options = ArchiveOptions { output: OutputOptions { append: true, append_index: index.cloned(), ..OPTIONS.output }, ..OPTIONS };
+
+            archive(&client, &pacer(), Some(&output), &[ChannelId("ch".to_owned())], &options).await.unwrap();
+        }
+
+        let mut ids: Vec<_> = reader::read_note_values(&output).unwrap().into_iter().map(|(_, x)| x["id"].as_str().unwrap().to_owned()).collect();
+        ids.sort_unstable();
+        assert_eq!(ids, ["n1", "n2", "n3", "n4"]);
+        let out = fs::read_to_string(&output).unwrap();
+        assert_eq!(out.matches(r#""kind":"meta""#).count(), 3);
+        // 2回目はファイルを読み、3回目は索引を読む
+        assert!(out.contains("skipped 2 notes already present") && out.contains("skipped 0 notes already present"));
+        assert_eq!(fs::read_to_string(&index).unwrap(), "n1\nn2\nn3\nn4\n");
+    }
+
+    #[tokio::test]
+    async fn page_markers_record_the_request_and_form_a_chain() {
+        let dir = capture_dir("page-markers", &[
+            me(),
+            channel("ch"),
+            probe("ch"),
+            exchange("channels/timeline", json!({ "channelId": "ch", "limit": 60 }), &json!([note_json("n3", "2024-01-03T00:00:00.000Z"), note_json("n2", "2024-01-02T00:00:00.000Z")])),
+            exchange("channels/timeline", json!({ "channelId": "ch", "limit": 60, "untilId": "n2" }), &json!([note_json("n1", "2024-01-01T00:00:00.000Z")])),
+            exchange("channels/timeline", json!({ "channelId": "ch", "limit": 60, "untilId": "n1" }), &json!([])),
+        ]);
+        let client = Arc::new(ReplayClient::open(&dir).unwrap());
+        let output = dir.join("out.jsonl");
+        let options = ArchiveOptions { page_markers: true, ..OPTIONS };
+
+        archive(&client, &pacer(), Some(&output), &[ChannelId("ch".to_owned())], &options).await.unwrap();
+
+        let out = fs::read_to_string(&output).unwrap();
+        let markers: Vec<serde_json::Value> = out.lines().filter(|x| x.contains(r#""kind":"page""#)).map(|x| serde_json::from_str(x).unwrap()).collect();
+        assert_eq!(markers, [
+            json!({ "kind": "page", "params": { "channelId": "ch", "limit": 60, "attempt": 1 }, "returned": 2, "new": 2 }),
+            json!({ "kind": "page", "params": { "channelId": "ch", "limit": 60, "untilId": "n2", "attempt": 1 }, "returned": 1, "new": 1 }),
+        ]);
+        let schema = schema::document(None);
+        schema::validate(&schema, &schema, &markers[1], "marker").unwrap();
+        // 記録のすぐ後にそのページが来る
+        let lines: Vec<_> = out.lines().collect();
+        assert!(lines[lines.iter().position(|x| x.contains(r#""untilId":"n2""#)).unwrap() + 1].starts_with("[{"));
+        assert_eq!(page_markers::verify(&output).unwrap(), (vec![], 2));
+    }
+}
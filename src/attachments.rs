@@ -0,0 +1,268 @@
+//! `estimate-files`。添付ファイルを落とさずに、全て落とすとどれだけの大きさになるかを見積もる。
+//!
+//! チャンネルを遡るか`--input`のアーカイブを読み、ノートの`files`の`size`を足す。同じファイルは1度だけ数える。
+//! `size`の無いファイルは`--sample`個までHEADで`Content-Length`を確かめ、その平均で残りを埋めて`approximate`にする。
+
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, HashSet};
+use std::error::Error;
+use std::fmt::Write as _;
+use std::path::Path;
+
+use reqwest::header::CONTENT_LENGTH;
+use serde::Serialize;
+use url::Url;
+
+use crate::api::{ApiClient, Timeline};
+use crate::archive::PAGE_SIZE;
+use crate::host::Host;
+use crate::log::{debug, progress};
+use crate::model::{DriveFile, NoteId};
+use crate::pacer::Pacer;
+use crate::pagination::{self, Direction};
+use crate::reader;
+
+/// MIMEタイプごとの合計。`bytes`は`size`の分かったものだけ
+#[derive(Default, Serialize)]
+struct TypeTotal {
+    files: usize,
+    bytes: u64,
+}
+
+pub struct FileEstimate {
+    seen: HashSet<String>,
+    /// `size`の分かったファイルを、大きい順に`top`個まで
+    largest: Vec<(NoteId, DriveFile)>,
+    top: usize,
+    files: usize,
+    /// `size`の分かったものの合計
+    bytes: u64,
+    by_type: BTreeMap<String, TypeTotal>,
+    /// `size`の無いファイル
+    without_size: Vec<DriveFile>,
+    /// HEADで大きさの分かった数と、その合計
+    probed: (usize, u64),
+}
+
+impl FileEstimate {
+    pub fn new(top: usize) -> Self {
+        Self { seen: HashSet::new(), largest: vec![], top, files: 0, bytes: 0, by_type: BTreeMap::new(), without_size: vec![], probed: (0, 0) }
+    }
+
+    pub fn add(&mut self, note_id: &NoteId, files: &[DriveFile]) {
+        for file in files {
+            if !self.seen.insert(file.id.clone()) {
+                continue
+            }
+            self.files += 1;
+            let total = self.by_type.entry(file.mime_type.clone().unwrap_or_else(|| "unknown".to_owned())).or_default();
+            total.files += 1;
+            let Some(size) = file.size else {
+                self.without_size.push(file.clone());
+                continue
+            };
+            self.bytes += size;
+            total.bytes += size;
+            self.largest.push((note_id.clone(), file.clone()));
+            self.largest.sort_by_key(|(_, x)| Reverse(x.size));
+            self.largest.truncate(self.top);
+        }
+    }
+
+    /// `size`の無いファイルのうち、URLのある`sample`個の大きさをHEADで確かめる。確かめられなかったものは数えない。
+    /// 相対URLは`host`から解決する。
+    pub async fn probe(&mut self, http: &reqwest::Client, pacer: &Pacer, host: Option<&Host>, sample: usize) {
+        for url in self.without_size.iter().filter_map(|x| x.url.as_deref()).take(sample) {
+            let url = match host.map_or_else(|| Url::parse(url), |x| x.resolve(url)) {
+                Ok(url) => url,
+                Err(e) => {
+                    debug!("cannot resolve {url}: {e}");
+                    continue
+                }
+            };
+            pacer.wait().await;
+            match content_length(http, &url).await {
+                Ok(Some(size)) => {
+                    self.probed.0 += 1;
+                    self.probed.1 += size;
+                }
+                Ok(None) => debug!("HEAD {url} did not report Content-Length"),
+                Err(e) => debug!("HEAD {url} failed: {e}"),
+            }
+        }
+    }
+
+    /// `size`の無いファイルを確かめた平均で埋めた合計
+    const fn estimated_bytes(&self) -> u64 {
+        let (probed, probed_bytes) = self.probed;
+        if probed == 0 {
+            return self.bytes
+        }
+
+        self.bytes + probed_bytes * self.without_size.len() as u64 / probed as u64
+    }
+
+    /// `{"kind": "file-estimate"}`
+    pub fn record(&self) -> serde_json::Value {
+        serde_json::json!({
+            "kind": "file-estimate",
+            "files": self.files,
+            "bytes": self.estimated_bytes(),
+            "known_bytes": self.bytes,
+            "approximate": !self.without_size.is_empty(),
+            "without_size": self.without_size.len(),
+            "probed": self.probed.0,
+            "by_type": self.by_type,
+            "largest": self.largest.iter().map(|(note_id, file)| serde_json::json!({
+                "note_id": note_id,
+                "id": file.id,
+                "name": file.name,
+                "type": file.mime_type,
+                "size": file.size,
+                "url": file.url,
+            })).collect::<Vec<_>>(),
+        })
+    }
+
+    /// 人が読む表。`size`の無いものを含む合計には`~`を付ける。
+    pub fn table(&self) -> String {
+        let mut text = String::from("| Type | Files | Bytes |\n| --- | ---: | ---: |\n");
+        for (mime_type, total) in &self.by_type {
+            let _ = writeln!(text, "| {mime_type} | {} | {} |", total.files, human(total.bytes));
+        }
+        let approximate = if self.without_size.is_empty() { "" } else { "~" };
+        let _ = writeln!(text, "| total | {} | {approximate}{} |", self.files, human(self.estimated_bytes()));
+        if !self.without_size.is_empty() {
+            let _ = writeln!(text, "\n{} file(s) without size; estimated from {} HEAD request(s)", self.without_size.len(), self.probed.0);
+        }
+        if !self.largest.is_empty() {
+            text.push_str("\n| Largest | Type | Bytes | Note |\n| --- | --- | ---: | --- |\n");
+            for (note_id, file) in &self.largest {
+                let name = file.name.as_deref().unwrap_or(&file.id);
+                let _ = writeln!(text, "| {name} | {} | {} | {} |", file.mime_type.as_deref().unwrap_or("unknown"), human(file.size.unwrap_or(0)), note_id.0);
+            }
+        }
+
+        text
+    }
+}
+
+/// 1024ごとに単位を上げ、小数点以下1桁にする。
+#[allow(clippy::cast_precision_loss)]
+fn human(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit + 1 < UNITS.len() {
+        value /= 1024.0;
+        unit += 1;
+    }
+
+    if unit == 0 { format!("{bytes} B") } else { format!("{value:.1} {}", UNITS[unit]) }
+}
+
+/// reqwestの`content_length`はHEADでは本文の長さの0を返すので、ヘッダーを読む。
+async fn content_length(http: &reqwest::Client, url: &Url) -> Result<Option<u64>, reqwest::Error> {
+    let response = http.head(url.clone()).send().await?.error_for_status()?;
+
+    Ok(response.headers().get(CONTENT_LENGTH).and_then(|x| x.to_str().ok()?.parse().ok()))
+}
+
+/// `timeline`を最後まで遡り、ノートのファイルを数える。書き出しはしない。
+pub async fn walk(client: &impl ApiClient, pacer: &Pacer, timeline: &Timeline, estimate: &mut FileEstimate) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let mut walk = pagination::new(Direction::Backward, None, None);
+    let mut notes = 0;
+    loop {
+        pacer.wait().await;
+        let mut page = walk.command(timeline, PAGE_SIZE).send(client).await?.notes;
+        if page.is_empty() {
+            return Ok(())
+        }
+        walk.advance(&mut page);
+        for note in &page {
+            estimate.add(&note.id, &note.files);
+        }
+        notes += page.len();
+        progress!("estimate-files: {notes} notes, {} files", estimate.files);
+    }
+}
+
+/// `archive`の出力のノートのファイルを数える。`import`したノートなら`extra.files`も見る。
+pub fn read_archive(path: &Path, estimate: &mut FileEstimate) -> Result<(), Box<dyn Error + Send + Sync>> {
+    reader::for_each_note_value(path, |at, mut note| {
+        let files = match note["files"].take() {
+            serde_json::Value::Null => note["extra"]["files"].take(),
+            files => files,
+        };
+        if files.is_null() {
+            return Ok(())
+        }
+        let note_id: NoteId = serde_json::from_value(note["id"].take()).map_err(|e| format!("{at}: {e}"))?;
+        let files: Vec<DriveFile> = serde_json::from_value(files).map_err(|e| format!("{at}: {e}"))?;
+        estimate.add(&note_id, &files);
+
+        Ok(())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::num::NonZeroU32;
+    use std::thread;
+    use std::time::Duration;
+
+    use serde_json::json;
+
+    use crate::attachments::FileEstimate;
+    use crate::model::{DriveFile, NoteId};
+    use crate::pacer::Pacer;
+    use crate::schema;
+
+    fn file(id: &str, mime_type: &str, size: Option<u64>, url: &str) -> DriveFile {
+        serde_json::from_value(json!({ "id": id, "name": format!("{id}.bin"), "type": mime_type, "size": size, "url": url })).unwrap()
+    }
+
+    /// HEADのたびに、`Content-Length: 3000`を返す。
+    fn serve(listener: TcpListener, requests: usize) {
+        thread::spawn(move || {
+            for stream in listener.incoming().take(requests) {
+                let mut reader = BufReader::new(stream.unwrap());
+                let mut head = String::new();
+                while !head.ends_with("\r\n\r\n") {
+                    reader.read_line(&mut head).unwrap();
+                }
+                assert!(head.starts_with("HEAD "), "{head}");
+                reader.get_mut().write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 3000\r\nConnection: close\r\n\r\n").unwrap();
+            }
+        });
+    }
+
+    #[tokio::test]
+    async fn sizes_are_summed_once_and_missing_ones_are_extrapolated() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let base = format!("http://{}", listener.local_addr().unwrap());
+        serve(listener, 1);
+        let mut estimate = FileEstimate::new(2);
+        let shared = file("f1", "image/png", Some(1000), "https://misskey.example/files/f1");
+
+        estimate.add(&NoteId("n1".to_owned()), &[shared.clone(), file("f2", "image/png", Some(5000), "https://misskey.example/files/f2")]);
+        // 同じファイルを添付したノート
+        estimate.add(&NoteId("n2".to_owned()), &[shared, file("f3", "video/mp4", Some(2000), "https://misskey.example/files/f3")]);
+        estimate.add(&NoteId("n3".to_owned()), &[file("f4", "video/mp4", None, &format!("{base}/f4")), file("f5", "video/mp4", None, &format!("{base}/f5"))]);
+        estimate.probe(&reqwest::Client::new(), &Pacer::with_burst(Duration::ZERO, NonZeroU32::MIN), None, 1).await;
+
+        let record = estimate.record();
+        assert_eq!((record["files"].as_u64(), record["known_bytes"].as_u64(), record["bytes"].as_u64()), (Some(5), Some(8000), Some(14000)));
+        assert_eq!((record["approximate"].as_bool(), record["without_size"].as_u64(), record["probed"].as_u64()), (Some(true), Some(2), Some(1)));
+        assert_eq!(record["by_type"], json!({ "image/png": { "files": 2, "bytes": 6000 }, "video/mp4": { "files": 3, "bytes": 2000 } }));
+        assert_eq!(record["largest"].as_array().unwrap().iter().map(|x| x["id"].as_str().unwrap()).collect::<Vec<_>>(), ["f2", "f3"]);
+        let document = schema::document(None);
+        schema::validate(&document, &document, &record, "estimate").unwrap();
+        let table = estimate.table();
+        assert!(table.contains("| image/png | 2 | 5.9 KiB |\n"), "{table}");
+        assert!(table.contains("| total | 5 | ~13.7 KiB |\n"), "{table}");
+        assert!(table.contains("| f2.bin | image/png | 4.9 KiB | n1 |\n"), "{table}");
+    }
+}
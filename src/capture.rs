@@ -0,0 +1,138 @@
+//! APIとのやり取りをディレクトリに保存し、後からネットワークを使わずに再生する。
+//!
+//! 1回のやり取りは`<連番>-<endpoint>.json`という1ファイルになり、中身は[`Exchange`]である。
+//! トークンはリクエストボディに含まれないため、キャプチャを他人に渡しても漏れない。
+
+use std::error::Error;
+use std::fmt::Write;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+use serde::{Deserialize, Serialize};
+
+use crate::api::{ApiClient, RawResponse, ResponseHeaders};
+
+#[derive(Serialize, Deserialize)]
+pub struct Exchange {
+    pub endpoint: String,
+    pub request: serde_json::Value,
+    pub status: u16,
+    /// 壊れたレスポンスも再現できるよう、JSONとして解釈せずそのまま持つ
+    pub response: String,
+    #[serde(default)]
+    pub headers: ResponseHeaders,
+}
+
+/// 内側のクライアントに委譲しつつ、やり取りを全て`dir`に書き出す。
+pub struct CapturingClient<C> {
+    inner: C,
+    dir: PathBuf,
+    sequence: AtomicUsize,
+}
+
+impl<C> CapturingClient<C> {
+    pub fn new(inner: C, dir: PathBuf) -> std::io::Result<Self> {
+        fs::create_dir_all(&dir)?;
+
+        Ok(Self {
+            inner,
+            dir,
+            sequence: AtomicUsize::new(0),
+        })
+    }
+}
+
+impl<C: ApiClient + Sync> ApiClient for CapturingClient<C> {
+    async fn call(&self, endpoint: &str, body: serde_json::Value) -> Result<RawResponse, Box<dyn Error + Send + Sync>> {
+        let response = self.inner.call(endpoint, body.clone()).await?;
+        let exchange = Exchange {
+            endpoint: endpoint.to_owned(),
+            request: body,
+            status: response.status,
+            response: response.body.clone(),
+            headers: response.headers.clone(),
+        };
+        let sequence = self.sequence.fetch_add(1, Ordering::SeqCst);
+        let path = self.dir.join(format!("{sequence:05}-{}.json", endpoint.replace('/', "_")));
+        fs::write(path, serde_json::to_string_pretty(&exchange)?)?;
+
+        Ok(response)
+    }
+}
+
+/// キャプチャ済みのやり取りから応答するクライアント。
+/// エンドポイントとリクエストボディが一致するものを、記録された順に1回ずつ消費する。
+pub struct ReplayClient {
+    exchanges: Mutex<Vec<Exchange>>,
+}
+
+impl ReplayClient {
+    pub fn open(dir: &Path) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        let mut paths = fs::read_dir(dir)?
+            .map(|entry| entry.map(|e| e.path()))
+            .collect::<Result<Vec<_>, _>>()?;
+        paths.retain(|p| p.extension().is_some_and(|ext| ext == "json"));
+        paths.sort();
+
+        let exchanges = paths.iter()
+            .map(|p| -> Result<Exchange, Box<dyn Error + Send + Sync>> {
+                let text = fs::read_to_string(p)?;
+                serde_json::from_str(&text).map_err(|e| format!("{}: {e}", p.display()).into())
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(Self {
+            exchanges: Mutex::new(exchanges),
+        })
+    }
+}
+
+impl ApiClient for ReplayClient {
+    async fn call(&self, endpoint: &str, body: serde_json::Value) -> Result<RawResponse, Box<dyn Error + Send + Sync>> {
+        let mut exchanges = self.exchanges.lock().expect("poisoned");
+        let position = exchanges.iter().position(|e| e.endpoint == endpoint && e.request == body);
+
+        let Some(position) = position else {
+            let mut message = format!("no captured response for {endpoint} {body}; available:");
+            if exchanges.is_empty() {
+                message.push_str(" (none)");
+            }
+            for e in exchanges.iter() {
+                write!(message, "\n  {} {}", e.endpoint, e.request).expect("writing to String never fails");
+            }
+            drop(exchanges);
+
+            return Err(message.into());
+        };
+        let exchange = exchanges.remove(position);
+        drop(exchanges);
+
+        Ok(RawResponse {
+            status: exchange.status,
+            body: exchange.response,
+            headers: exchange.headers,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use crate::api::ApiClient;
+    use crate::capture::ReplayClient;
+    use crate::testing::{capture_dir, exchange};
+
+    #[tokio::test]
+    async fn unmatched_request_lists_available_captures() {
+        let dir = capture_dir("unmatched", &[exchange("users/show", json!({ "userId": "a" }), &json!({}))]);
+        let client = ReplayClient::open(&dir).unwrap();
+
+        let message = client.call("users/show", json!({ "userId": "b" })).await.err().unwrap().to_string();
+
+        assert!(message.contains(r#"users/show {"userId":"b"}"#));
+        assert!(message.contains(r#"users/show {"userId":"a"}"#));
+    }
+}
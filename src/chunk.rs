@@ -0,0 +1,245 @@
+//! `--output-layout chunked`。ノートをIDの順に`--chunk-size`ずつ`chunk-<最初のノートのID>.jsonl`に書き、
+//! rsyncなどで送り直す量を減らす。
+//!
+//! ```text
+//! dir/chunk-<note id>.jsonl
+//! ```
+//!
+//! 埋まったファイルは二度と書き換えない。書き足すのは最後の埋まっていないファイルだけで、埋まれば次のファイルを作る。
+//! 前の実行で書いたノートは、数が変わっていても書き直さない。読む側は、ディレクトリを1つのアーカイブとして読む。
+
+use std::collections::{BTreeMap, HashSet};
+use std::fs;
+use std::io::{self, Write};
+use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
+
+use serde_json::Value;
+
+use crate::filename::sanitize_filename;
+use crate::log::info;
+use crate::model::Note;
+use crate::output::{OutputOptions, RecordFile};
+use crate::field_map::{Renamed, Renames};
+use crate::provenance::{Run, Stamped};
+use crate::timestamp::{Formatted, TimestampFormat};
+
+/// `--chunk-size`の既定
+pub const DEFAULT_CHUNK_SIZE: NonZeroUsize = NonZeroUsize::new(1000).unwrap();
+
+const PREFIX: &str = "chunk-";
+const EXTENSION: &str = "jsonl";
+
+/// 書き出す1行と、そのノートのID
+type Line = (String, String);
+
+pub struct ChunkWriter {
+    dir: PathBuf,
+    size: NonZeroUsize,
+    atomic: bool,
+    meta: String,
+    timestamp_format: TimestampFormat,
+    /// ノートに添える実行
+    run: Option<Run>,
+    renames: Renames,
+    /// 前の実行までに書いたファイル。名前の順
+    existing: Vec<PathBuf>,
+    /// 前の実行までに書いたノート
+    seen: HashSet<String>,
+    /// 最後のファイルが埋まっていなければ、その名前と中のノート
+    tail: Option<(PathBuf, Vec<Line>)>,
+    /// この実行で新しく見つけたノート
+    pending: BTreeMap<String, String>,
+}
+
+impl ChunkWriter {
+    /// `dir`に前の実行で書いたファイルがあれば、そのノートを覚えておく。
+    pub fn new(dir: &Path, options: &OutputOptions) -> io::Result<Self> {
+        let existing = chunk_files(dir)?;
+        let meta = options.meta();
+        let mut seen = HashSet::new();
+        let mut tail = None;
+        for (i, path) in existing.iter().enumerate() {
+            let mut lines = vec![];
+            for line in fs::read_to_string(path)?.lines() {
+                let record: Value = serde_json::from_str(line).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{}: {e}", path.display())))?;
+                if record["kind"] == "meta" && record["timestamp_format"] != options.timestamp_format.name() {
+                    return Err(io::Error::new(io::ErrorKind::InvalidInput, format!(
+                        "{} was written with --timestamp-format {}; use the same format to append to it", path.display(), record["timestamp_format"],
+                    )))
+                }
+                if record["kind"] == "meta" && record.get("field_map").cloned().unwrap_or_default() != meta.get("field_map").cloned().unwrap_or_default() {
+                    return Err(io::Error::new(io::ErrorKind::InvalidInput, format!(
+                        "{} was written with a different --field-map; use the same map to append to it", path.display(),
+                    )))
+                }
+                if let Some(id) = record["note"][options.field_map.note.name_of("id")].as_str() {
+                    seen.insert(id.to_owned());
+                    lines.push((id.to_owned(), line.to_owned()));
+                }
+            }
+            if i + 1 == existing.len() && lines.len() < options.chunk_size.get() {
+                tail = Some((path.clone(), lines));
+            }
+        }
+
+        Ok(Self {
+            dir: dir.to_path_buf(),
+            size: options.chunk_size,
+            atomic: options.atomic,
+            meta: meta.to_string(),
+            timestamp_format: options.timestamp_format,
+            run: options.stamping(),
+            renames: options.field_map.note.clone(),
+            existing,
+            seen,
+            tail,
+            pending: BTreeMap::new(),
+        })
+    }
+
+    /// 前の実行までに書いていないノートだけを覚える。書き出すのは`finish`のとき。
+    pub fn write_page(&mut self, notes: &[Note]) {
+        let stamp = self.run.map(Run::stamp);
+        for note in notes.iter().filter(|x| !self.seen.contains(&x.id.0)) {
+            let note_value = Renamed(Stamped(Formatted(note, self.timestamp_format), stamp), &self.renames);
+            let line = serde_json::json!({ "kind": "note", "note": note_value }).to_string();
+            self.pending.insert(note.id.0.clone(), line);
+        }
+    }
+
+    /// 新しいノートを最後のファイルに書き足し、溢れた分を新しいファイルに書く。全てのファイルを名前の順に返す。
+    pub fn finish(self) -> io::Result<Vec<PathBuf>> {
+        let mut files = self.existing;
+        let (replaced, mut lines) = match self.tail {
+            Some((path, lines)) => (Some(path), lines),
+            None => (None, vec![]),
+        };
+        if self.pending.is_empty() {
+            return Ok(files)
+        }
+        files.retain(|x| Some(x) != replaced.as_ref());
+        // 埋まったファイルより古いノートが見つかっても、最後のファイルに入れる
+        lines.extend(self.pending);
+        lines.sort_by(|a, b| a.0.cmp(&b.0));
+
+        fs::create_dir_all(&self.dir)?;
+        for chunk in lines.chunks(self.size.get()) {
+            let path = self.dir.join(format!("{PREFIX}{}.{EXTENSION}", sanitize_filename(&chunk[0].0, "_")));
+            let mut file = RecordFile::create(&path, self.atomic)?;
+            writeln!(file, "{}", self.meta)?;
+            for (_, line) in chunk {
+                writeln!(file, "{line}")?;
+            }
+            files.push(file.finish()?);
+        }
+        if let Some(replaced) = replaced.filter(|x| !files.contains(x)) {
+            fs::remove_file(replaced)?;
+        }
+        info!("wrote {} new notes to {}", lines.len(), self.dir.display());
+        files.sort();
+
+        Ok(files)
+    }
+}
+
+/// `dir`にある`chunk-*.jsonl`を名前の順に返す。`dir`が無ければ空。
+pub fn chunk_files(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(e),
+    };
+    let mut files: Vec<_> = entries
+        .map(|x| x.map(|x| x.path()))
+        .filter(|x| x.as_ref().map_or(true, |x| {
+            x.extension().is_some_and(|x| x == EXTENSION) && x.file_name().and_then(|x| x.to_str()).is_some_and(|x| x.starts_with(PREFIX))
+        }))
+        .collect::<io::Result<_>>()?;
+    files.sort();
+
+    Ok(files)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::num::NonZeroUsize;
+    use std::path::PathBuf;
+    use std::time::SystemTime;
+
+    use crate::chunk::ChunkWriter;
+    use crate::model::Note;
+    use crate::output::{Layout, OutputOptions};
+    use crate::reader;
+    use crate::field_map::FieldMap;
+    use crate::testing::{capture_dir, note_json, RUN};
+    use crate::timestamp::TimestampFormat;
+    use crate::timezone::Timezone;
+
+    fn run(dir: &std::path::Path, ids: &[&str]) -> Vec<PathBuf> {
+        let options = OutputOptions {
+            split_by: None,
+            timezone: Timezone::default(),
+            atomic: true,
+            timestamp_format: TimestampFormat::Rfc3339,
+            layout: Layout::Chunked,
+            chunk_size: NonZeroUsize::new(2).unwrap(),
+            sink: None,
+            canonical: false,
+            possibly_incomplete: vec![],
+            run: RUN,
+            field_map: FieldMap::EMPTY,
+            detect_language: false,
+            append: false,
+            append_index: None,
+        };
+        let notes: Vec<Note> = ids.iter().map(|x| serde_json::from_value(note_json(x, "2024-01-01T00:00:00.000Z")).unwrap()).collect();
+        let mut writer = ChunkWriter::new(dir, &options).unwrap();
+        writer.write_page(&notes);
+        writer.finish().unwrap()
+    }
+
+    fn snapshot(dir: &std::path::Path) -> Vec<(String, Vec<u8>, SystemTime)> {
+        let mut files: Vec<_> = fs::read_dir(dir).unwrap().map(|x| {
+            let path = x.unwrap().path();
+            let name = path.file_name().unwrap().to_string_lossy().into_owned();
+            (name, fs::read(&path).unwrap(), fs::metadata(&path).unwrap().modified().unwrap())
+        }).collect();
+        files.sort();
+        files
+    }
+
+    /// 前の実行と比べて、中身か更新日時が変わったか、無くなったファイル
+    fn touched(before: &[(String, Vec<u8>, SystemTime)], after: &[(String, Vec<u8>, SystemTime)]) -> Vec<String> {
+        before.iter().filter(|x| !after.contains(x)).map(|x| x.0.clone()).collect()
+    }
+
+    #[test]
+    fn appending_touches_at_most_the_last_chunk() {
+        let dir = capture_dir("chunked", &[]).join("archive");
+        let files = run(&dir, &["a5", "a4", "a3", "a2", "a1"]);
+        assert_eq!(files, ["chunk-a1.jsonl", "chunk-a3.jsonl", "chunk-a5.jsonl"].map(|x| dir.join(x)));
+
+        // 既に書いたa3は書き直さず、a6は埋まっていないchunk-a5に入る
+        let before = snapshot(&dir);
+        run(&dir, &["a6", "a3"]);
+        let after = snapshot(&dir);
+        assert_eq!(touched(&before, &after), ["chunk-a5.jsonl"]);
+        assert_eq!(after.len(), 3);
+
+        // 最後のファイルが埋まっていれば、既にあるファイルには触れない
+        let before = after;
+        run(&dir, &["a8", "a7"]);
+        let after = snapshot(&dir);
+        assert!(touched(&before, &after).is_empty());
+        assert_eq!(after.iter().map(|x| x.0.as_str()).collect::<Vec<_>>(), ["chunk-a1.jsonl", "chunk-a3.jsonl", "chunk-a5.jsonl", "chunk-a7.jsonl"]);
+
+        // 何も増えなければ何も書かない
+        run(&dir, &["a1", "a8"]);
+        assert!(touched(&after, &snapshot(&dir)).is_empty());
+
+        let ids: Vec<_> = reader::read_notes(&dir).unwrap().into_iter().map(|x| x.id.0).collect();
+        assert_eq!(ids, ["a1", "a2", "a3", "a4", "a5", "a6", "a7", "a8"]);
+    }
+}
@@ -0,0 +1,898 @@
+use std::error::Error;
+use std::fs;
+use std::net::{IpAddr, SocketAddr};
+use std::num::{NonZeroU32, NonZeroU64, NonZeroUsize};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use clap::{Args, CommandFactory, Parser, Subcommand, ValueEnum, ValueHint};
+use clap::error::ErrorKind;
+use url::Url;
+
+use crate::api::{ExtraHeader, MisskeyAuthorizationToken};
+use crate::chunk;
+use crate::deletion;
+use crate::explore::Granularity;
+use crate::export::ExportFormat;
+use crate::field_map::FieldMap;
+use crate::graph::UserRef;
+use crate::host::Host;
+use crate::http_tuning::HttpTuning;
+use crate::i18n::{msg, Lang};
+use crate::import::ImportFormat;
+use crate::log::{Level, LogFormat};
+use crate::mfm::{EmojiStyle, Linkify, MfmStyle};
+use crate::model::{ChannelId, ListId, NoteId, UserId};
+use crate::notify::NotifyFormat;
+use crate::output::{FlushPolicy, Layout, UserLayout};
+use crate::pagination::Direction;
+use crate::reactions::Bucket;
+use crate::report::Period;
+use crate::schema::{RecordKind, SchemaFormat};
+use crate::sink::{HttpSinkOptions, RemoteSink, SinkKind};
+use crate::split::SplitBy;
+use crate::timestamp::TimestampFormat;
+use crate::timezone::Timezone;
+
+#[derive(Eq, PartialEq, Parser)]
+#[command(version, after_help = crate::status::EXIT_CODES)]
+pub struct Cli {
+    #[command(flatten)]
+    pub global: GlobalArgs,
+    #[command(subcommand)]
+    pub cmd: Command,
+}
+
+/// 全てのサブコマンドで共通のオプション。サブコマンドの前後どちらに書いてもよい。
+#[derive(Eq, PartialEq, Args)]
+#[allow(clippy::struct_excessive_bools)]
+pub struct GlobalArgs {
+    #[clap(long, global = true)]
+    /// `https://misskey.example/`のようにURLで書いてもよい。
+    pub host: Option<Host>,
+    #[clap(long, global = true, conflicts_with_all = ["token_file", "token_env"])]
+    pub token: Option<MisskeyAuthorizationToken>,
+    #[clap(long, global = true, conflicts_with = "token_env", value_hint = ValueHint::FilePath)]
+    /// トークンをこのファイルから読む。前後の空白は無視する。
+    pub token_file: Option<PathBuf>,
+    #[clap(long, global = true)]
+    /// トークンをこの名前の環境変数から読む。
+    pub token_env: Option<String>,
+    #[cfg(feature = "keyring")]
+    #[clap(long, global = true, conflicts_with_all = ["token", "token_file", "token_env"])]
+    /// トークンを、`auth store`でこの名前の項目としてOSの鍵束に保存したものから読む。
+    pub token_keyring: Option<String>,
+    #[clap(long, global = true, value_name = "COMMAND")]
+    /// 実行の途中でトークンが拒まれたら、このコマンドを`sh -c`で実行し、標準出力を新しいトークンとして同じリクエストから続ける。
+    /// 無ければ、拒まれた時点で残りを遡らず、終了コード3で終わる。どこまで遡ったかは`gap`の記録に残る。
+    pub reauth_command: Option<String>,
+    #[clap(long = "cool-down", global = true)]
+    /// リクエストの間隔をミリ秒で指定。
+    pub cool_down_millisecond: Option<NonZeroUsize>,
+    #[clap(long, global = true, default_value = "1")]
+    /// クールダウンを待たずに続けて送ってよいリクエストの数。
+    pub burst: NonZeroU32,
+    #[clap(long = "timeout", global = true)]
+    /// 1回のリクエスト全体のタイムアウトを秒で指定。
+    pub timeout_second: Option<NonZeroU64>,
+    #[clap(long = "connect-timeout", global = true)]
+    /// 接続確立までのタイムアウトを秒で指定。
+    pub connect_timeout_second: Option<NonZeroU64>,
+    #[clap(long, global = true, conflicts_with = "ipv6_only")]
+    /// IPv4 だけで接続する。AAAAレコードへの接続が詰まる環境向け。
+    pub ipv4_only: bool,
+    #[clap(long, global = true)]
+    /// IPv6 だけで接続する。
+    pub ipv6_only: bool,
+    #[clap(long, global = true, value_name = "HOST:PORT:ADDR")]
+    /// curlと同じく、`HOST:PORT`への接続をDNSを引かずに`ADDR`へ向ける。繰り返し指定できる。
+    pub resolve: Vec<ResolveOverride>,
+    #[clap(long, global = true, value_name = "N")]
+    /// 1つのホストに、使い終えた接続をいくつまで残しておくか。APIと添付ファイルで別々に残す。ない場合はreqwestの既定。
+    pub pool_max_idle_per_host: Option<usize>,
+    #[clap(long, global = true)]
+    /// HTTP/1.1を試さず、初めからHTTP/2で話す。HTTP/2に対応していないサーバーやプロキシには繋がらない。
+    pub http2_prior_knowledge: bool,
+    #[clap(long, global = true, value_parser = parse_duration)]
+    /// `30s`のように単位を付けて指定する。接続にこの間隔でTCPのkeepaliveを送る。
+    pub tcp_keepalive: Option<Duration>,
+    #[clap(long, global = true)]
+    /// 送るリクエストの数の上限。達したらそこで止め、終了コード6で終わる。
+    pub max_requests: Option<NonZeroU64>,
+    #[clap(long, global = true, value_parser = parse_duration)]
+    /// `50m`のように、単位を付けて指定する。始めてからこれだけ過ぎたら新しいリクエストを送らずに止め、終了コード6で終わる。
+    /// 送っている最中のリクエストは待つ。`archive`では、続きを`{"kind": "gap"}`に書く。
+    pub max_duration: Option<Duration>,
+    #[clap(long, global = true, value_name = "PERCENT", value_parser = clap::value_parser!(u8).range(0..=100))]
+    /// あるエンドポイントへのリクエストのうち、頼み直しても失敗したものの割合がこれを超えたら、
+    /// 最後まで終わっても終了コード2で終わる。
+    pub fail_threshold: Option<u8>,
+    #[clap(long, global = true)]
+    /// 終了時に、エンドポイントごとの成功、頼み直した失敗、頼み直しても取れなかった失敗の数と、最も遅かった応答を
+    /// 標準エラー出力に表で書く。
+    pub retries_report: bool,
+    #[clap(long = "header", global = true, value_name = "NAME: VALUE")]
+    /// 全てのリクエストにこのヘッダーを付ける。認証プロキシの後ろにあるインスタンス向け。繰り返し指定できる。
+    pub headers: Vec<ExtraHeader>,
+    #[clap(long, global = true, conflicts_with = "replay", value_hint = ValueHint::DirPath)]
+    /// APIとのやり取りをこのディレクトリに保存する。
+    pub capture: Option<PathBuf>,
+    #[clap(long, global = true, value_hint = ValueHint::DirPath)]
+    /// ネットワークに接続せず、`--capture`で保存したやり取りを再生する。
+    pub replay: Option<PathBuf>,
+    #[clap(long, global = true, value_hint = ValueHint::FilePath)]
+    /// 標準出力ではなく、このファイルに書き出す。`{channel}`を含めるとチャンネルごとに別のファイルになる。
+    pub output: Option<PathBuf>,
+    #[clap(long, global = true, value_enum, default_value_t)]
+    /// 書いた記録を、いつ標準出力やファイル、`--sink`に渡すか。既定では、ページを書き終えるたびと終わるときに渡す。
+    /// `every-record`なら1つ書くたびに渡すので、書いている最中の出力を読むときに使う。`manual`なら溜まったときと終わるときだけ渡す。
+    pub flush: FlushPolicy,
+    #[clap(long, global = true, value_enum, requires_if("http", "sink_url"))]
+    /// 記録の書き出し先。無ければ、`--output`があればそのファイル、無ければ標準出力。
+    pub sink: Option<SinkKind>,
+    #[clap(long, global = true)]
+    /// `--sink http`で、記録をJSONの配列にまとめてPOSTする先。
+    pub sink_url: Option<Url>,
+    #[clap(long = "sink-header", global = true, value_name = "NAME: VALUE")]
+    /// `--sink http`のPOSTに付けるヘッダー。繰り返し指定できる。
+    pub sink_headers: Vec<ExtraHeader>,
+    #[clap(long, global = true, default_value = "100")]
+    /// `--sink http`で、1回のPOSTに含める記録の数の上限。ページを書き終えるたびにも送る。
+    pub sink_batch: NonZeroUsize,
+    #[clap(long, global = true, default_value = "misskey-channel-archiver-spill.jsonl", value_hint = ValueHint::FilePath)]
+    /// `--sink http`や`--sink s3`で、何度送っても失敗した記録を書き足すファイル。その場合は終了コード7で終わる。
+    pub sink_spill: PathBuf,
+    #[cfg(feature = "s3")]
+    #[clap(long, global = true)]
+    /// `--sink s3`でアップロードするバケット。認証情報は`AWS_ACCESS_KEY_ID`などの環境変数から読む。
+    pub s3_bucket: Option<String>,
+    #[cfg(feature = "s3")]
+    #[clap(long, global = true, default_value = "")]
+    /// `--sink s3`で、`--output`のファイル名の前に付けてオブジェクトの名前にする。
+    pub s3_prefix: String,
+    #[cfg(feature = "s3")]
+    #[clap(long, global = true)]
+    /// MinIOなど、AWS以外のS3互換ストレージのURL。無ければ`AWS_ENDPOINT_URL`か、リージョンのAWSのエンドポイント。
+    pub s3_endpoint: Option<Url>,
+    #[clap(long, global = true)]
+    /// `--output`の一時ファイルを使わず、直接書き込む。書き込み中のファイルを`tail -f`で見たいときに使う。
+    pub no_atomic: bool,
+    #[clap(short, long, global = true, conflicts_with = "verbose")]
+    /// エラー以外を標準エラー出力に書かない。
+    pub quiet: bool,
+    #[clap(short, long, global = true)]
+    /// 送ったリクエストの本文なども標準エラー出力に書く。
+    pub verbose: bool,
+    #[clap(long, global = true)]
+    /// ページごとの進み具合を標準エラー出力に書かない。
+    pub no_progress: bool,
+    #[clap(long, global = true, value_enum, default_value_t)]
+    /// `json`なら、標準エラー出力に書くものを1行に1つのJSONにする。引数の誤りなどのエラーも、
+    /// `{"kind": "usage-error"}`のような1つのオブジェクトとして書く。
+    pub log_format: LogFormat,
+    #[clap(long, global = true, value_hint = ValueHint::FilePath)]
+    /// 終了時に、リクエスト数などをPrometheusのtextfile形式でこのファイルに書き出す。
+    pub metrics_output: Option<PathBuf>,
+    #[clap(long, global = true, value_name = "ADDR")]
+    /// `127.0.0.1:9184`のように指定すると、実行中は`GET /metrics`にPrometheusの形式で同じ数を返す。
+    /// トークンやホスト名は含めない。
+    pub metrics_listen: Option<SocketAddr>,
+    #[clap(long, global = true, value_hint = ValueHint::FilePath)]
+    /// リクエストの間隔の状態を、`--host`ごとにこのファイルに残す。同じインスタンスへの次の実行は、そこから間隔を保って始める。
+    /// 同時に動く実行が同じファイルを使ったときは、警告して遅い方の時刻を残す。
+    pub pacer_state: Option<PathBuf>,
+    #[clap(long, global = true)]
+    /// 終わったときに、結果をこのURLへPOSTする。送れなくても終了コードは変わらない。
+    pub notify_webhook: Option<Url>,
+    #[clap(long, global = true, value_enum, default_value_t)]
+    /// `--notify-webhook`へ送る本文の形。
+    pub notify_format: NotifyFormat,
+    #[clap(long, global = true, default_value = "UTC")]
+    /// `--split-by`で日付を区切るときのタイムゾーン。`Asia/Tokyo`のようなIANAの名前か、`+09:00`のようなオフセット。
+    /// 書き出す`createdAt`はUTCのまま。
+    pub timezone: Timezone,
+    #[clap(long, global = true, value_enum, default_value_t)]
+    /// 書き出すノートの`createdAt`の形式。
+    pub timestamp_format: TimestampFormat,
+    #[clap(long, global = true, value_name = "PATH", value_parser = FieldMap::open)]
+    /// 書き出すノートとユーザーのフィールドの名前を変える。`{"note": {"cw": "cw_text"}, "user": {"username": "handle"}}`のようなJSON。
+    /// 変えられるのは一番上のフィールドだけ。対応は`meta`の記録に`field_map`として書く。
+    pub field_map: Option<FieldMap>,
+    #[clap(long, global = true, value_enum)]
+    /// エラーや警告など、人が読むメッセージの言葉。ない場合は`LC_ALL`、`LC_MESSAGES`、`LANG`から決める。
+    /// JSONの記録は変えない。
+    pub lang: Option<Lang>,
+}
+
+impl GlobalArgs {
+    /// `--sink`と`--output`の組み合わせが合わなければ、その説明のキーを返す。
+    pub fn sink_conflict(&self) -> Option<&'static str> {
+        match (self.sink, &self.output, &self.sink_url) {
+            (Some(SinkKind::Http), Some(_), _) => Some("sink-http-output"),
+            (Some(SinkKind::Stdout), Some(_), _) => Some("sink-stdout-output"),
+            (Some(SinkKind::File), None, _) => Some("sink-file-output"),
+            (_, _, Some(_)) if self.sink != Some(SinkKind::Http) => Some("sink-url-http"),
+            #[cfg(feature = "s3")]
+            (Some(SinkKind::S3), None, _) => Some("sink-s3-output"),
+            #[cfg(feature = "s3")]
+            (Some(SinkKind::S3), Some(_), _) if self.s3_bucket.is_none() => Some("sink-s3-bucket"),
+            _ => None,
+        }
+    }
+
+    pub const fn http_tuning(&self) -> HttpTuning {
+        HttpTuning {
+            pool_max_idle_per_host: self.pool_max_idle_per_host,
+            http2_prior_knowledge: self.http2_prior_knowledge,
+            tcp_keepalive: self.tcp_keepalive,
+        }
+    }
+
+    /// `--sink http`や`--sink s3`なら、その設定を返す。組み合わせは`usage`で確かめてある。
+    pub fn remote_sink(&self) -> Result<Option<RemoteSink>, clap::Error> {
+        if let Some(key) = self.sink_conflict() {
+            return Err(Cli::command().error(ErrorKind::ArgumentConflict, msg(key, &[])))
+        }
+        match (self.sink, &self.output, &self.sink_url) {
+            (Some(SinkKind::Http), None, Some(url)) => Ok(Some(RemoteSink::Http(HttpSinkOptions {
+                url: url.clone(),
+                headers: self.sink_headers.clone(),
+                batch: self.sink_batch,
+                spill: self.sink_spill.clone(),
+                backoff: Duration::from_secs(1),
+            }))),
+            #[cfg(feature = "s3")]
+            (Some(SinkKind::S3), Some(_), _) => self.s3_sink().map(|x| Some(RemoteSink::S3(x))),
+            _ => Ok(None),
+        }
+    }
+
+    #[cfg(feature = "s3")]
+    fn s3_sink(&self) -> Result<crate::s3::S3SinkOptions, clap::Error> {
+        let missing = |message: String| Cli::command().error(ErrorKind::MissingRequiredArgument, message);
+        let bucket = self.s3_bucket.clone().ok_or_else(|| missing("--sink s3 requires --s3-bucket".to_owned()))?;
+        let region = crate::s3::region_from_env();
+        let endpoint = match &self.s3_endpoint {
+            Some(endpoint) => endpoint.clone(),
+            None => std::env::var("AWS_ENDPOINT_URL").unwrap_or_else(|_| format!("https://s3.{region}.amazonaws.com"))
+                .parse().map_err(|e| missing(format!("AWS_ENDPOINT_URL is not a valid URL: {e}")))?,
+        };
+
+        Ok(crate::s3::S3SinkOptions {
+            endpoint,
+            region,
+            bucket,
+            prefix: self.s3_prefix.clone(),
+            credentials: crate::s3::Credentials::from_env().map_err(missing)?,
+            part_size: crate::s3::MIN_PART_SIZE,
+            spill: self.sink_spill.clone(),
+            backoff: Duration::from_secs(1),
+        })
+    }
+
+    pub const fn log_level(&self) -> Level {
+        if self.quiet {
+            Level::Quiet
+        } else if self.verbose {
+            Level::Debug
+        } else {
+            Level::Normal
+        }
+    }
+
+    /// `--token`で渡されたものは複製せずに取り出す。
+    pub fn resolve_token(&mut self) -> Result<Option<MisskeyAuthorizationToken>, Box<dyn Error + Send + Sync>> {
+        if let Some(token) = self.token.take() {
+            return Ok(Some(token));
+        }
+
+        if let Some(path) = &self.token_file {
+            let content = fs::read_to_string(path).map_err(|e| format!("failed to read token file {}: {e}", path.display()))?;
+            return Ok(Some(MisskeyAuthorizationToken::new(content)));
+        }
+
+        if let Some(name) = &self.token_env {
+            let value = std::env::var(name).map_err(|e| format!("failed to read token from ${name}: {e}"))?;
+            return Ok(Some(MisskeyAuthorizationToken::new(value)));
+        }
+
+        #[cfg(feature = "keyring")]
+        if let Some(entry) = &self.token_keyring {
+            return crate::keyring::load(entry).map(Some);
+        }
+
+        Ok(None)
+    }
+
+    pub fn cool_down(&self) -> Duration {
+        self.cool_down_millisecond.map_or(Duration::ZERO, |x| Duration::from_millis(x.get() as u64))
+    }
+}
+
+/// `export`で、本文のMFMをどうHTMLにするか
+#[derive(Eq, PartialEq, Args)]
+pub struct TextArgs {
+    #[clap(long, value_enum, default_value_t)]
+    /// `plain`なら記法を取り除き、`render-basic`なら太字や引用などをHTMLの要素にする。
+    pub mfm: MfmStyle,
+    #[clap(long, value_enum, default_value_t)]
+    /// `image`なら、`--emoji-dir`にある`<name>.png`などの画像を`<img>`で書く。`unicode-fallback`なら、
+    /// 名前がUnicodeの絵文字のショートコードと同じものをその絵文字にする。どちらも、見つからない絵文字は`:name:`のまま書く。
+    pub emoji_style: EmojiStyle,
+    #[clap(long, value_hint = ValueHint::DirPath, required_if_eq("emoji_style", "image"))]
+    /// `--emoji-style image`で、絵文字の画像を探すディレクトリ。`src`にはこのパスをそのまま書く。
+    pub emoji_dir: Option<PathBuf>,
+    #[clap(long, value_enum, default_value_t)]
+    /// `on`なら、本文のURLを`<a>`にする。
+    pub linkify: Linkify,
+}
+
+/// `archive`と`archive-list`で共通の、タイムラインの辿り方と書き出し方
+#[derive(Eq, PartialEq, Args)]
+#[allow(clippy::struct_excessive_bools)]
+pub struct TimelineArgs {
+    #[clap(long)]
+    /// どこから遡るか。ない場合は実行時点の最新のノートから。
+    pub before: Option<NoteId>,
+    #[clap(long)]
+    /// どこまで遡るか。ない場合は実行時点の最古のノートまで。
+    pub after: Option<NoteId>,
+    #[clap(long)]
+    /// 事前確認と最初の1ページの取得だけを行い、所要時間を見積もって終了する。
+    pub dry_run: bool,
+    #[clap(long, value_name = "day|month|count:<n>")]
+    /// ノートを作成日(UTC)ごと、またはn件ごとに別のファイルへ書き出す。ファイル名は`--output`を元にする。
+    pub split_by: Option<SplitBy>,
+    #[clap(long, value_hint = ValueHint::FilePath)]
+    /// 終了時に、書き出したファイルのSHA-256をこのファイルに記録する。
+    pub manifest: Option<PathBuf>,
+    #[clap(long)]
+    /// 遡ったノートへの返信も取得する。返信がチャンネルの外にあっても取得する。
+    pub fetch_replies_to_archived: bool,
+    #[clap(long, default_value = "1", requires = "fetch_replies_to_archived")]
+    /// 返信への返信を何段まで辿るか。
+    pub max_reply_depth: NonZeroUsize,
+    #[clap(long, value_hint = ValueHint::FilePath, requires_all = ["fetch_replies_to_archived", "append"])]
+    /// 返信を全て書き出した親のIDを書き足していくファイル。次の実行ではそこにある親を飛ばす。
+    /// 前の実行で書いた返信を残すため、`--append`と使う。
+    pub reply_checkpoint: Option<PathBuf>,
+    #[clap(long)]
+    /// それぞれのノートとユーザーに、`--host`から見たURLを`local_url`として書き足す。
+    pub emit_note_urls: bool,
+    #[clap(long)]
+    /// タイムラインに含まれている作者の`username`、`name`、`avatarUrl`、`host`も書き出す。
+    /// 表示するだけなら`fetch-user`が要らなくなる。
+    pub inline_user_detail: bool,
+    #[clap(long, value_enum, default_value_t, conflicts_with = "split_by")]
+    /// `tree`なら、`--output`のディレクトリに1つのノートを1つの整形したJSONファイルとして書く。
+    /// 中身が変わらないファイルは書き換えないので、gitで管理しやすい。
+    /// `chunked`なら、ノートをIDの順に`--chunk-size`ずつ`chunk-<最初のノートのID>.jsonl`に書く。
+    /// 書き足しで変わるのは最後のファイルだけなので、rsyncで送り直す量が少ない。
+    pub output_layout: Layout,
+    #[clap(long)]
+    /// `untilId`より新しいノートなど、頼んだ範囲の外のノートがAPIから返ってきても取り除かない。
+    pub no_range_filter: bool,
+    #[clap(long, value_enum, default_value_t, requires_if("forward", "after"))]
+    /// `forward`なら、`--after`から新しい方へ進み、古いノートから順に書き出す。
+    pub direction: Direction,
+    #[clap(long, value_name = "LANG")]
+    /// 本文のあるノートを`notes/translate`でこの言語に訳し、`translation`として書き足す。
+    /// ノート1つにつき1回リクエストが増える。サーバーで翻訳が使えなければ、警告を出して訳さない。
+    pub translate: Option<String>,
+    #[clap(long)]
+    /// `--before`と`--after`の両方があっても、前後が正しいかを`notes/show`で確かめない。
+    /// トークンにノートを読む権限が無いときに使う。
+    pub force_range: bool,
+    #[clap(long)]
+    /// それぞれのノートに、正規化したJSONのSHA-256を`content_hash`として埋め込む。
+    /// 同じハッシュを、ノートのIDと書き出した日時と共に`--hashes-output`にも書く。`verify-notes`で確かめられる。
+    pub hash_notes: bool,
+    #[clap(long, default_value = "hashes.ndjson", value_hint = ValueHint::FilePath, requires = "hash_notes")]
+    pub hashes_output: PathBuf,
+    #[clap(long, conflicts_with_all = ["split_by", "output_layout"])]
+    /// 同じ範囲を遡れば、先頭の`meta`を除いて同じバイト列になるように書き出す。ページを1つのノートにつき1行の
+    /// `{"kind": "note"}`に分け、全ての記録のキーを並べ、ノートをIDの順に並べる。日時は`--timestamp-format`によらずRFC 3339で書き、
+    /// 実行ごとに変わる`log`の記録はファイルではなく標準エラー出力に書く。`archived_at`と`run_id`はノートに添えず、`meta`にだけ書く。
+    pub canonical: bool,
+    #[clap(long, default_value = "3", value_name = "N")]
+    /// サーバーが結果の一部しか返せなかったと示したとき、同じ範囲を頼み直す回数。
+    /// それでも欠けていれば、そのページを使って`warning`を記録する。
+    pub partial_retries: u32,
+    #[clap(long, default_value = "5s", value_parser = parse_duration)]
+    /// 頼み直すまでに待つ時間。2回目からは、回数に比例して長く待つ。
+    pub partial_backoff: Duration,
+    #[clap(long, value_name = "N")]
+    /// それぞれのノートのリアクションを、数の多いN種類だけ書く。残りの数の合計は`other_reactions_count`に書く。
+    /// ない場合は全て書く。
+    pub max_reactions_per_note: Option<NonZeroUsize>,
+    #[clap(long, value_name = "N", default_value_t = chunk::DEFAULT_CHUNK_SIZE)]
+    /// `--output-layout chunked`で、1つのファイルに入れるノートの数。同じディレクトリに書き足すときは変えない。
+    pub chunk_size: NonZeroUsize,
+    #[clap(long)]
+    /// それぞれのノートに、本文から推定した言語を`detected_language`として付ける。短い本文とリノートには付けない。
+    pub detect_language: bool,
+    #[clap(long, conflicts_with_all = ["split_by", "output_layout"])]
+    /// `--output`のファイルを消さずに書き足す。始める前にファイルからノートのIDだけを読み、既にあるノートは書かない。
+    /// 一時ファイルは使わないので、途中で止まっても書いた分は残る。
+    pub append: bool,
+    #[clap(long, value_hint = ValueHint::FilePath, requires = "append")]
+    /// `--append`で、書いたノートのIDを1行に1つ覚えておくファイル。あれば`--output`の代わりにこれを読み、終わったときに書き直す。
+    pub append_index: Option<PathBuf>,
+    #[clap(long)]
+    /// それぞれのページの前に、そのページを取ったリクエストの`untilId`、`sinceId`、`limit`と何回目に取れたかを
+    /// `{"kind": "page"}`として書く。トークンは書かない。`verify-pages`で、ページが重ならずに続いているかを確かめられる。
+    pub emit_page_markers: bool,
+}
+
+#[derive(Eq, PartialEq, Subcommand)]
+pub enum Command {
+    Archive {
+        #[clap(long, required_unless_present = "channels_from")]
+        /// 複数回指定すると、指定した順に遡る。
+        channel_id: Vec<ChannelId>,
+        #[clap(long, value_hint = ValueHint::FilePath)]
+        /// チャンネルIDを1行に1つずつ書いたファイル。`--channel-id`と併用できる。
+        channels_from: Option<PathBuf>,
+        #[clap(long)]
+        /// あるチャンネルで失敗したら、残りのチャンネルを遡らずに終了する。
+        fail_fast: bool,
+        #[clap(long, default_value = "1")]
+        /// 同時に遡るチャンネルの数。2以上にする場合は`--output`に`{channel}`を含める必要がある。
+        /// リクエストの間隔は全てのチャンネルで合わせて守る。
+        parallel_channels: NonZeroUsize,
+        #[clap(long)]
+        /// チャンネルの説明やバナーのURL、ピン留めされたノートも書き出す。
+        with_channel_info: bool,
+        #[clap(long, value_name = "N")]
+        /// 全てを遡ったチャンネルで、書き出したノートが`notesCount`よりこれを超えて少なければ、終了コード2で終わる。
+        max_missing_notes: Option<usize>,
+        #[clap(long, value_name = "PREVIOUS", value_hint = ValueHint::FilePath)]
+        /// 前の実行のアーカイブ。そこにあり、今回遡った範囲に見えなかったノートを`{"kind": "tombstone"}`として書く。
+        /// 前に書いた中身は`last_known`に残す。
+        detect_deletions: Option<PathBuf>,
+        #[clap(long, value_name = "N", default_value_t = deletion::DEFAULT_SAMPLE)]
+        /// 見えなかったノートのうち、新しいものからN個だけ`notes/show`で消えたことを確かめる。
+        /// 残りは`"confirmed": false`として書く。
+        deletion_sample: NonZeroUsize,
+        #[clap(long, requires = "detect_deletions")]
+        /// 見えなかったノートを全て確かめる。
+        verify_deletions_all: bool,
+        #[clap(long)]
+        /// ミュートかブロックしているユーザーがいても警告せず、`possibly_incomplete`にもしない。
+        /// `mute/list`と`blocking/list`を引かないので、ノートに`hidden_author`も付けない。
+        ignore_mutes_warning: bool,
+        #[command(flatten)]
+        timeline: TimelineArgs,
+    },
+    /// ユーザーリストのタイムラインを遡る。先頭にリストの名前とメンバーを書く。
+    ArchiveList {
+        #[clap(long)]
+        list_id: ListId,
+        #[command(flatten)]
+        timeline: TimelineArgs,
+    },
+    /// `archive`や`archive-list`が書いた`{"kind": "gap"}`の範囲だけを遡り直す。
+    /// `--before`、`--after`、`--direction`は記録のものを使う。
+    Backfill {
+        #[clap(long, value_hint = ValueHint::FilePath)]
+        /// `gap`の記録を含む、前回の出力
+        input: PathBuf,
+        #[command(flatten)]
+        timeline: TimelineArgs,
+    },
+    /// 与えたIDのノートを`notes/show`で1つずつ取り、`archive`と同じ形で、与えた順に書き出す。
+    /// 消えたノートは`{"kind": "tombstone"}`として記録する。
+    FetchNotes {
+        #[clap(long, value_hint = ValueHint::FilePath)]
+        /// ノートのIDを1行に1つずつ書いたファイル。空行と`#`から始まる行は無視し、同じIDは1度だけ取る。
+        ids_from: PathBuf,
+        #[clap(long)]
+        /// それぞれのノートとユーザーに、`--host`から見たURLを`local_url`として書き足す。
+        emit_note_urls: bool,
+        #[clap(long)]
+        /// 作者の`username`、`name`、`avatarUrl`、`host`も書き出す。
+        inline_user_detail: bool,
+        #[clap(long, value_hint = ValueHint::FilePath)]
+        /// 終了時に、書き出したファイルのSHA-256をこのファイルに記録する。
+        manifest: Option<PathBuf>,
+    },
+    /// `archive`の出力のうち、`--older-than`より前の状態のノートを`notes/show`で取り直し、
+    /// リアクションや返信の数を新しくして元の順に書き出す。消えたノートは`{"kind": "tombstone"}`にする。
+    Refresh {
+        #[clap(long, value_hint = ValueHint::FilePath)]
+        input: PathBuf,
+        #[clap(long, default_value = "7d", value_parser = parse_duration)]
+        /// `30m`、`12h`、`7d`のように、単位を付けて指定する。前の`refresh`で取り直した日時か、無ければ`--input`の更新日時と比べる。
+        older_than: Duration,
+    },
+    FetchUser {
+        #[clap(long)]
+        user: Vec<UserId>,
+        #[clap(long, value_hint = ValueHint::FilePath)]
+        /// 取得したユーザーをこのファイルに残し、次からは`--user-cache-ttl`より新しければそれを使う。
+        user_cache: Option<PathBuf>,
+        #[clap(long, default_value = "7d", value_parser = parse_duration, requires = "user_cache")]
+        /// `30m`、`12h`、`7d`のように、単位を付けて指定する。
+        user_cache_ttl: Duration,
+        #[clap(long, alias = "output-format", value_enum, default_value_t)]
+        /// `per-user`なら、`--output`のディレクトリに1人を1つの整形したJSONファイルとして書き、`index.json`にIDと取得した日時をまとめる。
+        /// 中身が変わらないファイルは書き換えない。`map`なら、全てのユーザーをIDをキーとする1つのJSONオブジェクトにして最後に書く。
+        output_layout: UserLayout,
+        #[clap(long)]
+        /// `--output-layout map`で、`--output`のファイルにあるユーザーを読み込み、取得したユーザーで置き換えて書き直す。
+        append: bool,
+        #[clap(long)]
+        /// プロフィールにピン留めしたノートも`{"kind": "pinned-note"}`として書き出す。
+        with_pinned_notes: bool,
+        #[clap(long, value_hint = ValueHint::FilePath, requires = "with_pinned_notes")]
+        /// この`archive`の出力にあるノートは、ピン留めされていても書き出さない。
+        archived: Option<PathBuf>,
+    },
+    /// ユーザーをフォローしているユーザーを、`{"kind": "follow"}`の辺として書き出す。
+    FetchFollowers {
+        #[clap(long, required_unless_present = "users_from")]
+        /// `9abc`のようなIDか、`@alice`や`@alice@misskey.example`のような名前。繰り返し指定できる。
+        user: Vec<UserRef>,
+        #[clap(long, value_hint = ValueHint::FilePath)]
+        /// `archive`の出力に現れたノートの作者も全て辿る。
+        users_from: Option<PathBuf>,
+    },
+    /// ユーザーがフォローしているユーザーを、`{"kind": "follow"}`の辺として書き出す。
+    FetchFollowing {
+        #[clap(long, required_unless_present = "users_from")]
+        /// `9abc`のようなIDか、`@alice`や`@alice@misskey.example`のような名前。繰り返し指定できる。
+        user: Vec<UserRef>,
+        #[clap(long, value_hint = ValueHint::FilePath)]
+        /// `archive`の出力に現れたノートの作者も全て辿る。
+        users_from: Option<PathBuf>,
+    },
+    /// ノートへのリアクションを、付けた人と日時と共に書き出す。
+    FetchReactions {
+        #[clap(long, required_unless_present = "notes_from")]
+        /// 繰り返し指定できる。
+        note_id: Vec<NoteId>,
+        #[clap(long, value_hint = ValueHint::FilePath)]
+        /// `archive`の出力に現れたノートのうち、リアクションのあるものも全て辿る。
+        notes_from: Option<PathBuf>,
+        #[clap(long, value_name = "RFC3339")]
+        /// これより前に付いたリアクションは書き出さない。
+        since: Option<DateTime<Utc>>,
+        #[clap(long, value_name = "RFC3339")]
+        /// これ以降に付いたリアクションは書き出さない。
+        until: Option<DateTime<Utc>>,
+        #[clap(long, value_enum)]
+        /// 1つずつではなく、`--timezone`で区切った時間ごとの数を、ノートごとと全体で書き出す。
+        histogram: Option<Bucket>,
+    },
+    /// ノートをRenoteしたユーザーとその日時を、`{"kind": "renote"}`として書き出す。`user_id`で`fetch-user`の出力とつなげられる。
+    /// Renoteされたノート1つごとに、少なくとも1回のリクエストがかかる。
+    FetchRenotes {
+        #[clap(long, required_unless_present = "notes_from")]
+        /// 繰り返し指定できる。
+        note_id: Vec<NoteId>,
+        #[clap(long, value_hint = ValueHint::FilePath)]
+        /// `archive`の出力に現れたノートのうち、`--min-renote-count`回以上Renoteされたものも全て辿る。
+        notes_from: Option<PathBuf>,
+        #[clap(long, value_name = "N", default_value = "1")]
+        /// `--notes-from`のノートのうち、`renoteCount`がこれ以上のものだけを辿る。
+        min_renote_count: NonZeroUsize,
+        #[clap(long, value_hint = ValueHint::FilePath)]
+        /// 書き終えたノートのIDを書き足していくファイル。次の実行ではそこにあるノートを飛ばし、
+        /// `--output`のファイルも消さずに書き足す。
+        checkpoint: Option<PathBuf>,
+    },
+    /// インスタンスで目立っているチャンネルとハッシュタグを、`{"kind": "featured-snapshot"}`として1行書き出す。
+    /// cronで`archive`と並べて動かし、同じディレクトリに残しておくと、その頃のインスタンスの様子が分かる。トークンは無くてもよい。
+    SnapshotFeatured,
+    /// 添付ファイルを落とさずに、全て落とすとどれだけの大きさになるかを見積もる。
+    /// MIMEタイプごとの表と大きいファイルを標準エラー出力に、`{"kind": "file-estimate"}`を1行書き出す。
+    EstimateFiles {
+        #[clap(long, required_unless_present = "input")]
+        /// 最後まで遡って数える。繰り返し指定できる。
+        channel_id: Vec<ChannelId>,
+        #[clap(long, value_hint = ValueHint::FilePath, conflicts_with = "channel_id")]
+        /// 遡らずに、この`archive`の出力にあるファイルを数える。
+        input: Option<PathBuf>,
+        #[clap(long, value_name = "N", default_value = "10")]
+        /// 大きい順に挙げるファイルの数
+        largest: usize,
+        #[clap(long, value_name = "N", default_value = "20")]
+        /// `size`の無いファイルのうち、HEADで大きさを確かめる数。その平均で残りを見積もる
+        sample: usize,
+    },
+    /// ノートを全て辿らずに、チャンネルのノートが時期ごとにどれだけあるかを見積もる。
+    /// ヒストグラムを標準エラー出力に、チャンネルごとに`{"kind": "note-density"}`を1行書き出す。区切りは`--timezone`で決める。
+    Explore {
+        #[clap(long, required = true)]
+        /// 繰り返し指定できる。
+        channel_id: Vec<ChannelId>,
+        #[clap(long, value_enum, default_value_t)]
+        granularity: Granularity,
+    },
+    /// ブラウザで承認してもらい、このツールに必要な権限だけを持つトークンを発行する。
+    /// トークンは標準出力に書き出す。
+    Auth {
+        #[cfg(feature = "keyring")]
+        #[command(subcommand)]
+        action: Option<AuthAction>,
+        #[clap(long, default_value = "misskey-channel-archiver")]
+        /// Misskeyのアクセストークンの一覧に表示される名前。
+        name: String,
+        #[clap(long = "wait", default_value = "300")]
+        /// 承認を待つ秒数。
+        wait_second: NonZeroU64,
+    },
+    /// 初めて使うときに、ホスト、トークン、遡るチャンネルを順に尋ね、トークンとチャンネルの一覧をファイルに書く。
+    /// 最後に、それを使って`archive`を始めるコマンドを標準出力に書く。
+    /// `--host`、`--token-file`などのトークンのフラグ、`--miauth`、`--channel-id`を渡せば、その分は尋ねない。
+    Init {
+        #[clap(long)]
+        /// トークンを貼り付ける代わりに、MiAuthでブラウザから発行する。
+        miauth: bool,
+        #[clap(long)]
+        /// 遡るチャンネル。複数指定できる。あれば一覧から選ばない。
+        channel_id: Vec<ChannelId>,
+        #[clap(long, default_value = ".", value_hint = ValueHint::DirPath)]
+        /// トークンとチャンネルの一覧を書くディレクトリ。
+        profile_dir: PathBuf,
+        #[clap(long = "wait", default_value = "300")]
+        /// MiAuthで、承認を待つ秒数。
+        wait_second: NonZeroU64,
+    },
+    /// `--manifest`で記録したファイルのハッシュを求め直し、壊れたり無くなったりしていないか確かめる。
+    VerifyManifest {
+        #[clap(value_hint = ValueHint::FilePath)]
+        path: PathBuf,
+    },
+    /// `--hash-notes`で埋め込んだハッシュを求め直し、書き出した後にノートが書き換えられていないか確かめる。
+    VerifyNotes {
+        #[clap(required = true, value_hint = ValueHint::FilePath)]
+        input: Vec<PathBuf>,
+        #[clap(long, value_hint = ValueHint::FilePath)]
+        /// `--hashes-output`のファイル。あれば、そこに残したハッシュとも比べる。
+        hashes: Option<PathBuf>,
+    },
+    /// `--emit-page-markers`で書いたアーカイブを読み、それぞれのページが前のページに続き、重なっていないか確かめる。
+    VerifyPages {
+        #[clap(value_hint = ValueHint::FilePath)]
+        input: PathBuf,
+    },
+    /// 書き出したアーカイブを集計し、日ごとのノート数や投稿の多い人などをまとめたJSONを書き出す。
+    /// 日付と時刻は`--timezone`で数える。
+    Report {
+        #[clap(long, required = true, value_hint = ValueHint::FilePath)]
+        /// `archive`の出力。複数指定でき、同じノートは1度だけ数える。
+        input: Vec<PathBuf>,
+        #[clap(long, value_hint = ValueHint::FilePath)]
+        /// `fetch-user`の出力。あればusernameを添える。
+        users: Option<PathBuf>,
+        #[clap(long)]
+        /// `2024-11`のような月。無ければ全体を集計する。
+        period: Option<Period>,
+        #[clap(long, value_hint = ValueHint::FilePath)]
+        /// 表にしたMarkdownもここに書き出す。
+        markdown: Option<PathBuf>,
+    },
+    /// 書き出したアーカイブを読み、リアクションの多かったノートの順位表をJSONで書き出す。
+    /// 同じ点数なら先に書かれたノートを上にする。`--host`があれば、ノートへのリンクをそこから組み立てる。
+    Leaderboard {
+        #[clap(long, required = true, value_hint = ValueHint::AnyPath)]
+        /// `archive`の出力。複数指定でき、同じノートは1度だけ数える。
+        input: Vec<PathBuf>,
+        #[clap(long, value_hint = ValueHint::FilePath)]
+        /// `fetch-user`の出力。あれば作者の名前とusernameを添える。
+        users: Option<PathBuf>,
+        #[clap(long, default_value = "20", value_name = "N")]
+        /// 載せるノートの数。
+        top: NonZeroUsize,
+        #[clap(long)]
+        /// `2024-11`のような月。`--timezone`で数える。無ければ全体から選ぶ。
+        period: Option<Period>,
+        #[clap(long, value_hint = ValueHint::FilePath)]
+        /// 1行に`<絵文字>=<重み>`を書いたファイル。書いていない絵文字の重みは1。
+        /// 絵文字はアーカイブでの書き方で、カスタム絵文字なら`:blobcat@.:`のように書く。
+        weights: Option<PathBuf>,
+        #[clap(long, value_hint = ValueHint::FilePath)]
+        /// そのまま貼れるMarkdownの表もここに書き出す。
+        markdown: Option<PathBuf>,
+    },
+    /// 書き出したアーカイブを読み、ユーザーがどのチャンネルにどれだけノートを書いたかを、
+    /// ユーザーとチャンネルの組ごとに`{"kind": "user-activity"}`として書き出す。
+    UserActivity {
+        #[clap(long, required = true, value_hint = ValueHint::AnyPath)]
+        /// `archive`の出力か、それを置いたディレクトリ。ディレクトリなら、その直下の`.jsonl`と`.ndjson`を読む。
+        /// 複数指定でき、同じノートは1度だけ数える。
+        input: Vec<PathBuf>,
+        #[clap(long, required_unless_present = "users_from")]
+        /// 複数指定できる。
+        user: Vec<UserId>,
+        #[clap(long, value_hint = ValueHint::FilePath)]
+        /// ユーザーIDを1行に1つずつ書いたファイル。`--user`と併用できる。
+        users_from: Option<PathBuf>,
+        #[clap(long, value_hint = ValueHint::FilePath)]
+        /// 表にしたMarkdownもここに書き出す。
+        markdown: Option<PathBuf>,
+    },
+    /// 他の形式で書き出されたノートを、`archive`の出力と同じ形に変換して`--output`に書く。
+    /// チャンネルを遡ったアーカイブと、ユーザーが自分で書き出したノートを合わせて読めるようになる。
+    Import {
+        #[clap(long, value_enum)]
+        from: ImportFormat,
+        #[clap(long, value_hint = ValueHint::FilePath)]
+        input: PathBuf,
+        #[clap(long)]
+        /// 書き出したユーザーのID。Misskeyの書き出しには含まれないので、全てのノートの作者とする。
+        user: UserId,
+    },
+    /// 書き出したアーカイブを、他のFediverseのアーカイブツールが読める形に変換する。
+    /// オブジェクトのIDを組み立てるのに`--host`を使う。
+    Export {
+        #[clap(long, required = true, value_hint = ValueHint::FilePath)]
+        /// `archive`の出力。複数指定でき、同じノートは1度だけ書く。
+        input: Vec<PathBuf>,
+        #[clap(long, value_enum)]
+        format: ExportFormat,
+        #[clap(long, value_hint = ValueHint::FilePath)]
+        /// `fetch-user`の出力。あれば`attributedTo`にusernameを添える。
+        users: Option<PathBuf>,
+        #[clap(long, value_enum, default_value_t)]
+        /// `tree`なら、`--output`のディレクトリに1つのオブジェクトを1つの整形したJSONファイルとして書き、
+        /// `collection.json`にそのIDを並べる。
+        output_layout: Layout,
+        #[command(flatten)]
+        text: TextArgs,
+    },
+    /// 書き出す記録の形をJSON Schema (2020-12)で書き出す。無ければ、どの行にも当てはまる1つの文書にする。
+    Schema {
+        #[clap(long, value_name = "KIND")]
+        /// この種類の記録だけを書き出す。
+        record: Option<RecordKind>,
+        #[clap(long, default_value = "json-schema")]
+        /// `typescript`なら、同じ形をTypeScriptの型として書き出す。
+        format: SchemaFormat,
+    },
+    /// シェル補完スクリプトやmanページを出力する。
+    #[command(hide = true)]
+    Generate {
+        target: GenerateTarget,
+        #[clap(long, value_hint = ValueHint::DirPath)]
+        /// 標準出力ではなく、このディレクトリにファイルとして書き出す。
+        out_dir: Option<PathBuf>,
+    },
+}
+
+#[cfg(feature = "keyring")]
+#[derive(Eq, PartialEq, Subcommand)]
+pub enum AuthAction {
+    /// `--token`などで渡したトークンを、OSの鍵束に`entry`という名前で保存する。
+    Store {
+        entry: String,
+    },
+}
+
+/// `30s`、`15m`、`12h`、`7d`
+fn parse_duration(s: &str) -> Result<Duration, String> {
+    let unit = match s.chars().last() {
+        Some('s') => 1,
+        Some('m') => 60,
+        Some('h') => 60 * 60,
+        Some('d') => 24 * 60 * 60,
+        _ => return Err("expected a number followed by s, m, h or d".to_owned()),
+    };
+    let value: u64 = s[..s.len() - 1].parse().map_err(|_| format!("invalid duration {s}"))?;
+    let secs = value.checked_mul(unit).ok_or_else(|| format!("duration {s} is too long"))?;
+
+    Ok(Duration::from_secs(secs))
+}
+
+/// `--resolve`の1つ分
+#[derive(Eq, PartialEq, Clone, Debug)]
+pub struct ResolveOverride {
+    pub host: String,
+    pub port: u16,
+    pub addr: IpAddr,
+}
+
+impl FromStr for ResolveOverride {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.splitn(3, ':');
+        let (Some(host), Some(port), Some(addr)) = (parts.next(), parts.next(), parts.next()) else {
+            return Err("expected HOST:PORT:ADDR".to_owned())
+        };
+        let port = port.parse().map_err(|_| format!("invalid port {port}"))?;
+        let addr = addr.trim_start_matches('[').trim_end_matches(']');
+        let addr = addr.parse().map_err(|_| format!("invalid address {addr}"))?;
+
+        Ok(Self { host: host.to_owned(), port, addr })
+    }
+}
+
+#[derive(Eq, PartialEq, Copy, Clone, ValueEnum)]
+pub enum GenerateTarget {
+    Bash,
+    Zsh,
+    Fish,
+    Powershell,
+    Man,
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+
+    use std::time::Duration;
+
+    use crate::cli::{parse_duration, Cli, Command, ResolveOverride, TimelineArgs};
+    use crate::host::Host;
+
+    #[test]
+    fn old_archive_syntax_still_parses() {
+        let cli = Cli::try_parse_from([
+            "misskey-channel-archiver", "archive",
+            "--host", "misskey.example", "--token", "t", "--channel-id", "c", "--cool-down", "1000", "--before", "n",
+        ]).unwrap();
+
+        assert_eq!(cli.global.host.as_ref().map(Host::name), Some("misskey.example"));
+        assert_eq!(cli.global.cool_down_millisecond.map(std::num::NonZeroUsize::get), Some(1000));
+        assert!(matches!(cli.cmd, Command::Archive { timeline: TimelineArgs { before: Some(_), .. }, ref channel_id, .. } if channel_id.len() == 1));
+    }
+
+    #[test]
+    fn old_fetch_user_syntax_still_parses() {
+        let cli = Cli::try_parse_from([
+            "misskey-channel-archiver", "fetch-user",
+            "--user", "a", "--user", "b", "--host", "misskey.example", "--token", "t",
+        ]).unwrap();
+
+        assert!(cli.global.token.is_some());
+        assert!(matches!(cli.cmd, Command::FetchUser { user, .. } if user.len() == 2));
+    }
+
+    #[test]
+    fn global_args_before_subcommand() {
+        let cli = Cli::try_parse_from([
+            "misskey-channel-archiver", "--host", "misskey.example", "--token-env", "MISSKEY_TOKEN",
+            "fetch-user", "--user", "a",
+        ]).unwrap();
+
+        assert_eq!(cli.global.token_env.as_deref(), Some("MISSKEY_TOKEN"));
+    }
+
+    #[test]
+    fn token_sources_are_exclusive() {
+        assert!(Cli::try_parse_from([
+            "misskey-channel-archiver", "fetch-user", "--token", "t", "--token-env", "X",
+        ]).is_err());
+    }
+
+    #[test]
+    fn parses_resolve_like_curl() {
+        let v4: ResolveOverride = "misskey.example:443:192.0.2.1".parse().unwrap();
+        assert_eq!((v4.host.as_str(), v4.port, v4.addr.to_string().as_str()), ("misskey.example", 443, "192.0.2.1"));
+
+        let v6: ResolveOverride = "misskey.example:443:[2001:db8::1]".parse().unwrap();
+        assert_eq!(v6.addr.to_string(), "2001:db8::1");
+
+        assert!("misskey.example:443".parse::<ResolveOverride>().is_err());
+        assert!("misskey.example:https:192.0.2.1".parse::<ResolveOverride>().is_err());
+    }
+
+    #[test]
+    fn parses_durations_with_units() {
+        assert_eq!(parse_duration("7d"), Ok(Duration::from_hours(7 * 24)));
+        assert_eq!(parse_duration("90s"), Ok(Duration::from_secs(90)));
+        assert!(parse_duration("7").is_err());
+        assert!(parse_duration("d").is_err());
+    }
+
+    #[test]
+    fn overflowing_durations_are_rejected() {
+        assert_eq!(parse_duration("999999999999999999d"), Err("duration 999999999999999999d is too long".to_owned()));
+        let parsed = Cli::try_parse_from(["misskey-channel-archiver", "archive", "--channel-id", "c", "--max-duration", "999999999999999999d"]);
+        assert!(parsed.is_err_and(|e| e.to_string().contains("too long")));
+    }
+}
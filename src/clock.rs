@@ -0,0 +1,47 @@
+//! サーバーの時計のずれや、連合で届いた未来の`createdAt`への備え。
+//!
+//! 書き出す記録の日時はそのまま残す。振り分けやヒストグラムのように日時から導くものだけ、[`effective`]で取得した時刻に丸める。
+
+use chrono::{DateTime, TimeDelta, Utc};
+
+/// 取得した時刻よりこれだけ先までは、時計のずれとして受け入れる
+pub const TOLERANCE: TimeDelta = TimeDelta::hours(1);
+
+/// `created_at`が、`fetched_at`より[`TOLERANCE`]を超えて先にある。過去はどれだけ古くても疑わない
+pub fn is_future(created_at: DateTime<Utc>, fetched_at: DateTime<Utc>) -> bool {
+    created_at.signed_duration_since(fetched_at) > TOLERANCE
+}
+
+/// 日時から導く計算に使う作成日時。未来のものは`fetched_at`にする
+pub fn effective(created_at: DateTime<Utc>, fetched_at: DateTime<Utc>) -> DateTime<Utc> {
+    if is_future(created_at, fetched_at) { fetched_at } else { created_at }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{DateTime, TimeDelta, Utc};
+
+    use crate::clock::{effective, is_future, TOLERANCE};
+
+    #[test]
+    fn only_dates_beyond_the_tolerance_are_clamped() {
+        let fetched_at: DateTime<Utc> = "2024-11-01T00:00:00Z".parse().unwrap();
+        let cases = [
+            // 過去はどれだけ古くてもそのまま
+            (DateTime::UNIX_EPOCH, false),
+            ("2024-10-31T23:59:59Z".parse().unwrap(), false),
+            (fetched_at, false),
+            // 時計のずれ
+            (fetched_at + TOLERANCE, false),
+            (fetched_at + TOLERANCE + TimeDelta::milliseconds(1), true),
+            ("2106-02-07T06:28:16Z".parse().unwrap(), true),
+            (DateTime::<Utc>::MAX_UTC, true),
+        ];
+
+        for (created_at, future) in cases {
+            assert_eq!(is_future(created_at, fetched_at), future, "{created_at}");
+            assert_eq!(effective(created_at, fetched_at), if future { fetched_at } else { created_at }, "{created_at}");
+        }
+        assert_eq!(effective(DateTime::<Utc>::MIN_UTC, fetched_at), DateTime::<Utc>::MIN_UTC);
+    }
+}
@@ -0,0 +1,203 @@
+//! `--hash-notes`。書き出したノートが後から書き換えられていないことを示すためのハッシュ。
+//!
+//! ハッシュは、`content_hash`を除いたノートを正規化したJSONのSHA-256。正規化では、
+//! オブジェクトのキーをバイト順に並べ、`createdAt`をミリ秒までのRFC 3339にし、整数の値の小数は整数として書く。
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt::Write as _;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::Path;
+use std::sync::Mutex;
+
+use chrono::{DateTime, SecondsFormat, Utc};
+use ring::digest::{digest, SHA256};
+use serde_json::Value;
+
+use crate::manifest::to_hex;
+use crate::model::NoteId;
+use crate::timestamp::TimestampFormat;
+
+/// これより大きい整数の値の`f64`は、`i64`に直すと値が変わりうる
+const EXACT_INTEGER: f64 = 9_007_199_254_740_992.0;
+
+/// 書き出したノートのハッシュを、ノートとは別のファイルにも残す。チャンネルをまたいで共有する。
+pub struct HashLog {
+    file: Mutex<File>,
+}
+
+impl HashLog {
+    pub fn create(path: &Path) -> io::Result<Self> {
+        Ok(Self { file: Mutex::new(File::create(path)?) })
+    }
+
+    pub fn record(&self, note_id: &NoteId, hash: &str, archived_at: DateTime<Utc>) -> io::Result<()> {
+        let line = serde_json::json!({ "note_id": note_id, "content_hash": hash, "archived_at": archived_at });
+        writeln!(self.file.lock().expect("not poisoned"), "{line}")
+    }
+}
+
+/// `--hashes`のファイルを読み、ノートのIDからハッシュを引けるようにする。
+pub fn read_hash_log(path: &Path) -> Result<HashMap<String, String>, Box<dyn Error + Send + Sync>> {
+    let file = BufReader::new(File::open(path).map_err(|e| format!("failed to open {}: {e}", path.display()))?);
+    let mut hashes = HashMap::new();
+    for line in file.lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue
+        }
+        let record: Value = serde_json::from_str(&line)?;
+        if let (Some(id), Some(hash)) = (record["note_id"].as_str(), record["content_hash"].as_str()) {
+            hashes.insert(id.to_owned(), hash.to_owned());
+        }
+    }
+
+    Ok(hashes)
+}
+
+/// 書き出すときの形のノートのハッシュ。`createdAt`が数なら`timestamp_format`で読む。いつどの実行で取ったかは含めない。
+pub fn hash_note(note: &Value, timestamp_format: TimestampFormat) -> String {
+    let mut note = note.clone();
+    if let Some(object) = note.as_object_mut() {
+        object.remove("content_hash");
+        object.remove("archived_at");
+        object.remove("run_id");
+        if let Some(at) = object.get("createdAt").and_then(|x| created_at(x, timestamp_format)) {
+            object.insert("createdAt".to_owned(), at.to_rfc3339_opts(SecondsFormat::Millis, true).into());
+        }
+    }
+
+    to_hex(digest(&SHA256, canonical_json(&note).as_bytes()).as_ref())
+}
+
+fn created_at(value: &Value, timestamp_format: TimestampFormat) -> Option<DateTime<Utc>> {
+    match (value, timestamp_format) {
+        (Value::String(s), _) => s.parse().ok(),
+        (Value::Number(n), TimestampFormat::EpochS) => DateTime::from_timestamp(n.as_i64()?, 0),
+        (Value::Number(n), _) => DateTime::from_timestamp_millis(n.as_i64()?),
+        _ => None,
+    }
+}
+
+/// 空白を入れず、キーをバイト順に並べたJSON
+pub fn canonical_json(value: &Value) -> String {
+    let mut out = String::new();
+    write_canonical(&mut out, value);
+    out
+}
+
+fn write_canonical(out: &mut String, value: &Value) {
+    match value {
+        Value::Null | Value::Bool(_) | Value::String(_) => out.push_str(&value.to_string()),
+        Value::Number(n) => match n.as_f64().filter(|_| n.is_f64()) {
+            Some(f) if f.fract() == 0.0 && f.abs() < EXACT_INTEGER => {
+                // `-0.0`も`0`にする
+                #[allow(clippy::cast_possible_truncation)]
+                let _ = write!(out, "{}", f as i64);
+            }
+            _ => out.push_str(&n.to_string()),
+        },
+        Value::Array(items) => {
+            out.push('[');
+            for (i, item) in items.iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                write_canonical(out, item);
+            }
+            out.push(']');
+        }
+        Value::Object(map) => {
+            let mut entries: Vec<_> = map.iter().collect();
+            entries.sort_by(|a, b| a.0.as_bytes().cmp(b.0.as_bytes()));
+            out.push('{');
+            for (i, (key, item)) in entries.into_iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                out.push_str(&Value::String(key.clone()).to_string());
+                out.push(':');
+                write_canonical(out, item);
+            }
+            out.push('}');
+        }
+    }
+}
+
+/// 確かめた結果、問題があったノート
+pub enum Problem {
+    /// `--hash-notes`を付けずに書き出した
+    Unhashed { note_id: String },
+    /// 埋め込んだハッシュと合わない
+    Mismatch { note_id: String, expected: String, actual: String },
+    /// 別のファイルに残したハッシュと合わない
+    DetachedMismatch { note_id: String, expected: String, actual: String },
+}
+
+/// `notes`は`reader::read_note_values`で読んだもの。確かめたノートの数も返す。
+pub fn verify(notes: &[Value], detached: &HashMap<String, String>) -> (Vec<Problem>, usize) {
+    let mut problems = vec![];
+    for note in notes {
+        let note_id = note["id"].as_str().unwrap_or_default().to_owned();
+        let actual = hash_note(note, TimestampFormat::Rfc3339);
+        match note["content_hash"].as_str() {
+            None => problems.push(Problem::Unhashed { note_id: note_id.clone() }),
+            Some(expected) if expected != actual => problems.push(Problem::Mismatch { note_id: note_id.clone(), expected: expected.to_owned(), actual: actual.clone() }),
+            Some(_) => {}
+        }
+        if let Some(expected) = detached.get(&note_id).filter(|x| **x != actual) {
+            problems.push(Problem::DetachedMismatch { note_id, expected: expected.clone(), actual });
+        }
+    }
+
+    (problems, notes.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use serde_json::json;
+
+    use crate::content_hash::{canonical_json, hash_note, verify, Problem};
+    use crate::testing::note_json;
+    use crate::timestamp::TimestampFormat;
+
+    #[test]
+    fn keys_are_sorted_at_every_depth() {
+        let a: serde_json::Value = serde_json::from_str(r#"{"b":1,"a":{"z":[{"y":2,"x":3}],"é":null,"Z":true}}"#).unwrap();
+        let b: serde_json::Value = serde_json::from_str(r#"{"a":{"Z":true,"é":null,"z":[{"x":3,"y":2}]},"b":1}"#).unwrap();
+
+        assert_eq!(canonical_json(&a), r#"{"a":{"Z":true,"z":[{"x":3,"y":2}],"é":null},"b":1}"#);
+        assert_eq!(canonical_json(&a), canonical_json(&b));
+    }
+
+    #[test]
+    fn numbers_have_one_spelling() {
+        let value = json!([1, 1.0, -0.0, 0.1, 2.5e-8, -3, 12_345_678_901_u64, 1e300, "1.0"]);
+
+        assert_eq!(canonical_json(&value), r#"[1,1,0,0.1,2.5e-8,-3,12345678901,1e300,"1.0"]"#);
+    }
+
+    #[test]
+    fn hash_does_not_depend_on_the_timestamp_format_or_itself() {
+        let rfc3339 = note_json("n1", "2024-01-01T00:00:00.120Z");
+        let mut epoch_ms = rfc3339.clone();
+        epoch_ms["createdAt"] = json!(1_704_067_200_120_i64);
+        let mut with_hash = rfc3339.clone();
+        with_hash["content_hash"] = json!("stale");
+
+        let hash = hash_note(&rfc3339, TimestampFormat::Rfc3339);
+        assert_eq!(hash, hash_note(&epoch_ms, TimestampFormat::EpochMs));
+        assert_eq!(hash, hash_note(&with_hash, TimestampFormat::Rfc3339));
+        assert_eq!(hash.len(), 64);
+
+        let mut tampered = rfc3339;
+        tampered["text"] = json!("edited");
+        tampered["content_hash"] = json!(hash);
+        let (problems, checked) = verify(&[tampered], &HashMap::new());
+        assert_eq!(checked, 1);
+        assert!(matches!(&problems[..], [Problem::Mismatch { note_id, .. }] if note_id == "n1"));
+    }
+}
@@ -0,0 +1,93 @@
+//! `archive --detect-deletions`。前の実行のアーカイブにあり、今回遡り直した範囲に見えなかったノートを、消えたものとして記録する。
+//!
+//! 見えなかったノートは`notes/show`で確かめ、消えていれば`confirmed`を`true`にする。確かめるのは新しいものから
+//! `--deletion-sample`個までで、`--verify-deletions-all`なら全て確かめる。記録には前の実行で書いた中身も残す。
+
+use std::error::Error;
+use std::io::{self, Write};
+use std::num::NonZeroUsize;
+use std::path::Path;
+
+use chrono::Utc;
+
+use crate::api::{ApiClient, ApiError, NoteShowCommand};
+use crate::model::{ChannelId, Note, NoteId};
+use crate::note_set::NoteSet;
+use crate::output::Destination;
+use crate::pacer::Pacer;
+use crate::reader;
+use crate::timestamp::{Formatted, TimestampFormat};
+
+/// `--deletion-sample`の既定
+pub const DEFAULT_SAMPLE: NonZeroUsize = NonZeroUsize::new(10).unwrap();
+
+pub struct DeletionCheck {
+    /// 前の実行で書いたノート
+    previous: Vec<Note>,
+    /// `None`なら全て確かめる
+    sample: Option<NonZeroUsize>,
+}
+
+impl DeletionCheck {
+    pub fn read(path: &Path, sample: Option<NonZeroUsize>) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        let previous = reader::read_notes(path).map_err(|e| format!("failed to read {}: {e}", path.display()))?;
+
+        Ok(Self { previous, sample })
+    }
+
+    /// `channel_id`の前のノートのうち、`before`と`after`の間にあって`seen`に無いもの。新しいものから並べる。
+    fn missing<'a>(&'a self, channel_id: &ChannelId, before: Option<&NoteId>, after: Option<&NoteId>, seen: &NoteSet) -> Vec<&'a Note> {
+        let mut missing: Vec<_> = self.previous.iter()
+            .filter(|x| x.channel_id.as_ref() == Some(channel_id))
+            .filter(|x| before.is_none_or(|b| x.id.0 < b.0) && after.is_none_or(|a| x.id.0 > a.0))
+            .filter(|x| !seen.contains(&x.id))
+            .collect();
+        missing.sort_by(|a, b| b.id.0.cmp(&a.id.0));
+        missing.dedup_by(|a, b| a.id == b.id);
+
+        missing
+    }
+
+    /// 見えなかったノートを`{"kind": "tombstone"}`として書き出し、その数を返す。
+    /// `notes/show`で取れたノートは消えていないので書かない。
+    #[allow(clippy::too_many_arguments)]
+    pub async fn detect(
+        &self,
+        client: &impl ApiClient,
+        pacer: &Pacer,
+        out: &mut Destination,
+        channel_id: &ChannelId,
+        before: Option<&NoteId>,
+        after: Option<&NoteId>,
+        seen: &NoteSet,
+        timestamp_format: TimestampFormat,
+    ) -> io::Result<usize> {
+        let missing = self.missing(channel_id, before, after, seen);
+        let probed = self.sample.map_or(missing.len(), NonZeroUsize::get);
+        let mut tombstones = 0;
+        for (i, note) in missing.into_iter().enumerate() {
+            let mut record = serde_json::json!({
+                "kind": "tombstone",
+                "note_id": note.id,
+                "channel_id": channel_id,
+                "confirmed": false,
+                "checked_at": null,
+                "last_known": Formatted(note, timestamp_format),
+            });
+            if i < probed {
+                pacer.wait().await;
+                let result = NoteShowCommand { note_id: note.id.clone() }.send(client).await;
+                record["checked_at"] = serde_json::json!(Utc::now());
+                match result {
+                    Ok(_) => continue,
+                    Err(e) if e.downcast_ref::<ApiError>().is_some_and(|x| x.code == "NO_SUCH_NOTE") => record["confirmed"] = true.into(),
+                    Err(e) => record["error"] = e.to_string().into(),
+                }
+            }
+            writeln!(out, "{record}")?;
+            tombstones += 1;
+        }
+
+        Ok(tombstones)
+    }
+}
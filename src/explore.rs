@@ -0,0 +1,299 @@
+//! `explore`。ノートを全て辿らずに、チャンネルのノートが時期ごとにどれだけあるかを見積もる。
+//!
+//! 区切りごとに`sinceDate`と`untilDate`で挟んだページを1つ頼み、[`LIMIT`]件に満たなければそれが数になる。
+//! 埋まっていれば期間を半分に分けて、[`DEPTH`]回まで同じことを繰り返す。それでも埋まる所は、ページが覆った時間の割合から延ばして`exact`を外す。
+//! ノートのIDに通し番号は無いので、`limit: 1`で境目を確かめるだけでは数は分からない。
+
+use std::error::Error;
+use std::fmt::Write as _;
+use std::num::NonZeroUsize;
+
+use chrono::{DateTime, Datelike, Months, NaiveDate, TimeDelta, Utc};
+use clap::ValueEnum;
+use serde::Serialize;
+
+use crate::api::{ApiClient, Timeline, TimelineCommand};
+use crate::log::progress;
+use crate::model::ChannelId;
+use crate::pacer::Pacer;
+use crate::timezone::Timezone;
+
+/// `channels/timeline`で頼める最大の数
+const LIMIT: NonZeroUsize = NonZeroUsize::new(100).unwrap();
+
+/// 1つの区切りを半分に分ける回数の上限。区切りごとのリクエストは`2^DEPTH`回ほどに収まる
+const DEPTH: u32 = 4;
+
+/// `explore --granularity`
+#[derive(Eq, PartialEq, Copy, Clone, Debug, Default, ValueEnum)]
+pub enum Granularity {
+    Day,
+    #[default]
+    Month,
+    Year,
+}
+
+impl Granularity {
+    const fn name(self) -> &'static str {
+        match self {
+            Self::Day => "day",
+            Self::Month => "month",
+            Self::Year => "year",
+        }
+    }
+
+    /// `date`を含む区切りの最初の日
+    fn start_of(self, date: NaiveDate) -> NaiveDate {
+        match self {
+            Self::Day => date,
+            Self::Month => date.with_day(1).expect("every month has a first day"),
+            Self::Year => NaiveDate::from_ymd_opt(date.year(), 1, 1).expect("every year has a first day"),
+        }
+    }
+
+    const fn next(self, start: NaiveDate) -> NaiveDate {
+        match self {
+            Self::Day => start.succ_opt(),
+            Self::Month => start.checked_add_months(Months::new(1)),
+            Self::Year => start.checked_add_months(Months::new(12)),
+        }.expect("the date is in range")
+    }
+
+    fn label(self, start: NaiveDate) -> String {
+        let format = match self {
+            Self::Day => "%Y-%m-%d",
+            Self::Month => "%Y-%m",
+            Self::Year => "%Y",
+        };
+
+        start.format(format).to_string()
+    }
+}
+
+/// 1つの区切り。`since`から`until`の手前まで
+#[derive(Serialize)]
+struct Bucket {
+    #[serde(rename = "bucket")]
+    label: String,
+    since: DateTime<Utc>,
+    until: DateTime<Utc>,
+    notes: usize,
+    /// 延ばして見積もったところがなければ`true`
+    exact: bool,
+}
+
+pub struct Density {
+    channel_id: ChannelId,
+    granularity: Granularity,
+    buckets: Vec<Bucket>,
+}
+
+impl Density {
+    /// `{"kind": "note-density"}`
+    pub fn record(&self) -> serde_json::Value {
+        serde_json::json!({
+            "kind": "note-density",
+            "channel_id": self.channel_id,
+            "granularity": self.granularity.name(),
+            "buckets": self.buckets,
+        })
+    }
+
+    /// 人が読むヒストグラム。見積もった数には`~`を付ける。
+    pub fn histogram(&self) -> String {
+        const WIDTH: usize = 40;
+        let max = self.buckets.iter().map(|x| x.notes).max().unwrap_or(0).max(1);
+        let mut text = format!("{}\n", self.channel_id.0);
+        for bucket in &self.buckets {
+            let approximate = if bucket.exact { "" } else { "~" };
+            let bar = "#".repeat((bucket.notes * WIDTH).div_ceil(max));
+            let _ = writeln!(text, "{:<10} {:>8} {bar}", bucket.label, format!("{approximate}{}", bucket.notes));
+        }
+
+        text
+    }
+}
+
+/// `local`の0時を、UTCにしたもの。その時点でのオフセットで戻す。
+fn boundary(timezone: &Timezone, local: NaiveDate) -> DateTime<Utc> {
+    let naive = local.and_hms_opt(0, 0, 0).expect("midnight exists").and_utc();
+
+    naive - TimeDelta::seconds(timezone.offset_at(naive).local_minus_utc().into())
+}
+
+/// `since`の後で`until`より前のノートを、`limit`件まで新しい順に頼み、その作成日時を返す。
+/// `until`が無ければ、最も古いものから古い順に返る。
+async fn created_at(
+    client: &impl ApiClient,
+    pacer: &Pacer,
+    timeline: &Timeline,
+    limit: NonZeroUsize,
+    since: DateTime<Utc>,
+    until: Option<DateTime<Utc>>,
+) -> Result<Vec<DateTime<Utc>>, Box<dyn Error + Send + Sync>> {
+    pacer.wait().await;
+    let page = TimelineCommand {
+        timeline: timeline.clone(),
+        limit,
+        note_after: None,
+        note_before: None,
+        // 境目ちょうどのノートも、この区切りに含める
+        date_after: Some((since - TimeDelta::milliseconds(1)).into()),
+        date_before: until.map(Into::into),
+    }.send(client).await?;
+
+    Ok(page.notes.iter().map(|x| x.created_at).collect())
+}
+
+/// ページが覆った`oldest`から`until`までの割合で、`since`からの数を延ばす。
+fn extrapolate(notes: usize, since: DateTime<Utc>, until: DateTime<Utc>, oldest: DateTime<Utc>) -> usize {
+    let whole = (until - since).num_milliseconds();
+    let covered = (until - oldest).num_milliseconds().max(1);
+    let notes = i64::try_from(notes).unwrap_or(i64::MAX);
+
+    usize::try_from(notes.saturating_mul(whole) / covered).unwrap_or(usize::MAX)
+}
+
+/// `since`から`until`の手前までのノートの数と、それが正確かどうか。
+async fn count(
+    client: &impl ApiClient,
+    pacer: &Pacer,
+    timeline: &Timeline,
+    since: DateTime<Utc>,
+    until: DateTime<Utc>,
+) -> Result<(usize, bool), Box<dyn Error + Send + Sync>> {
+    // 後半はさっき頼んだページで分かることがあるので、あればそれも積む
+    let mut stack = vec![(since, until, DEPTH, None)];
+    let (mut notes, mut exact) = (0, true);
+    while let Some((since, until, depth, known)) = stack.pop() {
+        let page = match known {
+            Some(page) => page,
+            None => created_at(client, pacer, timeline, LIMIT, since, Some(until)).await?,
+        };
+        if page.len() < LIMIT.get() {
+            notes += page.len();
+            continue
+        }
+        let oldest = page.iter().min().copied().expect("the page is full");
+        if depth == 0 {
+            notes += extrapolate(page.len(), since, until, oldest);
+            exact = false;
+            continue
+        }
+        let mid = since + (until - since) / 2;
+        if oldest < mid {
+            // 後半のノートは全てこのページにある
+            notes += page.iter().filter(|x| **x >= mid).count();
+        } else {
+            stack.push((mid, until, depth - 1, Some(page)));
+        }
+        stack.push((since, mid, depth - 1, None));
+    }
+
+    Ok((notes, exact))
+}
+
+/// 最も古いノートを含む区切りから、`now`を含む区切りまでを数える。ノートが無ければ区切りも無い。
+pub async fn explore(
+    client: &impl ApiClient,
+    pacer: &Pacer,
+    channel_id: ChannelId,
+    granularity: Granularity,
+    timezone: &Timezone,
+    now: DateTime<Utc>,
+) -> Result<Density, Box<dyn Error + Send + Sync>> {
+    let timeline = Timeline::Channel(channel_id.clone());
+    let oldest = created_at(client, pacer, &timeline, NonZeroUsize::MIN, DateTime::UNIX_EPOCH, None).await?.first().copied();
+    let mut buckets = vec![];
+    if let Some(oldest) = oldest {
+        let mut start = granularity.start_of(timezone.to_local(oldest).date_naive());
+        while boundary(timezone, start) <= now {
+            let next = granularity.next(start);
+            let (since, until) = (boundary(timezone, start), boundary(timezone, next).min(now));
+            let (notes, exact) = count(client, pacer, &timeline, since, until).await?;
+            buckets.push(Bucket { label: granularity.label(start), since, until, notes, exact });
+            progress!("explore: {} {}", channel_id.0, granularity.label(start));
+            start = next;
+        }
+    }
+
+    Ok(Density { channel_id, granularity, buckets })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::error::Error;
+    use std::num::NonZeroU32;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    use chrono::{DateTime, TimeDelta, Utc};
+    use serde_json::json;
+
+    use crate::api::{ApiClient, RawResponse, ResponseHeaders};
+    use crate::explore::{explore, Granularity};
+    use crate::model::ChannelId;
+    use crate::pacer::Pacer;
+    use crate::schema;
+    use crate::testing::note_json;
+    use crate::timezone::Timezone;
+
+    /// `sinceDate`と`untilDate`を、Misskeyと同じ向きで解く
+    struct FakeTimeline {
+        notes: Vec<DateTime<Utc>>,
+        calls: AtomicUsize,
+    }
+
+    impl ApiClient for FakeTimeline {
+        async fn call(&self, endpoint: &str, body: serde_json::Value) -> Result<RawResponse, Box<dyn Error + Send + Sync>> {
+            assert_eq!(endpoint, "channels/timeline");
+            self.calls.fetch_add(1, Ordering::Relaxed);
+            let time = |key| body[key].as_i64().map(|x| DateTime::from_timestamp_millis(x).unwrap());
+            let (since, until) = (time("sinceDate"), time("untilDate"));
+            let mut notes = self.notes.iter()
+                .filter(|x| since.is_none_or(|since| **x > since) && until.is_none_or(|until| **x < until))
+                .collect::<Vec<_>>();
+            notes.sort();
+            if until.is_some() {
+                notes.reverse();
+            }
+            notes.truncate(body["limit"].as_u64().unwrap().try_into().unwrap());
+            let notes = notes.iter().enumerate().map(|(i, x)| note_json(&format!("n{i}"), &x.to_rfc3339())).collect::<Vec<_>>();
+
+            Ok(RawResponse { status: 200, body: json!(notes).to_string(), headers: ResponseHeaders::default() })
+        }
+    }
+
+    fn at(s: &str) -> DateTime<Utc> {
+        s.parse().unwrap()
+    }
+
+    #[tokio::test]
+    async fn full_pages_are_split_until_they_can_be_counted() {
+        // 日本時間の1月1日0時ちょうどのノートと、2月は無く、3月は1ページに収まらないほど
+        let mut notes = vec![at("2023-12-31T15:00:00Z"), at("2024-01-15T00:00:00Z"), at("2024-01-31T14:59:59Z")];
+        notes.extend((0..250).map(|i| at("2024-03-01T00:00:00Z") + TimeDelta::hours(2) * i));
+        // 4月は5分おきで、分けても埋まる
+        notes.extend((0..2000).map(|i| at("2024-04-01T00:00:00Z") + TimeDelta::minutes(5) * i));
+        let client = FakeTimeline { notes, calls: AtomicUsize::new(0) };
+        let pacer = Pacer::with_burst(Duration::ZERO, NonZeroU32::MIN);
+        let timezone = "+09:00".parse::<Timezone>().unwrap();
+
+        let density = explore(&client, &pacer, ChannelId("ch".to_owned()), Granularity::Month, &timezone, at("2024-04-10T00:00:00Z")).await.unwrap();
+
+        let record = density.record();
+        let buckets = record["buckets"].as_array().unwrap();
+        let counted = buckets.iter().map(|x| (x["bucket"].as_str().unwrap(), x["notes"].as_u64().unwrap(), x["exact"].as_bool().unwrap())).collect::<Vec<_>>();
+        assert_eq!(counted[..3], [("2024-01", 3, true), ("2024-02", 0, true), ("2024-03", 250, true)]);
+        assert_eq!((counted[3].0, counted[3].2), ("2024-04", false));
+        assert!((1800..2200).contains(&counted[3].1), "{counted:?}");
+        assert_eq!((&buckets[0]["since"], &buckets[3]["until"]), (&json!("2023-12-31T15:00:00Z"), &json!("2024-04-10T00:00:00Z")));
+        // 区切りごとのリクエストは、分けた回数で抑えられる
+        assert!(client.calls.load(Ordering::Relaxed) <= 1 + 4 * 16, "{}", client.calls.load(Ordering::Relaxed));
+        let document = schema::document(None);
+        schema::validate(&document, &document, &record, "explore").unwrap();
+        let histogram = density.histogram();
+        assert!(histogram.contains("2024-02           0 \n"), "{histogram}");
+        assert!(histogram.contains("\n2024-04       ~"), "{histogram}");
+    }
+}
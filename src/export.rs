@@ -0,0 +1,190 @@
+//! `export`。書き出したアーカイブを、他のFediverseのアーカイブツールが読めるActivityStreamsに変換する。
+//!
+//! ノートは`Note`に、本文の無いRNは`Announce`にする。IDは`--host`から見たURLで、リモートのノートはAPIが返したURIを使う。
+//! 添付ファイルと公開範囲はアーカイブに残していないので書かない。本文のMFMの扱いは`--mfm`などで選ぶ。
+
+use std::collections::HashMap;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use clap::ValueEnum;
+use serde_json::{json, Value};
+
+use crate::filename::sanitize_filename;
+use crate::host::Host;
+use crate::mfm::{to_html, TextOptions};
+use crate::model::{Note, NoteId, UserId};
+use crate::tree::write_if_changed;
+
+/// MisskeyがActivityPubで使う語彙のうち、ここで書くもの
+const MISSKEY_NAMESPACE: &str = "https://misskey-hub.net/ns#";
+
+/// `export --format`
+#[derive(Eq, PartialEq, Copy, Clone, Debug, ValueEnum)]
+pub enum ExportFormat {
+    Activitystreams,
+}
+
+/// IDを組み立てるのに使う
+pub struct Exporter<'a> {
+    pub host: &'a Host,
+    /// `fetch-user`の出力から読んだusername。ノートにusernameが無ければこれを使う
+    pub usernames: &'a HashMap<UserId, String>,
+    /// `content`に書くHTMLの作り方
+    pub text: &'a TextOptions,
+}
+
+impl Exporter<'_> {
+    fn note_iri(&self, note_id: &NoteId) -> String {
+        self.host.url(&format!("notes/{}", note_id.0)).to_string()
+    }
+
+    fn actor_iri(&self, user_id: &UserId) -> String {
+        self.host.url(&format!("users/{}", user_id.0)).to_string()
+    }
+
+    /// `attributedTo`などに埋め込む、ユーザーの最低限の情報
+    fn actor(&self, note: &Note) -> Value {
+        let mut actor = json!({ "id": self.actor_iri(&note.user.id), "type": "Person" });
+        if let Some(username) = note.user.username.as_ref().or_else(|| self.usernames.get(&note.user.id)) {
+            actor["preferredUsername"] = username.clone().into();
+        }
+        if let Some(name) = &note.user.name {
+            actor["name"] = name.clone().into();
+        }
+
+        actor
+    }
+
+    /// 1つのノートを、`@context`を持たないオブジェクトにする。
+    pub fn object(&self, note: &Note) -> Value {
+        let id = note.uri.as_ref().map_or_else(|| self.note_iri(&note.id), ToString::to_string);
+        if let (None, Some(renote)) = (&note.text, &note.renote_on) {
+            return json!({
+                "id": format!("{id}/activity"),
+                "type": "Announce",
+                "actor": self.actor(note),
+                "published": note.created_at,
+                "object": self.note_iri(renote),
+            })
+        }
+
+        let mut object = json!({
+            "id": id,
+            "type": "Note",
+            "attributedTo": self.actor(note),
+            "published": note.created_at,
+        });
+        if let Some(text) = &note.text {
+            object["content"] = to_html(&text.0, self.text).into();
+            object["source"] = json!({ "content": text.0, "mediaType": "text/x.misskeymarkdown" });
+        }
+        if let Some(cw) = &note.spoiler_disclaimer_text {
+            object["summary"] = cw.clone().into();
+            object["sensitive"] = true.into();
+        }
+        if let Some(reply) = &note.reply_to {
+            object["inReplyTo"] = self.note_iri(reply).into();
+        }
+        if let Some(quote) = &note.renote_on {
+            object["_misskey_quote"] = self.note_iri(quote).into();
+        }
+        if let Some(url) = &note.local_url {
+            object["url"] = url.to_string().into();
+        }
+
+        object
+    }
+
+    /// 全てのノートを1つの`OrderedCollection`にまとめる。
+    pub fn collection(&self, notes: &[Note]) -> Value {
+        json!({
+            "@context": context(),
+            "type": "OrderedCollection",
+            "totalItems": notes.len(),
+            "orderedItems": notes.iter().map(|x| self.object(x)).collect::<Vec<_>>(),
+        })
+    }
+
+    /// `--output-layout tree`。`dir/objects/<note id>.json`に1つずつ書き、`dir/collection.json`にIDを並べる。
+    /// 中身が変わらないファイルは書き換えない。書いたファイルを返す。
+    pub fn write_tree(&self, dir: &Path, notes: &[Note], atomic: bool) -> io::Result<Vec<PathBuf>> {
+        let mut files = vec![];
+        let mut ids = vec![];
+        for note in notes {
+            let mut object = self.object(note);
+            ids.push(object["id"].clone());
+            object["@context"] = context();
+            let path = dir.join("objects").join(format!("{}.json", sanitize_filename(&note.id.0, "_")));
+            write_if_changed(&path, &object, atomic)?;
+            files.push(path);
+        }
+        let path = dir.join("collection.json");
+        write_if_changed(&path, &json!({
+            "@context": context(),
+            "type": "OrderedCollection",
+            "totalItems": ids.len(),
+            "orderedItems": ids,
+        }), atomic)?;
+        files.push(path);
+
+        Ok(files)
+    }
+}
+
+fn context() -> Value {
+    json!([
+        "https://www.w3.org/ns/activitystreams",
+        { "misskey": MISSKEY_NAMESPACE, "_misskey_quote": "misskey:_misskey_quote" },
+    ])
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use serde_json::json;
+
+    use crate::export::Exporter;
+    use crate::mfm::{to_html, TextOptions};
+    use crate::model::{Note, UserId};
+    use crate::testing::note_json;
+
+    #[test]
+    fn content_is_escaped_html() {
+        assert_eq!(to_html("<b>a & b</b>\n\"$[x2 'hi']\"", &TextOptions::default()), "<p>&lt;b&gt;a &amp; b&lt;/b&gt;<br>&quot;$[x2 &#39;hi&#39;]&quot;</p>");
+    }
+
+    #[test]
+    fn essential_fields_round_trip() {
+        let mut reply = note_json("n2", "2024-01-02T00:00:00.000Z");
+        reply["replyId"] = json!("n1");
+        reply["cw"] = json!("spoiler");
+        let mut renote = note_json("n3", "2024-01-03T00:00:00.000Z");
+        renote["text"] = json!(null);
+        renote["renoteId"] = json!("n2");
+        let notes: Vec<Note> = [reply, renote].into_iter().map(|x| serde_json::from_value(x).unwrap()).collect();
+        let host = "misskey.example".parse().unwrap();
+        let usernames = HashMap::from([(UserId("u1".to_owned()), "alice".to_owned())]);
+
+        let collection = Exporter { host: &host, usernames: &usernames, text: &TextOptions::default() }.collection(&notes);
+
+        // 文字列にしてから読み直しても、JSON-LDとして要る形が残っている
+        let collection: serde_json::Value = serde_json::from_str(&collection.to_string()).unwrap();
+        assert_eq!(collection["@context"][0], "https://www.w3.org/ns/activitystreams");
+        assert_eq!(collection["type"], "OrderedCollection");
+        assert_eq!(collection["totalItems"], 2);
+        let [note, announce] = collection["orderedItems"].as_array().unwrap().as_slice() else { panic!("expected 2 items") };
+        assert_eq!(note["id"], "https://misskey.example/notes/n2");
+        assert_eq!(note["type"], "Note");
+        assert_eq!(note["attributedTo"], json!({ "id": "https://misskey.example/users/u1", "type": "Person", "preferredUsername": "alice" }));
+        assert_eq!(note["published"], "2024-01-02T00:00:00Z");
+        assert_eq!(note["content"], "<p>hello</p>");
+        assert_eq!(note["source"]["content"], "hello");
+        assert_eq!(note["summary"], "spoiler");
+        assert_eq!(note["inReplyTo"], "https://misskey.example/notes/n1");
+        assert_eq!(announce["type"], "Announce");
+        assert_eq!(announce["object"], "https://misskey.example/notes/n2");
+        assert_eq!(announce["actor"]["id"], "https://misskey.example/users/u1");
+    }
+}
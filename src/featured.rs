@@ -0,0 +1,103 @@
+//! `snapshot-featured`。その時インスタンスで目立っていたチャンネルとハッシュタグを、1つの記録として残す。
+//!
+//! `channels/featured`と`hashtags/trend`を1度ずつ呼び、`{"kind": "featured-snapshot"}`にまとめる。
+//! サーバーがどちらかを無効にしていれば、その部分を空にして`channels_unavailable`や`trending_tags_unavailable`に理由を書く。
+
+use std::error::Error;
+
+use chrono::{DateTime, Utc};
+
+use crate::api::{ApiClient, ApiError, FeaturedChannelsCommand, TrendingTagsCommand};
+use crate::pacer::Pacer;
+
+/// エンドポイントが無いか、ロールで使えなくしてあることを示すコード
+const DISABLED_CODES: [&str; 3] = ["UNKNOWN_API_ENDPOINT", "ROLE_PERMISSION_DENIED", "ACCESS_DENIED"];
+
+/// 無効にされていれば、その理由として書くもの。それ以外の失敗は`None`
+fn disabled(e: &(dyn Error + Send + Sync + 'static)) -> Option<String> {
+    let e = e.downcast_ref::<ApiError>()?;
+    match &*e.code {
+        "" if e.status == 404 => Some(format!("{} returned 404", e.endpoint)),
+        code if DISABLED_CODES.contains(&code) => Some(code.to_owned()),
+        _ => None,
+    }
+}
+
+/// 無効にされていれば空にして、その理由を添える。
+fn tolerate<T: Default>(result: Result<T, Box<dyn Error + Send + Sync>>) -> Result<(T, Option<String>), Box<dyn Error + Send + Sync>> {
+    match result {
+        Ok(x) => Ok((x, None)),
+        Err(e) => disabled(&*e).map(|reason| (T::default(), Some(reason))).ok_or(e),
+    }
+}
+
+pub async fn snapshot(client: &impl ApiClient, pacer: &Pacer, taken_at: DateTime<Utc>) -> Result<serde_json::Value, Box<dyn Error + Send + Sync>> {
+    pacer.wait().await;
+    let (channels, channels_unavailable) = tolerate(FeaturedChannelsCommand {}.send(client).await)?;
+    pacer.wait().await;
+    let (tags, tags_unavailable) = tolerate(TrendingTagsCommand {}.send(client).await)?;
+
+    Ok(serde_json::json!({
+        "kind": "featured-snapshot",
+        "taken_at": taken_at,
+        "channels": channels.iter().map(|x| serde_json::json!({ "id": x.id, "name": x.name })).collect::<Vec<_>>(),
+        "channels_unavailable": channels_unavailable,
+        "trending_tags": tags,
+        "trending_tags_unavailable": tags_unavailable,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::num::NonZeroU32;
+    use std::time::Duration;
+
+    use serde_json::json;
+
+    use crate::api::ResponseHeaders;
+    use crate::capture::{Exchange, ReplayClient};
+    use crate::featured::snapshot;
+    use crate::pacer::Pacer;
+    use crate::schema;
+    use crate::testing::{capture_dir, exchange};
+
+    fn error(endpoint: &str, status: u16, code: &str) -> Exchange {
+        Exchange {
+            endpoint: endpoint.to_owned(),
+            request: json!({}),
+            status,
+            response: json!({ "error": { "code": code, "message": "" } }).to_string(),
+            headers: ResponseHeaders::default(),
+        }
+    }
+
+    #[tokio::test]
+    async fn disabled_endpoints_leave_an_empty_part_with_the_reason() {
+        let dir = capture_dir("featured", &[
+            exchange("channels/featured", json!({}), &json!([
+                { "id": "ch1", "name": "雑談", "notesCount": 12, "bannerUrl": null },
+                { "id": "ch2", "name": "お絵描き" },
+            ])),
+            error("hashtags/trend", 404, "UNKNOWN_API_ENDPOINT"),
+        ]);
+        let client = ReplayClient::open(&dir).unwrap();
+        let pacer = Pacer::with_burst(Duration::ZERO, NonZeroU32::MIN);
+
+        let record = snapshot(&client, &pacer, "2024-01-01T00:00:00Z".parse().unwrap()).await.unwrap();
+
+        assert_eq!(record, json!({
+            "kind": "featured-snapshot",
+            "taken_at": "2024-01-01T00:00:00Z",
+            "channels": [{ "id": "ch1", "name": "雑談" }, { "id": "ch2", "name": "お絵描き" }],
+            "channels_unavailable": null,
+            "trending_tags": [],
+            "trending_tags_unavailable": "UNKNOWN_API_ENDPOINT",
+        }));
+        let document = schema::document(None);
+        schema::validate(&document, &document, &record, "snapshot").unwrap();
+
+        // 無効にされたのでなければ、そのまま失敗する
+        let dir = capture_dir("featured-failed", &[error("channels/featured", 500, "INTERNAL_ERROR")]);
+        assert!(snapshot(&ReplayClient::open(&dir).unwrap(), &pacer, "2024-01-01T00:00:00Z".parse().unwrap()).await.is_err());
+    }
+}
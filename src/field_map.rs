@@ -0,0 +1,163 @@
+//! `--field-map`。書き出すノートとユーザーのフィールドの名前を、読む側の決めた名前に変える。
+//!
+//! `{"note": {"cw": "cw_text"}, "user": {"username": "handle"}}`のようなJSONを読む。変えられるのは一番上のフィールドだけで、
+//! [`schema`]に無い名前は始める前に弾く。`meta`の記録にそのまま書くので、読む側は戻せる。
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+
+use serde::ser::Error as _;
+use serde::{Deserialize, Serialize, Serializer};
+use serde_json::Value;
+
+use crate::schema;
+
+/// 1つの型のフィールドの、元の名前から書き出す名前への対応
+#[derive(Clone, Default, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct Renames(BTreeMap<String, String>);
+
+impl Renames {
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// `field`を書き出すときの名前
+    pub fn name_of<'a>(&'a self, field: &'a str) -> &'a str {
+        self.0.get(field).map_or(field, String::as_str)
+    }
+
+    /// 逆向きの対応。`meta`の記録から読む
+    pub fn inverse(&self) -> Self {
+        Self(self.0.iter().map(|(from, to)| (to.clone(), from.clone())).collect())
+    }
+
+    /// `value`がオブジェクトならそのフィールドの名前を、配列ならその要素のフィールドの名前を変える。
+    pub fn apply(&self, value: &mut Value) {
+        match value {
+            Value::Array(values) => values.iter_mut().for_each(|x| self.apply(x)),
+            Value::Object(object) => {
+                // 名前を入れ替えるときに上書きしないよう、先に全て取り出す
+                let moved: Vec<_> = self.0.iter().filter_map(|(from, to)| Some((to.clone(), object.remove(from)?))).collect();
+                object.extend(moved);
+            }
+            _ => {}
+        }
+    }
+
+    /// `known`は、`type_name`の一番上のフィールドの名前
+    fn validate(&self, type_name: &str, known: &BTreeSet<String>) -> Result<(), String> {
+        let mut written = known.iter().filter(|x| !self.0.contains_key(*x)).collect::<BTreeSet<_>>();
+        for (from, to) in &self.0 {
+            if from.contains('.') || from.contains('/') {
+                return Err(format!("{type_name}.{from}: only top-level fields can be renamed"))
+            }
+            if !known.contains(from) {
+                return Err(format!("{type_name}.{from}: unknown field; expected one of {}", known.iter().cloned().collect::<Vec<_>>().join(", ")))
+            }
+            if to.is_empty() {
+                return Err(format!("{type_name}.{from}: the new name is empty"))
+            }
+            if !written.insert(to) {
+                return Err(format!("{type_name}.{from}: {to} would be written twice"))
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// `--field-map`
+#[derive(Clone, Default, Debug, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FieldMap {
+    #[serde(default, skip_serializing_if = "Renames::is_empty")]
+    pub note: Renames,
+    #[serde(default, skip_serializing_if = "Renames::is_empty")]
+    pub user: Renames,
+}
+
+impl FieldMap {
+    pub const EMPTY: Self = Self { note: Renames(BTreeMap::new()), user: Renames(BTreeMap::new()) };
+
+    /// 引数を読むときに呼ぶので、名前の誤りは何も始める前に分かる。
+    pub fn open(path: &str) -> Result<Self, String> {
+        let text = fs::read_to_string(path).map_err(|e| format!("failed to open {path}: {e}"))?;
+        let map: Self = serde_json::from_str(&text).map_err(|e| format!("{path}: {e}"))?;
+        map.validate().map_err(|e| format!("{path}: {e}"))?;
+
+        Ok(map)
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        self.note.validate("note", &schema::field_names("Note"))?;
+        self.user.validate("user", &schema::field_names("User"))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.note.is_empty() && self.user.is_empty()
+    }
+}
+
+/// [`Renames`]を当てて書き出すための包み
+pub struct Renamed<'a, T>(pub T, pub &'a Renames);
+
+impl<T: Serialize> Serialize for Renamed<'_, T> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if self.1.is_empty() {
+            return self.0.serialize(serializer)
+        }
+        let mut value = serde_json::to_value(&self.0).map_err(S::Error::custom)?;
+        self.1.apply(&mut value);
+
+        value.serialize(serializer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use serde_json::json;
+
+    use crate::field_map::{FieldMap, Renamed};
+    use crate::model::Note;
+    use crate::testing::{capture_dir, note_json};
+
+    fn open(dir: &std::path::Path, map: &serde_json::Value) -> Result<FieldMap, String> {
+        let path = dir.join("field-map.json");
+        fs::write(&path, map.to_string()).unwrap();
+        FieldMap::open(path.to_str().unwrap())
+    }
+
+    #[test]
+    fn top_level_fields_are_renamed_and_the_rest_pass_through() {
+        let dir = capture_dir("field-map", &[]);
+        let map = open(&dir, &json!({ "note": { "cw": "cw_text", "text": "body" } })).unwrap();
+        let note: Note = serde_json::from_value(note_json("n1", "2024-01-01T00:00:00.000Z")).unwrap();
+
+        let mut value = serde_json::to_value(Renamed(&note, &map.note)).unwrap();
+        assert_eq!((&value["cw_text"], &value["body"], value.get("cw")), (&json!(null), &json!("hello"), None));
+        assert_eq!((&value["id"], &value["reactions"]["👍"]), (&json!("n1"), &json!(1)));
+        map.note.inverse().apply(&mut value);
+        assert_eq!(value, serde_json::to_value(&note).unwrap());
+    }
+
+    #[test]
+    fn mistakes_are_rejected_before_anything_is_written() {
+        let dir = capture_dir("field-map-invalid", &[]);
+        let error = |map| open(&dir, &map).unwrap_err();
+
+        assert!(error(json!({ "note": { "cw_tex": "cw_text" } })).contains("note.cw_tex: unknown field; expected one of"));
+        // 入れ子のフィールドは変えられない
+        assert!(error(json!({ "note": { "reactions.👍": "likes" } })).contains("note.reactions.👍: only top-level fields can be renamed"));
+        assert!(error(json!({ "note": { "user/id": "author_id" } })).contains("only top-level fields"));
+        assert!(error(json!({ "note": { "cw": "text" } })).contains("note.cw: text would be written twice"));
+        assert!(error(json!({ "user": { "name": "" } })).contains("user.name: the new name is empty"));
+        assert!(error(json!({ "notes": {} })).contains("unknown field `notes`"));
+        // 入れ替えるのは構わない
+        let swapped = open(&dir, &json!({ "note": { "text": "cw", "cw": "text" }, "user": { "username": "handle" } })).unwrap();
+        let mut value = json!({ "text": "body", "cw": "warning" });
+        swapped.note.apply(&mut value);
+        assert_eq!(value, json!({ "text": "warning", "cw": "body" }));
+    }
+}
@@ -0,0 +1,106 @@
+//! サーバーから来た名前をファイル名にする。どのOSでも書けて、ディレクトリの外を指さないようにする。
+
+use std::collections::HashSet;
+
+/// Windowsのパスの長さの上限に掛からないよう、ファイル名はこれより短くする
+pub const MAX_FILE_NAME: usize = 64;
+
+/// これより長いものは拡張子とみなさない
+const MAX_EXTENSION: usize = 16;
+
+/// Windowsで拡張子を付けても使えない名前
+const RESERVED: &[&str] = &[
+    "CON", "PRN", "AUX", "NUL",
+    "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8", "COM9",
+    "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// パスの区切りやNTFSで使えない文字を`_`にし、拡張子を残して[`MAX_FILE_NAME`]文字に縮める。
+/// 何も残らなければ`fallback`(ファイルのIDなど)を使う。
+pub fn sanitize_filename(name: &str, fallback: &str) -> String {
+    let mapped: String = name.chars()
+        .map(|c| if c.is_control() || matches!(c, '/' | '\\' | '<' | '>' | ':' | '"' | '|' | '?' | '*') { '_' } else { c })
+        .collect();
+    // `..`や隠しファイルにならないよう、また末尾の`.`と空白はWindowsが黙って消すので、両端から取り除く
+    let trimmed = mapped.trim_matches(|c: char| c == '.' || c.is_whitespace());
+    if trimmed.is_empty() {
+        return if fallback.is_empty() { "_".to_owned() } else { sanitize_filename(fallback, "") }
+    }
+
+    let (stem, extension) = match trimmed.rsplit_once('.') {
+        Some((stem, extension)) if !stem.is_empty() && extension.chars().count() <= MAX_EXTENSION => (stem, Some(extension)),
+        _ => (trimmed, None),
+    };
+    let reserved = RESERVED.iter().any(|r| stem.split('.').next().is_some_and(|first| first.eq_ignore_ascii_case(r)));
+    let prefix = if reserved { "_" } else { "" };
+    let room = MAX_FILE_NAME - prefix.len() - extension.map_or(0, |e| e.chars().count() + 1);
+    let stem: String = stem.chars().take(room).collect();
+
+    extension.map_or_else(|| format!("{prefix}{stem}"), |e| format!("{prefix}{stem}.{e}"))
+}
+
+/// 同じディレクトリに書くファイル名。大文字と小文字を区別しないファイルシステムでも重ならないようにする。
+#[derive(Default)]
+pub struct UniqueNames {
+    used: HashSet<String>,
+}
+
+impl UniqueNames {
+    /// 既に使われていれば、拡張子の前に`-1`、`-2`…と付ける。
+    pub fn claim(&mut self, name: String) -> String {
+        if self.used.insert(name.to_lowercase()) {
+            return name
+        }
+
+        let (stem, extension) = name.rsplit_once('.').map_or((name.as_str(), None), |(stem, e)| (stem, Some(e)));
+        let mut i = 1;
+        loop {
+            let candidate = extension.map_or_else(|| format!("{stem}-{i}"), |e| format!("{stem}-{i}.{e}"));
+            if self.used.insert(candidate.to_lowercase()) {
+                return candidate
+            }
+            i += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::filename::{sanitize_filename, UniqueNames, MAX_FILE_NAME};
+
+    #[test]
+    fn traversal_and_illegal_characters_are_neutralized() {
+        assert_eq!(sanitize_filename("photo.webp", "f1"), "photo.webp");
+        assert_eq!(sanitize_filename("../../etc/passwd", "f1"), "_.._etc_passwd");
+        assert_eq!(sanitize_filename("..\\..\\boot.ini", "f1"), "_.._boot.ini");
+        assert_eq!(sanitize_filename("a<b>c:d\"e|f?g*h\u{0}.png", "f1"), "a_b_c_d_e_f_g_h_.png");
+        assert_eq!(sanitize_filename("trailing. ", "f1"), "trailing");
+        assert_eq!(sanitize_filename("..", "f1"), "f1");
+        assert_eq!(sanitize_filename("", "../"), "_");
+    }
+
+    #[test]
+    fn reserved_windows_names_are_prefixed() {
+        assert_eq!(sanitize_filename("CON", "f1"), "_CON");
+        assert_eq!(sanitize_filename("nul.txt", "f1"), "_nul.txt");
+        assert_eq!(sanitize_filename("com1.tar.gz", "f1"), "_com1.tar.gz");
+        assert_eq!(sanitize_filename("console.txt", "f1"), "console.txt");
+    }
+
+    #[test]
+    fn long_names_keep_their_extension() {
+        let name = sanitize_filename(&format!("{}.jpeg", "あ".repeat(300)), "f1");
+        assert_eq!(name.chars().count(), MAX_FILE_NAME);
+        assert!(name.ends_with("あ.jpeg"));
+    }
+
+    #[test]
+    fn collisions_get_a_counter() {
+        let mut names = UniqueNames::default();
+        assert_eq!(names.claim("a.png".to_owned()), "a.png");
+        assert_eq!(names.claim("A.png".to_owned()), "A-1.png");
+        assert_eq!(names.claim("a.png".to_owned()), "a-2.png");
+        assert_eq!(names.claim("b".to_owned()), "b");
+        assert_eq!(names.claim("b".to_owned()), "b-1");
+    }
+}
@@ -0,0 +1,334 @@
+//! `clap::Command`の定義からシェル補完スクリプトとmanページを組み立てる。
+//! `clap_complete`/`clap_mangen`と同じことを、このツールで使う範囲に絞って行う。
+
+use std::error::Error;
+use std::fmt::Write;
+use std::fs;
+use std::path::Path;
+
+use clap::{Arg, CommandFactory, ValueHint};
+
+use crate::cli::{Cli, GenerateTarget};
+
+struct Flag {
+    long: String,
+    help: String,
+    value_name: Option<String>,
+    hint: ValueHint,
+    possible_values: Vec<String>,
+}
+
+impl Flag {
+    const fn is_path(&self) -> bool {
+        matches!(self.hint, ValueHint::FilePath | ValueHint::DirPath | ValueHint::AnyPath)
+    }
+}
+
+struct Sub {
+    name: String,
+    about: String,
+    flags: Vec<Flag>,
+    positional_values: Vec<String>,
+}
+
+fn help_of(arg: &Arg) -> String {
+    arg.get_help().map(ToString::to_string).unwrap_or_default()
+}
+
+fn possible_values_of(arg: &Arg) -> Vec<String> {
+    arg.get_possible_values().into_iter()
+        .filter(|v| !v.is_hide_set())
+        .map(|v| v.get_name().to_owned())
+        .collect()
+}
+
+fn flags_of(cmd: &clap::Command) -> Vec<Flag> {
+    cmd.get_arguments()
+        .filter(|a| !a.is_hide_set())
+        .filter_map(|a| {
+            let long = a.get_long()?.to_owned();
+            let value_name = a.get_action().takes_values().then(|| {
+                a.get_value_names()
+                    .and_then(|v| v.first())
+                    .map_or_else(|| a.get_id().as_str().to_uppercase(), ToString::to_string)
+            });
+
+            Some(Flag {
+                long,
+                help: help_of(a),
+                value_name,
+                hint: a.get_value_hint(),
+                possible_values: possible_values_of(a),
+            })
+        })
+        .collect()
+}
+
+fn subcommands() -> (String, Vec<Sub>) {
+    let mut cmd = Cli::command();
+    cmd.build();
+
+    let subs = cmd.get_subcommands()
+        .filter(|s| !s.is_hide_set())
+        .map(|s| Sub {
+            name: s.get_name().to_owned(),
+            about: s.get_about().map(ToString::to_string).unwrap_or_default(),
+            flags: flags_of(s),
+            positional_values: s.get_positionals().flat_map(possible_values_of).collect(),
+        })
+        .collect();
+
+    (cmd.get_name().to_owned(), subs)
+}
+
+fn bash(bin: &str, subs: &[Sub]) -> String {
+    let function = format!("_{}", bin.replace('-', "_"));
+    let names = subs.iter().map(|s| s.name.as_str()).collect::<Vec<_>>().join(" ");
+    let mut s = String::new();
+
+    writeln!(s, "{function}() {{").unwrap();
+    writeln!(s, "    local cur prev sub").unwrap();
+    writeln!(s, "    cur=\"${{COMP_WORDS[COMP_CWORD]}}\"").unwrap();
+    writeln!(s, "    prev=\"${{COMP_WORDS[COMP_CWORD-1]}}\"").unwrap();
+    writeln!(s, "    sub=\"${{COMP_WORDS[1]}}\"").unwrap();
+    writeln!(s, "    if [[ ${{COMP_CWORD}} -eq 1 ]]; then").unwrap();
+    writeln!(s, "        COMPREPLY=($(compgen -W \"{names}\" -- \"${{cur}}\"))").unwrap();
+    writeln!(s, "        return 0").unwrap();
+    writeln!(s, "    fi").unwrap();
+    writeln!(s, "    case \"$sub\" in").unwrap();
+    for sub in subs {
+        writeln!(s, "        {})", sub.name).unwrap();
+        writeln!(s, "            case \"$prev\" in").unwrap();
+        for flag in &sub.flags {
+            let reply = if flag.hint == ValueHint::DirPath {
+                "compgen -d -- \"${cur}\"".to_owned()
+            } else if flag.is_path() {
+                "compgen -f -- \"${cur}\"".to_owned()
+            } else if !flag.possible_values.is_empty() {
+                format!("compgen -W \"{}\" -- \"${{cur}}\"", flag.possible_values.join(" "))
+            } else {
+                continue
+            };
+            writeln!(s, "                --{})", flag.long).unwrap();
+            writeln!(s, "                    COMPREPLY=($({reply}))").unwrap();
+            writeln!(s, "                    return 0").unwrap();
+            writeln!(s, "                    ;;").unwrap();
+        }
+        writeln!(s, "            esac").unwrap();
+        let words = sub.flags.iter().map(|f| format!("--{}", f.long))
+            .chain(sub.positional_values.iter().cloned())
+            .collect::<Vec<_>>()
+            .join(" ");
+        writeln!(s, "            COMPREPLY=($(compgen -W \"{words}\" -- \"${{cur}}\"))").unwrap();
+        writeln!(s, "            ;;").unwrap();
+    }
+    writeln!(s, "    esac").unwrap();
+    writeln!(s, "}}").unwrap();
+    writeln!(s).unwrap();
+    writeln!(s, "complete -F {function} -o bashdefault -o default {bin}").unwrap();
+
+    s
+}
+
+/// zshの`_arguments`の説明部分に入れられるようにする
+fn zsh_escape(s: &str) -> String {
+    s.replace('\'', "'\\''").replace('[', "\\[").replace(']', "\\]").replace(':', "\\:")
+}
+
+fn zsh(bin: &str, subs: &[Sub]) -> String {
+    let function = format!("_{}", bin.replace('-', "_"));
+    let mut s = String::new();
+
+    writeln!(s, "#compdef {bin}").unwrap();
+    writeln!(s).unwrap();
+    writeln!(s, "{function}() {{").unwrap();
+    writeln!(s, "    local line state").unwrap();
+    writeln!(s, "    _arguments -C '1: :->cmds' '*:: :->args'").unwrap();
+    writeln!(s, "    case $state in").unwrap();
+    writeln!(s, "        cmds)").unwrap();
+    write!(s, "            _values 'command'").unwrap();
+    for sub in subs {
+        write!(s, " \\\n                '{}[{}]'", sub.name, zsh_escape(&sub.about)).unwrap();
+    }
+    writeln!(s).unwrap();
+    writeln!(s, "            ;;").unwrap();
+    writeln!(s, "        args)").unwrap();
+    writeln!(s, "            case $line[1] in").unwrap();
+    for sub in subs {
+        writeln!(s, "                {})", sub.name).unwrap();
+        write!(s, "                    _arguments").unwrap();
+        for flag in &sub.flags {
+            let help = zsh_escape(&flag.help);
+            match &flag.value_name {
+                None => write!(s, " \\\n                        '--{}[{help}]'", flag.long).unwrap(),
+                Some(value_name) => {
+                    let action = match flag.hint {
+                        ValueHint::DirPath => "_files -/".to_owned(),
+                        ValueHint::FilePath | ValueHint::AnyPath => "_files".to_owned(),
+                        _ if !flag.possible_values.is_empty() => format!("({})", flag.possible_values.join(" ")),
+                        _ => " ".to_owned(),
+                    };
+                    write!(s, " \\\n                        '--{}=[{help}]:{value_name}:{action}'", flag.long).unwrap();
+                }
+            }
+        }
+        if !sub.positional_values.is_empty() {
+            write!(s, " \\\n                        ':value:({})'", sub.positional_values.join(" ")).unwrap();
+        }
+        writeln!(s).unwrap();
+        writeln!(s, "                    ;;").unwrap();
+    }
+    writeln!(s, "            esac").unwrap();
+    writeln!(s, "            ;;").unwrap();
+    writeln!(s, "    esac").unwrap();
+    writeln!(s, "}}").unwrap();
+    writeln!(s).unwrap();
+    writeln!(s, "{function} \"$@\"").unwrap();
+
+    s
+}
+
+fn single_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\\', "\\\\").replace('\'', "\\'"))
+}
+
+fn fish(bin: &str, subs: &[Sub]) -> String {
+    let mut s = String::new();
+
+    for sub in subs {
+        writeln!(s, "complete -c {bin} -n \"__fish_use_subcommand\" -f -a {} -d {}", sub.name, single_quote(&sub.about)).unwrap();
+    }
+    for sub in subs {
+        let condition = format!("-n \"__fish_seen_subcommand_from {}\"", sub.name);
+        for flag in &sub.flags {
+            let value = match (&flag.value_name, flag.hint) {
+                (None, _) => String::new(),
+                (Some(_), ValueHint::DirPath) => " -r -f -a \"(__fish_complete_directories)\"".to_owned(),
+                (Some(_), ValueHint::FilePath | ValueHint::AnyPath) => " -r -F".to_owned(),
+                (Some(_), _) if !flag.possible_values.is_empty() => format!(" -r -f -a \"{}\"", flag.possible_values.join(" ")),
+                (Some(_), _) => " -r".to_owned(),
+            };
+            writeln!(s, "complete -c {bin} {condition} -l {}{value} -d {}", flag.long, single_quote(&flag.help)).unwrap();
+        }
+        if !sub.positional_values.is_empty() {
+            writeln!(s, "complete -c {bin} {condition} -f -a \"{}\"", sub.positional_values.join(" ")).unwrap();
+        }
+    }
+
+    s
+}
+
+fn powershell(bin: &str, subs: &[Sub]) -> String {
+    let quote = |v: &str| format!("'{}'", v.replace('\'', "''"));
+    let list = |items: Vec<String>| format!("@({})", items.join(", "));
+    let path_flags = subs.iter()
+        .flat_map(|s| &s.flags)
+        .filter(|f| f.is_path())
+        .map(|f| quote(&format!("--{}", f.long)))
+        .collect::<Vec<_>>();
+    let mut s = String::new();
+
+    writeln!(s, "Register-ArgumentCompleter -Native -CommandName {} -ScriptBlock {{", quote(bin)).unwrap();
+    writeln!(s, "    param($wordToComplete, $commandAst, $cursorPosition)").unwrap();
+    writeln!(s, "    $words = @($commandAst.CommandElements | ForEach-Object {{ $_.ToString() }})").unwrap();
+    writeln!(s, "    $prev = if ($wordToComplete -ne '') {{ $words[-2] }} else {{ $words[-1] }}").unwrap();
+    // パスを取るオプションの後では何も返さず、PowerShell標準のパス補完に任せる
+    writeln!(s, "    if ({} -contains $prev) {{ return }}", list(path_flags)).unwrap();
+    writeln!(s, "    $candidates = if ($words.Count -le 1 -or ($words.Count -eq 2 -and $wordToComplete -ne '')) {{").unwrap();
+    writeln!(s, "        {}", list(subs.iter().map(|s| quote(&s.name)).collect())).unwrap();
+    writeln!(s, "    }} else {{").unwrap();
+    writeln!(s, "        switch ($words[1]) {{").unwrap();
+    for sub in subs {
+        let words = sub.flags.iter().map(|f| quote(&format!("--{}", f.long)))
+            .chain(sub.positional_values.iter().map(|v| quote(v)))
+            .collect();
+        writeln!(s, "            {} {{ {} }}", quote(&sub.name), list(words)).unwrap();
+    }
+    writeln!(s, "            default {{ @() }}").unwrap();
+    writeln!(s, "        }}").unwrap();
+    writeln!(s, "    }}").unwrap();
+    writeln!(s, "    $candidates | Where-Object {{ $_ -like \"$wordToComplete*\" }} | ForEach-Object {{").unwrap();
+    writeln!(s, "        [System.Management.Automation.CompletionResult]::new($_, $_, 'ParameterValue', $_)").unwrap();
+    writeln!(s, "    }}").unwrap();
+    writeln!(s, "}}").unwrap();
+
+    s
+}
+
+fn roff_escape(s: &str) -> String {
+    let escaped = s.replace('\\', "\\\\").replace('-', "\\-");
+    if escaped.starts_with(['.', '\'']) {
+        format!("\\&{escaped}")
+    } else {
+        escaped
+    }
+}
+
+fn man(bin: &str, subs: &[Sub]) -> String {
+    let mut s = String::new();
+
+    writeln!(s, ".TH {} 1 \"\" \"{bin} {}\"", roff_escape(&bin.to_uppercase()), env!("CARGO_PKG_VERSION")).unwrap();
+    writeln!(s, ".SH NAME").unwrap();
+    writeln!(s, "{}", roff_escape(bin)).unwrap();
+    writeln!(s, ".SH SYNOPSIS").unwrap();
+    writeln!(s, "\\fB{}\\fR <SUBCOMMAND> [OPTIONS]", roff_escape(bin)).unwrap();
+    writeln!(s, ".SH SUBCOMMANDS").unwrap();
+    for sub in subs {
+        writeln!(s, ".SS {}", roff_escape(&sub.name)).unwrap();
+        if !sub.about.is_empty() {
+            writeln!(s, "{}", roff_escape(&sub.about)).unwrap();
+        }
+        for flag in &sub.flags {
+            writeln!(s, ".TP").unwrap();
+            match &flag.value_name {
+                None => writeln!(s, "\\fB\\-\\-{}\\fR", roff_escape(&flag.long)).unwrap(),
+                Some(v) => writeln!(s, "\\fB\\-\\-{}\\fR \\fI<{}>\\fR", roff_escape(&flag.long), roff_escape(v)).unwrap(),
+            }
+            for line in flag.help.lines() {
+                writeln!(s, "{}", roff_escape(line)).unwrap();
+            }
+        }
+    }
+
+    s
+}
+
+pub fn generate(target: GenerateTarget, out_dir: Option<&Path>) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let (bin, subs) = subcommands();
+    let (file_name, content) = match target {
+        GenerateTarget::Bash => (format!("{bin}.bash"), bash(&bin, &subs)),
+        GenerateTarget::Zsh => (format!("_{bin}"), zsh(&bin, &subs)),
+        GenerateTarget::Fish => (format!("{bin}.fish"), fish(&bin, &subs)),
+        GenerateTarget::Powershell => (format!("_{bin}.ps1"), powershell(&bin, &subs)),
+        GenerateTarget::Man => (format!("{bin}.1"), man(&bin, &subs)),
+    };
+
+    match out_dir {
+        Some(dir) => {
+            fs::create_dir_all(dir)?;
+            fs::write(dir.join(file_name), content)?;
+        }
+        None => print!("{content}"),
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::generate::{bash, fish, man, subcommands, zsh};
+
+    #[test]
+    fn completions_cover_subcommands_and_path_hints() {
+        let (bin, subs) = subcommands();
+
+        let bash = bash(&bin, &subs);
+        assert!(bash.contains("compgen -W \"archive archive-list backfill fetch-notes refresh fetch-user"));
+        assert!(bash.contains("--replay)\n                    COMPREPLY=($(compgen -d"));
+        assert!(!bash.contains("generate"));
+
+        assert!(zsh(&bin, &subs).contains("'--capture=["));
+        assert!(fish(&bin, &subs).contains("-l replay -r -f -a \"(__fish_complete_directories)\""));
+        assert!(man(&bin, &subs).contains(".SS fetch\\-user"));
+    }
+}
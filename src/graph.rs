@@ -0,0 +1,179 @@
+//! `fetch-followers`と`fetch-following`。ユーザー同士のフォローの関係を、辺の記録として書き出す。
+
+use std::collections::HashSet;
+use std::error::Error;
+use std::io::Write;
+use std::num::NonZeroUsize;
+use std::path::Path;
+use std::str::FromStr;
+
+use crate::api::{ApiClient, ApiError, FollowListCommand, UserByNameCommand};
+use crate::model::{UserId, Following};
+use crate::pacer::Pacer;
+use crate::reader;
+
+/// 1ページの数。サーバーが受け付ける上限
+const PAGE_SIZE: NonZeroUsize = NonZeroUsize::new(100).unwrap();
+
+/// `--user`。IDか、`@alice`や`@alice@misskey.example`のような名前
+#[derive(Eq, PartialEq, Clone, Debug)]
+pub enum UserRef {
+    Id(UserId),
+    Mention { username: String, host: Option<String> },
+}
+
+impl FromStr for UserRef {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let Some(mention) = s.strip_prefix('@') else {
+            return Ok(Self::Id(UserId(s.to_owned())))
+        };
+        let (username, host) = mention.split_once('@').map_or((mention, None), |(username, host)| (username, Some(host.to_owned())));
+        if username.is_empty() || host.as_ref().is_some_and(String::is_empty) {
+            return Err(format!("expected a user id or a mention like @alice@misskey.example, got {s}"))
+        }
+
+        Ok(Self::Mention { username: username.to_owned(), host })
+    }
+}
+
+/// どちら向きの辺を辿るか
+#[derive(Eq, PartialEq, Copy, Clone, Debug)]
+pub enum Relation {
+    Followers,
+    Following,
+}
+
+impl Relation {
+    const fn endpoint(self) -> &'static str {
+        match self {
+            Self::Followers => "users/followers",
+            Self::Following => "users/following",
+        }
+    }
+
+    const fn name(self) -> &'static str {
+        match self {
+            Self::Followers => "followers",
+            Self::Following => "following",
+        }
+    }
+}
+
+/// `archive`の出力に現れたノートの作者を、最初に現れた順に返す。
+pub fn read_participants(path: &Path) -> Result<Vec<UserId>, Box<dyn Error + Send + Sync>> {
+    let mut seen = HashSet::new();
+
+    Ok(reader::read_notes(path)?.into_iter().map(|x| x.user.id).filter(|x| seen.insert(x.clone())).collect())
+}
+
+/// それぞれのユーザーの`relation`を全て辿り、`{"kind": "follow"}`の記録を書き出す。
+/// フォローの一覧を公開していないユーザーは、失敗とせず`{"kind": "hidden"}`の記録にする。
+pub async fn fetch_edges(
+    client: &impl ApiClient,
+    pacer: &Pacer,
+    out: &mut (impl Write + Send + ?Sized),
+    relation: Relation,
+    users: Vec<UserRef>,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    for user in users {
+        let user_id = match user {
+            UserRef::Id(id) => id,
+            UserRef::Mention { username, host } => {
+                pacer.wait().await;
+                UserByNameCommand { username, host }.send(client).await?.id
+            }
+        };
+
+        let mut until_id = None;
+        loop {
+            pacer.wait().await;
+            let command = FollowListCommand { endpoint: relation.endpoint(), user_id: user_id.clone(), limit: PAGE_SIZE, until_id };
+            let page = match command.send(client).await {
+                Ok(page) => page,
+                Err(e) if e.downcast_ref::<ApiError>().is_some_and(|x| x.code == "FORBIDDEN") => {
+                    writeln!(out, "{}", serde_json::json!({ "kind": "hidden", "user_id": user_id, "relation": relation.name() }))?;
+                    break
+                }
+                Err(e) => return Err(e),
+            };
+            let Some(last) = page.last() else {
+                break
+            };
+            until_id = Some(last.id.clone());
+
+            for Following { created_at, follower_id, followee_id, .. } in page {
+                writeln!(out, "{}", serde_json::json!({
+                    "kind": "follow",
+                    "follower": follower_id,
+                    "followee": followee_id,
+                    "created_at": created_at,
+                }))?;
+            }
+        }
+    }
+    out.flush()?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::num::NonZeroU32;
+    use std::time::Duration;
+
+    use serde_json::json;
+
+    use crate::api::ResponseHeaders;
+    use crate::capture::{Exchange, ReplayClient};
+    use crate::graph::{fetch_edges, Relation, UserRef};
+    use crate::model::UserId;
+    use crate::pacer::Pacer;
+    use crate::testing::{capture_dir, exchange};
+
+    fn follow(id: &str, follower: &str) -> serde_json::Value {
+        json!({ "id": id, "createdAt": "2024-01-01T00:00:00.000Z", "followerId": follower, "followeeId": "u1" })
+    }
+
+    #[test]
+    fn parses_ids_and_mentions() {
+        assert_eq!("9abc".parse(), Ok(UserRef::Id(UserId("9abc".to_owned()))));
+        assert_eq!("@alice".parse(), Ok(UserRef::Mention { username: "alice".to_owned(), host: None }));
+        assert_eq!("@alice@misskey.example".parse(), Ok(UserRef::Mention { username: "alice".to_owned(), host: Some("misskey.example".to_owned()) }));
+        assert!("@".parse::<UserRef>().is_err());
+        assert!("@alice@".parse::<UserRef>().is_err());
+    }
+
+    #[tokio::test]
+    async fn pages_through_followers_and_records_hidden_lists() {
+        let dir = capture_dir("graph", &[
+            exchange("users/followers", json!({ "userId": "u1", "limit": 100 }), &json!([follow("f2", "u3"), follow("f1", "u2")])),
+            exchange("users/followers", json!({ "userId": "u1", "limit": 100, "untilId": "f1" }), &json!([])),
+            exchange("users/show", json!({ "username": "bob", "host": null }), &json!({
+                "id": "u9", "name": null, "username": "bob", "isBot": false, "isCat": false,
+                "avatarUrl": "https://misskey.example/avatar.webp", "notesCount": 0,
+            })),
+            Exchange {
+                endpoint: "users/followers".to_owned(),
+                request: json!({ "userId": "u9", "limit": 100 }),
+                status: 400,
+                response: json!({ "error": { "message": "Forbidden.", "code": "FORBIDDEN", "id": "3c6a84db-d619-26af-ca14-06232a21df8a" } }).to_string(),
+                headers: ResponseHeaders::default(),
+            },
+        ]);
+        let client = ReplayClient::open(&dir).unwrap();
+        let pacer = Pacer::with_burst(Duration::ZERO, NonZeroU32::MIN);
+        let mut out = vec![];
+
+        let users = vec!["u1".parse().unwrap(), "@bob".parse().unwrap()];
+        fetch_edges(&client, &pacer, &mut out, Relation::Followers, users).await.unwrap();
+
+        let records: Vec<serde_json::Value> = String::from_utf8(out).unwrap().lines().map(|x| serde_json::from_str(x).unwrap()).collect();
+        assert_eq!(records, [
+            json!({ "kind": "follow", "follower": "u3", "followee": "u1", "created_at": "2024-01-01T00:00:00Z" }),
+            json!({ "kind": "follow", "follower": "u2", "followee": "u1", "created_at": "2024-01-01T00:00:00Z" }),
+            json!({ "kind": "hidden", "user_id": "u9", "relation": "followers" }),
+        ]);
+    }
+}
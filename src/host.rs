@@ -0,0 +1,158 @@
+//! `--host`で指定するサーバー。URLを貼り付けられても、ホスト名とポート、サブパスに置かれていればそのパスだけを取り出す。
+//!
+//! `https://example.com/misskey`のように置かれたサーバーでは、APIもノートのページも`/misskey/`の下にある。
+//! URLは文字列を繋げず、[`Url::join`]で組み立てる。
+
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+
+use url::Url;
+
+/// サブパスではなく、サーバーの中のページやAPIを指すパスの部分
+const PAGES: [&str; 5] = ["api", "notes", "channels", "clips", "users"];
+
+#[derive(Eq, PartialEq, Clone, Debug)]
+pub struct Host {
+    /// `https://<host>/`か`https://<host>/<prefix>/`。必ず`/`で終わる。国際化ドメイン名はPunycodeにしてある
+    base: Url,
+}
+
+impl Host {
+    pub fn name(&self) -> &str {
+        self.base.host_str().expect("validated by Host::from_str")
+    }
+
+    pub fn port(&self) -> u16 {
+        self.base.port_or_known_default().expect("https has a default port")
+    }
+
+    /// このサーバー上の`path`を指すURL。サブパスの下に置く
+    pub fn url(&self, path: &str) -> Url {
+        self.base.join(path.trim_start_matches('/')).expect("a relative path always joins onto the base")
+    }
+
+    /// `channels/timeline`のような`/api/`以下のパスのURL
+    pub fn endpoint(&self, endpoint: &str) -> Url {
+        self.url("api/").join(endpoint.trim_start_matches('/')).expect("a relative path always joins onto the base")
+    }
+
+    /// ドライブのファイルのURLのように、サーバーが返したURLを解決する。`/files/x`のような相対URLにも対応する。
+    pub fn resolve(&self, url: &str) -> Result<Url, url::ParseError> {
+        self.base.join(url)
+    }
+}
+
+impl Display for Host {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self.base.port() {
+            Some(port) => write!(f, "{}:{port}", self.name())?,
+            None => f.write_str(self.name())?,
+        }
+        f.write_str(self.base.path().trim_end_matches('/'))
+    }
+}
+
+impl FromStr for Host {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let trimmed = s.trim();
+        let rest = ["https://", "http://"].iter()
+            .find_map(|scheme| trimmed.get(..scheme.len()).filter(|x| x.eq_ignore_ascii_case(scheme)).map(|_| &trimmed[scheme.len()..]))
+            .unwrap_or(trimmed);
+        let rest = rest.trim_end_matches('/');
+        let rest = rest.strip_suffix("/api").unwrap_or(rest).trim_end_matches('/');
+
+        if rest.is_empty() {
+            return Err(format!("{s:?} has no host name; expected something like misskey.example"))
+        }
+        if rest.contains("://") {
+            return Err(format!("{s:?} has an unsupported scheme; only https is supported"))
+        }
+
+        let base = Url::parse(&format!("https://{rest}/")).map_err(|e| format!("{s:?} is not a valid host: {e}"))?;
+        if base.query().is_some() || base.fragment().is_some() {
+            return Err(format!("{s:?} must not contain a query or a fragment; expected something like misskey.example"))
+        }
+        // ノートやチャンネルのページを貼り付けたのを、サブパスと取り違えない
+        if base.path_segments().into_iter().flatten().any(|x| PAGES.contains(&x) || x.starts_with('@')) {
+            return Err(format!("{s:?} must not contain a path to a page or an endpoint; expected something like misskey.example or example.com/misskey"))
+        }
+        if !base.username().is_empty() || base.password().is_some() {
+            return Err(format!("{s:?} must not contain a user name; pass the token with --token instead"))
+        }
+        if base.host_str().is_none_or(str::is_empty) {
+            return Err(format!("{s:?} has no host name; expected something like misskey.example"))
+        }
+
+        Ok(Self { base })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::host::Host;
+
+    fn parse(s: &str) -> String {
+        s.parse::<Host>().unwrap().to_string()
+    }
+
+    #[test]
+    fn urls_pasted_as_host_are_reduced_to_the_host() {
+        assert_eq!(parse("misskey.example"), "misskey.example");
+        assert_eq!(parse("https://misskey.example/"), "misskey.example");
+        assert_eq!(parse("HTTPS://Misskey.Example//"), "misskey.example");
+        assert_eq!(parse("misskey.example/api"), "misskey.example");
+        assert_eq!(parse("https://misskey.example/api/"), "misskey.example");
+        assert_eq!(parse("misskey.example:8443"), "misskey.example:8443");
+        assert_eq!(parse("misskey.example:443"), "misskey.example");
+        assert_eq!(parse("[2001:db8::1]:8443"), "[2001:db8::1]:8443");
+
+        let host: Host = "misskey.example:8443/".parse().unwrap();
+        assert_eq!((host.name(), host.port()), ("misskey.example", 8443));
+        assert_eq!(host.endpoint("channels/timeline").as_str(), "https://misskey.example:8443/api/channels/timeline");
+    }
+
+    #[test]
+    fn hosts_under_a_subpath_keep_the_prefix() {
+        assert_eq!(parse("https://example.com/misskey"), "example.com/misskey");
+        assert_eq!(parse("example.com/misskey/api/"), "example.com/misskey");
+        assert_eq!(parse("example.com:8443/social/misskey//"), "example.com:8443/social/misskey");
+
+        for (host, prefix) in [("misskey.example", "https://misskey.example/"), ("https://example.com/misskey/", "https://example.com/misskey/")] {
+            let host: Host = host.parse().unwrap();
+            assert_eq!(host.endpoint("channels/timeline").as_str(), format!("{prefix}api/channels/timeline"));
+            assert_eq!(host.url("notes/9abc").as_str(), format!("{prefix}notes/9abc"));
+            assert_eq!(host.url("/@alice@remote.example").as_str(), format!("{prefix}@alice@remote.example"));
+            assert_eq!(host.resolve("files/f1").unwrap().as_str(), format!("{prefix}files/f1"));
+            // 絶対URLはそのまま
+            assert_eq!(host.resolve("https://media.example/f1.png").unwrap().as_str(), "https://media.example/f1.png");
+        }
+        // `/`から始まるものは、サーバーの根からのパス
+        let host: Host = "example.com/misskey".parse().unwrap();
+        assert_eq!(host.resolve("/misskey/files/f1").unwrap().as_str(), "https://example.com/misskey/files/f1");
+    }
+
+    #[test]
+    fn idn_hosts_become_punycode() {
+        assert_eq!(parse("https://みすきー.example/"), "xn--w8jxa7itv.example");
+    }
+
+    #[test]
+    fn malformed_hosts_are_rejected_with_a_reason() {
+        for (input, reason) in [
+            ("", "has no host name"),
+            ("https://", "has no host name"),
+            ("misskey.example/notes/1", "must not contain a path"),
+            ("example.com/misskey/@alice", "must not contain a path"),
+            ("misskey.example/?a=1", "must not contain a query"),
+            ("ftp://misskey.example", "unsupported scheme"),
+            ("alice@misskey.example", "must not contain a user name"),
+            ("misskey.example:99999", "is not a valid host"),
+            ("miss key.example", "is not a valid host"),
+        ] {
+            let e = input.parse::<Host>().unwrap_err();
+            assert!(e.contains(reason), "{input}: {e}");
+        }
+    }
+}
@@ -0,0 +1,104 @@
+//! `--pool-max-idle-per-host`、`--http2-prior-knowledge`、`--tcp-keepalive`。
+//!
+//! APIのクライアントと添付ファイルを取るクライアントのそれぞれに当てる。クライアントは分けてあるので、
+//! 添付ファイルの接続が溜まっても、APIへのリクエストの間隔には響かない。どれも無ければreqwestの既定のままにする。
+
+use std::time::Duration;
+
+use reqwest::ClientBuilder;
+
+#[derive(Clone, Copy, Default, Debug, Eq, PartialEq)]
+pub struct HttpTuning {
+    pub pool_max_idle_per_host: Option<usize>,
+    pub http2_prior_knowledge: bool,
+    pub tcp_keepalive: Option<Duration>,
+}
+
+/// [`HttpTuning`]を当てられるもの。[`ClientBuilder`]は当てた値を見せないので、テストでは受け取った値を覚えるものに当てる
+pub trait Tunable: Sized {
+    #[must_use]
+    fn pool_max_idle_per_host(self, max: usize) -> Self;
+    #[must_use]
+    fn http2_prior_knowledge(self) -> Self;
+    #[must_use]
+    fn tcp_keepalive(self, interval: Duration) -> Self;
+}
+
+impl Tunable for ClientBuilder {
+    fn pool_max_idle_per_host(self, max: usize) -> Self {
+        Self::pool_max_idle_per_host(self, max)
+    }
+
+    fn http2_prior_knowledge(self) -> Self {
+        Self::http2_prior_knowledge(self)
+    }
+
+    fn tcp_keepalive(self, interval: Duration) -> Self {
+        Self::tcp_keepalive(self, interval)
+    }
+}
+
+impl HttpTuning {
+    pub fn apply<B: Tunable>(self, mut builder: B) -> B {
+        if let Some(max) = self.pool_max_idle_per_host {
+            builder = builder.pool_max_idle_per_host(max);
+        }
+        if self.http2_prior_knowledge {
+            builder = builder.http2_prior_knowledge();
+        }
+        if let Some(interval) = self.tcp_keepalive {
+            builder = builder.tcp_keepalive(interval);
+        }
+
+        builder
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use clap::Parser;
+
+    use crate::cli::Cli;
+    use crate::http_tuning::{HttpTuning, Tunable};
+
+    /// 受け取った値を覚える
+    #[derive(Default, Debug, Eq, PartialEq)]
+    struct Recorded {
+        pool_max_idle_per_host: Option<usize>,
+        http2_prior_knowledge: bool,
+        tcp_keepalive: Option<Duration>,
+    }
+
+    impl Tunable for Recorded {
+        fn pool_max_idle_per_host(self, max: usize) -> Self {
+            Self { pool_max_idle_per_host: Some(max), ..self }
+        }
+
+        fn http2_prior_knowledge(self) -> Self {
+            Self { http2_prior_knowledge: true, ..self }
+        }
+
+        fn tcp_keepalive(self, interval: Duration) -> Self {
+            Self { tcp_keepalive: Some(interval), ..self }
+        }
+    }
+
+    #[test]
+    fn flags_reach_the_builder_and_defaults_leave_it_alone() {
+        let cli = Cli::try_parse_from([
+            "misskey-channel-archiver", "archive", "--channel-id", "c1",
+            "--pool-max-idle-per-host", "32", "--http2-prior-knowledge", "--tcp-keepalive", "30s",
+        ]).unwrap();
+
+        assert_eq!(cli.global.http_tuning().apply(Recorded::default()), Recorded {
+            pool_max_idle_per_host: Some(32),
+            http2_prior_knowledge: true,
+            tcp_keepalive: Some(Duration::from_secs(30)),
+        });
+        assert_eq!(HttpTuning::default().apply(Recorded::default()), Recorded::default());
+        // 本物のビルダーにも当てられる
+        assert!(cli.global.http_tuning().apply(reqwest::Client::builder()).build().is_ok());
+    }
+}
@@ -0,0 +1,207 @@
+//! 人が読むメッセージの言葉。`--lang`で選び、無ければ`LANG`などのロケールから決める。
+//!
+//! メッセージは全て[`msg`]にキーと値を渡して組み立てる。言葉を足すときは、[`EN`]と同じキーを持つ表を足す。
+//! JSONの記録に入る`message`などは読むプログラムのためのものなので、`--lang`に関わらず英語のまま変えない。
+
+use std::fmt::{Display, Formatter};
+use std::sync::atomic::{AtomicU8, Ordering};
+
+use clap::ValueEnum;
+
+/// `--lang`
+#[derive(Eq, PartialEq, Copy, Clone, Debug, ValueEnum)]
+#[repr(u8)]
+pub enum Lang {
+    Ja,
+    En,
+}
+
+impl Lang {
+    /// `LC_ALL`、`LC_MESSAGES`、`LANG`の順に、最初に空でないものを見る。
+    pub fn from_env() -> Self {
+        let locale = ["LC_ALL", "LC_MESSAGES", "LANG"].into_iter()
+            .filter_map(|x| std::env::var(x).ok())
+            .find(|x| !x.is_empty());
+
+        Self::from_locale(locale.as_deref())
+    }
+
+    /// `ja_JP.UTF-8`のようなロケールの名前。分からなければ英語にする。
+    pub fn from_locale(locale: Option<&str>) -> Self {
+        match locale {
+            Some(x) if x == "ja" || x.starts_with("ja_") || x.starts_with("ja.") => Self::Ja,
+            _ => Self::En,
+        }
+    }
+
+    const fn table(self) -> &'static [(&'static str, &'static str)] {
+        match self {
+            Self::Ja => &JA,
+            Self::En => &EN,
+        }
+    }
+}
+
+static LANG: AtomicU8 = AtomicU8::new(Lang::En as u8);
+
+pub fn init(lang: Lang) {
+    LANG.store(lang as u8, Ordering::Relaxed);
+}
+
+pub fn current() -> Lang {
+    if LANG.load(Ordering::Relaxed) == Lang::Ja as u8 { Lang::Ja } else { Lang::En }
+}
+
+/// `--lang`の言葉で`key`のメッセージを組み立てる。`{name}`は`args`の値で置き換える。
+pub fn msg(key: &str, args: &[(&str, &dyn Display)]) -> String {
+    msg_in(current(), key, args)
+}
+
+/// 表に無いキーは英語の表で探し、それも無ければキーをそのまま使う。
+pub fn msg_in(lang: Lang, key: &str, args: &[(&str, &dyn Display)]) -> String {
+    let find = |table: &[(&str, &'static str)]| table.iter().find(|x| x.0 == key).map(|x| x.1);
+    let template = find(lang.table()).or_else(|| find(&EN)).unwrap_or(key);
+
+    args.iter().fold(template.to_owned(), |text, (name, value)| text.replace(&format!("{{{name}}}"), &value.to_string()))
+}
+
+/// 後で言葉を選んで書くメッセージ。JSONには英語で、人には`--lang`の言葉で書くときに使う。
+#[derive(Clone, Debug)]
+pub struct Message {
+    pub key: &'static str,
+    args: Vec<(&'static str, String)>,
+}
+
+impl Message {
+    pub fn new(key: &'static str, args: &[(&'static str, &dyn Display)]) -> Self {
+        Self { key, args: args.iter().map(|(name, value)| (*name, value.to_string())).collect() }
+    }
+
+    pub fn render(&self, lang: Lang) -> String {
+        let args: Vec<(&str, &dyn Display)> = self.args.iter().map(|(name, value)| (*name, value as &dyn Display)).collect();
+        msg_in(lang, self.key, &args)
+    }
+}
+
+impl Display for Message {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.render(current()))
+    }
+}
+
+const EN: [(&str, &str); 40] = [
+    ("error", "Error: {message}"),
+    ("credentials-required", "one of --token, --token-file or --token-env is required"),
+    ("host-required", "--host is required"),
+    ("export-host-required", "export needs --host to build the ids of the objects"),
+    ("sink-http-output", "--sink http cannot be used with --output"),
+    ("sink-stdout-output", "--sink stdout cannot be used with --output"),
+    ("sink-file-output", "--sink file requires --output"),
+    ("sink-url-http", "--sink-url requires --sink http"),
+    ("sink-s3-output", "--sink s3 requires --output; its file name becomes the object name"),
+    ("sink-s3-bucket", "--sink s3 requires --s3-bucket"),
+    ("layout-requires-output", "--output-layout {layout} requires --output to name the directory"),
+    ("emoji-dir-requires-image", "--emoji-dir requires --emoji-style image"),
+    ("export-layout", "export does not support --output-layout chunked; use lines or tree"),
+    ("reaction-range", "--since ({since}) must be earlier than --until ({until})"),
+    ("pacer-state-requires-host", "--pacer-state requires --host; the state is kept per host"),
+    ("budget-exhausted", "reached --max-requests {limit}; stopped before sending more"),
+    ("time-budget-exhausted", "--max-duration {limit} has passed; stopped before sending more"),
+    ("channels-failed", "{failed} of {total} channel(s) failed"),
+    ("notes-failed", "{failed} of {total} note(s) could not be fetched"),
+    ("incomplete", "channel {channel} is missing {missing} note(s) compared to its notesCount"),
+    ("channel-unavailable", "channel {channel} was deleted or archived after {pages} page(s) ({code}); the notes so far were kept"),
+    ("invalid-range", "--after {after} ({after_at}) is not older than --before {before} ({before_at}); swap them or check the ids"),
+    ("pipe-closed", "the reader of stdout closed the pipe"),
+    ("boundary-gone-without-time", "{flag} {id} no longer exists and its id does not carry a time; the range is not checked"),
+    ("boundary-gone", "{flag} {id} no longer exists; the range is checked with the time in its id ({created_at}) and still applied by its id"),
+    ("boundary-outside-channels", "{flag} {id} is not in any of the channels being archived; the range is still applied by its id"),
+    ("possibly-incomplete", "warning: channel {channel} may be archived incompletely: {reasons}"),
+    ("probe-failed", "the newest notes could not be probed"),
+    ("notes-count-unknown", "the server does not report notesCount, so hidden notes cannot be noticed"),
+    ("probe-shortfall", "only {probed} of the newest {expected} note(s) implied by notesCount are visible"),
+    ("not-following", "the account neither follows nor owns the channel; notes limited to followers may be hidden"),
+    ("hidden-users", "the account mutes {muted} and blocks {blocked} user(s); their notes are not returned (--ignore-mutes-warning to acknowledge)"),
+    ("hidden-users-unknown", "mutes and blocks of the account could not be listed: {error}"),
+    ("notes-count-shortfall", "archived {notes} notes but the channel reported {missing} more; they may be deleted, hidden from this account, or missed"),
+    ("import-summary", "converted {notes} notes from {input}"),
+    ("export-summary", "wrote {files} file(s) to {dir}"),
+    ("fail-threshold", "{failures} of {attempts} request(s) to {endpoint} failed even after retries, above --fail-threshold {threshold}%"),
+    ("cloudflare-challenge", "{endpoint} was answered with a Cloudflare challenge: the server blocks clients that are not browsers. Ask its admin to allow API access, or run from where the challenge is not shown"),
+    ("pinned-notes-layout", "--with-pinned-notes cannot be used with --output-layout {layout}; pinned notes are written as lines"),
+    ("append-requires-map", "--append rewrites the file named by --output, so it requires --output-layout map and --output"),
+];
+
+const JA: [(&str, &str); 40] = [
+    ("error", "エラー: {message}"),
+    ("credentials-required", "--token、--token-file、--token-envのどれかが必要です"),
+    ("host-required", "--hostが必要です"),
+    ("export-host-required", "exportはオブジェクトのIDを作るために--hostが必要です"),
+    ("sink-http-output", "--sink httpは--outputと併用できません"),
+    ("sink-stdout-output", "--sink stdoutは--outputと併用できません"),
+    ("sink-file-output", "--sink fileには--outputが必要です"),
+    ("sink-url-http", "--sink-urlには--sink httpが必要です"),
+    ("sink-s3-output", "--sink s3には--outputが必要です。そのファイル名がオブジェクトの名前になります"),
+    ("sink-s3-bucket", "--sink s3には--s3-bucketが必要です"),
+    ("layout-requires-output", "--output-layout {layout}には、ディレクトリを指す--outputが必要です"),
+    ("emoji-dir-requires-image", "--emoji-dirには--emoji-style imageが必要です"),
+    ("export-layout", "exportは--output-layout chunkedに対応していません。linesかtreeを使ってください"),
+    ("reaction-range", "--since ({since})は--until ({until})より前にしてください"),
+    ("pacer-state-requires-host", "--pacer-stateには--hostが必要です。状態はホストごとに保存します"),
+    ("budget-exhausted", "--max-requests {limit}に達したため、それ以上送らずに止めました"),
+    ("time-budget-exhausted", "--max-duration {limit}が過ぎたため、それ以上送らずに止めました"),
+    ("channels-failed", "{total}個のうち{failed}個のチャンネルで失敗しました"),
+    ("notes-failed", "{total}個のうち{failed}個のノートを取得できませんでした"),
+    ("incomplete", "チャンネル{channel}のノートが、notesCountより{missing}個足りません"),
+    ("channel-unavailable", "チャンネル{channel}は{pages}ページ目の後で削除かアーカイブされました（{code}）。それまでのノートは残しました"),
+    ("invalid-range", "--after {after} ({after_at})が--before {before} ({before_at})より古くありません。入れ替えるか、IDを確かめてください"),
+    ("pipe-closed", "標準出力を読む側がパイプを閉じました"),
+    ("boundary-gone-without-time", "{flag} {id}は消えていて、IDから日時も分からないので、範囲を確かめません"),
+    ("boundary-gone", "{flag} {id}は消えています。IDの日時({created_at})で範囲を確かめ、範囲にはIDをそのまま使います"),
+    ("boundary-outside-channels", "{flag} {id}は遡るどのチャンネルにもありません。範囲にはIDをそのまま使います"),
+    ("possibly-incomplete", "警告: チャンネル{channel}のノートは全ては取れないかもしれません: {reasons}"),
+    ("probe-failed", "最新のノートを確かめられませんでした"),
+    ("notes-count-unknown", "サーバーがnotesCountを返さないので、見えないノートに気づけません"),
+    ("probe-shortfall", "notesCountからは最新の{expected}個のノートがあるはずですが、{probed}個しか見えません"),
+    ("not-following", "このアカウントはチャンネルをフォローも所有もしていないので、フォロワー限定のノートが隠れているかもしれません"),
+    ("hidden-users", "このアカウントは{muted}人をミュートし、{blocked}人をブロックしているので、その人たちのノートは返ってきません（承知の上なら--ignore-mutes-warning）"),
+    ("hidden-users-unknown", "このアカウントのミュートとブロックを確かめられませんでした: {error}"),
+    ("notes-count-shortfall", "{notes}個のノートを書き出しましたが、チャンネルにはさらに{missing}個あるはずです。消えたか、このアカウントから見えないか、取りこぼした可能性があります"),
+    ("import-summary", "{input}から{notes}個のノートを変換しました"),
+    ("export-summary", "{dir}に{files}個のファイルを書き出しました"),
+    ("fail-threshold", "{endpoint}への{attempts}個のリクエストのうち{failures}個が頼み直しても失敗し、--fail-threshold {threshold}%を超えました"),
+    ("cloudflare-challenge", "{endpoint}にCloudflareの確認ページが返されました。このサーバーはブラウザ以外からの接続を拒んでいます。管理者にAPIへの接続を許してもらうか、確認ページの出ない所から実行してください"),
+    ("pinned-notes-layout", "--with-pinned-notesは--output-layout {layout}と併用できません。ピン留めされたノートは1行ずつ書き出します"),
+    ("append-requires-map", "--appendは--outputのファイルを書き直すので、--output-layout mapと--outputが必要です"),
+];
+
+#[cfg(test)]
+mod tests {
+    use crate::i18n::{msg_in, Lang, Message, EN, JA};
+
+    #[test]
+    fn every_language_has_every_key() {
+        assert_eq!(JA.map(|x| x.0), EN.map(|x| x.0));
+        let args = |template: &str| template.matches('{').count();
+        for (en, ja) in EN.iter().zip(&JA) {
+            assert_eq!(args(en.1), args(ja.1), "{}", en.0);
+        }
+    }
+
+    #[test]
+    fn arguments_are_filled_in_the_chosen_language() {
+        let message = Message::new("channels-failed", &[("failed", &1), ("total", &3)]);
+        assert_eq!(message.render(Lang::En), "1 of 3 channel(s) failed");
+        assert_eq!(message.render(Lang::Ja), "3個のうち1個のチャンネルで失敗しました");
+        assert_eq!(msg_in(Lang::Ja, "unknown-key", &[]), "unknown-key");
+    }
+
+    #[test]
+    fn locale_names_choose_the_language() {
+        assert_eq!(Lang::from_locale(Some("ja_JP.UTF-8")), Lang::Ja);
+        assert_eq!(Lang::from_locale(Some("ja")), Lang::Ja);
+        assert_eq!(Lang::from_locale(Some("java")), Lang::En);
+        assert_eq!(Lang::from_locale(Some("C.UTF-8")), Lang::En);
+        assert_eq!(Lang::from_locale(None), Lang::En);
+    }
+}
@@ -0,0 +1,130 @@
+//! `import`。他の形式で書き出されたノートを、このツールのアーカイブと同じ`Note`にする。
+//!
+//! Misskeyの設定画面から書き出したノートは、ユーザーもリアクションも持たないので、
+//! ユーザーは`--user`で受け取り、数は0として埋める。移す先の無いフィールドは`extra`にそのまま残す。
+
+use clap::ValueEnum;
+use serde_json::{Map, Value};
+
+use crate::model::{Note, UserId};
+
+/// `Note`に同じ名前のフィールドがあり、そのまま移すもの
+const MAPPED_FIELDS: [&str; 11] = [
+    "id", "createdAt", "user", "text", "cw", "replyId", "renoteId", "renoteCount", "repliesCount", "reactions", "channelId",
+];
+
+/// `import --from`
+#[derive(Eq, PartialEq, Copy, Clone, Debug, ValueEnum)]
+pub enum ImportFormat {
+    /// Misskeyの設定画面の「ノートのエクスポート」で書き出したJSON
+    MisskeyExport,
+}
+
+/// `export`はノートの配列。順はそのまま保つ。
+pub fn from_misskey_export(export: Value, user: &UserId) -> Result<Vec<Note>, String> {
+    let Value::Array(notes) = export else {
+        return Err("expected an array of notes".to_owned())
+    };
+
+    notes.into_iter().enumerate().map(|(i, note)| {
+        let Value::Object(fields) = note else {
+            return Err(format!("note {i}: expected an object"))
+        };
+        let (mut mapped, extra): (Map<String, Value>, Map<String, Value>) = fields.into_iter()
+            .partition(|(key, _)| MAPPED_FIELDS.contains(&key.as_str()));
+        mapped.entry("user").or_insert_with(|| serde_json::json!({ "id": user }));
+        mapped.entry("reactions").or_insert_with(|| Value::Object(Map::new()));
+        for key in ["renoteCount", "repliesCount"] {
+            mapped.entry(key).or_insert_with(|| 0.into());
+        }
+        for key in ["text", "cw", "replyId", "renoteId"] {
+            mapped.entry(key).or_insert(Value::Null);
+        }
+        if !extra.is_empty() {
+            mapped.insert("extra".to_owned(), Value::Object(extra));
+        }
+
+        serde_json::from_value(Value::Object(mapped)).map_err(|e| format!("note {i}: {e}"))
+    }).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use crate::import::from_misskey_export;
+    use crate::model::UserId;
+    use crate::timestamp::{Formatted, TimestampFormat};
+
+    /// Misskey 2024.11の「ノートのエクスポート」が書き出すもの
+    const EXPORT: &str = r#"[
+        {
+            "id": "9xk2a1b2c3",
+            "text": "hello $[x2 world]",
+            "createdAt": "2024-11-01T12:34:56.789Z",
+            "fileIds": ["9xk29zzzzz"],
+            "files": [{ "id": "9xk29zzzzz", "name": "cat.png", "type": "image/png", "url": "https://misskey.example/files/cat.png" }],
+            "replyId": null,
+            "renoteId": null,
+            "poll": null,
+            "cw": "spoiler",
+            "visibility": "home",
+            "visibleUserIds": [],
+            "localOnly": false,
+            "reactionAcceptance": "likeOnly"
+        },
+        {
+            "id": "9xk2a1b2c4",
+            "text": null,
+            "createdAt": "2024-11-01T13:00:00.000Z",
+            "fileIds": [],
+            "files": [],
+            "replyId": null,
+            "renoteId": "9xk2a1b2c3",
+            "poll": null,
+            "cw": null,
+            "visibility": "public",
+            "visibleUserIds": [],
+            "localOnly": false,
+            "reactionAcceptance": null
+        }
+    ]"#;
+
+    #[test]
+    fn official_export_maps_to_notes() {
+        let notes = from_misskey_export(serde_json::from_str(EXPORT).unwrap(), &UserId("u1".to_owned())).unwrap();
+
+        let values: Vec<_> = notes.iter().map(|x| serde_json::to_value(Formatted(x, TimestampFormat::Rfc3339)).unwrap()).collect();
+        assert_eq!(values[0], json!({
+            "id": "9xk2a1b2c3",
+            "createdAt": "2024-11-01T12:34:56.789Z",
+            "user": { "id": "u1" },
+            "text": "hello $[x2 world]",
+            "cw": "spoiler",
+            "replyId": null,
+            "renoteId": null,
+            "renoteCount": 0,
+            "repliesCount": 0,
+            "reactions": {},
+            "extra": {
+                "fileIds": ["9xk29zzzzz"],
+                "files": [{ "id": "9xk29zzzzz", "name": "cat.png", "type": "image/png", "url": "https://misskey.example/files/cat.png" }],
+                "poll": null,
+                "visibility": "home",
+                "visibleUserIds": [],
+                "localOnly": false,
+                "reactionAcceptance": "likeOnly",
+            },
+        }));
+        assert_eq!(values[1]["renoteId"], "9xk2a1b2c3");
+        assert_eq!(values[1]["text"], json!(null));
+        assert_eq!(values[1]["extra"]["visibility"], "public");
+    }
+
+    #[test]
+    fn malformed_notes_are_reported_by_position() {
+        let Err(e) = from_misskey_export(json!([{ "id": "n1", "createdAt": "yesterday" }]), &UserId("u1".to_owned())) else { panic!("expected an error") };
+        assert!(e.starts_with("note 0: "), "{e}");
+        assert!(from_misskey_export(json!({ "notes": [] }), &UserId("u1".to_owned())).is_err());
+    }
+}
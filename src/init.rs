@@ -0,0 +1,265 @@
+//! `init`サブコマンド。初めて使う人に、ホスト、トークン、チャンネルを順に尋ね、`archive`に渡すファイルを書く。
+//!
+//! 尋ねることは全てフラグでも渡せるので、スクリプトからも使える。トークンは画面に出さず、ファイルにだけ書く。
+
+use std::collections::BTreeSet;
+use std::error::Error;
+use std::fmt::Write as _;
+use std::fs::{self, OpenOptions};
+use std::io::{self, IsTerminal, Write};
+use std::num::NonZeroUsize;
+use std::path::Path;
+use std::process::{self, Stdio};
+
+use crate::api::{ApiClient, ChannelListCommand, ChannelShowCommand, MisskeyAuthorizationToken};
+use crate::host::Host;
+use crate::model::{Channel, ChannelId};
+use crate::pacer::Pacer;
+
+/// 一覧に出すチャンネルの数。`channels/owned`と`channels/followed`のそれぞれで、これより多ければ`--channel-id`で渡す
+const LIST_LIMIT: NonZeroUsize = NonZeroUsize::new(100).unwrap();
+
+/// 書くファイルの名前
+const TOKEN_FILE: &str = "token";
+const CHANNELS_FILE: &str = "channels.txt";
+
+pub trait Prompt {
+    /// `question`を見せて1行読む。前後の空白は取り除く
+    fn ask(&mut self, question: &str) -> io::Result<String>;
+    /// 打った文字を画面に出さずに1行読む
+    fn ask_secret(&mut self, question: &str) -> io::Result<MisskeyAuthorizationToken>;
+}
+
+/// 標準エラー出力で尋ね、標準入力から読む。標準出力は最後に書くコマンドのために空けておく
+pub struct Terminal;
+
+impl Terminal {
+    fn read_line(question: &str) -> io::Result<String> {
+        eprint!("{question}: ");
+        io::stderr().flush()?;
+        let mut line = String::new();
+        if io::stdin().read_line(&mut line)? == 0 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, format!("no answer to \"{question}\"; pass it as a flag instead")))
+        }
+
+        Ok(line)
+    }
+}
+
+impl Prompt for Terminal {
+    fn ask(&mut self, question: &str) -> io::Result<String> {
+        Self::read_line(question).map(|x| x.trim().to_owned())
+    }
+
+    fn ask_secret(&mut self, question: &str) -> io::Result<MisskeyAuthorizationToken> {
+        let terminal = io::stdin().is_terminal();
+        if terminal {
+            stty("-echo")?;
+        }
+        let answer = Self::read_line(question);
+        if terminal {
+            stty("echo")?;
+            eprintln!();
+        }
+
+        answer.map(MisskeyAuthorizationToken::new)
+    }
+}
+
+/// 隠せないまま尋ねはしない
+fn stty(arg: &str) -> io::Result<()> {
+    let status = process::Command::new("stty").arg(arg).stdin(Stdio::inherit()).status()
+        .map_err(|e| io::Error::other(format!("failed to run stty to hide the token ({e}); pass it with --token-file instead")))?;
+    if status.success() {
+        Ok(())
+    } else {
+        Err(io::Error::other(format!("stty {arg} failed with {status}; pass the token with --token-file instead")))
+    }
+}
+
+pub fn ask_host(prompt: &mut impl Prompt) -> io::Result<Host> {
+    loop {
+        match prompt.ask("Misskey server (e.g. misskey.example)")?.parse() {
+            Ok(host) => return Ok(host),
+            Err(e) => eprintln!("{e}"),
+        }
+    }
+}
+
+/// 空なら、MiAuthで発行する
+pub fn ask_token(prompt: &mut impl Prompt) -> io::Result<Option<MisskeyAuthorizationToken>> {
+    let token = prompt.ask_secret("access token (leave empty to issue one in the browser with MiAuth)")?;
+
+    Ok((!token.expose().is_empty()).then_some(token))
+}
+
+/// `given`があれば、それぞれを`channels/show`で確かめる。無ければ、作ったかフォローしているチャンネルから選んでもらう
+pub async fn select_channels(
+    client: &impl ApiClient,
+    pacer: &Pacer,
+    prompt: &mut impl Prompt,
+    given: Vec<ChannelId>,
+) -> Result<Vec<Channel>, Box<dyn Error + Send + Sync>> {
+    if !given.is_empty() {
+        let mut channels = Vec::with_capacity(given.len());
+        for channel_id in given {
+            pacer.wait().await;
+            channels.push(ChannelShowCommand { channel_id }.send(client).await?);
+        }
+        return Ok(channels)
+    }
+
+    let mut channels = vec![];
+    for endpoint in ["channels/owned", "channels/followed"] {
+        pacer.wait().await;
+        channels.extend(ChannelListCommand { endpoint, limit: LIST_LIMIT }.send(client).await?);
+    }
+    let mut seen = BTreeSet::new();
+    channels.retain(|x| seen.insert(x.id.clone()));
+    if channels.is_empty() {
+        return Err("the account neither owns nor follows any channel; pass --channel-id".into())
+    }
+
+    for (i, channel) in channels.iter().enumerate() {
+        eprintln!("{:>3}. {} ({})", i + 1, channel.name, channel.id.0);
+    }
+    let chosen = loop {
+        match parse_selection(&prompt.ask("channels to archive, e.g. 1,3 (empty for all)")?, channels.len()) {
+            Ok(chosen) => break chosen,
+            Err(e) => eprintln!("{e}"),
+        }
+    };
+
+    Ok(channels.into_iter().enumerate().filter(|(i, _)| chosen.contains(i)).map(|(_, x)| x).collect())
+}
+
+/// `1,3`のような1から始まる番号を、0から始まる番号にする。空なら全て
+fn parse_selection(answer: &str, len: usize) -> Result<Vec<usize>, String> {
+    if answer.is_empty() {
+        return Ok((0..len).collect())
+    }
+
+    answer.split(',').map(str::trim).filter(|x| !x.is_empty()).map(|x| match x.parse::<usize>() {
+        Ok(n) if (1..=len).contains(&n) => Ok(n - 1),
+        _ => Err(format!("{x} is not one of 1 to {len}")),
+    }).collect()
+}
+
+/// `dir`にトークンとチャンネルの一覧を書き、`archive`を始めるコマンドを返す。トークンのファイルは本人しか読めなくする
+pub fn write_profile(dir: &Path, host: &Host, token: &MisskeyAuthorizationToken, channels: &[Channel]) -> io::Result<String> {
+    fs::create_dir_all(dir)?;
+    let token_path = dir.join(TOKEN_FILE);
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options.open(&token_path)?.write_all(token.expose().as_bytes())?;
+
+    let channels_path = dir.join(CHANNELS_FILE);
+    let mut list = String::new();
+    for channel in channels {
+        let _ = writeln!(list, "# {}\n{}", channel.name.replace('\n', " "), channel.id.0);
+    }
+    fs::write(&channels_path, list)?;
+
+    Ok(format!(
+        "misskey-channel-archiver archive --host {} --token-file {} --channels-from {} --output {}",
+        quote(&host.to_string()), quote(&token_path.to_string_lossy()), quote(&channels_path.to_string_lossy()),
+        quote(&dir.join("archive.jsonl").to_string_lossy()),
+    ))
+}
+
+/// シェルにそのまま貼れるようにする
+fn quote(s: &str) -> String {
+    if !s.is_empty() && s.chars().all(|c| c.is_ascii_alphanumeric() || "/._-:".contains(c)) {
+        s.to_owned()
+    } else {
+        format!("'{}'", s.replace('\'', r"'\''"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+    use std::fs;
+    use std::io;
+    use std::num::NonZeroU32;
+    use std::time::Duration;
+
+    use serde_json::json;
+
+    use crate::api::MisskeyAuthorizationToken;
+    use crate::archive;
+    use crate::capture::ReplayClient;
+    use crate::init::{ask_host, ask_token, select_channels, write_profile, Prompt};
+    use crate::model::{Channel, ChannelId};
+    use crate::pacer::Pacer;
+    use crate::testing::{capture_dir, exchange};
+
+    /// 決まった答えを順に返す
+    struct Scripted(VecDeque<&'static str>);
+
+    impl Prompt for Scripted {
+        fn ask(&mut self, _: &str) -> io::Result<String> {
+            self.0.pop_front().map(str::to_owned).ok_or_else(|| io::ErrorKind::UnexpectedEof.into())
+        }
+
+        fn ask_secret(&mut self, question: &str) -> io::Result<MisskeyAuthorizationToken> {
+            self.ask(question).map(MisskeyAuthorizationToken::new)
+        }
+    }
+
+    fn channel(id: &str, name: &str) -> serde_json::Value {
+        json!({ "id": id, "name": name, "notesCount": 1 })
+    }
+
+    #[tokio::test]
+    async fn owned_and_followed_channels_are_offered_once() {
+        let dir = capture_dir("init-channels", &[
+            exchange("channels/owned", json!({ "limit": 100 }), &json!([channel("c1", "mine")])),
+            exchange("channels/followed", json!({ "limit": 100 }), &json!([channel("c2", "theirs"), channel("c1", "mine"), channel("c3", "other")])),
+        ]);
+        let client = ReplayClient::open(&dir).unwrap();
+        let pacer = Pacer::with_burst(Duration::ZERO, NonZeroU32::MIN);
+        // 誤った番号なら尋ね直す
+        let mut prompt = Scripted(VecDeque::from(["4", "x", "3, 1"]));
+
+        let chosen = select_channels(&client, &pacer, &mut prompt, vec![]).await.unwrap();
+
+        assert_eq!(chosen.iter().map(|x| x.id.0.as_str()).collect::<Vec<_>>(), ["c1", "c3"]);
+        assert!(prompt.0.is_empty());
+    }
+
+    #[tokio::test]
+    async fn flags_skip_every_question() {
+        let dir = capture_dir("init-flags", &[exchange("channels/show", json!({ "channelId": "c9" }), &channel("c9", "given"))]);
+        let client = ReplayClient::open(&dir).unwrap();
+        let pacer = Pacer::with_burst(Duration::ZERO, NonZeroU32::MIN);
+        let mut prompt = Scripted(VecDeque::new());
+
+        let chosen = select_channels(&client, &pacer, &mut prompt, vec![ChannelId("c9".to_owned())]).await.unwrap();
+
+        assert_eq!(chosen[0].name, "given");
+        // 答えが無ければ、黙って進まずに失敗する
+        assert!(ask_host(&mut prompt).is_err());
+        assert!(ask_token(&mut Scripted(VecDeque::from([""]))).unwrap().is_none());
+        let mut prompt = Scripted(VecDeque::from(["https://", "https://misskey.example/"]));
+        assert_eq!(ask_host(&mut prompt).unwrap().to_string(), "misskey.example");
+    }
+
+    #[test]
+    fn the_profile_is_read_back_by_archive() {
+        let dir = capture_dir("init-profile", &[]).join("my profile");
+        let channels: Vec<Channel> = serde_json::from_value(json!([channel("c1", "weekly\nnews"), channel("c2", "chat")])).unwrap();
+        let token = MisskeyAuthorizationToken::new("secret-token\n".to_owned());
+
+        let command = write_profile(&dir, &"misskey.example".parse().unwrap(), &token, &channels).unwrap();
+
+        assert_eq!(fs::read_to_string(dir.join("token")).unwrap(), "secret-token");
+        #[cfg(unix)]
+        assert_eq!(std::os::unix::fs::PermissionsExt::mode(&fs::metadata(dir.join("token")).unwrap().permissions()) & 0o777, 0o600);
+        assert_eq!(archive::read_channel_list(&dir.join("channels.txt")).unwrap(), [ChannelId("c1".to_owned()), ChannelId("c2".to_owned())]);
+        assert!(command.starts_with("misskey-channel-archiver archive --host misskey.example --token-file '"));
+        assert!(command.contains("/my profile/channels.txt'") && !command.contains("secret-token"));
+    }
+}
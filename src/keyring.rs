@@ -0,0 +1,46 @@
+//! `--token-keyring`。OSの鍵束に保存したトークンを使う。
+//!
+//! 今のところSecret Service(`secret-tool`)だけに対応する。トークンはコマンドライン引数ではなく標準入出力で受け渡す。
+
+use std::error::Error;
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+use crate::api::MisskeyAuthorizationToken;
+
+/// 鍵束の項目を探すときの属性
+const SERVICE: &str = "misskey-channel-archiver";
+
+pub fn load(entry: &str) -> Result<MisskeyAuthorizationToken, Box<dyn Error + Send + Sync>> {
+    let output = Command::new("secret-tool")
+        .args(["lookup", "service", SERVICE, "entry", entry])
+        .stdin(Stdio::null())
+        .output()
+        .map_err(|e| format!("failed to run secret-tool: {e}"))?;
+    if !output.status.success() || output.stdout.is_empty() {
+        return Err(format!("no token found in keyring entry {entry}; it may be missing or the keyring may be locked").into())
+    }
+
+    let token = String::from_utf8(output.stdout).map_err(|_| format!("keyring entry {entry} is not valid UTF-8"))?;
+
+    Ok(MisskeyAuthorizationToken::new(token))
+}
+
+pub fn store(entry: &str, token: &MisskeyAuthorizationToken) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let mut child = Command::new("secret-tool")
+        .args(["store", "--label", &format!("{SERVICE} ({entry})"), "service", SERVICE, "entry", entry])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| format!("failed to run secret-tool: {e}"))?;
+    // 閉じると入力の終わりになる
+    child.stdin.take().expect("piped").write_all(token.expose().as_bytes())?;
+
+    let output = child.wait_with_output()?;
+    if !output.status.success() {
+        return Err(format!("failed to store token in keyring entry {entry}: {}", String::from_utf8_lossy(&output.stderr).trim()).into())
+    }
+
+    Ok(())
+}
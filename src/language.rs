@@ -0,0 +1,157 @@
+//! `--detect-language`。ノートの本文の言語を、文字の種類と英語のよく使う語から推定する。
+//!
+//! 統計のモデルは持たないので、同じ本文からはいつでも同じ結果になる。見分けるのは日本語、韓国語、中国語と英語だけで、
+//! それ以外や決められないものには何も付けない。推定の仕方を変えたら[`DETECTOR`]も変え、`meta`の記録で分かるようにする。
+
+use crate::mfm;
+use crate::model::Note;
+
+/// `meta`の記録に書く、推定の仕方の版
+pub const DETECTOR: &str = "script-stopwords/1";
+
+/// 本文の文字をラテン文字に直してこれより少なければ、推定しない
+const MIN_LETTERS: usize = 15;
+
+/// 漢字や仮名、ハングルの1文字は、ラテン文字の1文字より多くを表す
+const CJK_WEIGHT: usize = 3;
+
+/// 英語の文にはまず現れる語。全て小文字
+const ENGLISH_WORDS: [&str; 40] = [
+    "the", "a", "an", "and", "or", "but", "of", "to", "in", "on", "at", "for", "with", "from", "by", "is", "are", "was", "were", "be",
+    "it", "this", "that", "i", "you", "he", "she", "we", "they", "my", "your", "not", "no", "have", "has", "do", "does", "can", "will", "just",
+];
+
+#[derive(Default)]
+struct Letters {
+    kana: usize,
+    han: usize,
+    hangul: usize,
+    latin: usize,
+    other: usize,
+}
+
+impl Letters {
+    fn count(text: &str) -> Self {
+        let mut letters = Self::default();
+        for c in text.chars().filter(|x| x.is_alphabetic()) {
+            match c {
+                '\u{3040}'..='\u{30FF}' | '\u{31F0}'..='\u{31FF}' | '\u{FF66}'..='\u{FF9D}' => letters.kana += 1,
+                '\u{3400}'..='\u{4DBF}' | '\u{4E00}'..='\u{9FFF}' | '\u{F900}'..='\u{FAFF}' => letters.han += 1,
+                '\u{1100}'..='\u{11FF}' | '\u{3130}'..='\u{318F}' | '\u{AC00}'..='\u{D7AF}' => letters.hangul += 1,
+                'a'..='z' | 'A'..='Z' | '\u{C0}'..='\u{24F}' => letters.latin += 1,
+                _ => letters.other += 1,
+            }
+        }
+
+        letters
+    }
+
+    const fn cjk(&self) -> usize {
+        self.kana + self.han + self.hangul
+    }
+
+    /// ラテン文字に直した数
+    const fn weighted(&self) -> usize {
+        self.cjk() * CJK_WEIGHT + self.latin + self.other
+    }
+}
+
+/// リノートは元のノートの言語になるので推定しない
+pub fn detect_note(note: &Note) -> Option<&'static str> {
+    if note.renote_on.is_some() {
+        return None
+    }
+
+    detect(&mfm::to_plain_text(&note.text.as_ref()?.0))
+}
+
+/// BCP 47の言語の名前
+pub fn detect(text: &str) -> Option<&'static str> {
+    let letters = Letters::count(text);
+    if letters.weighted() < MIN_LETTERS {
+        return None
+    }
+
+    let cjk = letters.cjk() * CJK_WEIGHT;
+    if cjk >= letters.latin && cjk >= letters.other {
+        return Some(if letters.hangul > letters.kana + letters.han {
+            "ko"
+        } else if letters.kana > 0 {
+            "ja"
+        } else {
+            "zh"
+        })
+    }
+    if letters.latin < letters.other {
+        return None
+    }
+
+    let words: Vec<_> = text.split(|c: char| !c.is_alphabetic() && c != '\'').filter(|x| !x.is_empty()).collect();
+    let english = words.iter().filter(|x| ENGLISH_WORDS.iter().any(|w| x.eq_ignore_ascii_case(w))).count();
+    // 英語の文なら、3語か4語に1つはこれらになる
+    (english * 8 >= words.len() && english > 0).then_some("en")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use serde_json::json;
+
+    use crate::language::{detect, detect_note};
+    use crate::model::Note;
+    use crate::testing::note_json;
+
+    fn note(text: &str) -> Note {
+        let mut note = note_json("n1", "2024-01-01T00:00:00.000Z");
+        note["text"] = json!(text);
+        serde_json::from_value(note).unwrap()
+    }
+
+    #[test]
+    fn mixed_channels_are_told_apart() {
+        let cases = [
+            ("今日はいい天気ですね。散歩に行ってきます", Some("ja")),
+            ("新しいMisskeyのバージョンがリリースされました！", Some("ja")),
+            ("I just pushed the fix for the timeline bug, please test it", Some("en")),
+            ("오늘은 날씨가 정말 좋네요 산책하러 갑니다", Some("ko")),
+            ("今天天气很好我们去公园散步吧", Some("zh")),
+            // 英語の語が無い
+            ("Dzisiaj jest bardzo ładna pogoda", None),
+            // 短すぎる
+            ("lol ok", None),
+            ("草", None),
+        ];
+
+        for (text, expected) in cases {
+            assert_eq!(detect(text), expected, "{text}");
+        }
+        // 絵文字やURL、記法は数えない
+        assert_eq!(detect_note(&note("**:blobcat_happy_dance:** https://misskey.example/notes/abcdefghij")), None);
+        assert_eq!(detect_note(&note("$[tada **ありがとうございます！**] :thanks_everyone:")), Some("ja"));
+        let mut renote = note("今日はいい天気ですね。散歩に行ってきます");
+        renote.renote_on = Some(renote.id.clone());
+        assert_eq!(detect_note(&renote), None);
+    }
+
+    #[test]
+    fn a_large_corpus_is_annotated_quickly_and_the_same_way_every_time() {
+        let texts = [
+            "今日はいい天気ですね。散歩に行ってきます :blobcat:",
+            "I just pushed the fix for the timeline bug, please test it https://example.com/",
+            "**お知らせ** 明日の21時からメンテナンスを行います",
+            "Thanks! That was exactly what I was looking for :tada:",
+            "w",
+        ];
+        let notes: Vec<_> = (0..10_000).map(|i| note(texts[i % texts.len()])).collect();
+
+        let started = Instant::now();
+        let first: Vec<_> = notes.iter().map(detect_note).collect();
+        let elapsed = started.elapsed();
+
+        assert_eq!(first, notes.iter().map(detect_note).collect::<Vec<_>>());
+        assert_eq!(first[..5], [Some("ja"), Some("en"), Some("ja"), Some("en"), None]);
+        // デバッグビルドでも、1ページを取る間隔よりずっと短い
+        assert!(elapsed.as_secs() < 5, "{elapsed:?}");
+    }
+}
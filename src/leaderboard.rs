@@ -0,0 +1,211 @@
+//! `leaderboard`。アーカイブを読み、リアクションの多かったノートの順位表を作る。
+//!
+//! 点数はリアクションの数の合計で、`--weights`があれば絵文字ごとに重みを掛ける。同じ点数なら先に書かれたノートを上にする。
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt::Write as _;
+use std::fs;
+use std::path::Path;
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+
+use crate::host::Host;
+use crate::model::{Note, NoteId, UserId};
+use crate::report::Period;
+use crate::timezone::Timezone;
+
+/// `--weights`に無い絵文字と、どの絵文字か分からない`other_reactions_count`の重み
+const DEFAULT_WEIGHT: f64 = 1.0;
+
+/// `--users`から読んだ、作者の表示に使うもの
+pub struct Author {
+    pub username: Option<String>,
+    pub name: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct Leaderboard {
+    kind: &'static str,
+    period: Option<String>,
+    timezone: String,
+    entries: Vec<Entry>,
+}
+
+#[derive(Serialize)]
+struct Entry {
+    rank: usize,
+    note_id: NoteId,
+    user_id: UserId,
+    username: Option<String>,
+    name: Option<String>,
+    created_at: DateTime<Utc>,
+    reactions: usize,
+    score: f64,
+    url: Option<String>,
+}
+
+/// `--weights`のファイルを読む。1行に`<絵文字>=<重み>`を1つずつ書き、空行と`#`から始まる行は無視する。
+/// 絵文字はアーカイブでの書き方で、カスタム絵文字なら`:blobcat@.:`のように書く。
+pub fn parse_weights(text: &str) -> Result<HashMap<String, f64>, String> {
+    let mut weights = HashMap::new();
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue
+        }
+        let weight = line.rsplit_once('=')
+            .and_then(|(emoji, weight)| Some((emoji.trim(), weight.trim().parse::<f64>().ok()?)))
+            .filter(|(emoji, weight)| !emoji.is_empty() && weight.is_finite());
+        let Some((emoji, weight)) = weight else {
+            return Err(format!("line {}: expected <emoji>=<score>, got {line}", i + 1))
+        };
+        weights.insert(emoji.to_owned(), weight);
+    }
+
+    Ok(weights)
+}
+
+/// `fetch-user`の出力から、IDとusername、名前の対応を読む。
+pub fn read_authors(path: &Path) -> Result<HashMap<UserId, Author>, Box<dyn Error + Send + Sync>> {
+    let text = fs::read_to_string(path).map_err(|e| format!("failed to open {}: {e}", path.display()))?;
+    let mut authors = HashMap::new();
+    for line in text.lines().filter(|x| !x.trim().is_empty()) {
+        let record: serde_json::Value = serde_json::from_str(line)?;
+        if let Some(id) = record["id"].as_str() {
+            let author = Author { username: record["username"].as_str().map(str::to_owned), name: record["name"].as_str().map(str::to_owned) };
+            authors.insert(UserId(id.to_owned()), author);
+        }
+    }
+
+    Ok(authors)
+}
+
+/// リアクションに重みを掛けた合計
+#[allow(clippy::cast_precision_loss)]
+fn score(note: &Note, weights: &HashMap<String, f64>) -> f64 {
+    let weight = |key: &serde_json::Value| key.as_str().and_then(|x| weights.get(x)).copied().unwrap_or(DEFAULT_WEIGHT);
+    let known: f64 = note.reactions.0.iter()
+        .map(|(key, count)| weight(&serde_json::to_value(key).unwrap_or_default()) * count.get() as f64)
+        .sum();
+    let other = note.other_reactions_count.unwrap_or(0) as f64 * DEFAULT_WEIGHT;
+
+    known + other
+}
+
+/// `period`は`timezone`での月。点数が0のノートは載せない。
+pub fn build(
+    notes: &[Note],
+    authors: &HashMap<UserId, Author>,
+    weights: &HashMap<String, f64>,
+    period: Option<Period>,
+    timezone: &Timezone,
+    top: usize,
+    host: Option<&Host>,
+) -> Leaderboard {
+    let mut scored: Vec<_> = notes.iter()
+        .filter(|x| period.is_none_or(|p| p.contains(timezone.to_local(x.created_at).date_naive())))
+        .map(|x| (x, score(x, weights)))
+        .filter(|(_, score)| *score > 0.0)
+        .collect();
+    scored.sort_by(|a, b| b.1.total_cmp(&a.1).then_with(|| a.0.created_at.cmp(&b.0.created_at)).then_with(|| a.0.id.0.cmp(&b.0.id.0)));
+
+    let entries = scored.into_iter().take(top).zip(1..).map(|((note, score), rank)| {
+        let author = authors.get(&note.user.id);
+        let url = note.local_url.clone()
+            .or_else(|| host.map(|x| x.url(&format!("notes/{}", note.id.0))))
+            .or_else(|| note.url.clone().or_else(|| note.uri.clone()));
+        Entry {
+            rank,
+            note_id: note.id.clone(),
+            user_id: note.user.id.clone(),
+            username: author.and_then(|x| x.username.clone()).or_else(|| note.user.username.clone()),
+            name: author.and_then(|x| x.name.clone()).or_else(|| note.user.name.clone()),
+            created_at: note.created_at,
+            reactions: note.reactions.total() + note.other_reactions_count.unwrap_or(0),
+            score,
+            url: url.map(|x| x.to_string()),
+        }
+    }).collect();
+
+    Leaderboard { kind: "leaderboard", period: period.map(|p| p.to_string()), timezone: timezone.to_string(), entries }
+}
+
+/// そのまま貼れる表。作者の名前に含まれる`|`は表を崩さないようにエスケープする。
+pub fn to_markdown(leaderboard: &Leaderboard) -> String {
+    let escape = |x: &str| x.replace('|', "\\|");
+    let mut md = String::new();
+    let _ = writeln!(md, "# Most reacted notes{}\n", leaderboard.period.as_ref().map_or_else(String::new, |p| format!(" {p}")));
+    md.push_str("| # | Note | Author | Reactions | Score |\n| ---: | --- | --- | ---: | ---: |\n");
+    for entry in &leaderboard.entries {
+        let note = entry.url.as_ref().map_or_else(|| entry.note_id.0.clone(), |url| format!("[{}]({url})", entry.note_id.0));
+        let author = match (&entry.name, &entry.username) {
+            (Some(name), Some(username)) => format!("{} (@{username})", escape(name)),
+            (None, Some(username)) => format!("@{username}"),
+            (Some(name), None) => escape(name),
+            (None, None) => entry.user_id.0.clone(),
+        };
+        let _ = writeln!(md, "| {} | {note} | {author} | {} | {} |", entry.rank, entry.reactions, entry.score);
+    }
+
+    md
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use chrono::FixedOffset;
+    use serde_json::json;
+
+    use crate::leaderboard::{build, parse_weights, to_markdown, Author};
+    use crate::model::{Note, UserId};
+    use crate::testing::note_json;
+    use crate::timezone::Timezone;
+
+    fn note(id: &str, user: &str, created_at: &str, reactions: serde_json::Value) -> Note {
+        let mut note = note_json(id, created_at);
+        note["user"] = json!({ "id": user });
+        note["reactions"] = reactions;
+        serde_json::from_value(note).unwrap()
+    }
+
+    #[test]
+    fn weights_are_parsed_per_line() {
+        let weights = parse_weights("# comment\n\n:blobcat@.:=3\n👍 = 0.5\n").unwrap();
+        assert_eq!(weights, HashMap::from([(":blobcat@.:".to_owned(), 3.0), ("👍".to_owned(), 0.5)]));
+        assert_eq!(parse_weights("👍=many").unwrap_err(), "line 1: expected <emoji>=<score>, got 👍=many");
+        assert!(parse_weights("=1").is_err());
+    }
+
+    #[test]
+    fn notes_are_ranked_by_weighted_reactions_in_the_period() {
+        let notes = [
+            note("n1", "u1", "2024-11-03T00:00:00Z", json!({ "👍": 4 })),
+            // 重みを掛けると4点で、n1と同じ点数だが後に書かれた
+            note("n2", "u2", "2024-11-04T00:00:00Z", json!({ ":blobcat@.:": 2 })),
+            note("n3", "u2", "2024-11-02T00:00:00Z", json!({ "👍": 1, ":blobcat@.:": 3 })),
+            // 日本時間では12月1日
+            note("n4", "u1", "2024-11-30T15:30:00Z", json!({ "👍": 100 })),
+            note("n5", "u1", "2024-11-05T00:00:00Z", json!({})),
+        ];
+        let authors = HashMap::from([(UserId("u2".to_owned()), Author { username: Some("bob".to_owned()), name: Some("Bob | B".to_owned()) })]);
+        let weights = parse_weights(":blobcat@.:=2").unwrap();
+        let tokyo = Timezone::Fixed(FixedOffset::east_opt(9 * 3600).unwrap());
+        let host = "misskey.example".parse().unwrap();
+
+        let leaderboard = build(&notes, &authors, &weights, Some("2024-11".parse().unwrap()), &tokyo, 20, Some(&host));
+
+        let value = serde_json::to_value(&leaderboard).unwrap();
+        let ranked: Vec<_> = value["entries"].as_array().unwrap().iter().map(|x| (x["note_id"].as_str().unwrap(), x["score"].as_f64().unwrap())).collect();
+        assert_eq!(ranked, [("n3", 7.0), ("n1", 4.0), ("n2", 4.0)]);
+        assert_eq!(value["entries"][0]["url"], "https://misskey.example/notes/n3");
+        assert_eq!(value["entries"][0]["reactions"], 4);
+        assert_eq!(to_markdown(&leaderboard), "# Most reacted notes 2024-11\n\n\
+            | # | Note | Author | Reactions | Score |\n| ---: | --- | --- | ---: | ---: |\n\
+            | 1 | [n3](https://misskey.example/notes/n3) | Bob \\| B (@bob) | 4 | 7 |\n\
+            | 2 | [n1](https://misskey.example/notes/n1) | u1 | 4 | 4 |\n\
+            | 3 | [n2](https://misskey.example/notes/n2) | Bob \\| B (@bob) | 2 | 4 |\n");
+    }
+}
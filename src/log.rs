@@ -0,0 +1,124 @@
+//! 標準エラー出力への診断メッセージ。`--quiet`、`--verbose`、`--no-progress`で量を変え、`--log-format`で形を変える。
+//!
+//! エラーはここを通さず、常に表示する。
+
+use std::fmt::Arguments;
+use std::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+
+use clap::ValueEnum;
+
+#[derive(Eq, PartialEq, Ord, PartialOrd, Copy, Clone, Debug)]
+pub enum Level {
+    /// エラーだけ
+    Quiet,
+    Normal,
+    /// 送ったリクエストなども
+    Debug,
+}
+
+/// `--log-format`
+#[derive(Eq, PartialEq, Copy, Clone, Debug, Default, ValueEnum)]
+pub enum LogFormat {
+    #[default]
+    Text,
+    /// 1行に1つの`{"kind": "log"}`。エラーも1つのJSONのオブジェクトとして書く
+    Json,
+}
+
+impl LogFormat {
+    /// clapが引数を読めなかったときのために、`--log-format json`があるかだけを見る。
+    pub fn from_args(args: impl IntoIterator<Item = String>) -> Self {
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            let value = match arg.strip_prefix("--log-format") {
+                Some("") => args.next(),
+                Some(value) => value.strip_prefix('=').map(str::to_owned),
+                None => continue,
+            };
+            if value.as_deref() == Some("json") {
+                return Self::Json
+            }
+        }
+
+        Self::Text
+    }
+}
+
+static LEVEL: AtomicU8 = AtomicU8::new(Level::Normal as u8);
+static PROGRESS: AtomicBool = AtomicBool::new(true);
+static JSON: AtomicBool = AtomicBool::new(false);
+
+#[cfg(test)]
+thread_local! {
+    static CAPTURED: std::cell::RefCell<Option<String>> = const { std::cell::RefCell::new(None) };
+}
+
+pub fn init(level: Level, progress: bool, format: LogFormat) {
+    LEVEL.store(level as u8, Ordering::Relaxed);
+    PROGRESS.store(progress, Ordering::Relaxed);
+    JSON.store(format == LogFormat::Json, Ordering::Relaxed);
+}
+
+/// `--log-format json`
+pub fn json() -> bool {
+    JSON.load(Ordering::Relaxed)
+}
+
+pub fn enabled(level: Level) -> bool {
+    LEVEL.load(Ordering::Relaxed) >= level as u8
+}
+
+pub fn progress_enabled() -> bool {
+    enabled(Level::Normal) && PROGRESS.load(Ordering::Relaxed)
+}
+
+pub fn write(args: Arguments<'_>) {
+    #[cfg(test)]
+    if CAPTURED.with_borrow_mut(|captured| captured.as_mut().map(|c| std::fmt::Write::write_fmt(c, format_args!("{args}\n")))).is_some() {
+        return
+    }
+
+    if json() {
+        eprintln!("{}", serde_json::json!({ "kind": "log", "message": args.to_string() }));
+    } else {
+        eprintln!("{args}");
+    }
+}
+
+/// このスレッドで書かれるものを、標準エラー出力の代わりに溜める。
+#[cfg(test)]
+pub fn start_capture() {
+    CAPTURED.set(Some(String::new()));
+}
+
+#[cfg(test)]
+pub fn take_captured() -> String {
+    CAPTURED.take().unwrap_or_default()
+}
+
+macro_rules! info {
+    ($($arg:tt)*) => {
+        if $crate::log::enabled($crate::log::Level::Normal) {
+            $crate::log::write(format_args!($($arg)*));
+        }
+    };
+}
+
+macro_rules! debug {
+    ($($arg:tt)*) => {
+        if $crate::log::enabled($crate::log::Level::Debug) {
+            $crate::log::write(format_args!($($arg)*));
+        }
+    };
+}
+
+/// 進み具合。`--no-progress`で止められる。
+macro_rules! progress {
+    ($($arg:tt)*) => {
+        if $crate::log::progress_enabled() {
+            $crate::log::write(format_args!($($arg)*));
+        }
+    };
+}
+
+pub(crate) use {debug, info, progress};
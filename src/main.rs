@@ -1,402 +1,1079 @@
 #![deny(clippy::all)]
 #![warn(clippy::pedantic, clippy::nursery)]
-#![forbid(unsafe_code)]
+#![cfg_attr(not(test), forbid(unsafe_code))]
+// テストでは、メモリの使用量を数えるアロケーターだけが使う
+#![cfg_attr(test, deny(unsafe_code))]
 
-use std::collections::{BTreeSet, HashMap, HashSet};
-use std::convert::Infallible;
-use std::error::Error;
-use std::fmt::{Debug, Formatter, Write};
-use std::num::NonZeroUsize;
-use std::str::FromStr;
+mod activity;
+mod attachments;
+mod api;
+mod append;
+mod archive;
+mod capture;
+mod clock;
+mod chunk;
+mod cli;
+mod content_hash;
+mod deletion;
+mod explore;
+mod export;
+mod featured;
+mod field_map;
+mod filename;
+mod generate;
+mod graph;
+mod host;
+mod http_tuning;
+mod i18n;
+mod import;
+mod init;
+#[cfg(feature = "keyring")]
+mod keyring;
+mod language;
+mod leaderboard;
+mod log;
+mod manifest;
+mod metrics;
+mod mfm;
+mod miauth;
+mod model;
+mod note_set;
+mod notify;
+mod output;
+mod pacer;
+mod page_markers;
+mod pagination;
+mod pinned;
+mod provenance;
+mod preflight;
+mod reactions;
+mod reauth;
+mod reader;
+mod refresh;
+mod renotes;
+mod report;
+#[cfg(feature = "s3")]
+mod s3;
+mod schema;
+mod sink;
+mod split;
+mod status;
+mod timestamp;
+mod timezone;
+mod translate;
+mod tree;
+mod usage;
+mod user_cache;
+mod user_map;
+#[cfg(test)]
+mod testing;
 
-use std::time::Duration;
-use chrono::{DateTime, Utc};
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs;
+use std::io::Write;
+use std::num::{NonZeroU64, NonZeroUsize};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use chrono::Utc;
 use clap::Parser;
-use lazy_regex::Lazy;
 
-use reqwest::{Client, Method, Request, RequestBuilder};
-use url::Url;
-use serde::{Serialize, Deserialize, Deserializer, Serializer};
+use reqwest::Client;
+
+use crate::activity::Activity;
+use crate::api::{ApiClient, ApiError, HttpApiClient, MisskeyAuthorizationToken, RawResponse, Timeline, UserDetailCommand};
+use crate::capture::{CapturingClient, ReplayClient};
+use crate::cli::{Cli, Command, GlobalArgs, TextArgs, TimelineArgs};
+use crate::content_hash::HashLog;
+use crate::deletion::DeletionCheck;
+use crate::explore::Granularity;
+use crate::export::{ExportFormat, Exporter};
+use crate::field_map::{FieldMap, Renames};
+use crate::graph::{Relation, UserRef};
+use crate::i18n::{msg, Lang};
+use crate::import::ImportFormat;
+use crate::archive::{ArchiveOptions, PAGE_SIZE};
+use crate::attachments::FileEstimate;
+use crate::log::{info, Level, LogFormat};
+use crate::manifest::Problem;
+use crate::metrics::{MeteredClient, Metrics};
+use crate::mfm::TextOptions;
+use crate::model::{ChannelId, NoteId, UserId};
+use crate::preflight::authenticate;
+use crate::notify::Notification;
+use crate::output::{Destination, Layout, OutputOptions, RecordFile, UserLayout, UserOutput};
+use crate::pacer::{Pacer, PacerState};
+use crate::pagination::Direction;
+use crate::pinned::PinnedNotes;
+use crate::provenance::Run;
+use crate::reactions::{Bucket, ReactionFilter};
+use crate::reauth::Reauthenticating;
+use crate::renotes::Checkpoint;
+use crate::report::Period;
+use crate::schema::{RecordKind, SchemaFormat};
+use crate::timestamp::TimestampFormat;
+use crate::translate::Translator;
+use crate::tree::UserTree;
+use crate::user_map::UserMap;
+use crate::user_cache::UserCache;
+
+/// 引数に応じて選ばれたクライアント。
+enum AnyClient {
+    Http(Reauthenticating<HttpApiClient>),
+    Capturing(CapturingClient<Reauthenticating<HttpApiClient>>),
+    Replay(ReplayClient),
+}
+
+impl AnyClient {
+    fn new(global: &mut GlobalArgs) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        if let Some(replay) = &global.replay {
+            return Ok(Self::Replay(ReplayClient::open(replay)?));
+        }
+
+        let mut builder = global.http_tuning().apply(Client::builder().gzip(true).deflate(true).brotli(true)
+            .use_rustls_tls());
+        if let Some(timeout) = global.timeout_second {
+            builder = builder.timeout(Duration::from_secs(timeout.get()));
+        }
+        if let Some(timeout) = global.connect_timeout_second {
+            builder = builder.connect_timeout(Duration::from_secs(timeout.get()));
+        }
+        let host = global.host.clone().expect("checked by usage::check");
+        let (name, port) = (host.name(), host.port());
+        let mut resolution = "DNS".to_owned();
+        if global.ipv4_only {
+            builder = builder.local_address(IpAddr::from(Ipv4Addr::UNSPECIFIED));
+            resolution.push_str(", IPv4 only");
+        }
+        if global.ipv6_only {
+            builder = builder.local_address(IpAddr::from(Ipv6Addr::UNSPECIFIED));
+            resolution.push_str(", IPv6 only");
+        }
+        // reqwestはポートを見ないので、ここで絞る
+        if let Some(pinned) = global.resolve.iter().find(|r| r.host == name && r.port == port) {
+            builder = builder.resolve(name, SocketAddr::new(pinned.addr, port));
+            resolution = format!("{} (--resolve)", pinned.addr);
+        }
+        info!("connecting to {name}:{port} via {resolution}");
+        if !global.headers.is_empty() {
+            let headers = global.headers.iter().map(|h| (h.name.clone(), h.value.clone())).collect();
+            builder = builder.default_headers(headers);
+        }
+        let http = builder.build()?;
+        // `auth`はトークン無しで呼ぶ
+        let token = global.resolve_token()?;
+        let http = Reauthenticating::new(HttpApiClient::new(http, host, token), global.reauth_command.clone());
+
+        match &global.capture {
+            Some(dir) => Ok(Self::Capturing(CapturingClient::new(http, dir.clone())?)),
+            None => Ok(Self::Http(http)),
+        }
+    }
+}
+
+impl ApiClient for AnyClient {
+    async fn call(&self, endpoint: &str, body: serde_json::Value) -> Result<RawResponse, Box<dyn Error + Send + Sync>> {
+        match self {
+            Self::Http(c) => c.call(endpoint, body).await,
+            Self::Capturing(c) => c.call(endpoint, body).await,
+            Self::Replay(c) => c.call(endpoint, body).await,
+        }
+    }
+}
+
+/// `tree`があれば、`out`ではなくそちらに書く。`pinned`があれば、ユーザーの後にピン留めしたノートを書く。
+#[allow(clippy::too_many_arguments)]
+async fn fetch_users(
+    client: &impl ApiClient,
+    pacer: &Pacer,
+    out: &mut (impl Write + Send + ?Sized),
+    layout: &mut UserOutput,
+    renames: &Renames,
+    users: Vec<UserId>,
+    mut cache: Option<&mut UserCache>,
+    mut pinned: Option<&mut PinnedNotes>,
+    metrics: &Metrics,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let now = Utc::now();
+    for user_id in users {
+        if let Some((user, fetched_at)) = cache.as_deref().and_then(|c| c.get(&user_id, now)) {
+            metrics.record_user_cache(true);
+            layout.write_user(out, user, renames, fetched_at)?;
+            if let Some(pinned) = pinned.as_deref_mut() {
+                pinned.write(client, pacer, out, user).await?;
+            }
+            continue
+        }
+
+        let command = UserDetailCommand {
+            id: user_id.clone()
+        };
 
-use tokio::time::sleep;
+        pacer.wait().await;
+        let result = match command.send(client).await {
+            Ok(user) => user,
+            // 凍結されたユーザーは`isSuspended`付きで返り、消えたユーザーだけがここに来る
+            Err(e) if e.downcast_ref::<ApiError>().is_some_and(|x| x.code == "NO_SUCH_USER") => {
+                layout.write_missing(out, &user_id, now)?;
+                continue
+            }
+            Err(e) => return Err(e),
+        };
 
-#[derive(Eq, PartialEq, Clone, Serialize, Deserialize)]
-struct NoteId(String);
+        layout.write_user(out, &result, renames, now)?;
+        if let Some(pinned) = pinned.as_deref_mut() {
+            pinned.write(client, pacer, out, &result).await?;
+        }
+        if let Some(cache) = cache.as_deref_mut() {
+            metrics.record_user_cache(false);
+            cache.insert(result, now);
+        }
+    }
+    out.flush()?;
 
-impl FromStr for NoteId {
-    type Err = Infallible;
+    Ok(())
+}
 
-    fn from_str(s: &str) -> Result<Self, Self::Err> {
-        Ok(Self(s.to_owned()))
+#[allow(clippy::too_many_arguments)]
+async fn fetch_user(
+    global: &mut GlobalArgs,
+    metrics: &Arc<Metrics>,
+    pacer: &Pacer,
+    users: Vec<UserId>,
+    cache: Option<(PathBuf, Duration)>,
+    layout: UserLayout,
+    append: bool,
+    mut pinned: Option<PinnedNotes>,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let client = MeteredClient::new(AnyClient::new(global)?, Arc::clone(metrics)).with_budget(global.max_requests).with_deadline(global.max_duration);
+    let mut layout = match layout {
+        UserLayout::Lines => UserOutput::Lines,
+        UserLayout::PerUser => UserOutput::Tree(UserTree::open(global.output.as_deref().expect("checked by usage::check"))?),
+        UserLayout::Map => UserOutput::Map(UserMap::open(global.output.as_deref().filter(|_| append))?),
+    };
+    // ディレクトリに書くときは、標準出力には何も書かない
+    let path = if matches!(layout, UserOutput::Tree(_)) { None } else { global.output.as_deref() };
+    // 書き足すときは、書き終えるまで前の内容を残す
+    let mut out = output::open(path, !global.no_atomic || append, global.remote_sink()?.as_ref())?;
+    let mut cache = cache.map(|(path, ttl)| UserCache::open(&path, ttl)).transpose()?;
+    let renames = global.field_map.as_ref().map(|x| x.user.clone()).unwrap_or_default();
+    let result = fetch_users(&client, pacer, &mut out, &mut layout, &renames, users, cache.as_mut(), pinned.as_mut(), metrics).await;
+    // 途中で失敗しても、取れた分は残す
+    if let Some(cache) = cache {
+        cache.save()?;
     }
+    match layout {
+        UserOutput::Tree(tree) => tree.finish()?,
+        UserOutput::Map(map) if result.is_ok() => map.write(&mut out)?,
+        _ => {}
+    }
+    report_metrics(metrics, global.metrics_output.as_deref())?;
+    result?;
+    out.finish()?;
+
+    Ok(())
+}
+
+async fn snapshot_featured(global: &mut GlobalArgs, metrics: &Arc<Metrics>, pacer: &Pacer) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let client = MeteredClient::new(AnyClient::new(global)?, Arc::clone(metrics)).with_budget(global.max_requests).with_deadline(global.max_duration);
+    let mut out = output::open(global.output.as_deref(), !global.no_atomic, global.remote_sink()?.as_ref())?;
+    let result = featured::snapshot(&client, pacer, Utc::now()).await;
+    report_metrics(metrics, global.metrics_output.as_deref())?;
+    writeln!(out, "{}", result?)?;
+    out.finish()?;
+
+    Ok(())
 }
 
-#[derive(Eq, PartialEq, Clone, Deserialize, Serialize)]
-struct ChannelId(String);
+/// `input`があれば、APIは呼ばない。
+async fn estimate_files(
+    global: &mut GlobalArgs,
+    metrics: &Arc<Metrics>,
+    pacer: &Pacer,
+    channels: Vec<ChannelId>,
+    input: Option<&Path>,
+    largest: usize,
+    sample: usize,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let mut out = output::open(global.output.as_deref(), !global.no_atomic, global.remote_sink()?.as_ref())?;
+    let mut estimate = FileEstimate::new(largest);
+    if let Some(path) = input {
+        attachments::read_archive(path, &mut estimate)?;
+    } else {
+        let client = MeteredClient::new(AnyClient::new(global)?, Arc::clone(metrics)).with_budget(global.max_requests).with_deadline(global.max_duration);
+        let mut result = Ok(());
+        for channel_id in channels {
+            result = attachments::walk(&client, pacer, &Timeline::Channel(channel_id), &mut estimate).await;
+            if result.is_err() {
+                break
+            }
+        }
+        report_metrics(metrics, global.metrics_output.as_deref())?;
+        result?;
+    }
+    // 添付ファイルの接続はAPIのものと分けて持つ
+    let http = global.http_tuning().apply(Client::builder().use_rustls_tls()).build()?;
+    estimate.probe(&http, pacer, global.host.as_ref(), sample).await;
+    eprint!("{}", estimate.table());
+    writeln!(out, "{}", estimate.record())?;
+    out.finish()?;
 
-impl FromStr for ChannelId {
-    type Err = Infallible;
+    Ok(())
+}
 
-    fn from_str(s: &str) -> Result<Self, Self::Err> {
-        Ok(Self(s.to_owned()))
+async fn explore_density(
+    global: &mut GlobalArgs,
+    metrics: &Arc<Metrics>,
+    pacer: &Pacer,
+    channels: Vec<ChannelId>,
+    granularity: Granularity,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let client = MeteredClient::new(AnyClient::new(global)?, Arc::clone(metrics)).with_budget(global.max_requests).with_deadline(global.max_duration);
+    let mut out = output::open(global.output.as_deref(), !global.no_atomic, global.remote_sink()?.as_ref())?;
+    let mut result = Ok(());
+    for channel_id in channels {
+        match explore::explore(&client, pacer, channel_id, granularity, &global.timezone, Utc::now()).await {
+            Ok(density) => {
+                eprint!("{}", density.histogram());
+                writeln!(out, "{}", density.record())?;
+            }
+            Err(e) => {
+                result = Err(e);
+                break
+            }
+        }
     }
+    report_metrics(metrics, global.metrics_output.as_deref())?;
+    result?;
+    out.finish()?;
+
+    Ok(())
 }
 
-#[derive(Eq, PartialEq, Clone, Hash, Deserialize, Serialize)]
-struct UserId(String);
+/// 実行の最後に、インスタンスにかけた負荷を書き出す。
+fn report_metrics(metrics: &Metrics, path: Option<&Path>) -> std::io::Result<()> {
+    // 標準出力が閉じられていても、書き出したファイルは残す
+    let _ = writeln!(std::io::stdout(), "{}", metrics.summary());
+    if let Some(path) = path {
+        let mut out = RecordFile::create(path, true)?;
+        out.write_all(metrics.to_prometheus().as_bytes())?;
+        out.finish()?;
+    }
 
-impl FromStr for UserId {
-    type Err = Infallible;
+    Ok(())
+}
 
-    fn from_str(s: &str) -> Result<Self, Self::Err> {
-        Ok(Self(s.to_owned()))
+/// `--retries-report`。`--log-format json`なら`{"kind": "retries"}`を1つ書く
+fn report_retries(metrics: &Metrics) {
+    if log::json() {
+        eprintln!("{}", serde_json::json!({ "kind": "retries", "endpoints": metrics.summary()["endpoints"] }));
+    } else {
+        eprint!("{}", metrics.retries_report());
     }
 }
-#[derive(Eq, PartialEq, Clone, Serialize)]
-struct MisskeyAuthorizationToken(String);
 
-impl MisskeyAuthorizationToken {
-    fn leak(self) -> String {
-        self.0
+#[tokio::main]
+async fn main() -> ExitCode {
+    let cli = match Cli::try_parse() {
+        Ok(cli) => cli,
+        // `--help`や`--version`
+        Err(e) if !e.use_stderr() => {
+            let _ = e.print();
+            return ExitCode::SUCCESS
+        }
+        Err(e) => {
+            log::init(Level::Normal, true, LogFormat::from_args(std::env::args()));
+            return status::finish(&Err(e.into()), true)
+        }
+    };
+    log::init(cli.global.log_level(), !cli.global.no_progress, cli.global.log_format);
+    i18n::init(cli.global.lang.unwrap_or_else(Lang::from_env));
+    output::init(cli.global.flush);
+    let always = !matches!(cli.cmd, Command::Auth { .. } | Command::Report { .. } | Command::Leaderboard { .. } | Command::Import { .. } | Command::Export { .. } | Command::Schema { .. } | Command::Generate { .. });
+    if let Err(e) = usage::check(&cli) {
+        return status::finish(&Err(e.into()), always)
+    }
+    let (fail_threshold, retries_report) = (cli.global.fail_threshold, cli.global.retries_report);
+    let notify = cli.global.notify_webhook.clone().map(|url| (url, cli.global.notify_format));
+    let started = Instant::now();
+    let metrics = Arc::new(Metrics::default());
+    let pacer = Arc::new(Pacer::with_burst(cli.global.cool_down(), cli.global.burst).with_metrics(Arc::clone(&metrics)));
+    let pacer_state = cli.global.pacer_state.as_deref().zip(cli.global.host.as_ref())
+        .map(|(path, host)| PacerState::load(path, host.to_string(), &pacer));
+
+    let result = match cli.global.metrics_listen.map(|addr| metrics::serve(Arc::clone(&metrics), addr)).transpose() {
+        Ok(listening) => {
+            if let Some(addr) = listening {
+                info!("serving metrics on http://{addr}/metrics");
+            }
+            // 止められたら書き出し先を捨て、溜めていた記録を書いてから終わる
+            tokio::select! {
+                result = run(cli, Arc::clone(&metrics), Arc::clone(&pacer)) => result,
+                _ = tokio::signal::ctrl_c() => Err("interrupted".into()),
+            }
+        }
+        Err(e) => Err(format!("failed to listen on --metrics-listen: {e}").into()),
+    };
+    // 最後まで終わっても、失敗の多すぎたエンドポイントがあれば欠けたものとして扱う
+    let result = result.and_then(|()| fail_threshold.map_or(Ok(()), |x| metrics.check_failure_threshold(x).map_err(Into::into)));
+    if retries_report {
+        report_retries(&metrics);
+    }
+    if let Some(state) = pacer_state {
+        state.save(&pacer);
     }
+    if let Some((url, format)) = notify {
+        notify::send(&url, format, &Notification::new(&result, metrics.notes(), started.elapsed())).await;
+    }
+    status::finish(&result, always)
 }
 
-impl Debug for MisskeyAuthorizationToken {
-    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        f.debug_struct("MisskeyAuthorizationToken").field("value", &"*****").finish()
+/// `timeline`から組み立てる。チャンネルにしか関わらないものは既定のままにする。
+fn archive_options(global: &GlobalArgs, timeline: TimelineArgs, metrics: &Arc<Metrics>) -> Result<ArchiveOptions, Box<dyn Error + Send + Sync>> {
+    let TimelineArgs { before, after, dry_run, split_by, manifest, fetch_replies_to_archived, max_reply_depth, reply_checkpoint, emit_note_urls, inline_user_detail, output_layout, no_range_filter, direction, translate, force_range, hash_notes, hashes_output, partial_retries, partial_backoff, canonical, max_reactions_per_note, chunk_size, detect_language, append, append_index, emit_page_markers } = timeline;
+    if append_index.is_some() && global.output.as_deref().is_some_and(output::is_per_channel) {
+        return Err("--append-index holds the notes of a single file, so it cannot be used with a per-channel --output".into())
     }
+
+    Ok(ArchiveOptions {
+        before,
+        after,
+        cool_down: global.cool_down(),
+        dry_run,
+        fail_fast: false,
+        parallel_channels: NonZeroUsize::MIN,
+        output: OutputOptions {
+            split_by,
+            timezone: global.timezone.clone(),
+            atomic: !global.no_atomic,
+            timestamp_format: if canonical { TimestampFormat::Rfc3339 } else { global.timestamp_format },
+            layout: output_layout,
+            chunk_size,
+            sink: global.remote_sink()?,
+            canonical,
+            possibly_incomplete: vec![],
+            run: Run::current(),
+            field_map: global.field_map.clone().unwrap_or(FieldMap::EMPTY),
+            detect_language,
+            append,
+            append_index,
+        },
+        manifest,
+        reply_depth: fetch_replies_to_archived.then_some(max_reply_depth),
+        reply_checkpoint,
+        with_channel_info: false,
+        note_urls: global.host.clone().filter(|_| emit_note_urls),
+        inline_user_detail,
+        range_filter: !no_range_filter,
+        direction,
+        translate: translate.map(Translator::new),
+        metrics: Some(Arc::clone(metrics)),
+        max_missing_notes: None,
+        force_range,
+        hash_notes: hash_notes.then(|| HashLog::create(&hashes_output)).transpose()?.map(Arc::new),
+        partial_retries,
+        partial_backoff,
+        max_reactions: max_reactions_per_note,
+        detect_deletions: None,
+        check_hidden_users: false,
+        hidden_users: None,
+        page_markers: emit_page_markers,
+    })
 }
 
-impl FromStr for MisskeyAuthorizationToken {
-    type Err = Infallible;
+async fn fetch_notes(
+    global: &mut GlobalArgs,
+    metrics: &Arc<Metrics>,
+    pacer: &Pacer,
+    ids_from: &Path,
+    emit_note_urls: bool,
+    inline_user_detail: bool,
+    manifest: Option<PathBuf>,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let ids = archive::read_note_list(ids_from).map_err(|e| format!("failed to read {}: {e}", ids_from.display()))?;
+    let client = MeteredClient::new(AnyClient::new(global)?, Arc::clone(metrics)).with_budget(global.max_requests).with_deadline(global.max_duration);
+    let options = ArchiveOptions {
+        before: None,
+        after: None,
+        cool_down: global.cool_down(),
+        dry_run: false,
+        fail_fast: false,
+        parallel_channels: NonZeroUsize::MIN,
+        output: OutputOptions {
+            split_by: None,
+            timezone: global.timezone.clone(),
+            atomic: !global.no_atomic,
+            timestamp_format: global.timestamp_format,
+            layout: Layout::Lines,
+            chunk_size: chunk::DEFAULT_CHUNK_SIZE,
+            sink: global.remote_sink()?,
+            canonical: false,
+            possibly_incomplete: vec![],
+            run: Run::current(),
+            field_map: global.field_map.clone().unwrap_or(FieldMap::EMPTY),
+            detect_language: false,
+            append: false,
+            append_index: None,
+        },
+        manifest,
+        reply_depth: None,
+        reply_checkpoint: None,
+        with_channel_info: false,
+        note_urls: global.host.clone().filter(|_| emit_note_urls),
+        inline_user_detail,
+        range_filter: false,
+        direction: Direction::Backward,
+        translate: None,
+        metrics: Some(Arc::clone(metrics)),
+        max_missing_notes: None,
+        force_range: false,
+        hash_notes: None,
+        partial_retries: 0,
+        partial_backoff: Duration::ZERO,
+        max_reactions: None,
+        detect_deletions: None,
+        check_hidden_users: false,
+        hidden_users: None,
+        page_markers: false,
+    };
+    let result = archive::fetch_notes(&client, pacer, global.output.as_deref(), &ids, &options).await;
+    report_metrics(metrics, global.metrics_output.as_deref())?;
+
+    result
+}
 
-    fn from_str(s: &str) -> Result<Self, Self::Err> {
-        Ok(Self(s.to_owned()))
+async fn fetch_graph(
+    global: &mut GlobalArgs,
+    metrics: &Arc<Metrics>,
+    pacer: &Pacer,
+    relation: Relation,
+    mut users: Vec<UserRef>,
+    users_from: Option<&Path>,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    if let Some(path) = users_from {
+        users.extend(graph::read_participants(path)?.into_iter().map(UserRef::Id));
     }
+    let client = MeteredClient::new(AnyClient::new(global)?, Arc::clone(metrics)).with_budget(global.max_requests).with_deadline(global.max_duration);
+    let mut out = output::open(global.output.as_deref(), !global.no_atomic, global.remote_sink()?.as_ref())?;
+    let result = graph::fetch_edges(&client, pacer, &mut out, relation, users).await;
+    report_metrics(metrics, global.metrics_output.as_deref())?;
+    result?;
+    out.finish()?;
+
+    Ok(())
 }
 
-#[derive(Eq, PartialEq, Parser)]
-enum Args {
-    Archive {
-        #[clap(long)]
-        /// どこから遡るか。ない場合は実行時点の最新のノートから。
-        before: Option<NoteId>,
-        #[clap(long)]
-        /// どこまで遡るか。ない場合は実行時点の最古のノートまで。
-        after: Option<NoteId>,
-        #[clap(long)]
-        host: String,
-        #[clap(long)]
-        token: MisskeyAuthorizationToken,
-        #[clap(long)]
-        channel_id: ChannelId,
-        #[clap(long, long = "cool-down")]
-        /// リクエストの間隔をミリ秒で指定。
-        cool_down_millisecond: Option<NonZeroUsize>,
-    },
-    FetchUser {
-        #[clap(long)]
-        user: Vec<UserId>,
-        #[clap(long)]
-        host: String,
-        #[clap(long)]
-        token: MisskeyAuthorizationToken,
-        #[clap(long, long = "cool-down")]
-        /// リクエストの間隔をミリ秒で指定。
-        cool_down_millisecond: Option<NonZeroUsize>,
-    },
+async fn refresh(global: &mut GlobalArgs, metrics: &Arc<Metrics>, pacer: &Pacer, input: &Path, older_than: Duration) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let client = MeteredClient::new(AnyClient::new(global)?, Arc::clone(metrics)).with_budget(global.max_requests).with_deadline(global.max_duration);
+    let result = refresh::refresh(&client, pacer, input, global.output.as_deref(), older_than, &lines_output(global)?).await;
+    report_metrics(metrics, global.metrics_output.as_deref())?;
+
+    result
+}
+
+/// 振り分けずに`--output`へ1ページを1行として書く設定
+fn lines_output(global: &GlobalArgs) -> Result<OutputOptions, Box<dyn Error + Send + Sync>> {
+    Ok(OutputOptions {
+        split_by: None,
+        timezone: global.timezone.clone(),
+        atomic: !global.no_atomic,
+        timestamp_format: global.timestamp_format,
+        layout: Layout::Lines,
+        chunk_size: chunk::DEFAULT_CHUNK_SIZE,
+        sink: global.remote_sink()?,
+        canonical: false,
+        possibly_incomplete: vec![],
+        run: Run::current(),
+        field_map: global.field_map.clone().unwrap_or(FieldMap::EMPTY),
+        detect_language: false,
+        append: false,
+        append_index: None,
+    })
 }
 
-#[derive(Serialize)]
-struct ChannelTimelineCommand {
-    #[serde(rename = "channelId")]
-    channel_id: ChannelId,
-    limit: NonZeroUsize,
-    #[serde(skip_serializing_if = "Option::is_none", rename = "sinceId")]
-    note_after: Option<NoteId>,
-    #[serde(skip_serializing_if = "Option::is_none", rename = "untilId")]
-    note_before: Option<NoteId>,
-    #[serde(skip_serializing_if = "Option::is_none", rename = "sinceDate")]
-    date_after: Option<UnixDateTime>,
-    #[serde(skip_serializing_if = "Option::is_none", rename = "untilDate")]
-    date_before: Option<UnixDateTime>,
+async fn fetch_reactions(
+    global: &mut GlobalArgs,
+    metrics: &Arc<Metrics>,
+    pacer: &Pacer,
+    mut note_id: Vec<NoteId>,
+    notes_from: Option<&Path>,
+    filter: &ReactionFilter,
+    histogram: Option<Bucket>,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    if let Some(path) = notes_from {
+        note_id.extend(reactions::read_reacted_notes(path)?);
+    }
+    let client = MeteredClient::new(AnyClient::new(global)?, Arc::clone(metrics)).with_budget(global.max_requests).with_deadline(global.max_duration);
+    let mut out = output::open(global.output.as_deref(), !global.no_atomic, global.remote_sink()?.as_ref())?;
+    let histogram = histogram.map(|bucket| (bucket, &global.timezone));
+    let result = reactions::fetch_reactions(&client, pacer, &mut out, note_id, filter, histogram).await;
+    report_metrics(metrics, global.metrics_output.as_deref())?;
+    result?;
+    out.finish()?;
+
+    Ok(())
 }
 
-#[derive(Serialize)]
-struct WithTokenRef<'a, T> {
-    #[serde(rename = "i")]
-    token: &'a MisskeyAuthorizationToken,
-    #[serde(flatten)]
-    body: T,
+async fn fetch_renotes(
+    global: &mut GlobalArgs,
+    metrics: &Arc<Metrics>,
+    pacer: &Pacer,
+    mut note_id: Vec<NoteId>,
+    notes_from: Option<&Path>,
+    min_renote_count: NonZeroUsize,
+    checkpoint: Option<&Path>,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    if let Some(path) = notes_from {
+        note_id.extend(renotes::read_renoted_notes(path, min_renote_count)?);
+    }
+    let mut checkpoint = checkpoint.map(|path| Checkpoint::open(path).map_err(|e| format!("failed to open {}: {e}", path.display()))).transpose()?;
+    let client = MeteredClient::new(AnyClient::new(global)?, Arc::clone(metrics)).with_budget(global.max_requests).with_deadline(global.max_duration);
+    // 止まっても書いた分を残し、次の実行で書き足す
+    let mut out = if checkpoint.is_some() {
+        output::open_appending(global.output.as_deref(), global.remote_sink()?.as_ref())?
+    } else {
+        output::open(global.output.as_deref(), !global.no_atomic, global.remote_sink()?.as_ref())?
+    };
+    let result = renotes::fetch_renotes(&client, pacer, &mut out, note_id, checkpoint.as_mut()).await;
+    report_metrics(metrics, global.metrics_output.as_deref())?;
+    result?;
+    out.finish()?;
+
+    Ok(())
 }
 
-impl ChannelTimelineCommand {
-    async fn send(self, http_client: &Client, host: String, misskey_token: &MisskeyAuthorizationToken) -> Result<Vec<Note>, Box<dyn Error + Send + Sync>> {
-        let wtr = WithTokenRef {
-            token: misskey_token,
-            body: self,
+fn verify_manifest(global: &GlobalArgs, path: &Path) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let mut out = output::open(global.output.as_deref(), !global.no_atomic, global.remote_sink()?.as_ref())?;
+    let (problems, checked) = manifest::verify(path)?;
+    for problem in &problems {
+        let record = match problem {
+            Problem::Missing { path } => serde_json::json!({ "kind": "verify", "outcome": "missing", "path": path }),
+            Problem::Mismatch { path, expected, actual } => serde_json::json!({
+                "kind": "verify", "outcome": "mismatch", "path": path, "expected": expected, "actual": actual,
+            }),
         };
-        eprintln!("{}", serde_json::to_string(&wtr).unwrap());
-        let x = http_client.request(Method::POST, format!("https://{host}/api/channels/timeline"))
-            .json(&wtr)
-            .send()
-            .await?;
-        let status = x.status();
-        let text = x.text().await?;
-
-        let json = match serde_path_to_error::deserialize(&mut serde_json::de::Deserializer::from_str(&text)) {
-            Ok(x) => x,
-            Err(e) => {
-                eprintln!("ERROR: deserialize failed.");
-                eprintln!("raw: {text}", text = text);
-                eprintln!("status: {status}");
-                panic!("{e:?}");
-            }
+        writeln!(out, "{record}")?;
+    }
+    out.finish()?;
+
+    if !problems.is_empty() {
+        return Err(format!("{} of {checked} file(s) failed verification", problems.len()).into());
+    }
+
+    Ok(())
+}
+
+fn verify_notes(global: &GlobalArgs, input: &[PathBuf], hashes: Option<&Path>) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let detached = hashes.map(content_hash::read_hash_log).transpose()?.unwrap_or_default();
+    let mut notes = vec![];
+    for path in input {
+        notes.extend(reader::read_note_values(path)?.into_iter().map(|(_, note)| note));
+    }
+
+    let mut out = output::open(global.output.as_deref(), !global.no_atomic, global.remote_sink()?.as_ref())?;
+    let (problems, checked) = content_hash::verify(&notes, &detached);
+    for problem in &problems {
+        let record = match problem {
+            content_hash::Problem::Unhashed { note_id } => serde_json::json!({ "kind": "verify", "outcome": "unhashed", "note_id": note_id }),
+            content_hash::Problem::Mismatch { note_id, expected, actual } => serde_json::json!({
+                "kind": "verify", "outcome": "mismatch", "note_id": note_id, "expected": expected, "actual": actual,
+            }),
+            content_hash::Problem::DetachedMismatch { note_id, expected, actual } => serde_json::json!({
+                "kind": "verify", "outcome": "detached-mismatch", "note_id": note_id, "expected": expected, "actual": actual,
+            }),
         };
-        Ok(json)
-    }
-}
-
-#[derive(Eq, PartialEq, Ord, PartialOrd, Debug, Serialize)]
-struct UnixDateTime(u32);
-
-#[derive(Deserialize, Serialize)]
-struct Note {
-    id: NoteId,
-    #[serde(rename = "createdAt")]
-    created_at: DateTime<Utc>,
-    user: PartialUser,
-    /// 本文。RNなら[`None`]。QRNなら引用先の文。
-    text: Option<MisskeyFlavoredMarkdown>,
-    /// CWの折りたたみ時に表示されるテキスト
-    #[serde(rename = "cw")]
-    spoiler_disclaimer_text: Option<String>,
-    // visibility
-    #[serde(rename = "replyId")]
-    reply_to: Option<NoteId>,
-    #[serde(rename = "renoteId")]
-    renote_on: Option<NoteId>,
-    #[serde(rename = "renoteCount")]
-    renote_count: usize,
-    #[serde(rename = "repliesCount")]
-    reply_count: usize,
-    reactions: HashMap<CanonicalEmojiKey, NonZeroUsize>,
-}
-
-#[derive(Deserialize, Serialize)]
-struct PartialUser {
-    // NOTE: その他のプロパティを捨てているのは下流側の正規化が面倒になるため
-    id: UserId,
-}
-
-#[derive(Eq, PartialEq, Hash)]
-enum CanonicalEmojiKey {
-    SingleCodepointPunctuation(char),
-    BoxedSingleDigit {
-        digit: u8,
-    },
-    Unicode {
-        utf8: String,
-    },
-    Custom {
-        name: EmojiName,
-        host: LocalOnly,
-    },
-    Uncategorized(String),
-}
-
-impl<'de> Deserialize<'de> for CanonicalEmojiKey {
-    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error> where D: Deserializer<'de> {
-        let raw = String::deserialize(deserializer)?;
-        // ヒント: もしこれがエラーに見えているならIntelliJがおかしい
-        static PAT: Lazy<lazy_regex::Regex> = lazy_regex::lazy_regex!(r#"^:([a-z0-9_-]+)@\.:$"#);
-
-        if let Some(captures) = PAT.captures(&raw) {
-            let m = captures;
-            let name_range = m.get(1).expect("should be match").range();
-            // TODO: おそらくこの再アロケーションは避けられる
-            let name = EmojiName(raw[name_range].to_owned());
-
-            Ok(Self::Custom {
-                name,
-                host: LocalOnly,
-            })
-        } else if let Some(emoji) = emojis::iter().find(|x| x.as_str() == &raw) {
-            // 絵文字は単にUnicodeの「文字」であることもある
-            Ok(Self::Unicode {
-                utf8: emoji.to_string()
-            })
-        } else if raw.chars().next().expect("must not be empty").is_ascii_digit() && raw.chars().nth(1).expect("ow") == '\u{20e3}' {
-            Ok(Self::BoxedSingleDigit {
-                // Unicodeでは0-9は一列に並んでいるのでオフセットは引き算するだけで求められる
-                digit: u8::try_from(raw.chars().next().expect("1") as u32 - '0' as u32).expect("oops"),
-            })
-        } else {
-            Ok(Self::Uncategorized(raw))
-        }
-    }
-}
-
-impl Serialize for CanonicalEmojiKey {
-    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error> where S: Serializer {
-        match self {
-            Self::Unicode { utf8 } => {
-                serializer.serialize_str(utf8)
-            }
-            Self::Custom { name, .. } => {
-                let s = format!(":{}@.:", name.0);
-                serializer.serialize_str(&s)
-            }
-            Self::SingleCodepointPunctuation(c) => {
-                serializer.serialize_char(*c)
-            }
-            Self::BoxedSingleDigit { digit } => {
-                serializer.serialize_str(&format!("{digit}\u{20e3}"))
-            }
-            Self::Uncategorized(s) => {
-                serializer.serialize_str(&s)
-            }
-        }
+        writeln!(out, "{record}")?;
     }
+    out.finish()?;
+
+    if !problems.is_empty() {
+        return Err(format!("{} problem(s) found in {checked} note(s)", problems.len()).into());
+    }
+
+    Ok(())
 }
 
-#[derive(Eq, PartialEq, Hash)]
-struct EmojiName(String);
+fn verify_pages(global: &GlobalArgs, input: &Path) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let (problems, pages) = page_markers::verify(input)?;
+    let mut out = output::open(global.output.as_deref(), !global.no_atomic, global.remote_sink()?.as_ref())?;
+    for problem in &problems {
+        writeln!(out, "{}", problem.record())?;
+    }
+    out.finish()?;
 
-#[derive(Eq, PartialEq, Hash)]
-struct LocalOnly;
+    if !problems.is_empty() {
+        return Err(format!("{} problem(s) found in {pages} page(s)", problems.len()).into());
+    }
 
-#[derive(Deserialize, Serialize)]
-struct MisskeyFlavoredMarkdown(String);
+    Ok(())
+}
 
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn Error + Send + Sync>>{
-    let arg = Args::parse();
-    let client = Client::builder().gzip(true).deflate(true).brotli(true)
-        .use_rustls_tls()
-        .build()
-        .expect("panic");
-
-    match arg {
-        Args::Archive { before, after, host, token, channel_id, cool_down_millisecond } => {
-            let mut last_note = None;
-
-            let mut users = HashSet::with_capacity(100);
-
-            loop {
-                let send = ChannelTimelineCommand {
-                    channel_id: channel_id.clone(),
-                    limit: 60.try_into().unwrap(),
-                    note_after: after.clone(),
-                    note_before: last_note.clone(),
-                    date_after: None,
-                    date_before: None,
-                };
-
-                let result = send.send(&client, host.clone(), &token).await?;
-
-                if result.is_empty() {
-                    break
-                }
-
-                last_note = result.iter().min_by_key(|x| x.created_at).map(|x| x.id.clone());
-                println!(r#"{{ "kind": "log", "message": "proceeded by {last_note}"}}"#, last_note = last_note.clone().expect("must be Some").0);
-                println!("{}", serde_json::to_string(&result)?);
-                users.extend(result.into_iter().map(|n| n.user.id));
-
-                let sleep_sec = cool_down_millisecond.map(|x| x.get() / 1000).unwrap_or(0) as u64;
-                let sleep_nano = cool_down_millisecond.map(|x| x.get() as u64 - sleep_sec * 1000).unwrap_or(0) as u32 * 1_000_000;
-                println!(r#"{{ "kind": "log", "message": "sleep" }}"#);
-                sleep(Duration::new(sleep_sec, sleep_nano)).await;
-            }
+fn write_report(
+    global: &GlobalArgs,
+    input: &[PathBuf],
+    users: Option<&Path>,
+    period: Option<Period>,
+    markdown: Option<&Path>,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let mut notes = vec![];
+    for path in input {
+        notes.extend(reader::read_notes(path)?);
+    }
+    let users = users.map(report::read_users).transpose()?.unwrap_or_default();
+    let report = report::build(&notes, &users, period, &global.timezone, Utc::now());
+
+    // 1つの文書なので、行ごとの記録として送らない
+    let mut out = output::open(global.output.as_deref(), !global.no_atomic, None)?;
+    writeln!(out, "{}", serde_json::to_string_pretty(&report)?)?;
+    out.finish()?;
+    if let Some(path) = markdown {
+        let mut file = output::RecordFile::create(path, !global.no_atomic)?;
+        file.write_all(report::to_markdown(&report).as_bytes())?;
+        file.finish()?;
+    }
+
+    Ok(())
+}
+
+fn leaderboard(
+    global: &GlobalArgs,
+    input: &[PathBuf],
+    users: Option<&Path>,
+    top: NonZeroUsize,
+    period: Option<Period>,
+    weights: Option<&Path>,
+    markdown: Option<&Path>,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let notes = reader::read_all_notes(input)?;
+    let authors = users.map(leaderboard::read_authors).transpose()?.unwrap_or_default();
+    let weights = match weights {
+        Some(path) => {
+            let text = fs::read_to_string(path).map_err(|e| format!("failed to read {}: {e}", path.display()))?;
+            leaderboard::parse_weights(&text).map_err(|e| format!("{}: {e}", path.display()))?
         }
-        Args::FetchUser { user, host, token, cool_down_millisecond } => {
-            let users = user;
-            let mut user_info = Vec::with_capacity(users.len());
+        None => HashMap::new(),
+    };
+    let leaderboard = leaderboard::build(&notes, &authors, &weights, period, &global.timezone, top.get(), global.host.as_ref());
+
+    // 1つの文書なので、行ごとの記録として送らない
+    let mut out = output::open(global.output.as_deref(), !global.no_atomic, None)?;
+    writeln!(out, "{}", serde_json::to_string_pretty(&leaderboard)?)?;
+    out.finish()?;
+    if let Some(path) = markdown {
+        let mut file = output::RecordFile::create(path, !global.no_atomic)?;
+        file.write_all(leaderboard::to_markdown(&leaderboard).as_bytes())?;
+        file.finish()?;
+    }
+
+    Ok(())
+}
 
-            for user_id in users {
-                let command = UserDetailCommand {
-                    id: user_id
-                };
+fn schema(global: &GlobalArgs, record: Option<RecordKind>, format: SchemaFormat) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let mut out = output::open(global.output.as_deref(), !global.no_atomic, None)?;
+    match format {
+        SchemaFormat::JsonSchema => writeln!(out, "{}", serde_json::to_string_pretty(&schema::document(record))?)?,
+        SchemaFormat::Typescript => out.write_all(schema::typescript(record).as_bytes())?,
+    }
+    out.finish()?;
 
-                let result = command.send(&client, host.clone(), &token).await?;
+    Ok(())
+}
 
-                println!("{}", serde_json::to_string(&result)?);
+fn user_activity(
+    global: &GlobalArgs,
+    input: &[PathBuf],
+    mut users: Vec<UserId>,
+    users_from: Option<&Path>,
+    markdown: Option<&Path>,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    if let Some(path) = users_from {
+        users.extend(activity::read_user_list(path).map_err(|e| format!("failed to read {}: {e}", path.display()))?);
+    }
+    let mut activity = Activity::new(users);
+    for path in activity::expand_inputs(input)? {
+        activity.read(&path)?;
+    }
 
-                user_info.push(result);
-                let sleep_sec = cool_down_millisecond.map(|x| x.get() / 1000).unwrap_or(0) as u64;
-                let sleep_nano = cool_down_millisecond.map(|x| x.get() as u64 - sleep_sec * 1000).unwrap_or(0) as u32 * 1_000_000;
-                println!(r#"{{ "kind": "log", "message": "sleep" }}"#);
-                sleep(Duration::new(sleep_sec, sleep_nano)).await;
-            }
-        }
+    let mut out = output::open(global.output.as_deref(), !global.no_atomic, global.remote_sink()?.as_ref())?;
+    for record in activity.records() {
+        writeln!(out, "{record}")?;
+    }
+    out.finish()?;
+    if let Some(path) = markdown {
+        let mut file = output::RecordFile::create(path, !global.no_atomic)?;
+        file.write_all(activity.to_markdown().as_bytes())?;
+        file.finish()?;
     }
 
     Ok(())
 }
 
-#[derive(Eq, PartialEq, Serialize)]
-struct UserDetailCommand {
-    #[serde(rename = "userId")]
-    id: UserId,
-}
-
-#[derive(Serialize, Deserialize)]
-struct DetailedUser {
-    id: UserId,
-    #[serde(rename = "name")]
-    /// スクリーンネームを設定していない場合は[`None`]。その場合、見える文字列はmentionであるべき。
-    screen_name: Option<String>,
-    #[serde(rename = "username")]
-    mention: String,
-    #[serde(rename = "isBot")]
-    is_bot: bool,
-    #[serde(rename = "isCat")]
-    is_cat: bool,
-    #[serde(rename = "avatarUrl")]
-    /// 現在のアイコンのURL
-    icon_url: Url,
-    #[serde(rename = "notesCount")]
-    total_notes: usize,
-}
-
-impl UserDetailCommand {
-    async fn send(self, http_client: &Client, host: String, misskey_token: &MisskeyAuthorizationToken) -> Result<DetailedUser, Box<dyn Error + Send + Sync>> {
-        let wtr = WithTokenRef {
-            token: misskey_token,
-            body: self,
-        };
-        eprintln!("{}", serde_json::to_string(&wtr).unwrap());
-        let x = http_client.request(Method::POST, format!("https://{host}/api/users/show"))
-            .json(&wtr)
-            .send()
-            .await?;
-        let status = x.status();
-        let text = x.text().await?;
-
-        let json = match serde_path_to_error::deserialize(&mut serde_json::de::Deserializer::from_str(&text)) {
-            Ok(x) => x,
-            Err(e) => {
-                eprintln!("ERROR: deserialize failed.");
-                eprintln!("raw: {text}", text = text);
-                eprintln!("status: {status}");
-                panic!("{e:?}");
-            }
-        };
-        Ok(json)
+fn import(global: &GlobalArgs, from: ImportFormat, input: &Path, user: &UserId) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let ImportFormat::MisskeyExport = from;
+    let text = fs::read_to_string(input).map_err(|e| format!("failed to read {}: {e}", input.display()))?;
+    let export = serde_json::from_str(&text).map_err(|e| format!("{}: {e}", input.display()))?;
+    let notes = import::from_misskey_export(export, user).map_err(|e| format!("{}: {e}", input.display()))?;
+
+    let mut out = Destination::open(global.output.as_deref(), &lines_output(global)?)?;
+    for page in notes.chunks(PAGE_SIZE.get()) {
+        out.write_page(page)?;
+    }
+    out.finish()?;
+    info!("{}", msg("import-summary", &[("notes", &notes.len()), ("input", &input.display())]));
+
+    Ok(())
+}
+
+fn export(global: &GlobalArgs, input: &[PathBuf], format: ExportFormat, users: Option<&Path>, layout: Layout, text: TextArgs) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let host = global.host.as_ref().expect("checked by usage::check");
+    let notes = reader::read_all_notes(input)?;
+    let usernames = users.map(report::read_users).transpose()?.unwrap_or_default();
+    let ExportFormat::Activitystreams = format;
+    let text = TextOptions { mfm: text.mfm, emoji: text.emoji_style, emoji_dir: text.emoji_dir, linkify: text.linkify };
+    let exporter = Exporter { host, usernames: &usernames, text: &text };
+
+    if layout == Layout::Tree {
+        let dir = global.output.as_deref().expect("checked by usage::check");
+        let files = exporter.write_tree(dir, &notes, !global.no_atomic)?;
+        info!("{}", msg("export-summary", &[("files", &files.len()), ("dir", &dir.display())]));
+        return Ok(())
     }
+    // 1つの文書なので、行ごとの記録として送らない
+    let mut out = output::open(global.output.as_deref(), !global.no_atomic, None)?;
+    writeln!(out, "{}", serde_json::to_string_pretty(&exporter.collection(&notes))?)?;
+    out.finish()?;
+
+    Ok(())
+}
+
+/// MiAuthで承認してもらい、発行されたトークンを標準出力に書く。
+async fn authorize(global: &mut GlobalArgs, name: &str, wait_second: NonZeroU64) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let token = issue_token(global, name, wait_second).await?;
+    println!("{}", token.expose());
+
+    Ok(())
+}
+
+/// MiAuthでトークンを発行する。承認するURLは標準エラー出力に書く
+async fn issue_token(global: &mut GlobalArgs, name: &str, wait_second: NonZeroU64) -> Result<MisskeyAuthorizationToken, Box<dyn Error + Send + Sync>> {
+    let client = AnyClient::new(global)?;
+    let session = miauth::session_id()?;
+    let url = miauth::authorization_url(global.host.as_ref().expect("checked by usage::check"), &session, name);
+    eprintln!("open this URL in a browser and approve the request:");
+    eprintln!("{url}");
+
+    let (token, user) = tokio::select! {
+        approved = miauth::wait_for_approval(&client, &session, Duration::from_secs(wait_second.get())) => approved?,
+        _ = tokio::signal::ctrl_c() => return Err("cancelled".into()),
+    };
+    if let Some(user) = user {
+        info!("authorized as @{}", user.username);
+    }
+
+    Ok(token)
+}
+
+/// `init`。フラグで渡されなかったものだけを尋ねる
+async fn init(
+    global: &mut GlobalArgs,
+    pacer: &Pacer,
+    miauth: bool,
+    channel_id: Vec<ChannelId>,
+    profile_dir: &Path,
+    wait_second: NonZeroU64,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let mut prompt = init::Terminal;
+    if global.host.is_none() {
+        global.host = Some(init::ask_host(&mut prompt)?);
+    }
+    let token = match global.resolve_token()? {
+        Some(token) => token,
+        None => match if miauth { None } else { init::ask_token(&mut prompt)? } {
+            Some(token) => token,
+            None => issue_token(global, "misskey-channel-archiver", wait_second).await?,
+        },
+    };
+
+    global.token = Some(MisskeyAuthorizationToken::new(token.expose().to_owned()));
+    let client = AnyClient::new(global)?;
+    pacer.wait().await;
+    let account = authenticate(&client).await?;
+    info!("authenticated as @{}", account.username);
+    if channel_id.is_empty() {
+        preflight::check_permissions(&client, pacer, "init", &["channels/owned", "channels/followed"]).await?;
+    }
+    let channels = init::select_channels(&client, pacer, &mut prompt, channel_id).await?;
+    let host = global.host.as_ref().expect("asked above");
+    let command = init::write_profile(profile_dir, host, &token, &channels)?;
+    info!("wrote the token and {} channel(s) to {}; start archiving with:", channels.len(), profile_dir.display());
+    println!("{command}");
+
+    Ok(())
+}
+
+async fn run(mut cli: Cli, metrics: Arc<Metrics>, pacer: Arc<Pacer>) -> Result<(), Box<dyn Error + Send + Sync>> {
+    match cli.cmd {
+        Command::Archive { mut channel_id, channels_from, fail_fast, parallel_channels, with_channel_info, max_missing_notes, detect_deletions, deletion_sample, verify_deletions_all, ignore_mutes_warning, timeline } => {
+            channel_id.extend(channels_from.as_deref().map(archive::read_channel_list).transpose()?.unwrap_or_default());
+            let client = Arc::new(MeteredClient::new(AnyClient::new(&mut cli.global)?, Arc::clone(&metrics)).with_budget(cli.global.max_requests).with_deadline(cli.global.max_duration));
+            let options = ArchiveOptions {
+                fail_fast,
+                parallel_channels,
+                with_channel_info,
+                max_missing_notes,
+                detect_deletions: detect_deletions.map(|x| DeletionCheck::read(&x, (!verify_deletions_all).then_some(deletion_sample))).transpose()?.map(Arc::new),
+                check_hidden_users: !ignore_mutes_warning,
+                ..archive_options(&cli.global, timeline, &metrics)?
+            };
+            let result = archive::archive(&client, &pacer, cli.global.output.as_deref(), &channel_id, &options).await;
+            report_metrics(&metrics, cli.global.metrics_output.as_deref())?;
+            result?;
+        }
+        Command::ArchiveList { list_id, timeline } => {
+            let client = MeteredClient::new(AnyClient::new(&mut cli.global)?, Arc::clone(&metrics)).with_budget(cli.global.max_requests).with_deadline(cli.global.max_duration);
+            let options = archive_options(&cli.global, timeline, &metrics)?;
+            let result = archive::archive_list(&client, &pacer, cli.global.output.as_deref(), &list_id, &options).await;
+            report_metrics(&metrics, cli.global.metrics_output.as_deref())?;
+            result?;
+        }
+        Command::Backfill { input, timeline } => {
+            let gaps = archive::read_gaps(&input)?;
+            let client = MeteredClient::new(AnyClient::new(&mut cli.global)?, Arc::clone(&metrics)).with_budget(cli.global.max_requests).with_deadline(cli.global.max_duration);
+            let options = archive_options(&cli.global, timeline, &metrics)?;
+            let result = archive::backfill(&client, &pacer, cli.global.output.as_deref(), &gaps, &options).await;
+            report_metrics(&metrics, cli.global.metrics_output.as_deref())?;
+            result?;
+        }
+        Command::FetchNotes { ids_from, emit_note_urls, inline_user_detail, manifest } => {
+            fetch_notes(&mut cli.global, &metrics, &pacer, &ids_from, emit_note_urls, inline_user_detail, manifest).await?;
+        }
+        Command::Refresh { input, older_than } => {
+            refresh(&mut cli.global, &metrics, &pacer, &input, older_than).await?;
+        }
+        Command::FetchUser { user, user_cache, user_cache_ttl, output_layout, append, with_pinned_notes, archived } => {
+            let cache = user_cache.map(|path| (path, user_cache_ttl));
+            let archived = archived.map(|path| reader::read_notes(&path)).transpose()?.unwrap_or_default();
+            let pinned = with_pinned_notes.then(|| PinnedNotes::new(archived, cli.global.timestamp_format));
+            fetch_user(&mut cli.global, &metrics, &pacer, user, cache, output_layout, append, pinned).await?;
+        }
+        Command::FetchFollowers { user, users_from } => {
+            fetch_graph(&mut cli.global, &metrics, &pacer, Relation::Followers, user, users_from.as_deref()).await?;
+        }
+        Command::FetchFollowing { user, users_from } => {
+            fetch_graph(&mut cli.global, &metrics, &pacer, Relation::Following, user, users_from.as_deref()).await?;
+        }
+        Command::FetchReactions { note_id, notes_from, since, until, histogram } => {
+            fetch_reactions(&mut cli.global, &metrics, &pacer, note_id, notes_from.as_deref(), &ReactionFilter { since, until }, histogram).await?;
+        }
+        Command::FetchRenotes { note_id, notes_from, min_renote_count, checkpoint } => {
+            fetch_renotes(&mut cli.global, &metrics, &pacer, note_id, notes_from.as_deref(), min_renote_count, checkpoint.as_deref()).await?;
+        }
+        Command::SnapshotFeatured => snapshot_featured(&mut cli.global, &metrics, &pacer).await?,
+        Command::EstimateFiles { channel_id, input, largest, sample } => {
+            estimate_files(&mut cli.global, &metrics, &pacer, channel_id, input.as_deref(), largest, sample).await?;
+        }
+        Command::Explore { channel_id, granularity } => explore_density(&mut cli.global, &metrics, &pacer, channel_id, granularity).await?,
+        #[cfg(feature = "keyring")]
+        Command::Auth { action: Some(cli::AuthAction::Store { entry }), .. } => {
+            let token = cli.global.resolve_token()?.ok_or("one of --token, --token-file or --token-env is required")?;
+            keyring::store(&entry, &token)?;
+            info!("stored token in keyring entry {entry}");
+        }
+        Command::Auth { name, wait_second, .. } => {
+            authorize(&mut cli.global, &name, wait_second).await?;
+        }
+        Command::Init { miauth, channel_id, profile_dir, wait_second } => init(&mut cli.global, &pacer, miauth, channel_id, &profile_dir, wait_second).await?,
+        Command::Report { input, users, period, markdown } => {
+            write_report(&cli.global, &input, users.as_deref(), period, markdown.as_deref())?;
+        }
+        Command::Leaderboard { input, users, top, period, weights, markdown } => {
+            leaderboard(&cli.global, &input, users.as_deref(), top, period, weights.as_deref(), markdown.as_deref())?;
+        }
+        Command::UserActivity { input, user, users_from, markdown } => {
+            user_activity(&cli.global, &input, user, users_from.as_deref(), markdown.as_deref())?;
+        }
+        Command::Import { from, input, user } => {
+            import(&cli.global, from, &input, &user)?;
+        }
+        Command::Export { input, format, users, output_layout, text } => {
+            export(&cli.global, &input, format, users.as_deref(), output_layout, text)?;
+        }
+        Command::VerifyManifest { path } => {
+            verify_manifest(&cli.global, &path)?;
+        }
+        Command::VerifyNotes { input, hashes } => {
+            verify_notes(&cli.global, &input, hashes.as_deref())?;
+        }
+        Command::VerifyPages { input } => verify_pages(&cli.global, &input)?,
+        Command::Schema { record, format } => schema(&cli.global, record, format)?,
+        Command::Generate { target, out_dir } => generate::generate(target, out_dir.as_deref())?,
+    }
+
+    Ok(())
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::MisskeyAuthorizationToken;
+    use std::num::NonZeroU32;
+    use std::time::Duration;
+
+    use serde_json::json;
+
+    use crate::api::{MisskeyAuthorizationToken, ResponseHeaders};
+    use crate::capture::{Exchange, ReplayClient};
+    use crate::fetch_users;
+    use crate::field_map::Renames;
+    use crate::metrics::Metrics;
+    use crate::model::UserId;
+    use crate::output::UserOutput;
+    use crate::pacer::Pacer;
+    use crate::schema;
+    use crate::testing::{capture_dir, exchange};
 
     #[test]
     fn do_not_leak_token_from_debug_impl() {
         const TOKEN: &str = "sometokenhere";
-        let token = MisskeyAuthorizationToken(TOKEN.to_string());
+        let token = MisskeyAuthorizationToken::new(TOKEN.to_string());
         let debug_str = format!("{token:?}");
 
         assert!(!debug_str.contains(TOKEN));
     }
-}
\ No newline at end of file
+
+    #[tokio::test]
+    async fn suspended_users_are_kept_and_deleted_ones_become_tombstones() {
+        // Misskeyの`users/show`が凍結されたユーザーに返すもの
+        let suspended = json!({
+            "id": "9xyz", "name": "Spam", "username": "spam", "host": null, "avatarUrl": "https://misskey.example/identicon/9xyz",
+            "avatarBlurhash": null, "isBot": false, "isCat": false, "emojis": {}, "onlineStatus": "unknown",
+            "notesCount": 120, "followersCount": 0, "followingCount": 300, "pinnedNoteIds": [], "pinnedNotes": [],
+            "isLocked": false, "isSilenced": false, "isSuspended": true, "securityKeys": false,
+        });
+        let dir = capture_dir("fetch-users-moderation", &[
+            exchange("users/show", json!({ "userId": "9xyz" }), &suspended),
+            Exchange {
+                endpoint: "users/show".to_owned(),
+                request: json!({ "userId": "9gone" }),
+                status: 400,
+                response: json!({ "error": { "message": "No such user.", "code": "NO_SUCH_USER", "id": "4362f8dc-731f-4ad8-a694-be5a88922a24" } }).to_string(),
+                headers: ResponseHeaders::default(),
+            },
+        ]);
+        let client = ReplayClient::open(&dir).unwrap();
+        let pacer = Pacer::with_burst(Duration::ZERO, NonZeroU32::MIN);
+        let mut out = vec![];
+
+        let users = vec![UserId("9xyz".to_owned()), UserId("9gone".to_owned())];
+        fetch_users(&client, &pacer, &mut out, &mut UserOutput::Lines, &Renames::default(), users, None, None, &Metrics::default()).await.unwrap();
+
+        let records: Vec<serde_json::Value> = String::from_utf8(out).unwrap().lines().map(|x| serde_json::from_str(x).unwrap()).collect();
+        assert_eq!((&records[0]["isSuspended"], &records[0]["isLocked"], records[0].get("isDeleted")), (&json!(true), &json!(false), None));
+        assert_eq!((&records[1]["kind"], &records[1]["user_id"]), (&json!("user-tombstone"), &json!("9gone")));
+        let document = schema::document(None);
+        for record in &records {
+            schema::validate(&document, &document, record, "user").unwrap();
+        }
+    }
+}
@@ -0,0 +1,150 @@
+//! 書き出したファイルのSHA-256を記録し、後から壊れていないか確かめる。
+
+use std::error::Error;
+use std::fmt::Write as _;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+
+use ring::digest::{Context, SHA256};
+use serde::{Deserialize, Serialize};
+use url::Url;
+
+use crate::model::NoteId;
+use crate::output::RecordFile;
+
+#[derive(Serialize, Deserialize)]
+pub struct Manifest {
+    /// その実行についての情報
+    pub meta: serde_json::Value,
+    pub files: Vec<Entry>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Entry {
+    /// マニフェストのあるディレクトリからの相対パス。そこに無いファイルは絶対パス。
+    pub path: PathBuf,
+    pub size: u64,
+    pub sha256: String,
+    /// 添付ファイルなら、どこから取得したか
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source_url: Option<Url>,
+    /// 添付ファイルなら、どのノートのものか
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub note_id: Option<NoteId>,
+}
+
+/// 確かめた結果、問題があったファイル
+pub enum Problem {
+    Missing { path: PathBuf },
+    Mismatch { path: PathBuf, expected: String, actual: String },
+}
+
+/// ファイル全体を読み込まずに、大きさとSHA-256を求める。
+pub fn hash_file(path: &Path) -> io::Result<(u64, String)> {
+    let mut file = File::open(path)?;
+    let mut context = Context::new(&SHA256);
+    let mut buffer = vec![0; 64 * 1024];
+    let mut size = 0;
+    loop {
+        let read = file.read(&mut buffer)?;
+        if read == 0 {
+            break
+        }
+        context.update(&buffer[..read]);
+        size += read as u64;
+    }
+
+    Ok((size, to_hex(context.finish().as_ref())))
+}
+
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().fold(String::new(), |mut hex, b| {
+        let _ = write!(hex, "{b:02x}");
+        hex
+    })
+}
+
+fn base_of(manifest: &Path) -> PathBuf {
+    manifest.parent().filter(|p| !p.as_os_str().is_empty()).unwrap_or_else(|| Path::new(".")).to_path_buf()
+}
+
+/// `files`のハッシュを求めて、`manifest`に書き出す。
+pub fn write(manifest: &Path, meta: serde_json::Value, files: &[PathBuf]) -> io::Result<()> {
+    let base = fs::canonicalize(base_of(manifest))?;
+    let files = files.iter().map(|path| {
+        let (size, sha256) = hash_file(path)?;
+        let absolute = fs::canonicalize(path)?;
+        let path = absolute.strip_prefix(&base).map_or_else(|_| absolute.clone(), Path::to_path_buf);
+
+        Ok(Entry { path, size, sha256, source_url: None, note_id: None })
+    }).collect::<io::Result<_>>()?;
+
+    let mut out = RecordFile::create(manifest, true)?;
+    serde_json::to_writer_pretty(&mut out, &Manifest { meta, files })?;
+    writeln!(out)?;
+    out.finish()?;
+
+    Ok(())
+}
+
+/// `manifest`に書かれた全てのファイルのハッシュを求め直す。問題のあったファイルと、確かめたファイルの数を返す。
+pub fn verify(manifest: &Path) -> Result<(Vec<Problem>, usize), Box<dyn Error + Send + Sync>> {
+    let content = fs::read_to_string(manifest).map_err(|e| format!("failed to read manifest {}: {e}", manifest.display()))?;
+    let manifest_content: Manifest = serde_json::from_str(&content)?;
+    let base = base_of(manifest);
+
+    let mut problems = vec![];
+    for entry in &manifest_content.files {
+        let path = base.join(&entry.path);
+        if !path.exists() {
+            problems.push(Problem::Missing { path });
+            continue
+        }
+
+        let (size, actual) = hash_file(&path)?;
+        if size != entry.size || actual != entry.sha256 {
+            problems.push(Problem::Mismatch { path, expected: entry.sha256.clone(), actual });
+        }
+    }
+
+    Ok((problems, manifest_content.files.len()))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use crate::manifest::{hash_file, verify, write, Problem};
+    use crate::testing::capture_dir;
+
+    #[test]
+    fn hash_matches_known_digest() {
+        let dir = capture_dir("manifest-hash", &[]);
+        fs::write(dir.join("abc"), "abc").unwrap();
+
+        assert_eq!(
+            hash_file(&dir.join("abc")).unwrap(),
+            (3, "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad".to_owned()),
+        );
+    }
+
+    #[test]
+    fn verify_reports_missing_and_modified_files() {
+        let dir = capture_dir("manifest-verify", &[]);
+        for name in ["a.jsonl", "b.jsonl", "c.jsonl"] {
+            fs::write(dir.join(name), name).unwrap();
+        }
+        let manifest = dir.join("manifest.json");
+        write(&manifest, serde_json::json!({}), &["a.jsonl", "b.jsonl", "c.jsonl"].map(|n| dir.join(n))).unwrap();
+        assert!(fs::read_to_string(&manifest).unwrap().contains(r#""path": "a.jsonl""#));
+        assert!(verify(&manifest).unwrap().0.is_empty());
+
+        fs::write(dir.join("b.jsonl"), "bit rot").unwrap();
+        fs::remove_file(dir.join("c.jsonl")).unwrap();
+        let (problems, checked) = verify(&manifest).unwrap();
+
+        assert_eq!(checked, 3);
+        assert!(matches!(&problems[..], [Problem::Mismatch { path: b, .. }, Problem::Missing { path: c }] if b.ends_with("b.jsonl") && c.ends_with("c.jsonl")));
+    }
+}
@@ -0,0 +1,502 @@
+//! インスタンスにどれだけの負荷をかけたかを数える。
+
+use std::collections::{BTreeMap, HashMap};
+use std::error::Error;
+use std::fmt::{Display, Formatter, Write as _};
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::num::NonZeroU64;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+use crate::api::{ApiClient, RawResponse};
+use crate::i18n::{Lang, Message};
+use crate::log::debug;
+
+#[derive(Default)]
+pub struct Metrics {
+    /// エンドポイントごとのリクエスト数
+    requests: Mutex<BTreeMap<String, u64>>,
+    /// エンドポイントごとの、応答が返るまでの時間
+    latencies: Mutex<BTreeMap<String, Vec<Duration>>>,
+    /// 展開した後のレスポンスの大きさの合計
+    response_bytes: AtomicU64,
+    cool_down_nanos: AtomicU64,
+    user_cache_hits: AtomicU64,
+    user_cache_misses: AtomicU64,
+    /// 書き出したノートの数
+    notes: AtomicU64,
+    /// エンドポイントごとの、成功と失敗の数
+    outcomes: Mutex<BTreeMap<String, Outcomes>>,
+}
+
+/// 1つのエンドポイントへのリクエストの結果
+#[derive(Default)]
+struct Outcomes {
+    /// 失敗でなかったもの。`NO_SUCH_NOTE`のような4xxも、サーバーが答えたので含める
+    successes: u64,
+    /// 後で同じエンドポイントへのリクエストが成功した失敗
+    retried_failures: u64,
+    /// 最後の成功より後の失敗。実行の終わりには、頼み直しても取れなかったものになる
+    unresolved_failures: u64,
+}
+
+impl Metrics {
+    pub fn record_request(&self, endpoint: &str, response_bytes: usize) {
+        *self.requests.lock().expect("poisoned").entry(endpoint.to_owned()).or_default() += 1;
+        self.response_bytes.fetch_add(response_bytes as u64, Ordering::Relaxed);
+    }
+
+    /// 接続できなかったか、429か5xxを失敗とする。
+    pub fn record_outcome(&self, endpoint: &str, failed: bool) {
+        let mut outcomes = self.outcomes.lock().expect("poisoned");
+        let outcome = outcomes.entry(endpoint.to_owned()).or_default();
+        if failed {
+            outcome.unresolved_failures += 1;
+        } else {
+            outcome.successes += 1;
+            outcome.retried_failures += std::mem::take(&mut outcome.unresolved_failures);
+        }
+        drop(outcomes);
+    }
+
+    pub fn record_latency(&self, endpoint: &str, elapsed: Duration) {
+        self.latencies.lock().expect("poisoned").entry(endpoint.to_owned()).or_default().push(elapsed);
+    }
+
+    pub fn record_cool_down(&self, slept: Duration) {
+        self.cool_down_nanos.fetch_add(u64::try_from(slept.as_nanos()).unwrap_or(u64::MAX), Ordering::Relaxed);
+    }
+
+    pub fn record_user_cache(&self, hit: bool) {
+        let counter = if hit { &self.user_cache_hits } else { &self.user_cache_misses };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_notes(&self, notes: usize) {
+        self.notes.fetch_add(notes as u64, Ordering::Relaxed);
+    }
+
+    pub fn notes(&self) -> u64 {
+        self.notes.load(Ordering::Relaxed)
+    }
+
+    /// エンドポイントごとの、試した数、成功、頼み直して取れた失敗、取れなかった失敗と、最も遅かった応答
+    fn endpoints(&self) -> BTreeMap<String, serde_json::Value> {
+        let requests = self.requests.lock().expect("poisoned");
+        let outcomes = self.outcomes.lock().expect("poisoned");
+        let latencies = self.latencies.lock().expect("poisoned");
+        outcomes.iter().map(|(endpoint, x)| (endpoint.clone(), serde_json::json!({
+            "attempts": requests.get(endpoint).copied().unwrap_or(0),
+            "successes": x.successes,
+            "retried_failures": x.retried_failures,
+            "permanent_failures": x.unresolved_failures,
+            "max_latency_ms": latencies.get(endpoint).and_then(|x| x.iter().max()).map(Duration::as_millis),
+        }))).collect()
+    }
+
+    /// 取れなかった失敗の割合が`percent`を超えたエンドポイントのうち、最も割合の大きいもの
+    #[allow(clippy::cast_precision_loss)]
+    pub fn check_failure_threshold(&self, percent: u8) -> Result<(), FailThreshold> {
+        let worst = self.outcomes.lock().expect("poisoned").iter()
+            .map(|(endpoint, x)| {
+                let attempts = x.successes + x.retried_failures + x.unresolved_failures;
+                (endpoint.clone(), x.unresolved_failures, attempts, x.unresolved_failures as f64 * 100.0 / attempts as f64)
+            })
+            .filter(|x| x.3 > f64::from(percent))
+            .max_by(|a, b| a.3.total_cmp(&b.3));
+
+        worst.map_or(Ok(()), |(endpoint, failures, attempts, _)| Err(FailThreshold {
+            endpoint,
+            failures,
+            attempts,
+            threshold: percent,
+        }))
+    }
+
+    /// `--retries-report`で標準エラー出力に書く表
+    pub fn retries_report(&self) -> String {
+        let mut text = String::from("| Endpoint | Attempts | Successes | Retried failures | Permanent failures | Max latency (ms) |\n| --- | ---: | ---: | ---: | ---: | ---: |\n");
+        for (endpoint, x) in self.endpoints() {
+            let _ = writeln!(
+                text, "| {endpoint} | {} | {} | {} | {} | {} |",
+                x["attempts"], x["successes"], x["retried_failures"], x["permanent_failures"], x["max_latency_ms"],
+            );
+        }
+
+        text
+    }
+
+    fn cool_down(&self) -> Duration {
+        Duration::from_nanos(self.cool_down_nanos.load(Ordering::Relaxed))
+    }
+
+    /// 実行の最後に書き出す記録
+    pub fn summary(&self) -> serde_json::Value {
+        let requests = self.requests.lock().expect("poisoned").clone();
+        let (hits, misses) = (self.user_cache_hits.load(Ordering::Relaxed), self.user_cache_misses.load(Ordering::Relaxed));
+
+        let mut summary = serde_json::json!({
+            "kind": "metrics",
+            "requests": requests,
+            "response_bytes": self.response_bytes.load(Ordering::Relaxed),
+            "cool_down_seconds": self.cool_down().as_secs_f64(),
+        });
+        let endpoints = self.endpoints();
+        if !endpoints.is_empty() {
+            summary["endpoints"] = serde_json::json!(endpoints);
+        }
+        if hits + misses > 0 {
+            summary["user_cache"] = serde_json::json!({ "hits": hits, "fetched": misses });
+        }
+        let latencies = self.latencies.lock().expect("poisoned");
+        if !latencies.is_empty() {
+            summary["latency_ms"] = latencies.iter().map(|(endpoint, durations)| {
+                let mut millis: Vec<u128> = durations.iter().map(Duration::as_millis).collect();
+                millis.sort_unstable();
+                (endpoint.clone(), serde_json::json!({
+                    "p50": percentile(&millis, 50),
+                    "p90": percentile(&millis, 90),
+                    "p99": percentile(&millis, 99),
+                    "max": millis.last(),
+                }))
+            }).collect();
+        }
+
+        summary
+    }
+
+    /// `node_exporter`のtextfile collectorが読める形式
+    pub fn to_prometheus(&self) -> String {
+        let mut text = String::new();
+        let _ = writeln!(text, "# HELP misskey_channel_archiver_requests_total Requests sent, by endpoint.");
+        let _ = writeln!(text, "# TYPE misskey_channel_archiver_requests_total counter");
+        for (endpoint, count) in self.requests.lock().expect("poisoned").iter() {
+            let _ = writeln!(text, r#"misskey_channel_archiver_requests_total{{endpoint="{endpoint}"}} {count}"#);
+        }
+        let _ = writeln!(text, "# HELP misskey_channel_archiver_response_bytes_total Decompressed response bytes received.");
+        let _ = writeln!(text, "# TYPE misskey_channel_archiver_response_bytes_total counter");
+        let _ = writeln!(text, "misskey_channel_archiver_response_bytes_total {}", self.response_bytes.load(Ordering::Relaxed));
+        let _ = writeln!(text, "# HELP misskey_channel_archiver_cool_down_seconds_total Time spent waiting between requests.");
+        let _ = writeln!(text, "# TYPE misskey_channel_archiver_cool_down_seconds_total counter");
+        let _ = writeln!(text, "misskey_channel_archiver_cool_down_seconds_total {}", self.cool_down().as_secs_f64());
+        let _ = writeln!(text, "# HELP misskey_channel_archiver_notes_total Notes written.");
+        let _ = writeln!(text, "# TYPE misskey_channel_archiver_notes_total counter");
+        let _ = writeln!(text, "misskey_channel_archiver_notes_total {}", self.notes());
+
+        text
+    }
+}
+
+/// `--metrics-listen`。`GET /metrics`に、その時点の`to_prometheus`を返す。実行が終わるまで別のスレッドで待ち受ける。
+/// 待ち受けたアドレスを返す。
+pub fn serve(metrics: Arc<Metrics>, addr: SocketAddr) -> io::Result<SocketAddr> {
+    let listener = TcpListener::bind(addr)?;
+    let addr = listener.local_addr()?;
+    std::thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            if let Err(e) = respond(&metrics, stream) {
+                debug!("metrics-listen: {e}");
+            }
+        }
+    });
+
+    Ok(addr)
+}
+
+fn respond(metrics: &Metrics, stream: TcpStream) -> io::Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    let mut reader = BufReader::new(stream);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    // 本文の無いGETだけを受けるので、ヘッダーは読み捨てる
+    let mut header = String::new();
+    while reader.read_line(&mut header)? > 2 {
+        header.clear();
+    }
+
+    let (status, body) = match request_line.split_whitespace().nth(1) {
+        Some("/metrics") => ("200 OK", metrics.to_prometheus()),
+        _ => ("404 Not Found", String::new()),
+    };
+    write!(
+        reader.get_mut(),
+        "HTTP/1.1 {status}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len(),
+    )
+}
+
+/// 並べ替えた`sorted`の、`p`パーセンタイル。最も近い順位のものを選ぶ
+fn percentile(sorted: &[u128], p: usize) -> Option<u128> {
+    let rank = (sorted.len() * p).div_ceil(100).max(1);
+
+    sorted.get(rank - 1).copied()
+}
+
+/// `--max-requests`に達した
+#[derive(Debug)]
+pub struct BudgetExhausted {
+    pub limit: NonZeroU64,
+}
+
+impl BudgetExhausted {
+    pub fn message(&self) -> Message {
+        Message::new("budget-exhausted", &[("limit", &self.limit)])
+    }
+}
+
+impl Display for BudgetExhausted {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.message().render(Lang::En))
+    }
+}
+
+impl Error for BudgetExhausted {}
+
+/// `--max-duration`を過ぎた
+#[derive(Debug)]
+pub struct TimeBudgetExhausted {
+    pub limit: Duration,
+}
+
+impl TimeBudgetExhausted {
+    pub fn message(&self) -> Message {
+        Message::new("time-budget-exhausted", &[("limit", &format!("{}s", self.limit.as_secs()))])
+    }
+}
+
+impl Display for TimeBudgetExhausted {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.message().render(Lang::En))
+    }
+}
+
+impl Error for TimeBudgetExhausted {}
+
+/// `--max-requests`か`--max-duration`で止めたか。残りのチャンネルも送れない
+pub fn is_exhausted(e: &(dyn Error + Send + Sync + 'static)) -> bool {
+    e.is::<BudgetExhausted>() || e.is::<TimeBudgetExhausted>()
+}
+
+/// `--fail-threshold`を超えて失敗したエンドポイントがあった
+#[derive(Debug)]
+pub struct FailThreshold {
+    pub endpoint: String,
+    /// 頼み直しても取れなかった数
+    pub failures: u64,
+    pub attempts: u64,
+    pub threshold: u8,
+}
+
+impl FailThreshold {
+    pub fn message(&self) -> Message {
+        Message::new("fail-threshold", &[
+            ("endpoint", &self.endpoint), ("failures", &self.failures), ("attempts", &self.attempts), ("threshold", &self.threshold),
+        ])
+    }
+}
+
+impl Display for FailThreshold {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.message().render(Lang::En))
+    }
+}
+
+impl Error for FailThreshold {}
+
+/// 別のクライアントを包んで、リクエストを数え、かかった時間を測る。`budget`があれば、それを超えては送らない。
+/// `deadline`を過ぎてからは新しく送らないが、送っている最中のものは待つ。
+/// `--verbose`なら、1つのリクエストごとに結果を1行書く。
+pub struct MeteredClient<C> {
+    inner: C,
+    metrics: Arc<Metrics>,
+    budget: Option<NonZeroU64>,
+    /// 止める時刻と、`--max-duration`。テストで時計を止められるよう、tokioの時刻で持つ
+    deadline: Option<(Instant, Duration)>,
+    sent: AtomicU64,
+    /// エンドポイントごとの、続けて失敗した回数
+    failures: Mutex<HashMap<String, u32>>,
+}
+
+impl<C> MeteredClient<C> {
+    pub fn new(inner: C, metrics: Arc<Metrics>) -> Self {
+        Self { inner, metrics, budget: None, deadline: None, sent: AtomicU64::new(0), failures: Mutex::default() }
+    }
+
+    pub const fn with_budget(mut self, budget: Option<NonZeroU64>) -> Self {
+        self.budget = budget;
+        self
+    }
+
+    /// 今から`limit`が過ぎたら止める。
+    pub fn with_deadline(mut self, limit: Option<Duration>) -> Self {
+        self.deadline = limit.map(|x| (Instant::now() + x, x));
+        self
+    }
+}
+
+impl<C: ApiClient> ApiClient for MeteredClient<C> {
+    async fn call(&self, endpoint: &str, body: serde_json::Value) -> Result<RawResponse, Box<dyn Error + Send + Sync>> {
+        if let Some(limit) = self.budget {
+            if self.sent.fetch_add(1, Ordering::Relaxed) >= limit.get() {
+                return Err(Box::new(BudgetExhausted { limit }))
+            }
+        }
+        if let Some((_, limit)) = self.deadline.filter(|(at, _)| Instant::now() >= *at) {
+            return Err(Box::new(TimeBudgetExhausted { limit }))
+        }
+
+        let attempt = self.failures.lock().expect("poisoned").get(endpoint).copied().unwrap_or(0) + 1;
+        let started = Instant::now();
+        let result = self.inner.call(endpoint, body).await;
+        let elapsed = started.elapsed();
+        let bytes = result.as_ref().map_or(0, |raw| raw.body.len());
+        self.metrics.record_request(endpoint, bytes);
+        self.metrics.record_latency(endpoint, elapsed);
+        self.metrics.record_outcome(endpoint, result.as_ref().map_or(true, |raw| raw.status == 429 || raw.status >= 500));
+
+        let status = result.as_ref().ok().map(|raw| raw.status);
+        debug!(
+            "request endpoint={endpoint} attempt={attempt} status={} bytes={bytes} elapsed_ms={}",
+            status.map_or_else(|| "none".to_owned(), |x| x.to_string()),
+            elapsed.as_millis(),
+        );
+        let mut failures = self.failures.lock().expect("poisoned");
+        if status.is_some_and(|x| x < 400) {
+            failures.remove(endpoint);
+        } else {
+            *failures.entry(endpoint.to_owned()).or_default() += 1;
+        }
+
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+    use std::num::{NonZeroU32, NonZeroU64};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use serde_json::json;
+
+    use crate::api::ApiClient;
+    use crate::capture::ReplayClient;
+    use crate::metrics::{percentile, serve, BudgetExhausted, MeteredClient, Metrics};
+    use crate::status::Outcome;
+    use crate::pacer::Pacer;
+    use crate::testing::{capture_dir, channel, me};
+
+    #[tokio::test(start_paused = true)]
+    async fn counts_requests_bytes_and_cool_down() {
+        let dir = capture_dir("metrics", &[me(), channel("ch")]);
+        let metrics = Arc::new(Metrics::default());
+        let client = MeteredClient::new(ReplayClient::open(&dir).unwrap(), Arc::clone(&metrics));
+        let pacer = Pacer::with_burst(Duration::from_secs(2), NonZeroU32::MIN).with_metrics(Arc::clone(&metrics));
+
+        pacer.wait().await;
+        let me = client.call("i", json!({})).await.unwrap();
+        pacer.wait().await;
+        let channel = client.call("channels/show", json!({ "channelId": "ch" })).await.unwrap();
+
+        let summary = metrics.summary();
+        assert_eq!(summary["requests"], json!({ "i": 1, "channels/show": 1 }));
+        assert_eq!(summary["response_bytes"], me.body.len() + channel.body.len());
+        assert_eq!(summary["cool_down_seconds"], 2.0);
+        assert!(metrics.to_prometheus().contains(r#"misskey_channel_archiver_requests_total{endpoint="channels/show"} 1"#));
+    }
+
+    #[tokio::test]
+    async fn latency_percentiles_per_endpoint() {
+        let dir = capture_dir("metrics-latency", &[me()]);
+        let metrics = Arc::new(Metrics::default());
+        let client = MeteredClient::new(ReplayClient::open(&dir).unwrap(), Arc::clone(&metrics));
+        client.call("i", json!({})).await.unwrap();
+        for millis in [30, 10, 20, 1000] {
+            metrics.record_latency("notes/show", Duration::from_millis(millis));
+        }
+
+        let latency = &metrics.summary()["latency_ms"];
+        assert_eq!(latency["notes/show"], json!({ "p50": 20, "p90": 1000, "p99": 1000, "max": 1000 }));
+        assert!(latency["i"]["p50"].is_u64());
+        assert_eq!(percentile(&[], 50), None);
+    }
+
+    #[test]
+    fn listener_serves_the_current_counters() {
+        let metrics = Arc::new(Metrics::default());
+        let addr = serve(Arc::clone(&metrics), "127.0.0.1:0".parse().unwrap()).unwrap();
+        let scrape = || {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            stream.write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).unwrap();
+            response
+        };
+
+        assert!(scrape().contains("misskey_channel_archiver_notes_total 0\n"));
+        metrics.record_notes(3);
+        metrics.record_request("channels/timeline", 10);
+        let response = scrape();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("misskey_channel_archiver_notes_total 3\n"));
+        assert!(response.contains(r#"misskey_channel_archiver_requests_total{endpoint="channels/timeline"} 1"#));
+    }
+
+    #[tokio::test]
+    async fn budget_stops_before_sending() {
+        let dir = capture_dir("metrics-budget", &[me(), channel("ch")]);
+        let metrics = Arc::new(Metrics::default());
+        let client = MeteredClient::new(ReplayClient::open(&dir).unwrap(), Arc::clone(&metrics)).with_budget(NonZeroU64::new(1));
+
+        client.call("i", json!({})).await.unwrap();
+        let result = client.call("channels/show", json!({ "channelId": "ch" })).await;
+
+        assert!(result.is_err_and(|e| e.is::<BudgetExhausted>()));
+        assert_eq!(metrics.summary()["requests"], json!({ "i": 1 }));
+    }
+
+    #[test]
+    fn failures_followed_by_a_success_count_as_retried() {
+        let metrics = Metrics::default();
+        for failed in [true, true, false, false, true] {
+            metrics.record_request("channels/timeline", 0);
+            metrics.record_outcome("channels/timeline", failed);
+        }
+        metrics.record_latency("channels/timeline", Duration::from_millis(250));
+
+        assert_eq!(metrics.summary()["endpoints"]["channels/timeline"], json!({
+            "attempts": 5, "successes": 2, "retried_failures": 2, "permanent_failures": 1, "max_latency_ms": 250,
+        }));
+        assert!(metrics.retries_report().contains("| channels/timeline | 5 | 2 | 2 | 1 | 250 |"));
+    }
+
+    #[test]
+    fn threshold_is_checked_per_endpoint_on_permanent_failures() {
+        let metrics = Metrics::default();
+        // 頼み直して取れた失敗は数えない
+        for failed in [true, true, true, false] {
+            metrics.record_outcome("channels/timeline", failed);
+        }
+        // 10回のうち1回が取れなかった
+        for i in 0..10 {
+            metrics.record_outcome("notes/show", i == 9);
+        }
+        assert!(metrics.check_failure_threshold(10).is_ok());
+
+        let e = metrics.check_failure_threshold(9).unwrap_err();
+        assert_eq!((e.endpoint.as_str(), e.failures, e.attempts), ("notes/show", 1, 10));
+        assert_eq!(Outcome::classify(&e), Outcome::CompletedWithGaps);
+        assert_eq!(e.to_string(), "1 of 10 request(s) to notes/show failed even after retries, above --fail-threshold 9%");
+
+        // 最も割合の大きいエンドポイントを返す
+        metrics.record_outcome("users/show", true);
+        assert_eq!(metrics.check_failure_threshold(0).unwrap_err().endpoint, "users/show");
+        assert!(Metrics::default().check_failure_threshold(0).is_ok());
+    }
+}
@@ -0,0 +1,425 @@
+//! `export`で本文をHTMLにするときの、MFMの読み方と書き方。
+//!
+//! MFMの全てではなく、太字、斜体、小さい文字、中央寄せ、引用、コード、`$[...]`の関数、カスタム絵文字、URLだけを読む。
+//! 閉じていない記法は、そのままの文字として扱う。
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use clap::ValueEnum;
+
+use crate::filename::sanitize_filename;
+
+/// `--emoji-style image`で探す画像の拡張子
+const EMOJI_EXTENSIONS: [&str; 6] = ["png", "webp", "gif", "jpg", "jpeg", "svg"];
+
+/// `export --mfm`
+#[derive(Eq, PartialEq, Copy, Clone, Debug, Default, ValueEnum)]
+pub enum MfmStyle {
+    /// 記法を取り除いた文にする
+    Plain,
+    /// 記法をそのまま文として残す
+    #[default]
+    Passthrough,
+    /// 太字、斜体、小さい文字、中央寄せ、引用、コードをHTMLの要素にする。`$[...]`の関数は中身だけにする
+    RenderBasic,
+}
+
+/// `export --emoji-style`
+#[derive(Eq, PartialEq, Copy, Clone, Debug, Default, ValueEnum)]
+pub enum EmojiStyle {
+    /// `:name:`のまま書く
+    #[default]
+    Shortcode,
+    /// `--emoji-dir`にある画像を`<img>`で書く。無ければ`:name:`のまま書く
+    Image,
+    /// 名前がUnicodeの絵文字のショートコードと同じなら、その絵文字を書く。無ければ`:name:`のまま書く
+    UnicodeFallback,
+}
+
+/// `export --linkify`
+#[derive(Eq, PartialEq, Copy, Clone, Debug, Default, ValueEnum)]
+pub enum Linkify {
+    /// URLを`<a>`にする
+    On,
+    #[default]
+    Off,
+}
+
+/// 本文の書き方
+#[derive(Default)]
+pub struct TextOptions {
+    pub mfm: MfmStyle,
+    pub emoji: EmojiStyle,
+    /// `--emoji-style image`で、`<name>.png`などを探すディレクトリ
+    pub emoji_dir: Option<PathBuf>,
+    pub linkify: Linkify,
+}
+
+#[derive(Eq, PartialEq, Copy, Clone, Debug)]
+enum Style {
+    Bold,
+    Italic,
+    Small,
+    Center,
+    Quote,
+    /// `$[name ...]`。アニメーションなどは再現しない
+    Function,
+}
+
+/// 閉じる記法で囲む形のもの
+const PAIRS: [(&str, &str, Style); 5] = [
+    ("**", "**", Style::Bold),
+    ("<b>", "</b>", Style::Bold),
+    ("<i>", "</i>", Style::Italic),
+    ("<small>", "</small>", Style::Small),
+    ("<center>", "</center>", Style::Center),
+];
+
+#[derive(Eq, PartialEq, Clone, Debug)]
+enum Node {
+    Text(String),
+    Styled { style: Style, children: Vec<Self> },
+    Code { code: String, block: bool },
+    /// `:`を除いた名前
+    Emoji(String),
+    Url(String),
+}
+
+/// これより深く入れ子になった記法は、そのままの文字として扱う。Misskeyの`nestLimit`と揃える
+const MAX_NESTING: usize = 20;
+
+/// 閉じた記法の中身と、閉じる記法までのバイト数
+type Enclosed = (Vec<Node>, usize);
+
+/// 1つの本文を読む間の状態。位置は、そこから本文の終わりまでのバイト数で表す
+struct Context {
+    /// 閉じる記法ごとの、最後に現れる位置。これより後ろから始まる中身は閉じられない
+    last: HashMap<&'static str, usize>,
+    /// 記法の中身を読んだ結果を、中身の始まりと閉じる記法で覚えたもの。閉じられなければ[`None`]。
+    /// 同じ位置から読めば同じ結果になるので、閉じていない記法が入れ子になっても読み直さない。
+    /// 深さは含めないので、[`MAX_NESTING`]を超える入れ子では、先に読んだ深さでの結果を使う
+    memo: HashMap<(usize, &'static str), Option<Enclosed>>,
+    depth: usize,
+}
+
+impl Context {
+    fn new(text: &str, depth: usize) -> Self {
+        let closes = PAIRS.iter().map(|x| x.1).chain(["]"]);
+        let last = closes.filter_map(|close| Some((close, text.len() - text.rfind(close)?))).collect();
+
+        Self { last, memo: HashMap::new(), depth }
+    }
+}
+
+/// `markup`が偽なら、絵文字とURLだけを読む。
+fn parse(text: &str, markup: bool) -> Vec<Node> {
+    parse_until(text, None, true, markup, &mut Context::new(text, 0)).0
+}
+
+/// `close`が現れるまでを読む。読んだバイト数と、`close`で閉じたかも返す。`s`は`cx`を作った本文の終わりまでを含む。
+fn parse_until(s: &str, close: Option<&str>, line_start: bool, markup: bool, cx: &mut Context) -> (Vec<Node>, usize, bool) {
+    let mut nodes = vec![];
+    let mut text = String::new();
+    let mut i = 0;
+    while i < s.len() {
+        let rest = &s[i..];
+        if let Some(close) = close.filter(|x| rest.starts_with(x)) {
+            flush(&mut nodes, &mut text);
+            return (nodes, i + close.len(), true)
+        }
+        let at_line_start = if i == 0 { line_start } else { s[..i].ends_with('\n') };
+        let previous = s[..i].chars().next_back();
+        if let Some((node, len)) = syntax(rest, at_line_start, previous, markup, cx) {
+            flush(&mut nodes, &mut text);
+            nodes.push(node);
+            i += len;
+            continue
+        }
+        let c = rest.chars().next().expect("not empty");
+        text.push(c);
+        i += c.len_utf8();
+    }
+    flush(&mut nodes, &mut text);
+
+    (nodes, i, close.is_none())
+}
+
+fn flush(nodes: &mut Vec<Node>, text: &mut String) {
+    if !text.is_empty() {
+        nodes.push(Node::Text(std::mem::take(text)));
+    }
+}
+
+/// `rest`の先頭にある記法と、その長さ
+fn syntax(rest: &str, line_start: bool, previous: Option<char>, markup: bool, cx: &mut Context) -> Option<(Node, usize)> {
+    if markup {
+        if let Some(found) = block(rest, line_start, cx).or_else(|| inline(rest, cx)) {
+            return Some(found)
+        }
+    }
+    if rest.starts_with(':') && !previous.is_some_and(char::is_alphanumeric) {
+        let pattern = lazy_regex::regex!(r"^:([a-zA-Z0-9_+-]+(?:@[a-zA-Z0-9.-]+)?):");
+        if let Some(captures) = pattern.captures(rest) {
+            return Some((Node::Emoji(captures[1].to_owned()), captures[0].len()))
+        }
+    }
+    if rest.starts_with("https://") || rest.starts_with("http://") {
+        let len = rest.find(|c: char| c.is_whitespace() || matches!(c, '<' | '>' | '"' | '\'')).unwrap_or(rest.len());
+        return Some((Node::Url(rest[..len].to_owned()), len))
+    }
+
+    None
+}
+
+/// 行の始まりでだけ読むもの
+fn block(rest: &str, line_start: bool, cx: &Context) -> Option<(Node, usize)> {
+    if !line_start {
+        return None
+    }
+    if let Some(body) = rest.strip_prefix("```") {
+        let end = body.find("```")?;
+        // 最初の行は言語の名前
+        let code = body[..end].split_once('\n').map_or("", |x| x.1);
+        return Some((Node::Code { code: code.to_owned(), block: true }, 3 + end + 3))
+    }
+    if rest.starts_with('>') && cx.depth < MAX_NESTING {
+        let mut lines = vec![];
+        let mut len = 0;
+        for line in rest.split_inclusive('\n') {
+            let Some(content) = line.strip_prefix('>') else {
+                break
+            };
+            len += line.len();
+            lines.push(content.strip_prefix(' ').unwrap_or(content).trim_end_matches('\n'));
+        }
+        // 最後の改行は引用の外に残す
+        if rest[..len].ends_with('\n') {
+            len -= 1;
+        }
+        // 別の本文として読む
+        let quoted = lines.join("\n");
+        let children = parse_until(&quoted, None, true, true, &mut Context::new(&quoted, cx.depth + 1)).0;
+        return Some((Node::Styled { style: Style::Quote, children }, len))
+    }
+
+    None
+}
+
+fn inline(rest: &str, cx: &mut Context) -> Option<(Node, usize)> {
+    if let Some(body) = rest.strip_prefix('`') {
+        let end = body.find(['`', '\n'])?;
+        return body[end..].starts_with('`').then(|| (Node::Code { code: body[..end].to_owned(), block: false }, 1 + end + 1))
+    }
+    for (open, close, style) in PAIRS {
+        if let Some((children, len)) = rest.strip_prefix(open).and_then(|x| enclosed(x, close, true, cx)) {
+            return Some((Node::Styled { style, children }, open.len() + len))
+        }
+    }
+    if let Some(body) = rest.strip_prefix("$[") {
+        let (name, _) = body.split_once([' ', ']'])?;
+        let body = body[name.len()..].strip_prefix(' ')?;
+        if let Some((children, len)) = enclosed(body, "]", false, cx) {
+            return Some((Node::Styled { style: Style::Function, children }, 2 + name.len() + 1 + len))
+        }
+    }
+
+    None
+}
+
+/// `close`で閉じた`body`の中身と、`close`までのバイト数。`non_empty`なら、中身の無いものは閉じていないものとする
+fn enclosed(body: &str, close: &'static str, non_empty: bool, cx: &mut Context) -> Option<Enclosed> {
+    if cx.depth >= MAX_NESTING || cx.last.get(close).is_none_or(|x| body.len() < *x) {
+        return None
+    }
+    let key = (body.len(), close);
+    if let Some(found) = cx.memo.get(&key) {
+        return found.clone()
+    }
+    cx.depth += 1;
+    let (children, len, closed) = parse_until(body, Some(close), false, true, cx);
+    cx.depth -= 1;
+    let found = (closed && !(non_empty && children.is_empty())).then_some((children, len));
+    cx.memo.insert(key, found.clone());
+
+    found
+}
+
+/// 記法を取り除き、絵文字とURL、コードも除いた文。`--detect-language`で言語を推定するのに使う
+pub fn to_plain_text(text: &str) -> String {
+    fn push(out: &mut String, nodes: &[Node]) {
+        for node in nodes {
+            match node {
+                Node::Text(text) => out.push_str(text),
+                Node::Styled { children, .. } => push(out, children),
+                Node::Code { .. } | Node::Emoji(_) | Node::Url(_) => out.push(' '),
+            }
+        }
+    }
+    let mut out = String::new();
+    push(&mut out, &parse(text, true));
+
+    out
+}
+
+/// MFMの本文を、`<p>`で囲んだHTMLにする。改行は`<br>`にする。
+pub fn to_html(text: &str, options: &TextOptions) -> String {
+    let mut html = String::from("<p>");
+    render(&mut html, &parse(text, options.mfm != MfmStyle::Passthrough), options);
+    html.push_str("</p>");
+
+    html
+}
+
+fn render(out: &mut String, nodes: &[Node], options: &TextOptions) {
+    for node in nodes {
+        match node {
+            Node::Text(text) => escape(out, text, true),
+            Node::Styled { style, children } => {
+                let tags = match (options.mfm, style) {
+                    (MfmStyle::RenderBasic, Style::Bold) => Some(("<b>", "</b>")),
+                    (MfmStyle::RenderBasic, Style::Italic) => Some(("<i>", "</i>")),
+                    (MfmStyle::RenderBasic, Style::Small) => Some(("<small>", "</small>")),
+                    (MfmStyle::RenderBasic, Style::Center) => Some(("<div style=\"text-align: center\">", "</div>")),
+                    (MfmStyle::RenderBasic, Style::Quote) => Some(("<blockquote>", "</blockquote>")),
+                    _ => None,
+                };
+                let (open, close) = tags.unwrap_or_default();
+                out.push_str(open);
+                render(out, children, options);
+                out.push_str(close);
+            }
+            Node::Code { code, block } => match (options.mfm, block) {
+                (MfmStyle::RenderBasic, true) => {
+                    out.push_str("<pre><code>");
+                    escape(out, code, false);
+                    out.push_str("</code></pre>");
+                }
+                (MfmStyle::RenderBasic, false) => {
+                    out.push_str("<code>");
+                    escape(out, code, false);
+                    out.push_str("</code>");
+                }
+                _ => escape(out, code, true),
+            },
+            Node::Emoji(name) => {
+                let image = options.emoji_dir.as_deref().filter(|_| options.emoji == EmojiStyle::Image).and_then(|x| emoji_image(x, name));
+                let shortcode = format!(":{name}:");
+                if let Some(emoji) = emojis::get_by_shortcode(name).filter(|_| options.emoji == EmojiStyle::UnicodeFallback) {
+                    out.push_str(emoji.as_str());
+                } else if let Some(path) = image {
+                    out.push_str("<img class=\"emoji\" src=\"");
+                    escape(out, &path.to_string_lossy(), false);
+                    out.push_str("\" alt=\"");
+                    escape(out, &shortcode, false);
+                    out.push_str("\">");
+                } else {
+                    escape(out, &shortcode, true);
+                }
+            }
+            Node::Url(url) if options.linkify == Linkify::On => {
+                out.push_str("<a href=\"");
+                escape(out, url, false);
+                out.push_str("\">");
+                escape(out, url, false);
+                out.push_str("</a>");
+            }
+            Node::Url(url) => escape(out, url, true),
+        }
+    }
+}
+
+/// `dir`にある、`name`の絵文字の画像
+fn emoji_image(dir: &Path, name: &str) -> Option<PathBuf> {
+    let name = sanitize_filename(name, "_");
+    EMOJI_EXTENSIONS.iter().map(|x| dir.join(format!("{name}.{x}"))).find(|x| x.is_file())
+}
+
+fn escape(out: &mut String, text: &str, newline: bool) {
+    for c in text.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            '\n' if newline => out.push_str("<br>"),
+            c => out.push(c),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use crate::mfm::{to_html, to_plain_text, EmojiStyle, Linkify, MfmStyle, TextOptions};
+    use crate::testing::capture_dir;
+
+    const SAMPLES: [&str; 6] = [
+        "**bold** and <i>italic</i>",
+        "<small>quiet</small>\n<center>middle</center>",
+        "> quoted **line**\n> second\nafter",
+        "`a<b` and\n```js\nlet x = 1;\n```",
+        "$[tada $[x2 big]] :blobcat: at 10:30: https://example.com/?a=1&b=2",
+        "**not closed and <b>also",
+    ];
+
+    fn render_all(mfm: MfmStyle, linkify: Linkify) -> Vec<String> {
+        let options = TextOptions { mfm, linkify, ..TextOptions::default() };
+        SAMPLES.iter().map(|x| to_html(x, &options)).collect()
+    }
+
+    #[test]
+    fn samples_render_in_each_style() {
+        assert_eq!(render_all(MfmStyle::Passthrough, Linkify::Off), [
+            "<p>**bold** and &lt;i&gt;italic&lt;/i&gt;</p>",
+            "<p>&lt;small&gt;quiet&lt;/small&gt;<br>&lt;center&gt;middle&lt;/center&gt;</p>",
+            "<p>&gt; quoted **line**<br>&gt; second<br>after</p>",
+            "<p>`a&lt;b` and<br>```js<br>let x = 1;<br>```</p>",
+            "<p>$[tada $[x2 big]] :blobcat: at 10:30: https://example.com/?a=1&amp;b=2</p>",
+            "<p>**not closed and &lt;b&gt;also</p>",
+        ]);
+        assert_eq!(render_all(MfmStyle::Plain, Linkify::Off), [
+            "<p>bold and italic</p>",
+            "<p>quiet<br>middle</p>",
+            "<p>quoted line<br>second<br>after</p>",
+            "<p>a&lt;b and<br>let x = 1;<br></p>",
+            "<p>big :blobcat: at 10:30: https://example.com/?a=1&amp;b=2</p>",
+            "<p>**not closed and &lt;b&gt;also</p>",
+        ]);
+        assert_eq!(SAMPLES.map(to_plain_text)[3..], ["  and\n ", "big   at 10:30:  ", "**not closed and <b>also"]);
+        assert_eq!(render_all(MfmStyle::RenderBasic, Linkify::On), [
+            "<p><b>bold</b> and <i>italic</i></p>",
+            "<p><small>quiet</small><br><div style=\"text-align: center\">middle</div></p>",
+            "<p><blockquote>quoted <b>line</b><br>second</blockquote><br>after</p>",
+            "<p><code>a&lt;b</code> and<br><pre><code>let x = 1;\n</code></pre></p>",
+            "<p>big :blobcat: at 10:30: <a href=\"https://example.com/?a=1&amp;b=2\">https://example.com/?a=1&amp;b=2</a></p>",
+            "<p>**not closed and &lt;b&gt;also</p>",
+        ]);
+    }
+
+    #[test]
+    fn unclosed_nested_markup_is_read_in_time() {
+        for open in ["<b>", "$[x ", "<i><small>"] {
+            let text = open.repeat(40);
+            assert_eq!(to_plain_text(&text), text);
+            // 閉じる記法が後ろにあっても、1度閉じられなかったところは読み直さない
+            let closed = format!("{text}</b>]");
+            assert!(to_plain_text(&closed).len() <= closed.len());
+        }
+    }
+
+    #[test]
+    fn missing_emoji_images_fall_back_to_shortcodes() {
+        let dir = capture_dir("mfm-emoji", &[]);
+        fs::write(dir.join("blobcat.png"), b"").unwrap();
+        let options = TextOptions { emoji: EmojiStyle::Image, emoji_dir: Some(dir.clone()), ..TextOptions::default() };
+
+        let html = to_html(":blobcat: :missing:", &options);
+
+        assert_eq!(html, format!("<p><img class=\"emoji\" src=\"{}\" alt=\":blobcat:\"> :missing:</p>", dir.join("blobcat.png").display()));
+        let options = TextOptions { emoji: EmojiStyle::UnicodeFallback, ..TextOptions::default() };
+        assert_eq!(to_html(":tada: :blobcat:", &options), "<p>🎉 :blobcat:</p>");
+    }
+}
@@ -0,0 +1,119 @@
+//! `auth`サブコマンド。MiAuthでブラウザから承認してもらい、トークンを得る。
+
+use std::error::Error;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use tokio::time::{sleep, Instant};
+use url::Url;
+
+use crate::api::{ApiClient, MisskeyAuthorizationToken};
+use crate::host::Host;
+use crate::model::Account;
+
+/// このツールが使う全てのエンドポイントに必要な権限。[`crate::api::required_permission`]と揃える。
+pub const PERMISSIONS: &[&str] = &["read:account", "read:channels", "read:mutes", "read:blocks"];
+
+/// 承認されたかを確かめる間隔
+pub const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// `MiAuth`のセッションIDにするUUID v4
+pub fn session_id() -> Result<String, Box<dyn Error + Send + Sync>> {
+    let mut bytes = [0; 16];
+    getrandom::getrandom(&mut bytes).map_err(|e| format!("failed to generate a session id: {e}"))?;
+    bytes[6] = (bytes[6] & 0x0f) | 0x40;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+
+    let hex: Vec<String> = bytes.iter().map(|b| format!("{b:02x}")).collect();
+    Ok([&hex[..4], &hex[4..6], &hex[6..8], &hex[8..10], &hex[10..]].map(<[String]>::concat).join("-"))
+}
+
+/// ユーザーに開いてもらうURL
+pub fn authorization_url(host: &Host, session: &str, name: &str) -> Url {
+    let mut url = host.url(&format!("miauth/{session}"));
+    url.query_pairs_mut()
+        .append_pair("name", name)
+        .append_pair("permission", &PERMISSIONS.join(","));
+
+    url
+}
+
+#[derive(Serialize)]
+struct CheckCommand {}
+
+#[derive(Deserialize)]
+struct CheckResponse {
+    ok: bool,
+    token: Option<String>,
+    user: Option<Account>,
+}
+
+/// 承認されるか`timeout`が過ぎるまで、`POLL_INTERVAL`ごとに確かめる。
+pub async fn wait_for_approval(
+    client: &impl ApiClient,
+    session: &str,
+    timeout: Duration,
+) -> Result<(MisskeyAuthorizationToken, Option<Account>), Box<dyn Error + Send + Sync>> {
+    let deadline = Instant::now() + timeout;
+    let endpoint = format!("miauth/{session}/check");
+
+    loop {
+        let raw = client.call(&endpoint, serde_json::to_value(CheckCommand {})?).await?;
+        // 承認される前は`ok: false`の他にエラーが返ることもあるので、読めなければ待つ
+        if let Ok(CheckResponse { ok: true, token: Some(token), user }) = serde_json::from_str(&raw.body) {
+            return Ok((MisskeyAuthorizationToken::new(token), user))
+        }
+
+        if Instant::now() + POLL_INTERVAL > deadline {
+            return Err(format!("not approved within {} seconds", timeout.as_secs()).into())
+        }
+        sleep(POLL_INTERVAL).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use serde_json::json;
+
+    use crate::capture::ReplayClient;
+    use crate::miauth::{authorization_url, session_id, wait_for_approval};
+    use crate::testing::{capture_dir, exchange};
+
+    #[test]
+    fn url_requests_exactly_the_needed_permissions() {
+        let session = session_id().unwrap();
+        assert_eq!(session.len(), 36);
+        assert_eq!(&session[14..15], "4");
+
+        let url = authorization_url(&"misskey.example".parse().unwrap(), &session, "archiver");
+        assert_eq!(url.path(), format!("/miauth/{session}"));
+        assert_eq!(url.query(), Some("name=archiver&permission=read%3Aaccount%2Cread%3Achannels%2Cread%3Amutes%2Cread%3Ablocks"));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn polls_until_approved() {
+        let dir = capture_dir("miauth", &[
+            exchange("miauth/s/check", json!({}), &json!({ "ok": false })),
+            exchange("miauth/s/check", json!({}), &json!({ "ok": true, "token": "t", "user": { "id": "u", "username": "mod" } })),
+        ]);
+        let client = ReplayClient::open(&dir).unwrap();
+
+        let (token, user) = wait_for_approval(&client, "s", Duration::from_secs(30)).await.unwrap();
+
+        assert_eq!(token.expose(), "t");
+        assert_eq!(user.unwrap().username, "mod");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn gives_up_after_timeout() {
+        let dir = capture_dir("miauth-timeout", &[
+            exchange("miauth/s/check", json!({}), &json!({ "ok": false })),
+            exchange("miauth/s/check", json!({}), &json!({ "ok": false })),
+        ]);
+        let client = ReplayClient::open(&dir).unwrap();
+
+        assert!(wait_for_approval(&client, "s", Duration::from_secs(3)).await.is_err());
+    }
+}
@@ -0,0 +1,569 @@
+use std::convert::Infallible;
+use std::fmt;
+use std::num::NonZeroUsize;
+use std::str::FromStr;
+
+use chrono::{DateTime, Utc};
+use serde::de::{MapAccess, Visitor};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use url::Url;
+
+use crate::host::Host;
+
+/// 並びはサーバーが`untilId`や`sinceId`で比べるのと同じ、文字列の順
+#[derive(Eq, PartialEq, Ord, PartialOrd, Clone, Debug, Hash, Serialize, Deserialize)]
+pub struct NoteId(pub String);
+
+/// `aid`と`aidx`の日時は、2000年1月1日からのミリ秒
+const AID_EPOCH_MS: i64 = 946_684_800_000;
+
+impl NoteId {
+    /// Misskeyの既定の`aid`か`aidx`の形式なら、IDの先頭8文字に含まれる作成日時。他の形式なら[`None`]。
+    pub fn aid_timestamp(&self) -> Option<DateTime<Utc>> {
+        let id = &self.0;
+        if !matches!(id.len(), 10 | 16) || !id.bytes().all(|b| b.is_ascii_digit() || b.is_ascii_lowercase()) {
+            return None
+        }
+        let elapsed = i64::from_str_radix(&id[..8], 36).ok()?;
+
+        DateTime::from_timestamp_millis(AID_EPOCH_MS + elapsed)
+    }
+}
+
+impl FromStr for NoteId {
+    type Err = Infallible;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(Self(s.to_owned()))
+    }
+}
+
+#[derive(Eq, PartialEq, Ord, PartialOrd, Clone, Debug, Deserialize, Serialize)]
+pub struct ChannelId(pub String);
+
+impl FromStr for ChannelId {
+    type Err = Infallible;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(Self(s.to_owned()))
+    }
+}
+
+#[derive(Eq, PartialEq, Ord, PartialOrd, Clone, Debug, Hash, Deserialize, Serialize)]
+pub struct UserId(pub String);
+
+impl FromStr for UserId {
+    type Err = Infallible;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(Self(s.to_owned()))
+    }
+}
+
+/// `notes/show`のうち、`--before`と`--after`を確かめるのに使うところ
+#[derive(Debug, Deserialize)]
+pub struct NoteLocation {
+    pub id: NoteId,
+    #[serde(rename = "createdAt")]
+    pub created_at: DateTime<Utc>,
+    /// チャンネルの外のノートなら無い
+    #[serde(default, rename = "channelId")]
+    pub channel_id: Option<ChannelId>,
+}
+
+/// リアクションそのもののID。ページを進めるのに使う
+#[derive(Eq, PartialEq, Clone, Debug, Deserialize, Serialize)]
+pub struct ReactionId(pub String);
+
+/// `notes/reactions`が返す、1つのリアクション
+#[derive(Deserialize)]
+pub struct Reaction {
+    pub id: ReactionId,
+    #[serde(rename = "createdAt")]
+    pub created_at: DateTime<Utc>,
+    pub user: PartialUser,
+    /// `👍`や`:blobcat@.:`
+    #[serde(rename = "type")]
+    pub emoji: String,
+}
+
+/// `notes/renotes`が返す、1つのRenote。本文は読まない
+#[derive(Deserialize)]
+pub struct Renote {
+    pub id: NoteId,
+    #[serde(rename = "createdAt")]
+    pub created_at: DateTime<Utc>,
+    pub user: PartialUser,
+}
+
+#[derive(Eq, PartialEq, Clone, Debug, Deserialize, Serialize)]
+pub struct ListId(pub String);
+
+impl FromStr for ListId {
+    type Err = Infallible;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(Self(s.to_owned()))
+    }
+}
+
+/// UNIX時間のミリ秒。Misskeyの`sinceDate`と`untilDate`はこの形で受け取る
+#[derive(Eq, PartialEq, Ord, PartialOrd, Debug, Serialize)]
+pub struct UnixDateTime(pub i64);
+
+impl From<DateTime<Utc>> for UnixDateTime {
+    fn from(at: DateTime<Utc>) -> Self {
+        Self(at.timestamp_millis())
+    }
+}
+
+/// フォローの関係そのもののID。ページを進めるのに使う
+#[derive(Eq, PartialEq, Clone, Debug, Deserialize, Serialize)]
+pub struct FollowingId(pub String);
+
+/// ミュートかブロックの関係そのもののID。ページを進めるのに使う
+#[derive(Eq, PartialEq, Clone, Debug, Deserialize, Serialize)]
+pub struct HidingId(pub String);
+
+/// `mute/list`と`blocking/list`が返す、1つのミュートかブロック
+#[derive(Deserialize)]
+pub struct Hiding {
+    pub id: HidingId,
+    #[serde(rename = "muteeId", alias = "blockeeId")]
+    pub user_id: UserId,
+}
+
+/// `users/followers`と`users/following`が返す、1つのフォローの関係
+#[derive(Deserialize)]
+pub struct Following {
+    pub id: FollowingId,
+    #[serde(rename = "createdAt")]
+    pub created_at: DateTime<Utc>,
+    #[serde(rename = "followerId")]
+    pub follower_id: UserId,
+    #[serde(rename = "followeeId")]
+    pub followee_id: UserId,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct Note {
+    pub id: NoteId,
+    #[serde(rename = "createdAt")]
+    pub created_at: DateTime<Utc>,
+    pub user: PartialUser,
+    /// 本文。RNなら[`None`]。QRNなら引用先の文。
+    pub text: Option<MisskeyFlavoredMarkdown>,
+    /// CWの折りたたみ時に表示されるテキスト
+    #[serde(rename = "cw")]
+    pub spoiler_disclaimer_text: Option<String>,
+    // visibility
+    #[serde(rename = "replyId")]
+    pub reply_to: Option<NoteId>,
+    #[serde(rename = "renoteId")]
+    pub renote_on: Option<NoteId>,
+    #[serde(rename = "renoteCount")]
+    pub renote_count: usize,
+    #[serde(rename = "repliesCount")]
+    pub reply_count: usize,
+    pub reactions: Reactions,
+    /// 添付したドライブのファイル。中身は落とさず、どこにあるかだけを残す
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub files: Vec<DriveFile>,
+    /// `--max-reactions-per-note`で書かなかったリアクションの数の合計
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub other_reactions_count: Option<usize>,
+    /// 取得したアカウントが付けたリアクション
+    #[serde(default, rename = "myReaction", skip_serializing_if = "Option::is_none")]
+    pub my_reaction: Option<CanonicalEmojiKey>,
+    /// チャンネルのノートなら、そのチャンネル。APIの応答に無ければ、遡ったチャンネルを取得後に埋める。
+    #[serde(default, alias = "channelId", skip_serializing_if = "Option::is_none")]
+    pub channel_id: Option<ChannelId>,
+    /// チャンネルのノートなら、APIが添えるチャンネルの名前など
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub channel: Option<NoteChannel>,
+    /// リモートのノートなら、元のサーバーでのURL
+    #[serde(default, skip_serializing)]
+    pub url: Option<Url>,
+    /// リモートのノートなら、ActivityPubのID
+    #[serde(default, skip_serializing)]
+    pub uri: Option<Url>,
+    /// 元のノートへのリンク。`--emit-note-urls`のときに取得後に埋める。
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub local_url: Option<Url>,
+    /// `--translate`のときに取得後に埋める。
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub translation: Option<Translation>,
+    /// `refresh`で数を取り直した日時
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub refreshed_at: Option<DateTime<Utc>>,
+    /// `--detect-language`のときに、本文から推定した言語
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub detected_language: Option<String>,
+    /// トークンのアカウントが作者をミュートかブロックしていたら、そのどちらか
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hidden_author: Option<HiddenBy>,
+    /// `--hash-notes`のときに、他の全てを埋めた後で求める。
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content_hash: Option<String>,
+    /// `import`で、移す先の無かったフィールド
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub extra: Option<serde_json::Map<String, serde_json::Value>>,
+}
+
+/// ノートに添付されたドライブのファイルのうち、残すもの
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct DriveFile {
+    pub id: String,
+    #[serde(default)]
+    pub name: Option<String>,
+    /// MIMEタイプ
+    #[serde(default, rename = "type")]
+    pub mime_type: Option<String>,
+    /// バイト数。返さないフォークもある
+    #[serde(default)]
+    pub size: Option<u64>,
+    /// 返されたまま。相対URLのこともあるので、[`Host::resolve`]で解決する
+    #[serde(default)]
+    pub url: Option<String>,
+}
+
+/// ノートに添えられたチャンネル
+#[derive(Deserialize, Serialize)]
+pub struct NoteChannel {
+    pub id: ChannelId,
+    pub name: String,
+}
+
+/// トークンのアカウントがユーザーを隠している理由。そのユーザーのノートはAPIからほとんど返ってこない
+#[derive(Eq, PartialEq, Clone, Copy, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HiddenBy {
+    Muted,
+    Blocked,
+}
+
+/// サーバーの翻訳機能による訳
+#[derive(Deserialize, Serialize)]
+pub struct Translation {
+    /// 訳した先の言語
+    pub lang: String,
+    pub text: String,
+}
+
+impl Note {
+    /// `host`から見たノートとユーザーのURLを埋める。リモートのノートは、APIが返したURLを優先する。
+    pub fn fill_urls(&mut self, host: &Host) {
+        self.local_url = self.url.clone()
+            .or_else(|| self.uri.clone())
+            .or_else(|| Some(host.url(&format!("notes/{}", self.id.0))));
+        self.user.fill_url(host);
+    }
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct PartialUser {
+    // NOTE: その他のプロパティを捨てているのは下流側の正規化が面倒になるため
+    pub id: UserId,
+    /// URLを組み立てるためだけに読む
+    #[serde(default, skip_serializing)]
+    pub username: Option<String>,
+    /// リモートのユーザーなら、そのサーバー
+    #[serde(default, skip_serializing)]
+    pub host: Option<String>,
+    #[serde(default, skip_serializing)]
+    pub name: Option<String>,
+    #[serde(default, rename = "avatarUrl", skip_serializing)]
+    pub avatar_url: Option<Url>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub local_url: Option<Url>,
+    /// `--inline-user-detail`のときだけ埋めて書き出す。
+    #[serde(skip_deserializing, flatten)]
+    pub detail: Option<InlineUserDetail>,
+}
+
+/// タイムラインの応答に既に含まれている、表示に使うユーザーの情報
+#[derive(Serialize)]
+pub struct InlineUserDetail {
+    pub username: Option<String>,
+    pub name: Option<String>,
+    #[serde(rename = "avatarUrl")]
+    pub avatar_url: Option<Url>,
+    pub host: Option<String>,
+}
+
+impl PartialUser {
+    pub fn inline_detail(&mut self) {
+        self.detail = Some(InlineUserDetail {
+            username: self.username.clone(),
+            name: self.name.clone(),
+            avatar_url: self.avatar_url.clone(),
+            host: self.host.clone(),
+        });
+    }
+
+    fn fill_url(&mut self, host: &Host) {
+        let Some(username) = &self.username else {
+            return
+        };
+        let acct = self.host.as_ref().map_or_else(|| format!("@{username}"), |remote| format!("@{username}@{remote}"));
+        self.local_url = Some(host.url(&acct));
+    }
+}
+
+/// ノートに付いたリアクションとその数。何百種類も付くことがあるので、表にはせずAPIが返した順に並べて持つ。
+#[derive(Default, Debug)]
+pub struct Reactions(pub Vec<(CanonicalEmojiKey, NonZeroUsize)>);
+
+impl Reactions {
+    pub const fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// 全てのリアクションの数の合計
+    pub fn total(&self) -> usize {
+        self.0.iter().map(|x| x.1.get()).sum()
+    }
+
+    /// 数の多い`n`種類だけを残し、残さなかったものの数の合計を返す。同じ数なら先にあった方を残す。
+    pub fn keep_top(&mut self, n: usize) -> usize {
+        if self.0.len() <= n {
+            return 0
+        }
+        self.0.sort_by_key(|x| std::cmp::Reverse(x.1));
+
+        self.0.split_off(n).iter().map(|x| x.1.get()).sum()
+    }
+}
+
+impl<'de> Deserialize<'de> for Reactions {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error> where D: Deserializer<'de> {
+        struct ReactionsVisitor;
+
+        impl<'de> Visitor<'de> for ReactionsVisitor {
+            type Value = Reactions;
+
+            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                formatter.write_str("a map from reactions to their counts")
+            }
+
+            fn visit_map<A>(self, mut map: A) -> Result<Self::Value, A::Error> where A: MapAccess<'de> {
+                let mut reactions = Vec::with_capacity(map.size_hint().unwrap_or(0));
+                while let Some(entry) = map.next_entry()? {
+                    reactions.push(entry);
+                }
+
+                Ok(Reactions(reactions))
+            }
+        }
+
+        deserializer.deserialize_map(ReactionsVisitor)
+    }
+}
+
+impl Serialize for Reactions {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error> where S: Serializer {
+        serializer.collect_map(self.0.iter().map(|(key, count)| (key, count)))
+    }
+}
+
+#[derive(Eq, PartialEq, Hash, Debug)]
+pub enum CanonicalEmojiKey {
+    SingleCodepointPunctuation(char),
+    BoxedSingleDigit {
+        digit: u8,
+    },
+    Unicode {
+        utf8: String,
+    },
+    Custom {
+        name: EmojiName,
+        host: LocalOnly,
+    },
+    Uncategorized(String),
+}
+
+impl<'de> Deserialize<'de> for CanonicalEmojiKey {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error> where D: Deserializer<'de> {
+        let raw = String::deserialize(deserializer)?;
+        // ヒント: もしこれがエラーに見えているならIntelliJがおかしい
+        let pat = lazy_regex::regex!(r#"^:([a-z0-9_-]+)@\.:$"#);
+
+        let mut chars = raw.chars();
+        // 壊れたサーバーが空のキーを返しても落ちない
+        let Some(first) = chars.next() else {
+            return Ok(Self::Uncategorized(raw))
+        };
+        let second = chars.next();
+
+        if let Some(captures) = pat.captures(&raw) {
+            let m = captures;
+            let name_range = m.get(1).expect("should be match").range();
+            // TODO: おそらくこの再アロケーションは避けられる
+            let name = EmojiName(raw[name_range].to_owned());
+
+            Ok(Self::Custom {
+                name,
+                host: LocalOnly,
+            })
+        } else if let Some(emoji) = emojis::get(&raw).filter(|x| x.as_str() == raw) {
+            // 絵文字は単にUnicodeの「文字」であることもある
+            Ok(Self::Unicode {
+                utf8: emoji.to_string()
+            })
+        } else if first.is_ascii_digit() && second == Some('\u{20e3}') {
+            Ok(Self::BoxedSingleDigit {
+                // Unicodeでは0-9は一列に並んでいるのでオフセットは引き算するだけで求められる
+                digit: u8::try_from(first as u32 - '0' as u32).expect("oops"),
+            })
+        } else if first.is_ascii_punctuation() && second.is_none() {
+            Ok(Self::SingleCodepointPunctuation(first))
+        } else {
+            Ok(Self::Uncategorized(raw))
+        }
+    }
+}
+
+impl Serialize for CanonicalEmojiKey {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error> where S: Serializer {
+        match self {
+            Self::Unicode { utf8 } => {
+                serializer.serialize_str(utf8)
+            }
+            Self::Custom { name, .. } => {
+                let s = format!(":{}@.:", name.0);
+                serializer.serialize_str(&s)
+            }
+            Self::SingleCodepointPunctuation(c) => {
+                serializer.serialize_char(*c)
+            }
+            Self::BoxedSingleDigit { digit } => {
+                serializer.serialize_str(&format!("{digit}\u{20e3}"))
+            }
+            Self::Uncategorized(s) => {
+                serializer.serialize_str(s)
+            }
+        }
+    }
+}
+
+#[derive(Eq, PartialEq, Hash, Debug)]
+pub struct EmojiName(pub String);
+
+#[derive(Eq, PartialEq, Hash, Debug)]
+pub struct LocalOnly;
+
+#[derive(Deserialize, Serialize)]
+pub struct MisskeyFlavoredMarkdown(pub String);
+
+#[derive(Serialize, Deserialize)]
+pub struct DetailedUser {
+    pub id: UserId,
+    #[serde(rename = "name")]
+    /// スクリーンネームを設定していない場合は[`None`]。その場合、見える文字列はmentionであるべき。
+    pub screen_name: Option<String>,
+    #[serde(rename = "username")]
+    pub mention: String,
+    #[serde(rename = "isBot")]
+    pub is_bot: bool,
+    #[serde(rename = "isCat")]
+    pub is_cat: bool,
+    #[serde(rename = "avatarUrl")]
+    /// 現在のアイコンのURL
+    pub icon_url: Url,
+    #[serde(rename = "notesCount")]
+    pub total_notes: usize,
+    /// プロフィールにピン留めしたノート。古いバージョンでは返ってこない
+    #[serde(default, rename = "pinnedNoteIds", skip_serializing_if = "Vec::is_empty")]
+    pub pinned_note_ids: Vec<NoteId>,
+    /// `pinnedNoteIds`のノートそのもの。返さないサーバーもあるので、`--user-cache`には残さない
+    #[serde(default, rename = "pinnedNotes", skip_serializing)]
+    pub pinned_notes: Vec<Note>,
+    /// 凍結されているか。返さないサーバーでは[`None`]
+    #[serde(default, rename = "isSuspended", skip_serializing_if = "Option::is_none")]
+    pub is_suspended: Option<bool>,
+    /// フォローを承認制にしているか
+    #[serde(default, rename = "isLocked", skip_serializing_if = "Option::is_none")]
+    pub is_locked: Option<bool>,
+    /// 削除を待っているアカウントか。多くのサーバーは、消えたユーザーを返さず`NO_SUCH_USER`にする
+    #[serde(default, rename = "isDeleted", skip_serializing_if = "Option::is_none")]
+    pub is_deleted: Option<bool>,
+}
+
+/// `i`で得られる、認証に使っているアカウント
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Account {
+    pub id: UserId,
+    pub username: String,
+}
+
+/// `users/lists/show`が返すリスト
+#[derive(Serialize, Deserialize)]
+pub struct UserList {
+    pub id: ListId,
+    pub name: String,
+    #[serde(default, rename(deserialize = "userIds"))]
+    pub user_ids: Vec<UserId>,
+}
+
+/// `hashtags/trend`が返す、1つのハッシュタグ
+#[derive(Serialize, Deserialize)]
+pub struct TrendingTag {
+    pub tag: String,
+    /// 最近使ったユーザーの数
+    #[serde(rename(deserialize = "usersCount"))]
+    pub users_count: usize,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Channel {
+    pub id: ChannelId,
+    pub name: String,
+    /// フォークによっては返ってこない
+    #[serde(rename = "notesCount")]
+    pub notes_count: Option<usize>,
+    #[serde(default)]
+    pub description: Option<String>,
+    #[serde(default, rename = "bannerUrl")]
+    pub banner_url: Option<Url>,
+    /// 古いバージョンでは返ってこない
+    #[serde(default, rename = "pinnedNoteIds")]
+    pub pinned_note_ids: Vec<NoteId>,
+    /// 取得したアカウントがフォローしているか。認証していなければ返ってこない
+    #[serde(default, rename = "isFollowing", skip_serializing)]
+    pub is_following: Option<bool>,
+    /// 作った人
+    #[serde(default, rename = "userId", skip_serializing)]
+    pub user_id: Option<UserId>,
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use crate::model::{CanonicalEmojiKey, Note, Reactions};
+    use crate::testing::note_json;
+
+    #[test]
+    fn only_the_most_reacted_are_kept_and_the_rest_rolled_up() {
+        let mut value = note_json("n1", "2024-01-01T00:00:00.000Z");
+        // 1から1000まで、数の違う1000種類
+        value["reactions"] = (1..=1000).map(|i| (format!(":e{i}@.:"), json!(i))).collect::<serde_json::Map<_, _>>().into();
+        let mut note: Note = serde_json::from_value(value).unwrap();
+        assert_eq!(note.reactions.0.len(), 1000);
+        assert_eq!(note.reactions.total(), 500_500);
+
+        let other = note.reactions.keep_top(3);
+
+        assert_eq!(other, 500_500 - 1000 - 999 - 998);
+        assert_eq!(serde_json::to_value(&note.reactions).unwrap(), json!({ ":e1000@.:": 1000, ":e999@.:": 999, ":e998@.:": 998 }));
+        assert_eq!(note.reactions.keep_top(3), 0);
+    }
+
+    #[test]
+    fn empty_reaction_keys_are_uncategorized() {
+        let reactions: Reactions = serde_json::from_value(json!({ "": 1 })).unwrap();
+        assert_eq!(reactions.0.len(), 1);
+        assert_eq!(reactions.0[0].0, CanonicalEmojiKey::Uncategorized(String::new()));
+        assert_eq!(reactions.total(), 1);
+    }
+}
@@ -0,0 +1,145 @@
+//! 1回の実行で書き出したノートのID。`HashSet<NoteId>`の代わりに、IDを固定長のバイト列に詰めて持つ。
+//!
+//! Misskeyの既定の`aid`(10文字)と`aidx`(16文字)は[`WIDTH`]バイトに収まるので、整列した[`Vec`]に詰めて二分探索で引く。
+//! 新しいIDは`pending`に溜め、`sorted`の1/16を超えたら後ろから混ぜ込む。1つのIDにかかるのはおよそ16バイトで、
+//! `sorted`を伸ばす間だけ古い領域と新しい領域が並ぶので、多くて32バイトになる。100万個では、`HashSet<NoteId>`が
+//! 62MBほど確保するのに対し、多いときでも30MBほどに収まる。
+//! 収まらない長さのID(`meid`や`objectid`など)だけは、[`HashSet`]にそのまま持つ。
+
+use std::collections::HashSet;
+
+use crate::model::NoteId;
+
+/// 詰める長さ。`aidx`の長さ
+const WIDTH: usize = 16;
+
+/// `pending`がこれより小さいうちは混ぜ込まない
+const MIN_PENDING: usize = 4096;
+
+type Packed = [u8; WIDTH];
+
+#[derive(Default)]
+pub struct NoteSet {
+    /// 整列済み。同じものは無い
+    sorted: Vec<Packed>,
+    /// まだ`sorted`に混ぜていないもの
+    pending: HashSet<Packed>,
+    /// [`WIDTH`]に収まらないもの
+    long: HashSet<NoteId>,
+}
+
+impl NoteSet {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 無かったときだけ`true`を返す。
+    pub fn insert(&mut self, id: &NoteId) -> bool {
+        let Some(packed) = pack(id) else {
+            return self.long.insert(id.clone())
+        };
+        if self.sorted.binary_search(&packed).is_ok() || !self.pending.insert(packed) {
+            return false
+        }
+        if self.pending.len() >= MIN_PENDING.max(self.sorted.len() / 16) {
+            self.merge();
+        }
+
+        true
+    }
+
+    pub fn contains(&self, id: &NoteId) -> bool {
+        pack(id).map_or_else(|| self.long.contains(id), |packed| self.pending.contains(&packed) || self.sorted.binary_search(&packed).is_ok())
+    }
+
+    /// `pending`を整列し、`sorted`の後ろを伸ばして大きい方から並べ直す。
+    fn merge(&mut self) {
+        let mut pending: Vec<Packed> = self.pending.drain().collect();
+        pending.sort_unstable();
+        self.pending.shrink_to(MIN_PENDING);
+
+        let mut old = self.sorted.len();
+        self.sorted.reserve_exact(pending.len());
+        self.sorted.resize(old + pending.len(), [0; WIDTH]);
+        for at in (0..self.sorted.len()).rev() {
+            let Some(&last) = pending.last() else {
+                break
+            };
+            if old > 0 && self.sorted[old - 1] > last {
+                self.sorted[at] = self.sorted[old - 1];
+                old -= 1;
+            } else {
+                self.sorted[at] = last;
+                pending.pop();
+            }
+        }
+    }
+}
+
+impl Extend<NoteId> for NoteSet {
+    fn extend<T: IntoIterator<Item = NoteId>>(&mut self, iter: T) {
+        for id in iter {
+            self.insert(&id);
+        }
+    }
+}
+
+/// IDに`\0`は現れないので、後ろを`\0`で埋める。
+fn pack(id: &NoteId) -> Option<Packed> {
+    let bytes = id.0.as_bytes();
+    if bytes.len() > WIDTH || bytes.contains(&0) {
+        return None
+    }
+    let mut packed = [0; WIDTH];
+    packed[..bytes.len()].copy_from_slice(bytes);
+
+    Some(packed)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use crate::model::NoteId;
+    use crate::note_set::NoteSet;
+    use crate::testing::peak_allocation;
+
+    /// `aid`と同じ形の、時刻順に並ぶ10文字のID
+    fn aid(i: u64) -> NoteId {
+        NoteId(format!("9{:09x}", i * 7919))
+    }
+
+    #[test]
+    fn million_ids_fit_in_a_fraction_of_a_hash_set() {
+        const COUNT: u64 = 1_000_000;
+        let (set, peak) = peak_allocation(|| {
+            let mut set = NoteSet::new();
+            // 新しい順に辿るので、逆から入れる
+            for i in (0..COUNT).rev() {
+                assert!(set.insert(&aid(i)));
+            }
+            set
+        });
+        let (_, baseline) = peak_allocation(|| (0..COUNT).map(aid).collect::<HashSet<_>>());
+
+        assert!((0..COUNT).all(|i| set.contains(&aid(i))));
+        assert!(!set.contains(&aid(COUNT)));
+        // 伸ばす間も含めて、1つあたり32バイトを超えない
+        assert!(peak < 32_000_000, "{peak}");
+        assert!(peak < baseline / 2 + baseline / 10, "{peak} vs {baseline}");
+    }
+
+    #[test]
+    fn duplicates_are_rejected_in_every_part() {
+        let mut set = NoteSet::new();
+        let long = NoteId("65a1b2c3d4e5f60718293a4b".to_owned());
+        assert!(set.insert(&long));
+        assert!(!set.insert(&long));
+        for i in 0..10_000 {
+            set.insert(&aid(i));
+        }
+        // 混ぜ込んだものも、まだ`pending`にあるものも
+        assert!((0..10_000).all(|i| !set.insert(&aid(i))));
+        assert!(set.contains(&long) && !set.contains(&aid(10_000)));
+    }
+}
@@ -0,0 +1,161 @@
+//! `--notify-webhook`。実行が終わったら、成否をWebhookに知らせる。
+//!
+//! 知らせられなくても警告を書くだけで、終了コードは変えない。
+
+use std::error::Error;
+use std::time::Duration;
+
+use clap::ValueEnum;
+use url::Url;
+
+use crate::log::info;
+use crate::status::{self, Outcome};
+
+/// 知らせるのを諦めるまでの時間
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// `--notify-format`
+#[derive(Eq, PartialEq, Copy, Clone, Debug, Default, ValueEnum)]
+pub enum NotifyFormat {
+    /// 結果をそのままJSONにしたもの
+    #[default]
+    Generic,
+    /// Discordの`content`
+    Discord,
+    /// Slackの`text`
+    Slack,
+}
+
+/// 実行の結果
+pub struct Notification {
+    pub outcome: Outcome,
+    /// 書き出したノートの数
+    pub notes: u64,
+    pub gaps: usize,
+    pub duration: Duration,
+    pub error: Option<String>,
+}
+
+impl Notification {
+    pub fn new(result: &Result<(), Box<dyn Error + Send + Sync>>, notes: u64, duration: Duration) -> Self {
+        let (outcome, gaps, error) = status::summarize(result);
+
+        Self { outcome, notes, gaps, duration, error }
+    }
+
+    pub fn payload(&self, format: NotifyFormat) -> serde_json::Value {
+        match format {
+            NotifyFormat::Generic => serde_json::json!({
+                "kind": "notification",
+                "outcome": self.outcome.name(),
+                "exit_code": self.outcome.code(),
+                "notes": self.notes,
+                "gaps": self.gaps,
+                "duration_seconds": self.duration.as_secs_f64(),
+                "error": self.error,
+            }),
+            NotifyFormat::Discord => serde_json::json!({
+                "username": env!("CARGO_PKG_NAME"),
+                "content": self.text(),
+            }),
+            NotifyFormat::Slack => serde_json::json!({
+                "text": self.text(),
+            }),
+        }
+    }
+
+    /// チャットで読む1、2行の文
+    fn text(&self) -> String {
+        let icon = if self.outcome == Outcome::Success { "✅" } else { "⚠️" };
+        let mut text = format!(
+            "{icon} {}: {} ({} notes, {} gaps, {}s)",
+            env!("CARGO_PKG_NAME"),
+            self.outcome.name(),
+            self.notes,
+            self.gaps,
+            self.duration.as_secs(),
+        );
+        if let Some(error) = &self.error {
+            text.push_str("\nerror: ");
+            text.push_str(error);
+        }
+
+        text
+    }
+}
+
+/// 失敗しても警告を書くだけにする。
+pub async fn send(url: &Url, format: NotifyFormat, notification: &Notification) {
+    let result = async {
+        reqwest::Client::builder().use_rustls_tls().timeout(TIMEOUT).build()?
+            .post(url.clone())
+            .json(&notification.payload(format))
+            .send().await?
+            .error_for_status()
+    }.await;
+
+    if let Err(e) = result {
+        info!("notify: could not send the notification to {url}: {e}; the exit code is unchanged");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::time::Duration;
+
+    use serde_json::json;
+
+    use crate::notify::{send, Notification, NotifyFormat};
+    use crate::status::Outcome;
+
+    /// 1つのリクエストを受け、本文を返す。
+    fn receive_one(listener: &TcpListener) -> serde_json::Value {
+        let mut reader = BufReader::new(listener.incoming().next().unwrap().unwrap());
+        let mut head = String::new();
+        while !head.ends_with("\r\n\r\n") {
+            reader.read_line(&mut head).unwrap();
+        }
+        let length = head.lines()
+            .find_map(|x| x.to_ascii_lowercase().strip_prefix("content-length: ").map(str::to_owned))
+            .map_or(0, |x| x.parse().unwrap());
+        let mut body = vec![0; length];
+        reader.read_exact(&mut body).unwrap();
+        reader.get_mut().write_all(b"HTTP/1.1 204 No Content\r\nConnection: close\r\n\r\n").unwrap();
+
+        serde_json::from_slice(&body).unwrap()
+    }
+
+    #[tokio::test]
+    async fn payload_shape_for_each_format() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap()).parse().unwrap();
+        let server = std::thread::spawn(move || [(); 3].map(|()| receive_one(&listener)));
+        let notification = Notification {
+            outcome: Outcome::CompletedWithGaps,
+            notes: 120,
+            gaps: 1,
+            duration: Duration::from_millis(61_500),
+            error: Some("1 of 2 channel(s) failed".to_owned()),
+        };
+
+        for format in [NotifyFormat::Generic, NotifyFormat::Discord, NotifyFormat::Slack] {
+            send(&url, format, &notification).await;
+        }
+        let [generic, discord, slack] = server.join().unwrap();
+
+        let text = "⚠️ misskey-channel-archiver: completed-with-gaps (120 notes, 1 gaps, 61s)\nerror: 1 of 2 channel(s) failed";
+        assert_eq!(generic, json!({
+            "kind": "notification",
+            "outcome": "completed-with-gaps",
+            "exit_code": 2,
+            "notes": 120,
+            "gaps": 1,
+            "duration_seconds": 61.5,
+            "error": "1 of 2 channel(s) failed",
+        }));
+        assert_eq!(discord, json!({ "username": "misskey-channel-archiver", "content": text }));
+        assert_eq!(slack, json!({ "text": text }));
+    }
+}
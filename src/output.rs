@@ -0,0 +1,670 @@
+//! データの書き出し先。
+//!
+//! ファイルへは行単位でしか書かないので、途中で落ちても最後の行が欠けることはない。
+//! `--no-atomic`でなければ`<name>.tmp`に書き、正常に終わったときに名前を変える。
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU8, Ordering};
+
+use chrono::{DateTime, Utc};
+use clap::ValueEnum;
+
+use crate::append::Seen;
+use crate::chunk::ChunkWriter;
+use crate::content_hash;
+use crate::filename::{sanitize_filename, UniqueNames};
+use crate::language;
+use crate::log::info;
+use crate::model::{ChannelId, DetailedUser, Note, NoteId, UserId};
+use crate::field_map::{FieldMap, Renamed, Renames};
+use crate::provenance::{Run, Stamped};
+use crate::sink::{self, HttpSink, OutputSink, RemoteSink, StreamSink};
+use crate::split::{SplitBy, SplitWriter};
+use crate::timestamp::{Formatted, TimestampFormat};
+use crate::timezone::Timezone;
+use crate::tree::{TreeWriter, UserTree};
+use crate::user_map::UserMap;
+
+/// `--output`にこれが含まれていると、チャンネルごとに別のファイルへ書き出す。
+pub const CHANNEL_PLACEHOLDER: &str = "{channel}";
+
+/// これ以上溜まったら、区切りを待たずに完結した行を書き出す
+const CAPACITY: usize = 64 * 1024;
+
+/// `remote`があればそこへ送る。無ければ`--output`のファイルか、それも無ければ標準出力を開く。
+/// `--sink s3`では、`path`のファイル名がオブジェクトの名前になる。
+pub fn open(path: Option<&Path>, atomic: bool, remote: Option<&RemoteSink>) -> io::Result<Sink> {
+    let target: Box<dyn OutputSink> = match (remote, path) {
+        (Some(RemoteSink::Http(http)), _) => Box::new(HttpSink::new(http)),
+        #[cfg(feature = "s3")]
+        (Some(RemoteSink::S3(s3)), Some(path)) => Box::new(crate::s3::S3Sink::new(s3, path)),
+        #[cfg(feature = "s3")]
+        (Some(RemoteSink::S3(_)), None) => return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "--sink s3 uploads the file named by --output, so it cannot be used with --split-by or --output-layout tree",
+        )),
+        (None, Some(path)) => Box::new(RecordFile::create(path, atomic)?),
+        // `Stdout`は改行ごとにロックして書くので、まとめてから渡す
+        (None, None) => Box::new(StreamSink(io::BufWriter::with_capacity(CAPACITY, io::stdout()))),
+    };
+
+    Ok(Sink { target, pending: Vec::new(), flush: flush_policy() })
+}
+
+/// `open`と同じだが、`--output`のファイルがあれば消さずに書き足す。一時ファイルは使わないので、
+/// 途中で止まっても、書いた分はファイルに残る。
+pub fn open_appending(path: Option<&Path>, remote: Option<&RemoteSink>) -> io::Result<Sink> {
+    match (remote, path) {
+        (None, Some(path)) => Ok(Sink { target: Box::new(RecordFile::resume(path)?), pending: Vec::new(), flush: flush_policy() }),
+        _ => open(path, false, remote),
+    }
+}
+
+/// `--flush`。書いた記録をいつ標準出力やファイル、`--sink`に渡すか
+#[derive(Eq, PartialEq, Copy, Clone, Debug, Default, ValueEnum)]
+pub enum FlushPolicy {
+    /// 記録を1つ書くたびに渡す。書いている最中の出力を読む人向け
+    EveryRecord = 0,
+    /// ページを書き終えるたびと、終わるときに渡す
+    #[default]
+    EveryPage = 1,
+    /// 溜まったときと、終わるときにだけ渡す
+    Manual = 2,
+}
+
+static FLUSH: AtomicU8 = AtomicU8::new(FlushPolicy::EveryPage as u8);
+
+pub fn init(flush: FlushPolicy) {
+    FLUSH.store(flush as u8, Ordering::Relaxed);
+}
+
+fn flush_policy() -> FlushPolicy {
+    match FLUSH.load(Ordering::Relaxed) {
+        0 => FlushPolicy::EveryRecord,
+        2 => FlushPolicy::Manual,
+        _ => FlushPolicy::EveryPage,
+    }
+}
+
+/// 書き込み中の内容を置いておくファイルの名前
+pub fn temporary_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".tmp");
+    PathBuf::from(name)
+}
+
+/// 書かれた内容を行に分け、1行ずつ[`OutputSink`]に渡す。
+pub struct Sink {
+    target: Box<dyn OutputSink>,
+    /// まだ改行が来ていない内容
+    pending: Vec<u8>,
+    flush: FlushPolicy,
+}
+
+impl Sink {
+    /// 全て書き終えたときに呼ぶ。呼ばずに捨てると、一時ファイルはそのまま残る。
+    /// ファイルに書いていたなら、その名前を返す。
+    pub fn finish(mut self) -> io::Result<Option<PathBuf>> {
+        if !self.pending.is_empty() {
+            self.pending.push(b'\n');
+            self.pass_complete_lines()?;
+        }
+
+        self.target.finish()
+    }
+
+    fn pass_complete_lines(&mut self) -> io::Result<()> {
+        let Some(end) = self.pending.iter().rposition(|&b| b == b'\n') else {
+            return Ok(())
+        };
+        let lines: Vec<u8> = self.pending.drain(..=end).collect();
+        pass_lines(&mut *self.target, &lines, self.flush)
+    }
+}
+
+fn pass_lines(target: &mut dyn OutputSink, lines: &[u8], flush: FlushPolicy) -> io::Result<()> {
+    for line in String::from_utf8_lossy(lines).lines() {
+        target.write_record(line)?;
+        if flush == FlushPolicy::EveryRecord {
+            target.flush()?;
+        }
+    }
+
+    Ok(())
+}
+
+impl Write for Sink {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.pending.is_empty() {
+            // 書きかけの行が無ければ、ページのような大きな行も写さずに渡す
+            let end = buf.iter().rposition(|&b| b == b'\n').map_or(0, |x| x + 1);
+            pass_lines(&mut *self.target, &buf[..end], self.flush)?;
+            self.pending.extend_from_slice(&buf[end..]);
+            return Ok(buf.len())
+        }
+        self.pending.extend_from_slice(buf);
+        if buf.contains(&b'\n') {
+            self.pass_complete_lines()?;
+        }
+
+        Ok(buf.len())
+    }
+
+    /// ページを書き終えたときに呼ばれる
+    fn flush(&mut self) -> io::Result<()> {
+        self.pass_complete_lines()?;
+        if self.flush == FlushPolicy::Manual {
+            return Ok(())
+        }
+        self.target.flush()
+    }
+}
+
+/// 改行で終わる完結した行だけを、1回の書き込みでファイルに渡す。
+pub struct RecordFile {
+    file: File,
+    /// まだファイルに渡していない内容
+    pending: Vec<u8>,
+    path: PathBuf,
+    atomic: bool,
+}
+
+impl RecordFile {
+    pub fn create(path: &Path, atomic: bool) -> io::Result<Self> {
+        let target = if atomic { temporary_path(path) } else { path.to_path_buf() };
+
+        Ok(Self::new(File::create(target)?, path, atomic))
+    }
+
+    /// この実行で既に`create`したファイルに書き足す。
+    pub fn append(path: &Path, atomic: bool) -> io::Result<Self> {
+        let target = if atomic { temporary_path(path) } else { path.to_path_buf() };
+
+        Ok(Self::new(OpenOptions::new().append(true).open(target)?, path, atomic))
+    }
+
+    /// 前の実行で書いたファイルに書き足す。無ければ作る。
+    pub fn resume(path: &Path) -> io::Result<Self> {
+        Ok(Self::new(OpenOptions::new().create(true).append(true).open(path)?, path, false))
+    }
+
+    fn new(file: File, path: &Path, atomic: bool) -> Self {
+        Self {
+            file,
+            pending: Vec::new(),
+            path: path.to_path_buf(),
+            atomic,
+        }
+    }
+
+    /// 一時ファイルを本来の名前に変える。
+    pub fn finish(mut self) -> io::Result<PathBuf> {
+        Write::flush(&mut self)?;
+        if self.atomic {
+            fs::rename(temporary_path(&self.path), &self.path)?;
+        }
+
+        Ok(self.path.clone())
+    }
+
+    fn write_complete_lines(&mut self) -> io::Result<()> {
+        if let Some(end) = self.pending.iter().rposition(|&b| b == b'\n') {
+            self.file.write_all(&self.pending[..=end])?;
+            self.pending.drain(..=end);
+        }
+
+        Ok(())
+    }
+}
+
+impl Write for RecordFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.pending.extend_from_slice(buf);
+        if self.pending.len() >= CAPACITY {
+            self.write_complete_lines()?;
+        }
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.write_complete_lines()?;
+        self.file.flush()
+    }
+}
+
+impl Drop for RecordFile {
+    fn drop(&mut self) {
+        // 書きかけの行は捨てる
+        let _ = self.write_complete_lines();
+    }
+}
+
+pub fn is_per_channel(path: &Path) -> bool {
+    path.to_string_lossy().contains(CHANNEL_PLACEHOLDER)
+}
+
+/// チャンネルIDはファイル名として安全な形にし、`names`の中で重ならないようにして埋める。
+pub fn for_channel(template: &Path, channel_id: &ChannelId, names: &mut UniqueNames) -> PathBuf {
+    let name = names.claim(sanitize_filename(&channel_id.0, "_"));
+    PathBuf::from(template.to_string_lossy().replace(CHANNEL_PLACEHOLDER, &name))
+}
+
+/// `--output-layout`
+#[derive(Eq, PartialEq, Copy, Clone, Debug, Default, ValueEnum)]
+pub enum Layout {
+    /// 1ページを1行とするJSON Lines
+    #[default]
+    Lines,
+    /// `--output`のディレクトリに、1つのノートを1つのファイルとして書く
+    Tree,
+    /// `--output`のディレクトリに、`--chunk-size`ずつのノートを1つのファイルとして書く
+    Chunked,
+}
+
+/// `fetch-user`の`--output-layout`
+#[derive(Eq, PartialEq, Copy, Clone, Debug, Default, ValueEnum)]
+pub enum UserLayout {
+    /// 1人を1行とするJSON Lines
+    #[default]
+    Lines,
+    /// `--output`のディレクトリに、1人のユーザーを1つのファイルとして書く
+    PerUser,
+    /// 全てのユーザーを、IDをキーとする1つのJSONオブジェクトにまとめて最後に書く
+    Map,
+}
+
+/// `fetch-user`のユーザーの書き出し先
+pub enum UserOutput {
+    Lines,
+    Tree(UserTree),
+    Map(UserMap),
+}
+
+impl UserOutput {
+    pub fn write_user(&mut self, out: &mut (impl Write + ?Sized), user: &DetailedUser, renames: &Renames, fetched_at: DateTime<Utc>) -> io::Result<()> {
+        match self {
+            Self::Lines => writeln!(out, "{}", serde_json::to_string(&Renamed(user, renames))?),
+            Self::Tree(tree) => tree.write_user(user, renames, fetched_at),
+            Self::Map(map) => map.insert(user, renames).map_err(io::Error::from),
+        }
+    }
+
+    /// `users/show`が`NO_SUCH_USER`を返したユーザー。1行ずつ書くときだけ`{"kind": "user-tombstone"}`を書き、
+    /// ほかでは前の実行で書いたものを残す。
+    pub fn write_missing(&self, out: &mut (impl Write + ?Sized), user_id: &UserId, checked_at: DateTime<Utc>) -> io::Result<()> {
+        match self {
+            Self::Lines => writeln!(out, "{}", serde_json::json!({ "kind": "user-tombstone", "user_id": user_id, "checked_at": checked_at })),
+            Self::Tree(_) | Self::Map(_) => {
+                info!("user {} no longer exists; keeping what was written before", user_id.0);
+                Ok(())
+            }
+        }
+    }
+}
+
+/// 書き出し方の設定
+#[derive(Clone)]
+#[allow(clippy::struct_excessive_bools)]
+pub struct OutputOptions {
+    pub split_by: Option<SplitBy>,
+    /// 日付で振り分けるときに使う
+    pub timezone: Timezone,
+    /// 一時ファイルに書いてから名前を変える
+    pub atomic: bool,
+    pub timestamp_format: TimestampFormat,
+    pub layout: Layout,
+    /// `Layout::Chunked`で、1つのファイルに入れるノートの数
+    pub chunk_size: NonZeroUsize,
+    /// あれば、ログとノートの記録をファイルではなくここへ送る
+    pub sink: Option<RemoteSink>,
+    /// 同じ範囲からは同じバイト列になるよう、記録の形を揃える
+    pub canonical: bool,
+    /// 始める前に、ノートの一部しか見えないかもしれないと分かったチャンネルとその理由
+    pub possibly_incomplete: Vec<(ChannelId, Vec<String>)>,
+    /// `meta`に書き、`canonical`でなければノートにも添える
+    pub run: Run,
+    /// `meta`にも書く
+    pub field_map: FieldMap,
+    /// ノートに`detected_language`を付ける。`meta`にはその推定の仕方の版を書く
+    pub detect_language: bool,
+    /// `--output`のファイルを消さずに書き足し、既にあるノートは書かない
+    pub append: bool,
+    /// `append`で、書いたノートのIDを覚えておくファイル
+    pub append_index: Option<PathBuf>,
+}
+
+impl OutputOptions {
+    /// 読む側が形式を知るために、それぞれのファイルの先頭に書く記録
+    pub fn meta(&self) -> serde_json::Value {
+        let mut meta = serde_json::json!({
+            "kind": "meta",
+            "timestamp_format": self.timestamp_format.name(),
+            "timezone": self.timezone.to_string(),
+            "run_id": self.run.id,
+            "archived_at": self.run.started_at,
+        });
+        if !self.possibly_incomplete.is_empty() {
+            meta["possibly_incomplete"] = true.into();
+        }
+        if !self.field_map.is_empty() {
+            meta["field_map"] = serde_json::to_value(&self.field_map).expect("a map of strings is serializable");
+        }
+        if self.detect_language {
+            meta["language_detector"] = language::DETECTOR.into();
+        }
+
+        meta
+    }
+
+    /// ノートに添える実行。`canonical`なら`meta`にだけ書くので添えない
+    pub const fn stamping(&self) -> Option<Run> {
+        if self.canonical { None } else { Some(self.run) }
+    }
+}
+
+/// ログとノートの書き出し先。`--split-by`や`--output-layout tree`なら、ノートだけを別のファイルに振り分ける。
+pub struct Destination {
+    log: Sink,
+    split: Option<SplitWriter>,
+    tree: Option<TreeWriter>,
+    chunk: Option<ChunkWriter>,
+    timestamp_format: TimestampFormat,
+    /// `--canonical`なら、まだ改行まで届いていない書きかけの記録
+    canonical: Option<Vec<u8>>,
+    /// ノートに添える実行
+    run: Option<Run>,
+    renames: Renames,
+    /// 標準出力が閉じられたときに標準エラー出力へ書く、まだ書き終えていない範囲の`gap`の記録
+    resume: Option<serde_json::Value>,
+    /// `--append`なら、既に書いたノート
+    seen: Option<Seen>,
+}
+
+impl Destination {
+    /// `split_by`があれば`path`はファイル名の元に、`tree`や`chunked`なら書き出すディレクトリになり、ログは標準出力に書く。
+    pub fn open(path: Option<&Path>, options: &OutputOptions) -> io::Result<Self> {
+        let (mut split, mut tree, mut chunk) = (None, None, None);
+        match (path, options.split_by, options.layout) {
+            (Some(path), _, Layout::Tree) => tree = Some(TreeWriter::new(path, options)?),
+            (Some(path), _, Layout::Chunked) => chunk = Some(ChunkWriter::new(path, options)?),
+            (Some(path), Some(by), Layout::Lines) => split = Some(SplitWriter::new(path, by, options)),
+            _ => {}
+        }
+        let log_path = if split.is_some() || tree.is_some() || chunk.is_some() { None } else { path };
+        let (log, seen) = if options.append {
+            if options.sink.is_some() {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, "--append adds to the file named by --output, so it cannot be used with --sink"))
+            }
+            (open_appending(log_path, None)?, Some(Seen::open(log_path, options.append_index.as_deref())?))
+        } else {
+            (open(log_path, options.atomic, options.sink.as_ref())?, None)
+        };
+        let mut destination = Self { log, split, tree, chunk, timestamp_format: options.timestamp_format, canonical: options.canonical.then(Vec::new), run: options.stamping(), renames: options.field_map.note.clone(), resume: None, seen };
+        writeln!(destination.log, "{}", options.meta())?;
+        for (channel_id, reasons) in &options.possibly_incomplete {
+            writeln!(destination, "{}", serde_json::json!({
+                "kind": "warning",
+                "channel_id": channel_id,
+                "possibly_incomplete": true,
+                "message": format!("the archive of this channel may be incomplete: {}", reasons.join("; ")),
+            }))?;
+        }
+
+        Ok(destination)
+    }
+
+    /// 次に書くページの範囲を`gap`の記録として覚えておく。標準出力が閉じられたら、`backfill`で続きを取れるよう、
+    /// これを標準エラー出力に書く。ページを書き終えるたびに置き換える。
+    pub fn set_resume(&mut self, gap: Option<serde_json::Value>) {
+        self.resume = gap;
+    }
+
+    fn closed(&mut self, e: io::Error) -> io::Error {
+        if sink::is_pipe_closed(&e) {
+            if let Some(gap) = self.resume.take() {
+                eprintln!("{gap}");
+            }
+        }
+
+        e
+    }
+
+    /// `--append`で、既に書いたノートを除いて書く。`--split-by`や`--output-layout`とは使えないので、ログと同じところに書く。
+    fn write_unseen(&mut self, parent: Option<&NoteId>, notes: &[Note]) -> io::Result<()> {
+        let notes = self.seen.as_mut().map_or_else(Vec::new, |x| x.retain(notes));
+        if notes.is_empty() {
+            return Ok(())
+        }
+        let stamp = self.run.map(Run::stamp);
+        let notes: Vec<_> = notes.into_iter().map(|x| Renamed(Stamped(Formatted(x, self.timestamp_format), stamp), &self.renames)).collect();
+        let records = match parent {
+            Some(parent) => vec![serde_json::json!({ "kind": "replies", "parent_id": parent, "notes": notes })],
+            None if self.canonical.is_some() => notes.iter().map(|x| serde_json::json!({ "kind": "note", "note": x })).collect(),
+            None => vec![serde_json::to_value(&notes)?],
+        };
+        for record in records {
+            writeln!(self, "{record}")?;
+        }
+
+        Ok(())
+    }
+
+    /// 1つのノートを`kind`の記録として書き出す。
+    pub fn write_note(&mut self, kind: &str, channel_id: &ChannelId, note: &Note) -> io::Result<()> {
+        if let Some(tree) = &mut self.tree {
+            return tree.write_page(std::slice::from_ref(note))
+        }
+        if let Some(chunk) = &mut self.chunk {
+            chunk.write_page(std::slice::from_ref(note));
+            return Ok(())
+        }
+
+        writeln!(self, "{}", serde_json::json!({
+            "kind": kind,
+            "channel_id": channel_id,
+            "note": Renamed(Stamped(Formatted(note, self.timestamp_format), self.run.map(Run::stamp)), &self.renames),
+        }))
+    }
+
+    /// 書き終えたファイルの名前を返す。
+    pub fn finish(mut self) -> io::Result<Vec<PathBuf>> {
+        if let Some(seen) = self.seen.take() {
+            writeln!(self, "{}", serde_json::json!({ "kind": "log", "message": format!("skipped {} notes already present in the output", seen.skipped()) }))?;
+            seen.finish()?;
+        }
+        let mut files = match self.split {
+            Some(split) => split.finish()?,
+            None => vec![],
+        };
+        files.extend(self.tree.map(TreeWriter::finish).unwrap_or_default());
+        if let Some(chunk) = self.chunk {
+            files.extend(chunk.finish()?);
+        }
+        files.extend(self.log.finish()?);
+
+        Ok(files)
+    }
+
+    pub fn write_page(&mut self, notes: &[Note]) -> io::Result<()> {
+        if self.seen.is_some() {
+            return self.write_unseen(None, notes)
+        }
+        if let Some(tree) = &mut self.tree {
+            return tree.write_page(notes)
+        }
+        if let Some(chunk) = &mut self.chunk {
+            chunk.write_page(notes);
+            return Ok(())
+        }
+
+        match &mut self.split {
+            Some(split) => split.write_page(notes),
+            None if self.canonical.is_some() => {
+                for note in notes {
+                    writeln!(self, "{}", serde_json::json!({ "kind": "note", "note": Renamed(Formatted(note, self.timestamp_format), &self.renames) }))?;
+                }
+                Ok(())
+            }
+            None => {
+                // `writeln!`はページと改行を分けて渡すので、1つにまとめてから書く
+                let mut page = serde_json::to_vec(&Renamed(Stamped(Formatted(notes, self.timestamp_format), self.run.map(Run::stamp)), &self.renames))?;
+                page.push(b'\n');
+                self.log.write_all(&page).map_err(|e| self.closed(e))
+            }
+        }
+    }
+
+    /// `parent`への返信を書き出す。振り分ける場合は普通のノートと同じように扱う。
+    pub fn write_replies(&mut self, parent: &NoteId, notes: &[Note]) -> io::Result<()> {
+        if self.seen.is_some() {
+            return self.write_unseen(Some(parent), notes)
+        }
+        if let Some(tree) = &mut self.tree {
+            return tree.write_page(notes)
+        }
+        if let Some(chunk) = &mut self.chunk {
+            chunk.write_page(notes);
+            return Ok(())
+        }
+
+        match &mut self.split {
+            Some(split) => split.write_page(notes),
+            None => writeln!(self, "{}", serde_json::json!({
+                "kind": "replies",
+                "parent_id": parent,
+                "notes": Renamed(Stamped(Formatted(notes, self.timestamp_format), self.run.map(Run::stamp)), &self.renames),
+            })),
+        }
+    }
+}
+
+impl Write for Destination {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let Self { log, canonical: Some(pending), .. } = self else {
+            return self.log.write(buf).map_err(|e| self.closed(e))
+        };
+        pending.extend_from_slice(buf);
+        while let Some(end) = pending.iter().position(|x| *x == b'\n') {
+            let line: Vec<u8> = pending.drain(..=end).collect();
+            if let Err(e) = write_canonical(log, &line) {
+                return Err(self.closed(e))
+            }
+        }
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if let Some(split) = &mut self.split {
+            split.flush()?;
+        }
+        self.log.flush().map_err(|e| self.closed(e))
+    }
+}
+
+/// キーを並べ直して1行に書く。実行ごとに変わる`log`は、ファイルではなく標準エラー出力に書く。
+fn write_canonical(log: &mut Sink, line: &[u8]) -> io::Result<()> {
+    let Ok(record) = serde_json::from_slice::<serde_json::Value>(line) else {
+        return log.write_all(line)
+    };
+    if record["kind"] == "log" {
+        info!("{}", record["message"].as_str().unwrap_or_default());
+        return Ok(())
+    }
+
+    writeln!(log, "{}", content_hash::canonical_json(&record))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::io::{BufWriter, LineWriter, Write};
+    use std::sync::{Arc, Mutex};
+
+    use crate::output::{temporary_path, FlushPolicy, RecordFile, Sink, CAPACITY};
+    use crate::sink::StreamSink;
+    use crate::testing::capture_dir;
+
+    /// 書き込みを数える。パイプなら、1回の書き込みが1回のシステムコールになる
+    #[derive(Clone, Default)]
+    struct Counting(Arc<Mutex<(usize, Vec<u8>)>>);
+
+    impl Write for Counting {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            let mut written = self.0.lock().unwrap();
+            written.0 += 1;
+            written.1.extend_from_slice(buf);
+            drop(written);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// 100件ずつのページに分けて、10万件の記録を書く
+    fn write_records(target: Box<dyn crate::sink::OutputSink>, flush: FlushPolicy) {
+        let mut sink = Sink { target, pending: vec![], flush };
+        for i in 0..100_000 {
+            writeln!(sink, "{{\"kind\":\"note\",\"note\":{{\"id\":\"n{i}\"}}}}").unwrap();
+            if i % 100 == 99 {
+                sink.flush().unwrap();
+            }
+        }
+        sink.finish().unwrap();
+    }
+
+    #[test]
+    fn buffered_stdout_writes_far_less_often_than_line_by_line() {
+        // `Stdout`と同じく、改行ごとに渡す
+        let naive = Counting::default();
+        write_records(Box::new(StreamSink(LineWriter::new(naive.clone()))), FlushPolicy::EveryRecord);
+        let mut written = vec![];
+        for flush in [FlushPolicy::EveryPage, FlushPolicy::Manual] {
+            let buffered = Counting::default();
+            write_records(Box::new(StreamSink(BufWriter::with_capacity(CAPACITY, LineWriter::new(buffered.clone())))), flush);
+            written.push(Arc::try_unwrap(buffered.0).unwrap().into_inner().unwrap());
+        }
+
+        let naive = Arc::try_unwrap(naive.0).unwrap().into_inner().unwrap();
+        assert!(naive.0 >= 100_000, "{}", naive.0);
+        let [(pages, by_page), (manual, by_manual)] = <[_; 2]>::try_from(written).unwrap();
+        assert_eq!((&by_page, &by_manual), (&naive.1, &naive.1));
+        // ページごとに1回と、溜まるごとに1回
+        assert!(pages <= 100_000 / 100 + 1, "{pages}");
+        assert!(manual <= naive.1.len() / CAPACITY + 1, "{manual}");
+    }
+
+    #[test]
+    fn crash_leaves_only_complete_lines_in_temporary_file() {
+        let dir = capture_dir("atomic-crash", &[]);
+        let path = dir.join("archive.jsonl");
+
+        let mut file = RecordFile::create(&path, true).unwrap();
+        writeln!(file, "{{\"kind\":\"log\"}}").unwrap();
+        file.flush().unwrap();
+        write!(file, "[{{\"id\":\"trunc").unwrap();
+        drop(file);
+
+        assert!(!path.exists());
+        assert_eq!(fs::read_to_string(temporary_path(&path)).unwrap(), "{\"kind\":\"log\"}\n");
+    }
+
+    #[test]
+    fn finish_renames_temporary_file() {
+        let dir = capture_dir("atomic-finish", &[]);
+        let path = dir.join("archive.jsonl");
+
+        let mut file = RecordFile::create(&path, true).unwrap();
+        writeln!(file, "[]").unwrap();
+        file.finish().unwrap();
+
+        assert!(!temporary_path(&path).exists());
+        assert_eq!(fs::read_to_string(&path).unwrap(), "[]\n");
+    }
+}
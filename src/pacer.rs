@@ -0,0 +1,208 @@
+//! リクエストの間隔を保つ。全てのAPI呼び出しで1つを共有する。
+//!
+//! `interval`ごとに1つ補充され、最大`burst`個まで貯まるトークンバケットとして振る舞う。
+//! `burst`が1なら、単に前回のリクエストから`interval`以上空けるだけになる。
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{self, Write};
+use std::num::NonZeroU32;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use chrono::{DateTime, TimeDelta, Utc};
+use serde::{Deserialize, Serialize};
+use tokio::time::{sleep_until, Instant};
+
+use crate::log::info;
+use crate::metrics::Metrics;
+use crate::output::RecordFile;
+
+pub struct Pacer {
+    interval: Duration,
+    burst: NonZeroU32,
+    /// 次にトークンが1つも無くなる予定の時刻
+    theoretical_arrival: Mutex<Option<Instant>>,
+    metrics: Option<Arc<Metrics>>,
+}
+
+impl Pacer {
+    pub const fn with_burst(interval: Duration, burst: NonZeroU32) -> Self {
+        Self {
+            interval,
+            burst,
+            theoretical_arrival: Mutex::new(None),
+            metrics: None,
+        }
+    }
+
+    /// 待った時間を`metrics`に足す。
+    pub fn with_metrics(self, metrics: Arc<Metrics>) -> Self {
+        Self {
+            metrics: Some(metrics),
+            ..self
+        }
+    }
+
+    /// トークンが1つ得られるまで待つ。
+    pub async fn wait(&self) {
+        let now = Instant::now();
+        let at = {
+            let mut tat = self.theoretical_arrival.lock().expect("poisoned");
+            let current = tat.unwrap_or(now).max(now);
+            let tolerance = self.interval.saturating_mul(self.burst.get() - 1);
+            let at = current.checked_sub(tolerance).unwrap_or(now).max(now);
+            *tat = Some(current + self.interval);
+            at
+        };
+
+        if let Some(metrics) = &self.metrics {
+            metrics.record_cool_down(at.saturating_duration_since(now));
+        }
+        // 待たなくてよければ、他の処理に順番を譲らずにそのまま送る
+        if at > now {
+            sleep_until(at).await;
+        }
+    }
+
+    /// 次にトークンが1つも無くなる予定の時刻を、`at`より前にしない。
+    fn resume_at(&self, at: Instant) {
+        let mut tat = self.theoretical_arrival.lock().expect("poisoned");
+        *tat = Some(tat.map_or(at, |x| x.max(at)));
+    }
+
+    fn theoretical_arrival(&self) -> Option<Instant> {
+        *self.theoretical_arrival.lock().expect("poisoned")
+    }
+}
+
+/// `--pacer-state`のファイル。同じインスタンスへの別の実行と、トークンバケットを引き継ぐ。
+#[derive(Default, Serialize, Deserialize)]
+struct StateFile {
+    hosts: BTreeMap<String, HostState>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+struct HostState {
+    /// 次にトークンが1つも無くなる予定の時刻。過ぎていれば、バケットは満ちている
+    next_free_at: DateTime<Utc>,
+    updated_at: DateTime<Utc>,
+}
+
+/// 読めなくても書けなくても、警告を書くだけにする。ペースが前の実行とつながらないだけで、取得には関わらない。
+pub struct PacerState {
+    path: PathBuf,
+    host: String,
+    loaded_at: DateTime<Utc>,
+}
+
+impl PacerState {
+    /// `host`について前の実行が残した時刻がまだ来ていなければ、`pacer`をそこから始める。
+    pub fn load(path: &Path, host: String, pacer: &Pacer) -> Self {
+        let loaded_at = Utc::now();
+        if let Some(state) = read_state(path).hosts.get(&host) {
+            if let Ok(ahead) = (state.next_free_at - loaded_at).to_std() {
+                info!("pacer: continuing from {path}; the next request waits {ahead:?}", path = path.display());
+                pacer.resume_at(Instant::now() + ahead);
+            }
+        }
+
+        Self { path: path.to_path_buf(), host, loaded_at }
+    }
+
+    /// `pacer`の状態を書く。読んでから他の実行が書き換えていたら、警告して遅い方の時刻を残す。
+    pub fn save(&self, pacer: &Pacer) {
+        let Some(tat) = pacer.theoretical_arrival() else {
+            return
+        };
+        let now = Utc::now();
+        let ahead = TimeDelta::from_std(tat.saturating_duration_since(Instant::now())).unwrap_or(TimeDelta::MAX);
+        let mut state = HostState { next_free_at: now + ahead, updated_at: now };
+
+        let mut file = read_state(&self.path);
+        if let Some(other) = file.hosts.get(&self.host).filter(|x| x.updated_at > self.loaded_at) {
+            info!("pacer: another invocation updated {} while this one ran; keeping the later of the two", self.path.display());
+            state.next_free_at = state.next_free_at.max(other.next_free_at);
+        }
+        file.hosts.insert(self.host.clone(), state);
+
+        let result = RecordFile::create(&self.path, true).and_then(|mut out| {
+            writeln!(out, "{}", serde_json::to_string_pretty(&file)?)?;
+            out.finish()
+        });
+        if let Err(e) = result {
+            info!("pacer: could not write {}: {e}", self.path.display());
+        }
+    }
+}
+
+fn read_state(path: &Path) -> StateFile {
+    match fs::read(path) {
+        Ok(data) => serde_json::from_slice(&data).unwrap_or_else(|e| {
+            info!("pacer: ignoring {}: {e}", path.display());
+            StateFile::default()
+        }),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => StateFile::default(),
+        Err(e) => {
+            info!("pacer: ignoring {}: {e}", path.display());
+            StateFile::default()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::num::NonZeroU32;
+    use std::time::Duration;
+
+    use tokio::time::Instant;
+
+    use crate::pacer::{Pacer, PacerState};
+    use crate::testing::capture_dir;
+
+    #[tokio::test(start_paused = true)]
+    async fn keeps_interval_between_requests() {
+        let pacer = Pacer::with_burst(Duration::from_secs(1), NonZeroU32::MIN);
+        let start = Instant::now();
+
+        pacer.wait().await;
+        assert_eq!(start.elapsed(), Duration::ZERO);
+        pacer.wait().await;
+        pacer.wait().await;
+        assert_eq!(start.elapsed(), Duration::from_secs(2));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn burst_is_spent_first() {
+        let pacer = Pacer::with_burst(Duration::from_secs(1), NonZeroU32::new(3).unwrap());
+        let start = Instant::now();
+
+        for _ in 0..3 {
+            pacer.wait().await;
+        }
+        assert_eq!(start.elapsed(), Duration::ZERO);
+        pacer.wait().await;
+        assert_eq!(start.elapsed(), Duration::from_secs(1));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn state_carries_the_bucket_to_the_next_invocation() {
+        let path = capture_dir("pacer-state", &[]).join("pacer.json");
+        let first = Pacer::with_burst(Duration::from_secs(10), NonZeroU32::MIN);
+        let state = PacerState::load(&path, "misskey.example".to_owned(), &first);
+        first.wait().await;
+        state.save(&first);
+
+        let second = Pacer::with_burst(Duration::from_secs(10), NonZeroU32::MIN);
+        PacerState::load(&path, "misskey.example".to_owned(), &second);
+        let other_host = Pacer::with_burst(Duration::from_secs(10), NonZeroU32::MIN);
+        PacerState::load(&path, "other.example".to_owned(), &other_host);
+        let start = Instant::now();
+
+        other_host.wait().await;
+        assert_eq!(start.elapsed(), Duration::ZERO);
+        second.wait().await;
+        assert!(start.elapsed() >= Duration::from_secs(9));
+    }
+}
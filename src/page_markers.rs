@@ -0,0 +1,225 @@
+//! `verify-pages`。`--emit-page-markers`で書いた`page`の記録を順に読み、ページが重ならずに続いているかを確かめる。
+//!
+//! ページのノートは、その前にある最も近い`page`の記録のものとして読む。`meta`の記録からは別の実行として数え直す。
+//! 次のページの記録に`untilId`があれば新しい方から遡ったもの、無ければ`sinceId`で進んだものとして確かめる。
+
+use std::collections::{BTreeMap, HashSet};
+use std::error::Error;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+
+use serde_json::Value;
+
+use crate::field_map::FieldMap;
+
+#[derive(Debug, Eq, PartialEq)]
+pub enum Problem {
+    /// 前のページにもあったノート
+    Duplicate { at: String, note_id: String },
+    /// そのページを取ったリクエストか、次のページの起点から見て範囲の外のノート
+    OutOfRange { at: String, note_id: String, bound: String },
+    /// 次のページの起点が進んでいない
+    Stalled { at: String, previous: Option<String>, cursor: Option<String> },
+}
+
+impl Problem {
+    pub fn record(&self) -> Value {
+        match self {
+            Self::Duplicate { at, note_id } => serde_json::json!({ "kind": "verify", "outcome": "duplicate", "at": at, "note_id": note_id }),
+            Self::OutOfRange { at, note_id, bound } => serde_json::json!({
+                "kind": "verify", "outcome": "out-of-range", "at": at, "note_id": note_id, "bound": bound,
+            }),
+            Self::Stalled { at, previous, cursor } => serde_json::json!({
+                "kind": "verify", "outcome": "stalled", "at": at, "previous": previous, "cursor": cursor,
+            }),
+        }
+    }
+}
+
+/// 1つの`page`の記録と、その後に書かれたノート
+struct Marker {
+    at: String,
+    until: Option<String>,
+    since: Option<String>,
+    /// ノートと、それを読んだ行
+    notes: Vec<(String, String)>,
+}
+
+impl Marker {
+    fn new(at: String, params: &Value) -> Self {
+        let id = |key| params[key].as_str().map(str::to_owned);
+        Self { at, until: id("untilId"), since: id("sinceId"), notes: vec![] }
+    }
+
+    /// `next`は同じタイムラインの次のページ
+    fn check(&self, next: Option<&Self>, problems: &mut Vec<Problem>) {
+        let backward = next.is_some_and(|x| x.until.is_some());
+        let mut next = next;
+        if let Some(marker) = next {
+            let (previous, cursor) = if backward { (&self.until, &marker.until) } else { (&self.since, &marker.since) };
+            let advanced = match (previous, cursor) {
+                (_, None) => false,
+                (None, Some(_)) => true,
+                (Some(previous), Some(cursor)) => if backward { cursor < previous } else { cursor > previous },
+            };
+            if !advanced {
+                problems.push(Problem::Stalled { at: marker.at.clone(), previous: previous.clone(), cursor: cursor.clone() });
+                // 進んでいなければ、次の起点との比べようがない
+                next = None;
+            }
+        }
+
+        for (at, note_id) in &self.notes {
+            let bound = if self.until.as_ref().is_some_and(|x| note_id >= x) {
+                self.until.as_ref().map(|x| format!("untilId {x}"))
+            } else if self.since.as_ref().is_some_and(|x| note_id <= x) {
+                self.since.as_ref().map(|x| format!("sinceId {x}"))
+            } else {
+                match next {
+                    // 次のページの起点は、このページの最も古いノート
+                    Some(Self { until: Some(x), .. }) if note_id < x => Some(format!("older than the next untilId {x}")),
+                    Some(Self { until: None, since: Some(x), .. }) if note_id > x => Some(format!("newer than the next sinceId {x}")),
+                    _ => None,
+                }
+            };
+            if let Some(bound) = bound {
+                problems.push(Problem::OutOfRange { at: at.clone(), note_id: note_id.clone(), bound });
+            }
+        }
+    }
+}
+
+/// 1つの実行の中で、タイムラインごとに最後の`page`の記録を持つ
+#[derive(Default)]
+struct Run {
+    last: BTreeMap<String, Marker>,
+    /// ノートを足していくタイムライン
+    current: Option<String>,
+    seen: HashSet<String>,
+}
+
+impl Run {
+    fn finish(self, problems: &mut Vec<Problem>) {
+        for marker in self.last.into_values() {
+            marker.check(None, problems);
+        }
+    }
+}
+
+/// 見つけた問題と、読んだ`page`の記録の数を返す。
+pub fn verify(path: &Path) -> Result<(Vec<Problem>, usize), Box<dyn Error + Send + Sync>> {
+    let file = BufReader::new(File::open(path).map_err(|e| format!("failed to open {}: {e}", path.display()))?);
+    let (mut problems, mut pages) = (vec![], 0);
+    let mut run = Run::default();
+    let mut id_field = "id".to_owned();
+
+    for (i, line) in file.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue
+        }
+        let at = format!("{}:{}", path.display(), i + 1);
+        let record: Value = serde_json::from_str(&line).map_err(|e| format!("{at}: {e}"))?;
+
+        let notes = match record {
+            Value::Array(page) => page,
+            Value::Object(mut record) => match record.get("kind").and_then(Value::as_str) {
+                Some("meta") => {
+                    std::mem::take(&mut run).finish(&mut problems);
+                    let map: FieldMap = record.remove("field_map").map(serde_json::from_value).transpose().map_err(|e| format!("{at}: {e}"))?.unwrap_or_default();
+                    map.note.name_of("id").clone_into(&mut id_field);
+                    continue
+                }
+                Some("page") => {
+                    pages += 1;
+                    let params = &record["params"];
+                    let timeline = params["channelId"].as_str().or_else(|| params["listId"].as_str()).unwrap_or_default().to_owned();
+                    let marker = Marker::new(at, params);
+                    if let Some(previous) = run.last.insert(timeline.clone(), marker) {
+                        previous.check(run.last.get(&timeline), &mut problems);
+                    }
+                    run.current = Some(timeline);
+                    continue
+                }
+                // `--canonical`
+                Some("note") => record.remove("note").into_iter().collect(),
+                _ => continue,
+            },
+            _ => continue,
+        };
+
+        let Some(marker) = run.current.as_ref().and_then(|x| run.last.get_mut(x)) else {
+            continue
+        };
+        for note in notes {
+            let Some(note_id) = note[&id_field].as_str() else {
+                continue
+            };
+            if !run.seen.insert(note_id.to_owned()) {
+                problems.push(Problem::Duplicate { at: at.clone(), note_id: note_id.to_owned() });
+            }
+            marker.notes.push((at.clone(), note_id.to_owned()));
+        }
+    }
+    run.finish(&mut problems);
+
+    if pages == 0 {
+        return Err(format!("{} has no page markers; archive with --emit-page-markers", path.display()).into())
+    }
+
+    Ok((problems, pages))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use serde_json::json;
+
+    use crate::page_markers::{verify, Problem};
+    use crate::testing::capture_dir;
+
+    fn marker(until: Option<&str>, since: Option<&str>) -> String {
+        let mut params = json!({ "channelId": "ch", "limit": 2, "attempt": 1 });
+        if let Some(until) = until {
+            params["untilId"] = until.into();
+        }
+        if let Some(since) = since {
+            params["sinceId"] = since.into();
+        }
+        json!({ "kind": "page", "params": params, "returned": 2, "new": 2 }).to_string()
+    }
+
+    fn page(ids: &[&str]) -> String {
+        json!(ids.iter().map(|x| json!({ "id": x })).collect::<Vec<_>>()).to_string()
+    }
+
+    #[test]
+    fn broken_chains_are_reported() {
+        let dir = capture_dir("page-markers", &[]);
+        let path = dir.join("out.jsonl");
+        let meta = json!({ "kind": "meta", "timestamp_format": "rfc3339", "timezone": "UTC" }).to_string();
+        let check = |lines: &[String]| {
+            fs::write(&path, lines.join("\n")).unwrap();
+            verify(&path).unwrap()
+        };
+
+        let consistent = [meta.clone(), marker(None, None), page(&["n6", "n5"]), marker(Some("n5"), None), page(&["n4", "n3"])];
+        assert_eq!(check(&consistent), (vec![], 2));
+        let forward = [meta.clone(), marker(None, Some("n1")), page(&["n2", "n3"]), marker(None, Some("n3")), page(&["n4"])];
+        assert_eq!(check(&forward), (vec![], 2));
+        // 別の実行なら、同じノートがあってもよい
+        assert_eq!(check(&[&consistent[..], &consistent[..]].concat()).0, vec![]);
+
+        let (problems, _) = check(&[meta.clone(), marker(None, None), page(&["n6", "n4"]), marker(Some("n5"), None), page(&["n5", "n4"]), marker(Some("n5"), None)]);
+        assert_eq!(problems, [
+            Problem::OutOfRange { at: format!("{}:3", path.display()), note_id: "n4".to_owned(), bound: "older than the next untilId n5".to_owned() },
+            Problem::Duplicate { at: format!("{}:5", path.display()), note_id: "n4".to_owned() },
+            Problem::Stalled { at: format!("{}:6", path.display()), previous: Some("n5".to_owned()), cursor: Some("n5".to_owned()) },
+            Problem::OutOfRange { at: format!("{}:5", path.display()), note_id: "n5".to_owned(), bound: "untilId n5".to_owned() },
+        ][..]);
+        fs::write(&path, format!("{meta}\n{}", page(&["n1"]))).unwrap();
+        assert!(verify(&path).unwrap_err().to_string().contains("--emit-page-markers"));
+    }
+}
@@ -0,0 +1,246 @@
+//! タイムラインをどちら向きに辿るか。向きによって、次のページの指定の仕方と範囲の外の判定が異なる。
+
+use std::num::NonZeroUsize;
+
+use chrono::{DateTime, Utc};
+use clap::ValueEnum;
+use serde::Serialize;
+
+use crate::api::{Timeline, TimelineCommand};
+use crate::model::{Note, NoteId};
+
+/// `--direction`
+#[derive(Eq, PartialEq, Copy, Clone, Debug, Default, ValueEnum)]
+pub enum Direction {
+    /// 新しい方から`untilId`で遡る
+    #[default]
+    Backward,
+    /// `--after`から`sinceId`で新しい方へ進む。古いノートから順に書き出す。
+    Forward,
+}
+
+impl Direction {
+    pub const fn name(self) -> &'static str {
+        match self {
+            Self::Backward => "backward",
+            Self::Forward => "forward",
+        }
+    }
+}
+
+/// 次のページの起点にしたノート。`created_at`は読む人のために添える
+#[derive(Clone, Debug, Serialize)]
+pub struct Cursor {
+    pub id: NoteId,
+    pub created_at: DateTime<Utc>,
+}
+
+impl From<&Note> for Cursor {
+    fn from(note: &Note) -> Self {
+        Self { id: note.id.clone(), created_at: note.created_at }
+    }
+}
+
+pub trait Pagination: Send + Sync {
+    fn command(&self, timeline: &Timeline, limit: NonZeroUsize) -> TimelineCommand;
+
+    /// 範囲の外ならその理由
+    fn out_of_range(&self, note: &Note) -> Option<&'static str>;
+
+    /// `page`を書き出す順に並べ、次のページへ進む。
+    fn advance(&mut self, page: &mut [Note]);
+
+    /// 書き出さないページの分だけ進む。今の位置より先に進めなければ`false`
+    fn skip(&mut self, page: &[Note]) -> bool;
+
+    /// 最後に進んだ位置
+    fn cursor(&self) -> Option<&Cursor>;
+}
+
+pub fn new(direction: Direction, before: Option<NoteId>, after: Option<NoteId>) -> Box<dyn Pagination> {
+    match direction {
+        Direction::Backward => Box::new(Backward { until: before, since: after, cursor: None }),
+        Direction::Forward => Box::new(Forward { since: after, until: before, cursor: None }),
+    }
+}
+
+struct Backward {
+    /// `--before`
+    until: Option<NoteId>,
+    /// 動かない下限
+    since: Option<NoteId>,
+    /// これまでで最も古いノート。これより新しいノートは範囲の外
+    cursor: Option<Cursor>,
+}
+
+impl Pagination for Backward {
+    fn command(&self, timeline: &Timeline, limit: NonZeroUsize) -> TimelineCommand {
+        TimelineCommand {
+            timeline: timeline.clone(),
+            limit,
+            note_after: self.since.clone(),
+            note_before: self.cursor.as_ref().map(|x| x.id.clone()).or_else(|| self.until.clone()),
+            date_after: None,
+            date_before: None,
+        }
+    }
+
+    /// サーバーと同じくIDで比べる。最初のページでも`--before`を見る
+    fn out_of_range(&self, note: &Note) -> Option<&'static str> {
+        let until = self.cursor.as_ref().map(|x| &x.id).or(self.until.as_ref());
+        if until.is_some_and(|x| note.id >= *x) {
+            Some("newer than untilId")
+        } else if self.since.as_ref().is_some_and(|x| note.id <= *x) {
+            Some("older than sinceId")
+        } else {
+            None
+        }
+    }
+
+    /// 同じ日時のノートがいくつも並ぶことがあるので、サーバーが`untilId`で比べるのと同じくIDで選ぶ。
+    fn advance(&mut self, page: &mut [Note]) {
+        if let Some(min) = page.iter().min_by(|a, b| a.id.cmp(&b.id)) {
+            self.cursor = Some(min.into());
+        }
+    }
+
+    fn skip(&mut self, page: &[Note]) -> bool {
+        let until = self.cursor.as_ref().map(|x| &x.id).or(self.until.as_ref());
+        match page.iter().min_by(|a, b| a.id.cmp(&b.id)) {
+            Some(min) if until.is_none_or(|x| min.id < *x) => {
+                self.cursor = Some(min.into());
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn cursor(&self) -> Option<&Cursor> {
+        self.cursor.as_ref()
+    }
+}
+
+/// `untilId`と一緒に指定すると新しい順に返ってくるので、`sinceId`だけで進む。
+struct Forward {
+    /// `--after`
+    since: Option<NoteId>,
+    /// `--before`。リクエストには含めず、これより新しいノートを範囲の外とする
+    until: Option<NoteId>,
+    /// これまでで最も新しいノート。これより古いノートは範囲の外
+    cursor: Option<Cursor>,
+}
+
+impl Pagination for Forward {
+    fn command(&self, timeline: &Timeline, limit: NonZeroUsize) -> TimelineCommand {
+        TimelineCommand {
+            timeline: timeline.clone(),
+            limit,
+            note_after: self.cursor.as_ref().map(|x| x.id.clone()).or_else(|| self.since.clone()),
+            note_before: None,
+            date_after: None,
+            date_before: None,
+        }
+    }
+
+    fn out_of_range(&self, note: &Note) -> Option<&'static str> {
+        let since = self.cursor.as_ref().map(|x| &x.id).or(self.since.as_ref());
+        if since.is_some_and(|x| note.id <= *x) {
+            Some("older than sinceId")
+        } else if self.until.as_ref().is_some_and(|x| note.id >= *x) {
+            Some("newer than --before")
+        } else {
+            None
+        }
+    }
+
+    fn advance(&mut self, page: &mut [Note]) {
+        // サーバーによって返す順が違うので、ここで古い順に揃える。同じ日時ならIDの順にする
+        page.sort_by(|a, b| a.created_at.cmp(&b.created_at).then_with(|| a.id.cmp(&b.id)));
+        if let Some(max) = page.iter().max_by(|a, b| a.id.cmp(&b.id)) {
+            self.cursor = Some(max.into());
+        }
+    }
+
+    fn skip(&mut self, page: &[Note]) -> bool {
+        let since = self.cursor.as_ref().map(|x| &x.id).or(self.since.as_ref());
+        match page.iter().max_by(|a, b| a.id.cmp(&b.id)) {
+            Some(max) if since.is_none_or(|x| max.id > *x) => {
+                self.cursor = Some(max.into());
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn cursor(&self) -> Option<&Cursor> {
+        self.cursor.as_ref()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::num::NonZeroUsize;
+
+    use crate::api::Timeline;
+    use crate::model::{ChannelId, Note, NoteId};
+    use crate::pagination::{new, Direction, Pagination};
+    use crate::testing::note_json;
+
+    fn note(id: &str, day: u8) -> Note {
+        serde_json::from_value(note_json(id, &format!("2024-01-{day:02}T00:00:00.000Z"))).unwrap()
+    }
+
+    fn request(pagination: &dyn Pagination) -> serde_json::Value {
+        serde_json::to_value(pagination.command(&Timeline::Channel(ChannelId("ch".to_owned())), NonZeroUsize::MIN)).unwrap()
+    }
+
+    #[test]
+    fn backward_moves_until_id_to_the_oldest_note() {
+        let mut walk = new(Direction::Backward, None, Some(NoteId("n0".to_owned())));
+        assert_eq!(request(&*walk), serde_json::json!({ "channelId": "ch", "limit": 1, "sinceId": "n0" }));
+
+        let mut page = vec![note("n3", 3), note("n2", 2)];
+        walk.advance(&mut page);
+
+        assert_eq!(page[0].id.0, "n3");
+        assert_eq!(request(&*walk), serde_json::json!({ "channelId": "ch", "limit": 1, "sinceId": "n0", "untilId": "n2" }));
+        assert_eq!(walk.out_of_range(&note("n4", 4)), Some("newer than untilId"));
+        assert_eq!(walk.out_of_range(&note("n1", 1)), None);
+    }
+
+    #[test]
+    fn forward_moves_since_id_to_the_newest_note_and_emits_oldest_first() {
+        let mut walk = new(Direction::Forward, None, Some(NoteId("n0".to_owned())));
+        assert_eq!(request(&*walk), serde_json::json!({ "channelId": "ch", "limit": 1, "sinceId": "n0" }));
+
+        let mut page = vec![note("n2", 2), note("n1", 1)];
+        walk.advance(&mut page);
+
+        assert_eq!(page.iter().map(|n| n.id.0.as_str()).collect::<Vec<_>>(), ["n1", "n2"]);
+        assert_eq!(request(&*walk), serde_json::json!({ "channelId": "ch", "limit": 1, "sinceId": "n2" }));
+        assert_eq!(walk.out_of_range(&note("n1", 1)), Some("older than sinceId"));
+        assert_eq!(walk.cursor().map(|x| x.id.0.as_str()), Some("n2"));
+    }
+
+    #[test]
+    fn bounds_are_compared_by_id_from_the_first_page() {
+        let before = Some(NoteId("n5".to_owned()));
+        let after = Some(NoteId("n1".to_owned()));
+        let backward = new(Direction::Backward, before.clone(), after.clone());
+        // 日時が前後していても、IDで判断する
+        assert_eq!(backward.out_of_range(&note("n6", 1)), Some("newer than untilId"));
+        assert_eq!(backward.out_of_range(&note("n5", 5)), Some("newer than untilId"));
+        assert_eq!(backward.out_of_range(&note("n0", 9)), Some("older than sinceId"));
+        assert_eq!(backward.out_of_range(&note("n3", 3)), None);
+
+        let mut forward = new(Direction::Forward, before, after);
+        assert_eq!(forward.out_of_range(&note("n6", 6)), Some("newer than --before"));
+        assert_eq!(forward.out_of_range(&note("n1", 1)), Some("older than sinceId"));
+        assert_eq!(forward.out_of_range(&note("n3", 3)), None);
+
+        // 先へ進めないページは飛ばせない
+        assert!(!forward.skip(&[note("n1", 1)]));
+        assert!(forward.skip(&[note("n2", 2)]));
+        assert_eq!(request(&*forward), serde_json::json!({ "channelId": "ch", "limit": 1, "sinceId": "n2" }));
+    }
+}
@@ -0,0 +1,115 @@
+//! `fetch-user --with-pinned-notes`。ユーザーがプロフィールにピン留めしたノートを、`{"kind": "pinned-note"}`として書き出す。
+//!
+//! `users/show`がノートそのものを返さないサーバーや、`--user-cache`から読んだユーザーでは、`notes/show`で1つずつ取る。
+//! 取れなかったノートは`{"kind": "warning"}`にして、次へ進む。
+
+use std::collections::HashSet;
+use std::io::{self, Write};
+
+use crate::api::{ApiClient, NoteShowCommand};
+use crate::model::{DetailedUser, Note, NoteId};
+use crate::pacer::Pacer;
+use crate::timestamp::{Formatted, TimestampFormat};
+
+pub struct PinnedNotes {
+    /// 書き出したノートと、`--archived`にあったノート
+    seen: HashSet<NoteId>,
+    timestamp_format: TimestampFormat,
+}
+
+impl PinnedNotes {
+    /// `archived`にあるノートは書き出さない。
+    pub fn new(archived: Vec<Note>, timestamp_format: TimestampFormat) -> Self {
+        Self { seen: archived.into_iter().map(|x| x.id).collect(), timestamp_format }
+    }
+
+    /// `user`のピン留めしたノートを、ピン留めした順に書き出す。
+    pub async fn write(&mut self, client: &impl ApiClient, pacer: &Pacer, out: &mut (impl Write + Send + ?Sized), user: &DetailedUser) -> io::Result<()> {
+        for note_id in &user.pinned_note_ids {
+            if !self.seen.insert(note_id.clone()) {
+                continue
+            }
+
+            if let Some(note) = user.pinned_notes.iter().find(|x| x.id == *note_id) {
+                self.write_note(out, user, note)?;
+                continue
+            }
+            pacer.wait().await;
+            match (NoteShowCommand { note_id: note_id.clone() }).send(client).await {
+                Ok(note) => self.write_note(out, user, &note)?,
+                Err(e) => writeln!(out, "{}", serde_json::json!({
+                    "kind": "warning",
+                    "user_id": user.id,
+                    "note_id": note_id,
+                    "message": format!("failed to fetch a pinned note: {e}"),
+                }))?,
+            }
+        }
+
+        Ok(())
+    }
+
+    fn write_note(&self, out: &mut (impl Write + Send + ?Sized), user: &DetailedUser, note: &Note) -> io::Result<()> {
+        writeln!(out, "{}", serde_json::json!({
+            "kind": "pinned-note",
+            "user_id": user.id,
+            "note": Formatted(note, self.timestamp_format),
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::num::NonZeroU32;
+    use std::time::Duration;
+
+    use serde_json::json;
+
+    use crate::api::ResponseHeaders;
+    use crate::capture::{Exchange, ReplayClient};
+    use crate::model::{DetailedUser, Note};
+    use crate::pacer::Pacer;
+    use crate::pinned::PinnedNotes;
+    use crate::schema;
+    use crate::testing::{capture_dir, exchange, note_json};
+    use crate::timestamp::TimestampFormat;
+
+    fn user(id: &str, pinned_note_ids: &[&str], pinned_notes: &[serde_json::Value]) -> DetailedUser {
+        serde_json::from_value(json!({
+            "id": id, "name": null, "username": id, "isBot": false, "isCat": false,
+            "avatarUrl": "https://misskey.example/avatar.webp", "notesCount": 3,
+            "pinnedNoteIds": pinned_note_ids, "pinnedNotes": pinned_notes,
+        })).unwrap()
+    }
+
+    #[tokio::test]
+    async fn pinned_notes_are_fetched_when_missing_and_written_once() {
+        let dir = capture_dir("pinned-notes", &[
+            exchange("notes/show", json!({ "noteId": "p2" }), &note_json("p2", "2024-01-02T00:00:00.000Z")),
+            Exchange {
+                endpoint: "notes/show".to_owned(),
+                request: json!({ "noteId": "p3" }),
+                status: 400,
+                response: json!({ "error": { "code": "NO_SUCH_NOTE", "message": "No such note." } }).to_string(),
+                headers: ResponseHeaders::default(),
+            },
+        ]);
+        let client = ReplayClient::open(&dir).unwrap();
+        let pacer = Pacer::with_burst(Duration::ZERO, NonZeroU32::MIN);
+        let archived: Note = serde_json::from_value(note_json("a1", "2024-01-01T00:00:00.000Z")).unwrap();
+        let mut pinned = PinnedNotes::new(vec![archived], TimestampFormat::Rfc3339);
+        let mut out = vec![];
+
+        // 最初のユーザーは`pinnedNotes`も返し、次のユーザーはIDしか返さない
+        pinned.write(&client, &pacer, &mut out, &user("u1", &["p1", "a1"], &[note_json("p1", "2024-01-01T00:00:00.000Z")])).await.unwrap();
+        pinned.write(&client, &pacer, &mut out, &user("u2", &["p2", "p3", "p1"], &[])).await.unwrap();
+
+        let records: Vec<serde_json::Value> = String::from_utf8(out).unwrap().lines().map(|x| serde_json::from_str(x).unwrap()).collect();
+        let summary: Vec<_> = records.iter().map(|x| (x["kind"].as_str().unwrap(), x["user_id"].as_str().unwrap(), x["note"]["id"].as_str().or_else(|| x["note_id"].as_str()).unwrap())).collect();
+        assert_eq!(summary, [("pinned-note", "u1", "p1"), ("pinned-note", "u2", "p2"), ("warning", "u2", "p3")]);
+        let document = schema::document(None);
+        for record in &records {
+            schema::validate(&document, &document, record, "record").unwrap();
+        }
+    }
+}
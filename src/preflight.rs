@@ -0,0 +1,445 @@
+//! 本番のリクエストを投げ始める前の確認。
+
+use std::collections::HashSet;
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use std::num::NonZeroUsize;
+use std::time::Duration;
+
+use crate::api::{is_credential_failure, required_permission, ApiClient, ApiError, HidingListCommand, MeCommand, NoteShowCommand, ProbeCommand, Timeline, TimelineCommand};
+use crate::i18n::{msg, Lang, Message};
+use crate::log::info;
+use crate::model::{Account, Channel, ChannelId, HiddenBy, NoteId, NoteLocation, UserId};
+use crate::pacer::Pacer;
+
+/// トークンが有効であることを確かめる。権限が足りなければ、どの権限を有効にすべきかをエラーに含める。
+pub async fn authenticate(client: &impl ApiClient) -> Result<Account, Box<dyn Error + Send + Sync>> {
+    MeCommand {}.send(client).await
+}
+
+/// トークンに足りない権限
+#[derive(Debug)]
+pub struct MissingPermissions {
+    pub subcommand: &'static str,
+    /// 権限と、それを確かめたエンドポイント
+    pub missing: Vec<(&'static str, String)>,
+}
+
+impl Display for MissingPermissions {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let missing: Vec<_> = self.missing.iter().map(|(permission, endpoint)| format!("\"{permission}\" ({endpoint})")).collect();
+        write!(
+            f,
+            "{} needs permissions the token lacks: {}. Enable them when issuing the token in Settings > API (設定 > API > アクセストークンの発行)",
+            self.subcommand,
+            missing.join(", "),
+        )
+    }
+}
+
+impl Error for MissingPermissions {}
+
+/// `subcommand`が使う`endpoints`の権限がトークンにあるかを、本番のリクエストの前に確かめる。
+/// `i`からはトークンの権限が分からないので、権限の要るエンドポイントを1件だけ頼んでみる。
+/// Misskeyは引数より先に権限を確かめるので、`INVALID_PARAM`などが返れば権限はあるとする。`read:account`は`authenticate`で確かめてある。
+pub async fn check_permissions(
+    client: &impl ApiClient,
+    pacer: &Pacer,
+    subcommand: &'static str,
+    endpoints: &[&str],
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let mut probed = HashSet::new();
+    let mut missing = vec![];
+    for endpoint in endpoints {
+        let Some(permission) = required_permission(endpoint).filter(|x| *x != "read:account") else {
+            continue
+        };
+        if !probed.insert(permission) {
+            continue
+        }
+
+        pacer.wait().await;
+        let Err(e) = (ProbeCommand { endpoint }).send(client).await else {
+            continue
+        };
+        match e.downcast_ref::<ApiError>() {
+            Some(x) if x.code == "PERMISSION_DENIED" => missing.push((permission, (*endpoint).to_owned())),
+            Some(x) if x.status < 500 && !is_credential_failure(x.status, &x.code) => {}
+            _ => return Err(e),
+        }
+    }
+
+    if missing.is_empty() {
+        Ok(())
+    } else {
+        Err(MissingPermissions { subcommand, missing }.into())
+    }
+}
+
+/// `--after`が`--before`より古くない。間には1つもノートが無い
+#[derive(Debug)]
+pub struct InvalidRange {
+    pub before: NoteLocation,
+    pub after: NoteLocation,
+}
+
+impl InvalidRange {
+    pub fn message(&self) -> Message {
+        Message::new("invalid-range", &[
+            ("after", &self.after.id.0), ("after_at", &self.after.created_at),
+            ("before", &self.before.id.0), ("before_at", &self.before.created_at),
+        ])
+    }
+}
+
+impl Display for InvalidRange {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.message().render(Lang::En))
+    }
+}
+
+impl Error for InvalidRange {}
+
+/// `--before`と`--after`を`notes/show`で引き、`after`の方が古いことを確かめる。
+/// `channels`があれば、どちらかのノートがそのどれにも含まれないときに警告する。
+/// 消えたノートでも`untilId`などには使えるので、IDの日時で確かめ、それも分からなければ確かめずに進める。
+pub async fn check_range(
+    client: &impl ApiClient,
+    pacer: &Pacer,
+    before: &NoteId,
+    after: &NoteId,
+    channels: Option<&[ChannelId]>,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let before = locate_boundary(client, pacer, "--before", before, channels).await?;
+    let after = locate_boundary(client, pacer, "--after", after, channels).await?;
+    let (Some(before), Some(after)) = (before, after) else {
+        return Ok(())
+    };
+
+    if after.created_at < before.created_at {
+        Ok(())
+    } else {
+        Err(InvalidRange { before, after }.into())
+    }
+}
+
+/// 消えたノートなら、IDの日時を作成日時とする。それも分からなければ[`None`]。
+async fn locate_boundary(
+    client: &impl ApiClient,
+    pacer: &Pacer,
+    flag: &str,
+    note_id: &NoteId,
+    channels: Option<&[ChannelId]>,
+) -> Result<Option<NoteLocation>, Box<dyn Error + Send + Sync>> {
+    pacer.wait().await;
+    let note = match (NoteShowCommand { note_id: note_id.clone() }).locate(client).await {
+        Ok(note) => note,
+        Err(e) if e.downcast_ref::<ApiError>().is_some_and(|x| x.code == "NO_SUCH_NOTE") => {
+            let Some(created_at) = note_id.aid_timestamp() else {
+                info!("{}", msg("boundary-gone-without-time", &[("flag", &flag), ("id", &note_id.0)]));
+                return Ok(None)
+            };
+            info!("{}", msg("boundary-gone", &[("flag", &flag), ("id", &note_id.0), ("created_at", &created_at)]));
+            return Ok(Some(NoteLocation { id: note_id.clone(), created_at, channel_id: None }))
+        }
+        Err(e) => return Err(e),
+    };
+
+    if channels.is_some_and(|channels| !note.channel_id.as_ref().is_some_and(|x| channels.contains(x))) {
+        info!("{}", msg("boundary-outside-channels", &[("flag", &flag), ("id", &note.id.0)]));
+    }
+
+    Ok(Some(note))
+}
+
+/// 見えるノートの数を確かめるときのページの大きさ。サーバーが受け付ける上限
+const PROBE_LIMIT: NonZeroUsize = NonZeroUsize::new(100).unwrap();
+
+/// 最新の1ページを範囲を付けずに取り、このアカウントからはノートの一部しか見えないかもしれない理由を返す。
+pub async fn check_visibility(client: &impl ApiClient, pacer: &Pacer, channel: &Channel, account: &Account) -> Vec<Message> {
+    pacer.wait().await;
+    let probe = TimelineCommand {
+        timeline: Timeline::Channel(channel.id.clone()),
+        limit: PROBE_LIMIT,
+        note_after: None,
+        note_before: None,
+        date_after: None,
+        date_before: None,
+    };
+    let probed = probe.send(client).await.ok().map(|x| x.notes.len());
+    let owner = channel.user_id.as_ref().is_some_and(|x| *x == account.id);
+
+    incompleteness(channel.notes_count, probed, channel.is_following, owner)
+}
+
+/// 全てのノートが見えると言い切る方法は無いので、空でも完全だとは限らない。見つけた兆しだけを返す。
+pub fn incompleteness(notes_count: Option<usize>, probed: Option<usize>, following: Option<bool>, owner: bool) -> Vec<Message> {
+    let mut reasons = vec![];
+    match (notes_count, probed) {
+        (_, None) => reasons.push(Message::new("probe-failed", &[])),
+        (None, _) => reasons.push(Message::new("notes-count-unknown", &[])),
+        (Some(count), Some(probed)) if probed < count.min(PROBE_LIMIT.get()) => {
+            reasons.push(Message::new("probe-shortfall", &[("probed", &probed), ("expected", &count.min(PROBE_LIMIT.get()))]));
+        }
+        _ => {}
+    }
+    if !owner && following != Some(true) {
+        reasons.push(Message::new("not-following", &[]));
+    }
+
+    reasons
+}
+
+/// トークンのアカウントがミュートかブロックしているユーザー
+#[derive(Default, Debug)]
+pub struct HiddenUsers {
+    pub muted: HashSet<UserId>,
+    pub blocked: HashSet<UserId>,
+}
+
+impl HiddenUsers {
+    /// 1人でも隠していれば、ノートの一部しか見えないかもしれない理由。誰かは書かない
+    pub fn reason(&self) -> Option<Message> {
+        if self.muted.is_empty() && self.blocked.is_empty() {
+            return None
+        }
+
+        Some(Message::new("hidden-users", &[("muted", &self.muted.len()), ("blocked", &self.blocked.len())]))
+    }
+
+    /// ブロックしていれば、ミュートしていても[`HiddenBy::Blocked`]とする
+    pub fn of(&self, user_id: &UserId) -> Option<HiddenBy> {
+        if self.blocked.contains(user_id) {
+            Some(HiddenBy::Blocked)
+        } else if self.muted.contains(user_id) {
+            Some(HiddenBy::Muted)
+        } else {
+            None
+        }
+    }
+}
+
+/// `mute/list`と`blocking/list`を最後まで辿る。権限が足りないなどで引けなければエラー。
+pub async fn list_hidden_users(client: &impl ApiClient, pacer: &Pacer) -> Result<HiddenUsers, Box<dyn Error + Send + Sync>> {
+    Ok(HiddenUsers {
+        muted: list_hiding(client, pacer, "mute/list").await?,
+        blocked: list_hiding(client, pacer, "blocking/list").await?,
+    })
+}
+
+async fn list_hiding(client: &impl ApiClient, pacer: &Pacer, endpoint: &'static str) -> Result<HashSet<UserId>, Box<dyn Error + Send + Sync>> {
+    let mut users = HashSet::new();
+    let mut until_id = None;
+    loop {
+        pacer.wait().await;
+        let page = HidingListCommand { endpoint, limit: PROBE_LIMIT, until_id }.send(client).await?;
+        let Some(last) = page.last() else {
+            return Ok(users)
+        };
+        until_id = Some(last.id.clone());
+        users.extend(page.into_iter().map(|x| x.user_id));
+    }
+}
+
+/// `notes_count`件のノートを`page_size`件ずつ取得したときに、クールダウンで待つ時間の合計。
+/// 空のページが返ってきたら終わるので、最後のリクエストの後には待たない。
+pub fn estimate_run_time(notes_count: usize, page_size: NonZeroUsize, cool_down: Duration) -> Duration {
+    let pages = notes_count.div_ceil(page_size.get());
+
+    cool_down.saturating_mul(u32::try_from(pages).unwrap_or(u32::MAX))
+}
+
+/// 1つのチャンネルを遡るのに送るリクエストの数。前後2回の`channels/show`と、見えるノートを確かめる1ページ、
+/// 最後の空のページも数える。
+pub const fn estimate_requests(notes_count: usize, page_size: NonZeroUsize) -> usize {
+    notes_count.div_ceil(page_size.get()) + 4
+}
+
+#[cfg(test)]
+mod tests {
+    use std::num::NonZeroUsize;
+    use std::time::Duration;
+
+    use serde_json::json;
+
+    use std::num::NonZeroU32;
+
+    use crate::api::ResponseHeaders;
+    use crate::capture::{Exchange, ReplayClient};
+    use crate::i18n::Lang;
+    use crate::model::{HiddenBy, NoteId, UserId};
+    use crate::pacer::Pacer;
+    use crate::preflight::{authenticate, check_permissions, check_range, estimate_requests, estimate_run_time, incompleteness, list_hidden_users, HiddenUsers, InvalidRange};
+    use crate::status::Outcome;
+    use crate::testing::{capture_dir, exchange, note_json};
+
+    const PAGE: NonZeroUsize = NonZeroUsize::new(60).unwrap();
+
+    #[test]
+    fn estimate_rounds_partial_page_up() {
+        assert_eq!(estimate_run_time(0, PAGE, Duration::from_secs(1)), Duration::ZERO);
+        assert_eq!(estimate_run_time(60, PAGE, Duration::from_secs(1)), Duration::from_secs(1));
+        assert_eq!(estimate_run_time(61, PAGE, Duration::from_secs(1)), Duration::from_secs(2));
+    }
+
+    #[test]
+    fn requests_include_the_channel_and_the_last_empty_page() {
+        assert_eq!(estimate_requests(0, PAGE), 4);
+        assert_eq!(estimate_requests(61, PAGE), 6);
+    }
+
+    #[test]
+    fn followed_channel_with_every_note_visible_has_no_reason() {
+        assert!(incompleteness(Some(120), Some(100), Some(true), false).is_empty());
+        assert!(incompleteness(Some(3), Some(3), None, true).is_empty());
+    }
+
+    #[test]
+    fn shortfall_and_missing_relationship_are_both_reported() {
+        let reasons = incompleteness(Some(50), Some(48), Some(false), false);
+        assert_eq!(reasons.len(), 2);
+        assert!(reasons[0].render(Lang::En).contains("only 48 of the newest 50"));
+        assert!(reasons[1].render(Lang::En).contains("neither follows nor owns"));
+        assert_eq!(reasons[0].render(Lang::Ja), "notesCountからは最新の50個のノートがあるはずですが、48個しか見えません");
+
+        // 数えられないなら、見えていないかもしれないとする
+        assert_eq!(incompleteness(None, Some(10), Some(true), false).len(), 1);
+        assert_eq!(incompleteness(Some(10), None, Some(true), false).len(), 1);
+    }
+
+    #[test]
+    fn estimate_without_cool_down_is_zero() {
+        assert_eq!(estimate_run_time(10_000, PAGE, Duration::ZERO), Duration::ZERO);
+    }
+
+    #[tokio::test]
+    async fn permission_denied_names_the_permission_to_enable() {
+        let dir = capture_dir("permission-denied", &[Exchange {
+            endpoint: "i".to_owned(),
+            request: json!({}),
+            status: 403,
+            response: json!({ "error": {
+                "message": "Your app does not have the necessary permissions to use this endpoint.",
+                "code": "PERMISSION_DENIED",
+                "id": "1370e5b7-d4eb-4566-bb1d-7748ee6a1838",
+            }}).to_string(),
+            headers: ResponseHeaders::default(),
+        }]);
+        let client = ReplayClient::open(&dir).unwrap();
+
+        let Err(e) = authenticate(&client).await else {
+            panic!("must fail");
+        };
+
+        assert!(e.to_string().contains(r#""read:account""#));
+    }
+
+    #[tokio::test]
+    async fn missing_permissions_are_reported_for_the_subcommand() {
+        let denied = |endpoint: &str, status, code: &str| Exchange {
+            endpoint: endpoint.to_owned(),
+            request: json!({ "limit": 1 }),
+            status,
+            response: json!({ "error": { "message": "", "code": code, "id": "1370e5b7-d4eb-4566-bb1d-7748ee6a1838" } }).to_string(),
+            headers: ResponseHeaders::default(),
+        };
+        // 誰でも読めるエンドポイントは試さず、権限が通って引数で弾かれたものは足りているとする
+        let dir = capture_dir("missing-permissions", &[
+            denied("mute/list", 403, "PERMISSION_DENIED"),
+            denied("blocking/list", 400, "INVALID_PARAM"),
+        ]);
+        let client = ReplayClient::open(&dir).unwrap();
+        let pacer = Pacer::with_burst(Duration::ZERO, NonZeroU32::MIN);
+
+        let e = check_permissions(&client, &pacer, "archive", &["channels/timeline", "mute/list", "blocking/list", "i"]).await.unwrap_err();
+
+        assert_eq!(Outcome::classify(&*e), Outcome::Auth);
+        let message = e.to_string();
+        assert!(message.starts_with("archive needs") && message.contains(r#""read:mutes" (mute/list)"#) && !message.contains("read:blocks"));
+    }
+
+    #[tokio::test]
+    async fn hidden_users_are_counted_across_pages_without_being_named() {
+        let mutes: Vec<_> = (0..100).map(|i| json!({ "id": format!("m{i:03}"), "muteeId": format!("u{i}") })).collect();
+        let dir = capture_dir("hidden-users", &[
+            exchange("mute/list", json!({ "limit": 100 }), &json!(mutes)),
+            exchange("mute/list", json!({ "limit": 100, "untilId": "m099" }), &json!([{ "id": "m100", "muteeId": "troll" }])),
+            exchange("mute/list", json!({ "limit": 100, "untilId": "m100" }), &json!([])),
+            exchange("blocking/list", json!({ "limit": 100 }), &json!([{ "id": "b1", "blockeeId": "troll" }])),
+            exchange("blocking/list", json!({ "limit": 100, "untilId": "b1" }), &json!([])),
+        ]);
+        let client = ReplayClient::open(&dir).unwrap();
+        let pacer = Pacer::with_burst(Duration::ZERO, NonZeroU32::MIN);
+
+        let hidden = list_hidden_users(&client, &pacer).await.unwrap();
+
+        assert_eq!((hidden.muted.len(), hidden.blocked.len()), (101, 1));
+        assert_eq!((hidden.of(&UserId("u7".to_owned())), hidden.of(&UserId("troll".to_owned()))), (Some(HiddenBy::Muted), Some(HiddenBy::Blocked)));
+        assert_eq!(hidden.of(&UserId("u1000".to_owned())), None);
+        let reason = hidden.reason().unwrap().render(Lang::En);
+        assert!(reason.contains("mutes 101 and blocks 1 user(s)") && !reason.contains("troll"));
+        assert!(HiddenUsers::default().reason().is_none());
+    }
+
+    #[tokio::test]
+    async fn after_newer_than_before_is_rejected() {
+        let dir = capture_dir("invalid-range", &[
+            exchange("notes/show", json!({ "noteId": "old" }), &note_json("old", "2024-01-01T00:00:00.000Z")),
+            exchange("notes/show", json!({ "noteId": "new" }), &note_json("new", "2024-02-01T00:00:00.000Z")),
+        ]);
+        let client = ReplayClient::open(&dir).unwrap();
+        let pacer = Pacer::with_burst(Duration::ZERO, NonZeroU32::MIN);
+
+        let e = check_range(&client, &pacer, &NoteId("old".to_owned()), &NoteId("new".to_owned()), None).await.unwrap_err();
+
+        assert!(e.is::<InvalidRange>());
+        assert!(e.to_string().starts_with("--after new (2024-02-01 00:00:00 UTC) is not older than --before old"));
+    }
+
+    fn deleted(id: &str) -> Exchange {
+        Exchange {
+            endpoint: "notes/show".to_owned(),
+            request: json!({ "noteId": id }),
+            status: 400,
+            response: json!({ "error": { "message": "No such note.", "code": "NO_SUCH_NOTE", "id": "24fcbfc6-2e37-42b6-8388-c29b3861a08d" } }).to_string(),
+            headers: ResponseHeaders::default(),
+        }
+    }
+
+    #[tokio::test]
+    async fn deleted_boundaries_are_checked_by_the_time_in_their_ids() {
+        // それぞれ2024年1月1日、2月1日、3月1日のaid
+        let (january, february, march) = ("9nxpxc00ab", "9p60kqo0cd", "9qbgcg00ef");
+        let dir = capture_dir("deleted-range", &[
+            deleted(march),
+            exchange("notes/show", json!({ "noteId": january }), &note_json(january, "2024-01-01T00:00:00.000Z")),
+            exchange("notes/show", json!({ "noteId": february }), &note_json(february, "2024-02-01T00:00:00.000Z")),
+            deleted(january),
+            deleted(march),
+            deleted(january),
+            deleted(january),
+            deleted(march),
+        ]);
+        let client = ReplayClient::open(&dir).unwrap();
+        let pacer = Pacer::with_burst(Duration::ZERO, NonZeroU32::MIN);
+        let id = |x: &str| NoteId(x.to_owned());
+
+        // 消えた--before
+        check_range(&client, &pacer, &id(march), &id(january), None).await.unwrap();
+        // 消えた--after
+        check_range(&client, &pacer, &id(february), &id(january), None).await.unwrap();
+        // 両方が消えていても、逆なら弾く
+        check_range(&client, &pacer, &id(march), &id(january), None).await.unwrap();
+        let e = check_range(&client, &pacer, &id(january), &id(march), None).await.unwrap_err();
+        assert!(e.is::<InvalidRange>());
+    }
+
+    #[test]
+    fn aid_and_aidx_carry_their_creation_time() {
+        assert_eq!(NoteId("9nxpxc00ab".to_owned()).aid_timestamp().unwrap().to_rfc3339(), "2024-01-01T00:00:00+00:00");
+        assert_eq!(NoteId("9nxpxc00abcd0001".to_owned()).aid_timestamp().unwrap().to_rfc3339(), "2024-01-01T00:00:00+00:00");
+        // ULIDやObjectIdは読まない
+        assert_eq!(NoteId("01HK153X00ABCDEFGHJKMNPQRS".to_owned()).aid_timestamp(), None);
+        assert_eq!(NoteId("n1".to_owned()).aid_timestamp(), None);
+    }
+}
@@ -0,0 +1,130 @@
+//! どの実行でいつ取ったかを、書き出すノートに`archived_at`と`run_id`として添える。
+//!
+//! 実行ごとに`run_id`を1つ作り、`meta`の記録にも書く。APIのモデルには持たせず、書き出すときにだけ足すので、
+//! サーバーの応答を読むのには関わらない。`--canonical`ではノートには添えず、`meta`にだけ書く。
+
+use std::fmt::{Display, Formatter};
+use std::sync::OnceLock;
+
+use chrono::{DateTime, Utc};
+use serde::ser::Error as _;
+use serde::{Serialize, Serializer};
+use serde_json::Value;
+
+use crate::model::Note;
+use crate::timestamp::Formatted;
+
+/// UUID version 4
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub struct RunId(pub [u8; 16]);
+
+impl RunId {
+    fn random() -> Self {
+        let mut bytes = [0; 16];
+        getrandom::getrandom(&mut bytes).expect("the OS random number generator is available");
+        bytes[6] = (bytes[6] & 0x0f) | 0x40;
+        bytes[8] = (bytes[8] & 0x3f) | 0x80;
+
+        Self(bytes)
+    }
+}
+
+impl Display for RunId {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        for (i, byte) in self.0.iter().enumerate() {
+            if matches!(i, 4 | 6 | 8 | 10) {
+                f.write_str("-")?;
+            }
+            write!(f, "{byte:02x}")?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Serialize for RunId {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+/// 1回の実行
+#[derive(Clone, Copy, Debug)]
+pub struct Run {
+    pub id: RunId,
+    pub started_at: DateTime<Utc>,
+}
+
+impl Run {
+    /// この実行。最初に呼んだときに作る
+    pub fn current() -> Self {
+        static CURRENT: OnceLock<Run> = OnceLock::new();
+        *CURRENT.get_or_init(|| Self { id: RunId::random(), started_at: Utc::now() })
+    }
+
+    /// 今書き出すノートに添えるもの
+    pub fn stamp(self) -> Stamp {
+        Stamp { run_id: self.id, archived_at: Utc::now() }
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct Stamp {
+    run_id: RunId,
+    archived_at: DateTime<Utc>,
+}
+
+/// [`Formatted`]に、あれば[`Stamp`]を足して書き出すための包み
+pub struct Stamped<'a, T: ?Sized>(pub Formatted<'a, T>, pub Option<Stamp>);
+
+impl Serialize for Stamped<'_, Note> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let Some(stamp) = self.1 else {
+            return self.0.serialize(serializer)
+        };
+        let mut value = serde_json::to_value(&self.0).map_err(S::Error::custom)?;
+        value["archived_at"] = serde_json::to_value(stamp.archived_at).map_err(S::Error::custom)?;
+        value["run_id"] = stamp.run_id.to_string().into();
+
+        value.serialize(serializer)
+    }
+}
+
+impl Serialize for Stamped<'_, [Note]> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(self.0.0.iter().map(|note| Stamped(Formatted(note, self.0.1), self.1)))
+    }
+}
+
+/// 書き出したノートの`archived_at`
+pub fn archived_at(note: &Value) -> Option<DateTime<Utc>> {
+    note.get("archived_at")?.as_str()?.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use crate::model::Note;
+    use crate::provenance::{archived_at, Run, RunId, Stamped};
+    use crate::testing::note_json;
+    use crate::timestamp::{Formatted, TimestampFormat};
+
+    #[test]
+    fn notes_are_stamped_only_when_asked() {
+        let run = Run::current();
+        assert_eq!(run.id, Run::current().id);
+        let id = run.id.to_string();
+        assert_eq!((id.len(), &id[14..15]), (36, "4"));
+        assert!(matches!(&id[19..20], "8" | "9" | "a" | "b"), "{id}");
+
+        let note: Note = serde_json::from_value(note_json("n1", "2024-01-01T00:00:00.000Z")).unwrap();
+        let stamped = serde_json::to_value(Stamped(Formatted(&note, TimestampFormat::EpochMs), Some(run.stamp()))).unwrap();
+        assert_eq!((&stamped["id"], &stamped["createdAt"], &stamped["run_id"]), (&json!("n1"), &json!(1_704_067_200_000_i64), &json!(id)));
+        assert!(archived_at(&stamped).is_some_and(|x| x >= run.started_at));
+
+        let plain = serde_json::to_value(Stamped(Formatted(std::slice::from_ref(&note), TimestampFormat::Rfc3339), None)).unwrap();
+        assert_eq!(plain[0].get("run_id"), None);
+        assert_eq!(RunId([0xab; 16]).to_string(), "abababab-abab-abab-abab-abababababab");
+    }
+}
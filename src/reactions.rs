@@ -0,0 +1,202 @@
+//! `fetch-reactions`。ノートにいつ誰がリアクションしたかを書き出す。
+
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::io::Write;
+use std::num::NonZeroUsize;
+use std::path::Path;
+
+use chrono::{DateTime, Utc};
+use clap::ValueEnum;
+
+use crate::api::{ApiClient, NoteReactionsCommand};
+use crate::model::{NoteId, Reaction};
+use crate::pacer::Pacer;
+use crate::reader;
+use crate::timezone::Timezone;
+
+/// 1ページの数。サーバーが受け付ける上限
+const PAGE_SIZE: NonZeroUsize = NonZeroUsize::new(100).unwrap();
+
+/// `--histogram`
+#[derive(Eq, PartialEq, Copy, Clone, Debug, ValueEnum)]
+pub enum Bucket {
+    Hour,
+    Day,
+}
+
+impl Bucket {
+    const fn name(self) -> &'static str {
+        match self {
+            Self::Hour => "hour",
+            Self::Day => "day",
+        }
+    }
+
+    /// `at`を含む区切りの始まり。`timezone`での時刻で書く
+    fn start(self, at: DateTime<Utc>, timezone: &Timezone) -> String {
+        let local = timezone.to_local(at);
+        match self {
+            Self::Hour => local.format("%Y-%m-%dT%H:00:00%:z").to_string(),
+            Self::Day => local.format("%Y-%m-%d").to_string(),
+        }
+    }
+}
+
+/// どのリアクションを書き出すか
+pub struct ReactionFilter {
+    pub since: Option<DateTime<Utc>>,
+    pub until: Option<DateTime<Utc>>,
+}
+
+impl ReactionFilter {
+    fn contains(&self, at: DateTime<Utc>) -> bool {
+        self.since.is_none_or(|since| since <= at) && self.until.is_none_or(|until| at < until)
+    }
+}
+
+/// `archive`の出力に現れたノートのうち、リアクションが付いているものを返す。
+pub fn read_reacted_notes(path: &Path) -> Result<Vec<NoteId>, Box<dyn Error + Send + Sync>> {
+    Ok(reader::read_notes(path)?.into_iter().filter(|x| !x.reactions.is_empty()).map(|x| x.id).collect())
+}
+
+/// それぞれのノートのリアクションを全て辿る。`histogram`がなければ`{"kind": "reaction"}`を1つずつ、
+/// あれば`{"kind": "reaction-histogram"}`をノートごとと全体(`note_id`が`null`)で書き出す。
+pub async fn fetch_reactions(
+    client: &impl ApiClient,
+    pacer: &Pacer,
+    out: &mut (impl Write + Send + ?Sized),
+    notes: Vec<NoteId>,
+    filter: &ReactionFilter,
+    histogram: Option<(Bucket, &Timezone)>,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let mut overall = BTreeMap::new();
+
+    for note_id in notes {
+        let mut counts = BTreeMap::new();
+        let mut until_id = None;
+        loop {
+            pacer.wait().await;
+            let page = NoteReactionsCommand { note_id: note_id.clone(), limit: PAGE_SIZE, until_id }.send(client).await?;
+            let Some(last) = page.last() else {
+                break
+            };
+            until_id = Some(last.id.clone());
+            // 新しい順なので、これより後のページは全て`--since`より前
+            let reached_since = filter.since.is_some_and(|since| last.created_at < since);
+
+            for Reaction { created_at, user, emoji, .. } in page.into_iter().filter(|x| filter.contains(x.created_at)) {
+                match histogram {
+                    Some((bucket, timezone)) => {
+                        let start = bucket.start(created_at, timezone);
+                        *overall.entry(start.clone()).or_insert(0) += 1;
+                        *counts.entry(start).or_insert(0) += 1;
+                    }
+                    None => writeln!(out, "{}", serde_json::json!({
+                        "kind": "reaction",
+                        "note_id": note_id,
+                        "user_id": user.id,
+                        "reaction": emoji,
+                        "created_at": created_at,
+                    }))?,
+                }
+            }
+
+            if reached_since {
+                break
+            }
+        }
+
+        if let Some((bucket, _)) = histogram {
+            writeln!(out, "{}", histogram_record(Some(&note_id), bucket, counts))?;
+        }
+    }
+
+    if let Some((bucket, _)) = histogram {
+        writeln!(out, "{}", histogram_record(None, bucket, overall))?;
+    }
+    out.flush()?;
+
+    Ok(())
+}
+
+fn histogram_record(note_id: Option<&NoteId>, bucket: Bucket, counts: BTreeMap<String, usize>) -> serde_json::Value {
+    serde_json::json!({
+        "kind": "reaction-histogram",
+        "note_id": note_id,
+        "bucket": bucket.name(),
+        "counts": counts.into_iter().map(|(start, reactions)| serde_json::json!({ "start": start, "reactions": reactions })).collect::<Vec<_>>(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::num::NonZeroU32;
+    use std::time::Duration;
+
+    use chrono::FixedOffset;
+    use serde_json::json;
+
+    use crate::capture::ReplayClient;
+    use crate::model::NoteId;
+    use crate::pacer::Pacer;
+    use crate::reactions::{fetch_reactions, Bucket, ReactionFilter};
+    use crate::testing::{capture_dir, exchange};
+    use crate::timezone::Timezone;
+
+    fn reaction(id: &str, created_at: &str) -> serde_json::Value {
+        json!({ "id": id, "createdAt": created_at, "user": { "id": "u1" }, "type": "👍" })
+    }
+
+    fn records(out: Vec<u8>) -> Vec<serde_json::Value> {
+        String::from_utf8(out).unwrap().lines().map(|x| serde_json::from_str(x).unwrap()).collect()
+    }
+
+    #[tokio::test]
+    async fn stops_paging_once_past_since() {
+        let dir = capture_dir("reactions", &[
+            exchange("notes/reactions", json!({ "noteId": "n1", "limit": 100 }), &json!([
+                reaction("r3", "2024-01-03T00:00:00.000Z"),
+                reaction("r2", "2024-01-02T00:00:00.000Z"),
+            ])),
+            exchange("notes/reactions", json!({ "noteId": "n1", "limit": 100, "untilId": "r2" }), &json!([
+                reaction("r1", "2024-01-01T00:00:00.000Z"),
+            ])),
+        ]);
+        let client = ReplayClient::open(&dir).unwrap();
+        let pacer = Pacer::with_burst(Duration::ZERO, NonZeroU32::MIN);
+        let filter = ReactionFilter { since: Some("2024-01-01T12:00:00Z".parse().unwrap()), until: Some("2024-01-03T00:00:00Z".parse().unwrap()) };
+        let mut out = vec![];
+
+        fetch_reactions(&client, &pacer, &mut out, vec![NoteId("n1".to_owned())], &filter, None).await.unwrap();
+
+        assert_eq!(records(out), [
+            json!({ "kind": "reaction", "note_id": "n1", "user_id": "u1", "reaction": "👍", "created_at": "2024-01-02T00:00:00Z" }),
+        ]);
+    }
+
+    #[tokio::test]
+    async fn histogram_buckets_by_local_day() {
+        let dir = capture_dir("reactions-histogram", &[
+            exchange("notes/reactions", json!({ "noteId": "n1", "limit": 100 }), &json!([
+                // 日本時間では1月2日
+                reaction("r2", "2024-01-01T15:00:00.000Z"),
+                reaction("r1", "2024-01-01T14:59:00.000Z"),
+            ])),
+            exchange("notes/reactions", json!({ "noteId": "n1", "limit": 100, "untilId": "r1" }), &json!([])),
+        ]);
+        let client = ReplayClient::open(&dir).unwrap();
+        let pacer = Pacer::with_burst(Duration::ZERO, NonZeroU32::MIN);
+        let tokyo = Timezone::Fixed(FixedOffset::east_opt(9 * 3600).unwrap());
+        let mut out = vec![];
+
+        let filter = ReactionFilter { since: None, until: None };
+        fetch_reactions(&client, &pacer, &mut out, vec![NoteId("n1".to_owned())], &filter, Some((Bucket::Day, &tokyo))).await.unwrap();
+
+        let counts = json!([{ "start": "2024-01-01", "reactions": 1 }, { "start": "2024-01-02", "reactions": 1 }]);
+        assert_eq!(records(out), [
+            json!({ "kind": "reaction-histogram", "note_id": "n1", "bucket": "day", "counts": counts }),
+            json!({ "kind": "reaction-histogram", "note_id": null, "bucket": "day", "counts": counts }),
+        ]);
+    }
+}
@@ -0,0 +1,225 @@
+//! `archive`が書き出したJSON Linesを読み戻す。
+
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+
+use chrono::{DateTime, Utc};
+use serde_json::Value;
+
+use crate::chunk;
+use crate::field_map::{FieldMap, Renames};
+use crate::model::{Note, NoteId};
+use crate::provenance;
+
+/// ページ、返信、ピン留めされたノートの記録からノートを集める。同じノートが何度もあれば、[`read_all_notes`]と同じように選ぶ。
+pub fn read_notes(path: &Path) -> Result<Vec<Note>, Box<dyn Error + Send + Sync>> {
+    read_all_notes([path])
+}
+
+/// いくつもの実行の出力からノートを集める。同じノートは最初に現れた位置に1つだけ置き、`archived_at`の最も新しいものを残す。
+/// `archived_at`が同じか無ければ、先に現れたものを残す。
+pub fn read_all_notes(paths: impl IntoIterator<Item = impl AsRef<Path>>) -> Result<Vec<Note>, Box<dyn Error + Send + Sync>> {
+    let mut notes = vec![];
+    let mut index: HashMap<NoteId, (usize, Option<DateTime<Utc>>)> = HashMap::new();
+    for path in paths {
+        for (at, value) in read_note_values(path.as_ref())? {
+            let archived_at = provenance::archived_at(&value);
+            let note: Note = serde_json::from_value(value).map_err(|e| format!("{at}: {e}"))?;
+            match index.entry(note.id.clone()) {
+                Entry::Vacant(entry) => {
+                    entry.insert((notes.len(), archived_at));
+                    notes.push(note);
+                }
+                Entry::Occupied(mut entry) if archived_at > entry.get().1 => {
+                    entry.get_mut().1 = archived_at;
+                    notes[entry.get().0] = note;
+                }
+                Entry::Occupied(_) => {}
+            }
+        }
+    }
+
+    Ok(notes)
+}
+
+/// [`read_notes`]と同じ記録から、ノートを書かれたままの形で集める。同じノートが何度現れても全て返す。
+/// `createdAt`は、ファイルの`meta`の記録にある形式で読み、`--field-map`で変えた名前は戻す。`--canonical`で書いたノートには`archived_at`が無いので、
+/// `meta`のものを足す。どこにあったかも返す。
+pub fn read_note_values(path: &Path) -> Result<Vec<(String, Value)>, Box<dyn Error + Send + Sync>> {
+    let mut notes = vec![];
+    for_each_note_value(path, |at, note| {
+        notes.push((at, note));
+        Ok(())
+    })?;
+
+    Ok(notes)
+}
+
+/// [`read_note_values`]と同じものを、ファイル全体を読む前に1つずつ`f`に渡す。
+/// `path`がディレクトリなら、`--output-layout chunked`で書いたファイルを名前の順に読む。
+pub fn for_each_note_value(
+    path: &Path,
+    mut f: impl FnMut(String, Value) -> Result<(), Box<dyn Error + Send + Sync>>,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    if !path.is_dir() {
+        return for_each_note_value_in_file(path, &mut f)
+    }
+    for file in chunk::chunk_files(path).map_err(|e| format!("failed to read {}: {e}", path.display()))? {
+        for_each_note_value_in_file(&file, &mut f)?;
+    }
+
+    Ok(())
+}
+
+fn for_each_note_value_in_file(
+    path: &Path,
+    f: &mut impl FnMut(String, Value) -> Result<(), Box<dyn Error + Send + Sync>>,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let file = BufReader::new(File::open(path).map_err(|e| format!("failed to open {}: {e}", path.display()))?);
+    let mut timestamp_format = String::from("rfc3339");
+    let mut archived_at = None;
+    // `--field-map`で変えた名前を戻す
+    let mut renames = Renames::default();
+
+    for (i, line) in file.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue
+        }
+        let at = format!("{}:{}", path.display(), i + 1);
+        let record: Value = serde_json::from_str(&line).map_err(|e| format!("{at}: {e}"))?;
+
+        let found = match record {
+            Value::Array(page) => page,
+            Value::Object(mut record) => match record.get("kind").and_then(Value::as_str) {
+                Some("meta") => {
+                    if let Some(format) = record.get("timestamp_format").and_then(Value::as_str) {
+                        format.clone_into(&mut timestamp_format);
+                    }
+                    archived_at = record.remove("archived_at");
+                    if let Some(map) = record.remove("field_map") {
+                        let map: FieldMap = serde_json::from_value(map).map_err(|e| format!("{at}: {e}"))?;
+                        renames = map.note.inverse();
+                    }
+                    continue
+                }
+                Some("replies") => match record.remove("notes") {
+                    Some(Value::Array(notes)) => notes,
+                    _ => continue,
+                },
+                Some("pinned-note" | "note") => record.remove("note").into_iter().collect(),
+                _ => continue,
+            },
+            _ => continue,
+        };
+
+        for mut note in found {
+            renames.apply(&mut note);
+            normalize_created_at(&mut note, &timestamp_format);
+            if let (Some(note), Some(at)) = (note.as_object_mut(), &archived_at) {
+                note.entry("archived_at").or_insert_with(|| at.clone());
+            }
+            f(at.clone(), note)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// UNIX時間で書かれていれば、APIと同じRFC 3339に戻す。
+fn normalize_created_at(note: &mut Value, timestamp_format: &str) {
+    let Some(n) = note.get("createdAt").and_then(Value::as_i64) else {
+        return
+    };
+    let at = match timestamp_format {
+        "epoch-s" => DateTime::from_timestamp(n, 0),
+        _ => DateTime::from_timestamp_millis(n),
+    };
+    if let Some(at) = at {
+        note["createdAt"] = at.to_rfc3339().into();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use serde_json::json;
+
+    use crate::reader::{read_all_notes, read_notes};
+    use crate::testing::{capture_dir, note_json};
+
+    #[test]
+    fn reads_pages_replies_and_pinned_notes_once() {
+        let dir = capture_dir("reader", &[]);
+        let path = dir.join("archive.jsonl");
+        let mut epoch = note_json("n2", "");
+        epoch["createdAt"] = json!(1_704_067_200);
+        let lines = [
+            json!({ "kind": "meta", "timestamp_format": "epoch-s", "timezone": "UTC" }),
+            json!({ "kind": "log", "message": "archiving channel test" }),
+            json!([epoch]),
+            json!({ "kind": "replies", "parent_id": "n2", "notes": [note_json("n3", "2024-01-02T00:00:00Z")] }),
+            json!({ "kind": "pinned-note", "channel_id": "ch", "note": note_json("n3", "2024-01-02T00:00:00Z") }),
+        ];
+        fs::write(&path, lines.map(|x| x.to_string()).join("\n")).unwrap();
+
+        let notes = read_notes(&path).unwrap();
+
+        assert_eq!(notes.iter().map(|x| x.id.0.as_str()).collect::<Vec<_>>(), ["n2", "n3"]);
+        assert_eq!(notes[0].created_at.to_rfc3339(), "2024-01-01T00:00:00+00:00");
+    }
+
+    #[test]
+    fn fields_renamed_by_the_field_map_are_restored() {
+        let dir = capture_dir("reader-field-map", &[]);
+        let path = dir.join("archive.jsonl");
+        let mut note = note_json("n1", "");
+        note.as_object_mut().unwrap().remove("createdAt");
+        note["created_at"] = json!(1_704_067_200_000_i64);
+        note.as_object_mut().unwrap().remove("cw");
+        note["cw_text"] = json!("spoiler");
+        let lines = [
+            json!({ "kind": "meta", "timestamp_format": "epoch-ms", "timezone": "UTC", "field_map": { "note": { "createdAt": "created_at", "cw": "cw_text" } } }),
+            json!([note]),
+        ];
+        fs::write(&path, lines.map(|x| x.to_string()).join("\n")).unwrap();
+
+        let notes = read_notes(&path).unwrap();
+
+        assert_eq!((notes[0].id.0.as_str(), notes[0].created_at.to_rfc3339()), ("n1", "2024-01-01T00:00:00+00:00".to_owned()));
+        assert_eq!(notes[0].spoiler_disclaimer_text.as_deref(), Some("spoiler"));
+    }
+
+    #[test]
+    fn the_newest_snapshot_of_a_note_wins() {
+        let dir = capture_dir("reader-newest", &[]);
+        let snapshot = |id: &str, renotes: usize, archived_at: Option<&str>| {
+            let mut note = note_json(id, "2024-01-01T00:00:00Z");
+            note["renoteCount"] = json!(renotes);
+            if let Some(at) = archived_at {
+                note["archived_at"] = json!(at);
+            }
+            note
+        };
+        let old = dir.join("old.jsonl");
+        fs::write(&old, json!([snapshot("n1", 1, Some("2024-02-01T00:00:00Z")), snapshot("n2", 1, Some("2024-02-01T00:00:00Z"))]).to_string()).unwrap();
+        // `--canonical`の出力では、`meta`にだけある
+        let canonical = dir.join("canonical.jsonl");
+        fs::write(&canonical, [
+            json!({ "kind": "meta", "timestamp_format": "rfc3339", "timezone": "UTC", "archived_at": "2024-03-01T00:00:00Z" }),
+            json!({ "kind": "note", "note": snapshot("n1", 3, None) }),
+        ].map(|x| x.to_string()).join("\n")).unwrap();
+        let new = dir.join("new.jsonl");
+        fs::write(&new, json!([snapshot("n2", 2, Some("2024-04-01T00:00:00Z")), snapshot("n1", 2, Some("2024-01-15T00:00:00Z"))]).to_string()).unwrap();
+
+        let notes = read_all_notes([&old, &canonical, &new]).unwrap();
+
+        let summary: Vec<_> = notes.iter().map(|x| (x.id.0.as_str(), x.renote_count)).collect();
+        assert_eq!(summary, [("n1", 3), ("n2", 2)]);
+    }
+}
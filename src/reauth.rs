@@ -0,0 +1,140 @@
+//! `--reauth-command`。実行の途中でトークンが通らなくなったら、コマンドから新しいトークンを読んで続ける。
+//!
+//! トークンを一定の時間で入れ替えるサーバーで、長い実行が途中で401になって終わらないようにする。
+
+use std::error::Error;
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use tokio::sync::Mutex;
+
+use crate::api::{is_credential_failure, ApiClient, MisskeyAuthorizationToken, RawResponse, ReplaceToken};
+use crate::log::info;
+
+/// `command`が無ければ何もせず`inner`に渡す。
+pub struct Reauthenticating<C> {
+    inner: C,
+    command: Option<String>,
+    /// トークンを差し替えた回数。同時に失敗したリクエストが、それぞれコマンドを実行しないようにする
+    generation: AtomicU64,
+    running: Mutex<()>,
+}
+
+impl<C> Reauthenticating<C> {
+    pub const fn new(inner: C, command: Option<String>) -> Self {
+        Self { inner, command, generation: AtomicU64::new(0), running: Mutex::const_new(()) }
+    }
+}
+
+impl<C: ApiClient + ReplaceToken + Sync> ApiClient for Reauthenticating<C> {
+    async fn call(&self, endpoint: &str, body: serde_json::Value) -> Result<RawResponse, Box<dyn Error + Send + Sync>> {
+        let Some(command) = &self.command else {
+            return self.inner.call(endpoint, body).await
+        };
+        let generation = self.generation.load(Ordering::Acquire);
+        let response = self.inner.call(endpoint, body.clone()).await?;
+        if !is_rejected(&response) {
+            return Ok(response)
+        }
+
+        let running = self.running.lock().await;
+        if self.generation.load(Ordering::Acquire) == generation {
+            info!("reauth: {endpoint} was rejected with {}; running --reauth-command", response.status);
+            match run(command.clone()).await {
+                Ok(token) => self.inner.replace_token(token),
+                Err(e) => {
+                    info!("reauth: {e}; giving up");
+                    return Ok(response)
+                }
+            }
+            self.generation.fetch_add(1, Ordering::AcqRel);
+        }
+        drop(running);
+
+        self.inner.call(endpoint, body).await
+    }
+}
+
+/// Cloudflareの確認ページはトークンとは関わりなく返るので含めない。
+fn is_rejected(response: &RawResponse) -> bool {
+    if (200..300).contains(&response.status) || response.headers.cf_mitigated.is_some() {
+        return false
+    }
+    let body: serde_json::Value = serde_json::from_str(&response.body).unwrap_or_default();
+
+    is_credential_failure(response.status, body["error"]["code"].as_str().unwrap_or_default())
+}
+
+/// `command`をシェルで実行し、標準出力をトークンとして読む。
+async fn run(command: String) -> Result<MisskeyAuthorizationToken, Box<dyn Error + Send + Sync>> {
+    let output = tokio::task::spawn_blocking(move || {
+        Command::new("sh").arg("-c").arg(&command).stdin(Stdio::null()).stderr(Stdio::inherit()).output()
+    }).await?.map_err(|e| format!("failed to run --reauth-command: {e}"))?;
+    if !output.status.success() {
+        return Err(format!("--reauth-command exited with {}", output.status).into())
+    }
+    let token = MisskeyAuthorizationToken::new(String::from_utf8(output.stdout).map_err(|_| "--reauth-command printed a non-UTF-8 token")?);
+    if token.expose().is_empty() {
+        return Err("--reauth-command printed no token".into())
+    }
+
+    Ok(token)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::error::Error;
+    use std::fs;
+    use std::sync::Mutex;
+
+    use serde_json::json;
+
+    use crate::api::{ApiClient, MisskeyAuthorizationToken, RawResponse, ReplaceToken, ResponseHeaders};
+    use crate::reauth::Reauthenticating;
+    use crate::testing::capture_dir;
+
+    /// 2回目のリクエストから、`fresh`でないトークンを拒む
+    struct RotatingServer {
+        token: Mutex<String>,
+        calls: Mutex<Vec<String>>,
+    }
+
+    impl ApiClient for RotatingServer {
+        async fn call(&self, _: &str, _: serde_json::Value) -> Result<RawResponse, Box<dyn Error + Send + Sync>> {
+            let token = self.token.lock().unwrap().clone();
+            let count = {
+                let mut calls = self.calls.lock().unwrap();
+                calls.push(token.clone());
+                calls.len()
+            };
+            if count > 1 && token != "fresh" {
+                let body = json!({ "error": { "message": "Authentication failed.", "code": "AUTHENTICATION_FAILED", "id": "b0a7f5f8-dc2f-4171-b91f-de88ad238e14" } });
+                return Ok(RawResponse { status: 401, body: body.to_string(), headers: ResponseHeaders::default() })
+            }
+
+            Ok(RawResponse { status: 200, body: "[]".to_owned(), headers: ResponseHeaders::default() })
+        }
+    }
+
+    impl ReplaceToken for RotatingServer {
+        fn replace_token(&self, token: MisskeyAuthorizationToken) {
+            token.expose().clone_into(&mut self.token.lock().unwrap());
+        }
+    }
+
+    #[tokio::test]
+    async fn rejected_token_is_replaced_once_and_the_request_resent() {
+        let dir = capture_dir("reauth", &[]);
+        let runs = dir.join("runs");
+        let server = RotatingServer { token: Mutex::new("stale".to_owned()), calls: Mutex::new(vec![]) };
+        let client = Reauthenticating::new(server, Some(format!("echo >> '{}'; echo ' fresh'", runs.display())));
+
+        for _ in 0..3 {
+            let response = client.call("channels/timeline", json!({})).await.unwrap();
+            assert_eq!(response.status, 200);
+        }
+
+        assert_eq!(*client.inner.calls.lock().unwrap(), ["stale", "stale", "fresh", "fresh"]);
+        assert_eq!(fs::read_to_string(runs).unwrap().lines().count(), 1);
+    }
+}
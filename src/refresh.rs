@@ -0,0 +1,215 @@
+//! `refresh`。書き出した後も増え続けるリアクションなどの数を、古くなったノートだけ取り直して更新する。
+//!
+//! ノートがいつの状態かは、前の`refresh`で埋めた`refreshed_at`か、無ければ入力のファイルの更新日時で判断する。
+
+use std::error::Error;
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+use std::time::Duration;
+
+use chrono::{DateTime, TimeDelta, Utc};
+use serde_json::{Map, Value};
+
+use crate::api::{ApiClient, ApiError, NoteShowCommand};
+use crate::archive::{self, NotesFailed, PAGE_SIZE};
+use crate::content_hash;
+use crate::model::Note;
+use crate::output::{Destination, OutputOptions};
+use crate::pacer::Pacer;
+use crate::preflight::authenticate;
+use crate::reader;
+use crate::timestamp::Formatted;
+
+/// `input`のノートを元の順に書き出す。`older_than`より前の状態のものは`notes/show`で取り直し、
+/// 数が変わったものは`{"kind": "note-change"}`に、消えたものは`{"kind": "tombstone"}`にする。
+/// 最後に`{"kind": "refresh-report"}`で数をまとめる。取り直せなかったノートは前の状態のまま書く。
+pub async fn refresh(
+    client: &impl ApiClient,
+    pacer: &Pacer,
+    input: &Path,
+    output: Option<&Path>,
+    older_than: Duration,
+    options: &OutputOptions,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let archived_at: DateTime<Utc> = fs::metadata(input).and_then(|x| x.modified())
+        .map_err(|e| format!("failed to read the modification time of {}: {e}", input.display()))?
+        .into();
+    let notes = reader::read_notes(input)?;
+    pacer.wait().await;
+    let account = authenticate(client).await?;
+    let mut out = Destination::open(output, options)?;
+    archive::write_header(&mut out, &account)?;
+
+    let now = Utc::now();
+    let stale_before = now - TimeDelta::from_std(older_than)?;
+    let mut batch = vec![];
+    let mut changes = vec![];
+    let mut errors = vec![];
+    let (mut checked, mut deleted) = (0, 0);
+    for mut note in notes {
+        if note.refreshed_at.unwrap_or(archived_at) < stale_before {
+            checked += 1;
+            pacer.wait().await;
+            match (NoteShowCommand { note_id: note.id.clone() }).send(client).await {
+                Ok(fresh) => {
+                    changes.extend(merge(&mut note, fresh, now, options)?);
+                }
+                Err(e) if e.downcast_ref::<ApiError>().is_some_and(|x| x.code == "NO_SUCH_NOTE") => {
+                    // 元の順を保つため、先に溜めた分を書いてから記録する
+                    if !batch.is_empty() {
+                        out.write_page(&batch)?;
+                        batch.clear();
+                    }
+                    writeln!(out, "{}", serde_json::json!({ "kind": "tombstone", "note_id": note.id }))?;
+                    deleted += 1;
+                    continue
+                }
+                Err(e) => {
+                    writeln!(out, "{}", serde_json::json!({ "kind": "note-error", "note_id": note.id, "error": e.to_string() }))?;
+                    errors.push(e);
+                }
+            }
+        }
+        batch.push(note);
+        if batch.len() == PAGE_SIZE.get() {
+            out.write_page(&batch)?;
+            out.flush()?;
+            batch.clear();
+        }
+    }
+    if !batch.is_empty() {
+        out.write_page(&batch)?;
+    }
+
+    for change in &changes {
+        writeln!(out, "{change}")?;
+    }
+    writeln!(out, "{}", serde_json::json!({
+        "kind": "refresh-report",
+        "checked": checked,
+        "updated": changes.len(),
+        "deleted": deleted,
+        "failed": errors.len(),
+    }))?;
+    out.finish()?;
+
+    let failed = errors.len();
+    errors.pop().map_or(Ok(()), |last| Err(NotesFailed { failed, total: checked, last }.into()))
+}
+
+/// `fresh`の数を`note`に移す。取得後に埋めたURLや訳はそのまま残し、ハッシュがあれば求め直す。
+/// 数が変わっていれば、その差を返す。
+fn merge(note: &mut Note, fresh: Note, at: DateTime<Utc>, options: &OutputOptions) -> Result<Option<Value>, Box<dyn Error + Send + Sync>> {
+    let before = serde_json::to_value(&note.reactions)?;
+    let after = serde_json::to_value(&fresh.reactions)?;
+    let change = (before != after || note.renote_count != fresh.renote_count || note.reply_count != fresh.reply_count).then(|| serde_json::json!({
+        "kind": "note-change",
+        "note_id": note.id,
+        "reactions_gained": difference(&after, &before),
+        "reactions_lost": difference(&before, &after),
+        "renote_count": { "before": note.renote_count, "after": fresh.renote_count },
+        "reply_count": { "before": note.reply_count, "after": fresh.reply_count },
+    }));
+
+    note.reactions = fresh.reactions;
+    note.other_reactions_count = fresh.other_reactions_count;
+    note.my_reaction = fresh.my_reaction;
+    note.renote_count = fresh.renote_count;
+    note.reply_count = fresh.reply_count;
+    note.refreshed_at = Some(at);
+    if note.content_hash.is_some() {
+        let value = serde_json::to_value(Formatted(&*note, options.timestamp_format))?;
+        note.content_hash = Some(content_hash::hash_note(&value, options.timestamp_format));
+    }
+
+    Ok(change)
+}
+
+/// リアクションごとに、`a`が`b`より多い数
+fn difference(a: &Value, b: &Value) -> Map<String, Value> {
+    let count = |x: &Value, key: &str| x.get(key).and_then(Value::as_u64).unwrap_or(0);
+    a.as_object().into_iter().flatten()
+        .filter_map(|(key, _)| count(a, key).checked_sub(count(b, key)).filter(|n| *n > 0).map(|n| (key.clone(), n.into())))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::num::NonZeroU32;
+    use std::time::{Duration, SystemTime};
+
+    use serde_json::json;
+
+    use crate::api::ResponseHeaders;
+    use crate::capture::{Exchange, ReplayClient};
+    use crate::chunk;
+    use crate::output::{Layout, OutputOptions};
+    use crate::pacer::Pacer;
+    use crate::refresh::refresh;
+    use crate::field_map::FieldMap;
+    use crate::testing::{capture_dir, exchange, me, note_json, RUN};
+    use crate::timestamp::TimestampFormat;
+    use crate::timezone::Timezone;
+
+    #[tokio::test]
+    async fn stale_notes_are_refetched_and_deleted_ones_tombstoned() {
+        let mut recent = note_json("n3", "2024-01-03T00:00:00.000Z");
+        recent["refreshed_at"] = json!(chrono::Utc::now());
+        let mut fresh = note_json("n2", "2024-01-02T00:00:00.000Z");
+        fresh["reactions"] = json!({ ":blobcat@.:": 1, "🎉": 4 });
+        fresh["repliesCount"] = json!(2);
+        let dir = capture_dir("refresh", &[
+            me(),
+            exchange("notes/show", json!({ "noteId": "n2" }), &fresh),
+            Exchange {
+                endpoint: "notes/show".to_owned(),
+                request: json!({ "noteId": "n1" }),
+                status: 400,
+                response: json!({ "error": { "message": "No such note.", "code": "NO_SUCH_NOTE", "id": "24fcbfc6-2e37-42b6-8388-c29b3861a08d" } }).to_string(),
+                headers: ResponseHeaders::default(),
+            },
+        ]);
+        let input = dir.join("archive.jsonl");
+        let page = json!([recent, note_json("n2", "2024-01-02T00:00:00.000Z"), note_json("n1", "2024-01-01T00:00:00.000Z")]);
+        fs::write(&input, format!("{}\n{page}\n", json!({ "kind": "meta", "timestamp_format": "rfc3339", "timezone": "UTC" }))).unwrap();
+        // 30日前に書き出したアーカイブ
+        let archived_at = SystemTime::now() - Duration::from_hours(30 * 24);
+        fs::File::options().write(true).open(&input).unwrap().set_modified(archived_at).unwrap();
+        let output = dir.join("refreshed.jsonl");
+        let options = OutputOptions {
+            split_by: None,
+            timezone: Timezone::default(),
+            atomic: true,
+            timestamp_format: TimestampFormat::Rfc3339,
+            layout: Layout::Lines,
+            chunk_size: chunk::DEFAULT_CHUNK_SIZE,
+            sink: None,
+            canonical: false,
+            possibly_incomplete: vec![],
+            run: RUN,
+            field_map: FieldMap::EMPTY,
+            detect_language: false,
+            append: false,
+            append_index: None,
+        };
+        let client = ReplayClient::open(&dir).unwrap();
+        let pacer = Pacer::with_burst(Duration::ZERO, NonZeroU32::MIN);
+
+        refresh(&client, &pacer, &input, Some(&output), Duration::from_hours(7 * 24), &options).await.unwrap();
+
+        let records: Vec<serde_json::Value> = fs::read_to_string(output).unwrap().lines().map(|x| serde_json::from_str(x).unwrap()).collect();
+        let tail: Vec<_> = records.iter().skip_while(|x| x["kind"] != "account").skip(1).collect();
+        assert_eq!(tail.len(), 4);
+        let ids: Vec<_> = tail[0].as_array().unwrap().iter().map(|x| x["id"].as_str().unwrap()).collect();
+        assert_eq!(ids, ["n3", "n2"]);
+        assert_eq!(tail[0][1]["reactions"], json!({ ":blobcat@.:": 1, "🎉": 4 }));
+        assert_eq!(tail[0][1]["repliesCount"], 2);
+        assert!(tail[0][1]["refreshed_at"].is_string());
+        assert_eq!(tail[1], &json!({ "kind": "tombstone", "note_id": "n1" }));
+        assert_eq!(tail[2]["reactions_gained"], json!({ "🎉": 4 }));
+        assert_eq!(tail[2]["reactions_lost"], json!({ ":blobcat@.:": 1, "👍": 1 }));
+        assert_eq!(tail[3], &json!({ "kind": "refresh-report", "checked": 2, "updated": 1, "deleted": 1, "failed": 0 }));
+    }
+}
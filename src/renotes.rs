@@ -0,0 +1,179 @@
+//! `fetch-renotes`。ノートを誰がいつRenoteしたかを、`{"kind": "renote"}`の辺として書き出す。
+//!
+//! 1つのノートごとに`notes/renotes`を辿るので、リクエストはRenoteされたノートの数だけかかる。
+//! `--checkpoint`があれば、書き終えたノートのIDをそこに書き足し、次の実行ではそれらを飛ばす。
+
+use std::collections::{BTreeMap, HashSet};
+use std::error::Error;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::num::NonZeroUsize;
+use std::path::Path;
+
+use chrono::{DateTime, Utc};
+
+use crate::api::{ApiClient, NoteRenotesCommand};
+use crate::log::{info, progress};
+use crate::model::{NoteId, UserId};
+use crate::pacer::Pacer;
+use crate::reader;
+
+/// 1ページの数。サーバーが受け付ける上限
+const PAGE_SIZE: NonZeroUsize = NonZeroUsize::new(100).unwrap();
+
+/// `archive`の出力に現れたノートのうち、`min_renote_count`回以上Renoteされたものを返す。
+pub fn read_renoted_notes(path: &Path, min_renote_count: NonZeroUsize) -> Result<Vec<NoteId>, Box<dyn Error + Send + Sync>> {
+    Ok(reader::read_notes(path)?.into_iter().filter(|x| x.renote_count >= min_renote_count.get()).map(|x| x.id).collect())
+}
+
+/// 書き終えたノートのID。1行に1つずつ書き足していく
+pub struct Checkpoint {
+    done: HashSet<NoteId>,
+    file: File,
+}
+
+impl Checkpoint {
+    /// 無ければ作る。
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let done = match fs::read_to_string(path) {
+            Ok(text) => text.lines().map(str::trim).filter(|x| !x.is_empty()).map(|x| NoteId(x.to_owned())).collect(),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => HashSet::new(),
+            Err(e) => return Err(e),
+        };
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+
+        Ok(Self { done, file })
+    }
+
+    pub fn contains(&self, note_id: &NoteId) -> bool {
+        self.done.contains(note_id)
+    }
+
+    pub fn mark(&mut self, note_id: &NoteId) -> io::Result<()> {
+        writeln!(self.file, "{}", note_id.0)?;
+        self.done.insert(note_id.clone());
+
+        Ok(())
+    }
+}
+
+/// それぞれのノートのRenoteを全て辿り、ユーザーごとに最初のRenoteだけを古い順に書き出す。
+/// 同じノートは1度だけ辿る。`checkpoint`にあるノートは飛ばし、書き出して`out`を流したノートを書き足す。
+pub async fn fetch_renotes(
+    client: &impl ApiClient,
+    pacer: &Pacer,
+    out: &mut (impl Write + Send + ?Sized),
+    notes: Vec<NoteId>,
+    mut checkpoint: Option<&mut Checkpoint>,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let total = notes.len();
+    let mut seen = HashSet::new();
+    let mut skipped = 0;
+    for (i, note_id) in notes.into_iter().enumerate() {
+        if !seen.insert(note_id.clone()) {
+            continue
+        }
+        if checkpoint.as_ref().is_some_and(|x| x.contains(&note_id)) {
+            skipped += 1;
+            continue
+        }
+
+        let mut renoters: BTreeMap<UserId, DateTime<Utc>> = BTreeMap::new();
+        let mut until_id = None;
+        loop {
+            pacer.wait().await;
+            let page = NoteRenotesCommand { note_id: note_id.clone(), limit: PAGE_SIZE, until_id }.send(client).await?;
+            let Some(last) = page.last() else {
+                break
+            };
+            until_id = Some(last.id.clone());
+            for renote in page {
+                renoters.entry(renote.user.id).and_modify(|at| *at = (*at).min(renote.created_at)).or_insert(renote.created_at);
+            }
+        }
+
+        let mut edges: Vec<_> = renoters.into_iter().collect();
+        edges.sort_by_key(|(_, at)| *at);
+        for (user_id, renoted_at) in edges {
+            writeln!(out, "{}", serde_json::json!({
+                "kind": "renote",
+                "note_id": note_id,
+                "user_id": user_id,
+                "renoted_at": renoted_at,
+            }))?;
+        }
+        out.flush()?;
+        if let Some(checkpoint) = checkpoint.as_mut() {
+            checkpoint.mark(&note_id)?;
+        }
+        progress!("renotes: {}/{total} notes", i + 1);
+    }
+    if skipped > 0 {
+        info!("skipped {skipped} note(s) already in the checkpoint");
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::num::NonZeroU32;
+    use std::time::Duration;
+
+    use serde_json::json;
+
+    use crate::capture::ReplayClient;
+    use crate::model::NoteId;
+    use crate::pacer::Pacer;
+    use crate::renotes::{fetch_renotes, Checkpoint};
+    use crate::testing::{capture_dir, exchange, note_json};
+
+    fn renote(id: &str, user: &str, created_at: &str) -> serde_json::Value {
+        let mut note = note_json(id, created_at);
+        note["user"] = json!({ "id": user, "username": user });
+        note["renoteId"] = json!("n1");
+        note["text"] = json!(null);
+        note
+    }
+
+    fn records(out: Vec<u8>) -> Vec<serde_json::Value> {
+        String::from_utf8(out).unwrap().lines().map(|x| serde_json::from_str(x).unwrap()).collect()
+    }
+
+    #[tokio::test]
+    async fn renoters_are_deduplicated_and_done_notes_are_skipped() {
+        let dir = capture_dir("renotes", &[
+            exchange("notes/renotes", json!({ "noteId": "n1", "limit": 100 }), &json!([
+                renote("r3", "u1", "2024-01-03T00:00:00.000Z"),
+                renote("r2", "u2", "2024-01-02T00:00:00.000Z"),
+            ])),
+            // 同じユーザーが2回Renoteしていれば、最初のものだけを書く
+            exchange("notes/renotes", json!({ "noteId": "n1", "limit": 100, "untilId": "r2" }), &json!([
+                renote("r1", "u1", "2024-01-01T00:00:00.000Z"),
+            ])),
+            exchange("notes/renotes", json!({ "noteId": "n1", "limit": 100, "untilId": "r1" }), &json!([])),
+        ]);
+        let client = ReplayClient::open(&dir).unwrap();
+        let pacer = Pacer::with_burst(Duration::ZERO, NonZeroU32::MIN);
+        let path = dir.join("checkpoint.txt");
+        fs::write(&path, "n0\n").unwrap();
+        let mut checkpoint = Checkpoint::open(&path).unwrap();
+        let notes = ["n0", "n1", "n1"].map(|x| NoteId(x.to_owned())).to_vec();
+        let mut out = vec![];
+
+        fetch_renotes(&client, &pacer, &mut out, notes, Some(&mut checkpoint)).await.unwrap();
+
+        assert_eq!(records(out), [
+            json!({ "kind": "renote", "note_id": "n1", "user_id": "u1", "renoted_at": "2024-01-01T00:00:00Z" }),
+            json!({ "kind": "renote", "note_id": "n1", "user_id": "u2", "renoted_at": "2024-01-02T00:00:00Z" }),
+        ]);
+        assert_eq!(fs::read_to_string(&path).unwrap(), "n0\nn1\n");
+
+        // 全て済んでいれば、1つもリクエストを送らない
+        let mut out = vec![];
+        let notes = vec![NoteId("n1".to_owned())];
+        fetch_renotes(&client, &pacer, &mut out, notes, Some(&mut Checkpoint::open(&path).unwrap())).await.unwrap();
+        assert!(out.is_empty());
+    }
+}
@@ -0,0 +1,335 @@
+//! `report`サブコマンド。書き出したアーカイブを集計し、モデレーター向けの月報にする。
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::error::Error;
+use std::fmt::{Display, Formatter, Write as _};
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+
+use chrono::{DateTime, Datelike, Days, Months, NaiveDate, Timelike, Utc};
+use serde::Serialize;
+
+use crate::clock;
+use crate::model::{Note, NoteId, UserId};
+use crate::timezone::Timezone;
+
+/// 順位表に載せる数
+const TOP: usize = 10;
+
+/// `--period`。`2024-11`のような月
+#[derive(Eq, PartialEq, Copy, Clone, Debug)]
+pub struct Period {
+    year: i32,
+    month: u32,
+}
+
+impl Period {
+    pub fn contains(self, date: NaiveDate) -> bool {
+        date.year() == self.year && date.month() == self.month
+    }
+}
+
+impl FromStr for Period {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.split_once('-')
+            .and_then(|(year, month)| Some(Self { year: year.parse().ok()?, month: month.parse().ok()? }))
+            .filter(|p| NaiveDate::from_ymd_opt(p.year, p.month, 1).is_some())
+            .ok_or_else(|| format!("expected a month like 2024-11, got {s}"))
+    }
+}
+
+impl Display for Period {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:04}-{:02}", self.year, self.month)
+    }
+}
+
+#[derive(Serialize)]
+pub struct Report {
+    kind: &'static str,
+    period: Option<String>,
+    timezone: String,
+    notes: usize,
+    notes_per_day: Vec<DayCount>,
+    top_posters: Vec<Poster>,
+    top_reacted_notes: Vec<ReactedNote>,
+    busiest_hours: Vec<HourCount>,
+    new_participants_per_week: Vec<WeekParticipants>,
+    /// `--detect-language`で書き出したノートがあるときだけ書く
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    notes_per_language: Vec<LanguageCount>,
+}
+
+#[derive(Serialize)]
+struct DayCount {
+    date: NaiveDate,
+    notes: usize,
+}
+
+#[derive(Serialize)]
+struct Poster {
+    user_id: UserId,
+    username: Option<String>,
+    notes: usize,
+}
+
+#[derive(Serialize)]
+struct ReactedNote {
+    note_id: NoteId,
+    user_id: UserId,
+    created_at: DateTime<Utc>,
+    reactions: usize,
+}
+
+#[derive(Serialize)]
+struct HourCount {
+    hour: u32,
+    notes: usize,
+}
+
+#[derive(Serialize)]
+struct LanguageCount {
+    /// 推定できなかったノートは`und`
+    language: String,
+    notes: usize,
+    /// 書いた人の数
+    users: usize,
+}
+
+#[derive(Serialize)]
+struct WeekParticipants {
+    /// ISO 8601の週。`2024-W45`
+    week: String,
+    users: Vec<UserId>,
+}
+
+/// `fetch-user`の出力から、IDとusernameの対応を読む。
+pub fn read_users(path: &Path) -> Result<HashMap<UserId, String>, Box<dyn Error + Send + Sync>> {
+    let text = fs::read_to_string(path).map_err(|e| format!("failed to open {}: {e}", path.display()))?;
+    let mut users = HashMap::new();
+    for line in text.lines().filter(|x| !x.trim().is_empty()) {
+        let record: serde_json::Value = serde_json::from_str(line)?;
+        if let (Some(id), Some(username)) = (record["id"].as_str(), record["username"].as_str()) {
+            users.insert(UserId(id.to_owned()), username.to_owned());
+        }
+    }
+
+    Ok(users)
+}
+
+/// 日付と時刻は`timezone`で数える。新しい参加者は、`period`より前も含めた`notes`全体で最初に書いた週に数える。
+/// `now`より先の日付のノートは、`now`に書いたものとして数える。
+pub fn build(notes: &[Note], users: &HashMap<UserId, String>, period: Option<Period>, timezone: &Timezone, now: DateTime<Utc>) -> Report {
+    let local = |note: &Note| timezone.to_local(clock::effective(note.created_at, now));
+    let date_of = |note: &Note| local(note).date_naive();
+    let in_period: Vec<&Note> = notes.iter().filter(|x| period.is_none_or(|p| p.contains(date_of(x)))).collect();
+    let username = |id: &UserId| users.get(id).cloned()
+        .or_else(|| notes.iter().find(|x| x.user.id == *id).and_then(|x| x.user.username.clone()));
+
+    let mut per_day = BTreeMap::new();
+    let mut per_hour = [0; 24];
+    let mut per_user = HashMap::new();
+    for note in &in_period {
+        *per_day.entry(date_of(note)).or_insert(0) += 1;
+        per_hour[local(note).hour() as usize] += 1;
+        *per_user.entry(&note.user.id).or_insert(0) += 1;
+    }
+
+    let days = period.map_or_else(
+        || per_day.keys().next().copied().zip(per_day.keys().next_back().copied()),
+        |p| NaiveDate::from_ymd_opt(p.year, p.month, 1).map(|first| (first, first + Months::new(1) - Days::new(1))),
+    );
+    let notes_per_day = days.map_or_else(Vec::new, |(first, last)| {
+        first.iter_days().take_while(|x| *x <= last).map(|date| DayCount { date, notes: per_day.get(&date).copied().unwrap_or(0) }).collect()
+    });
+
+    let mut posters: Vec<_> = per_user.into_iter().collect();
+    posters.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.0.cmp(&b.0.0)));
+    let top_posters = posters.into_iter().take(TOP)
+        .map(|(id, notes)| Poster { user_id: id.clone(), username: username(id), notes })
+        .collect();
+
+    let mut reacted: Vec<_> = in_period.iter()
+        .map(|x| (x, x.reactions.total() + x.other_reactions_count.unwrap_or(0)))
+        .filter(|(_, n)| *n > 0)
+        .collect();
+    reacted.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.created_at.cmp(&b.0.created_at)));
+    let top_reacted_notes = reacted.into_iter().take(TOP)
+        .map(|(x, reactions)| ReactedNote { note_id: x.id.clone(), user_id: x.user.id.clone(), created_at: x.created_at, reactions })
+        .collect();
+
+    let mut first_seen: HashMap<&UserId, &Note> = HashMap::new();
+    for note in notes {
+        first_seen.entry(&note.user.id).and_modify(|x| if note.created_at < x.created_at { *x = note }).or_insert(note);
+    }
+    let mut per_week: BTreeMap<String, Vec<UserId>> = BTreeMap::new();
+    for (id, note) in first_seen.into_iter().filter(|(_, x)| period.is_none_or(|p| p.contains(date_of(x)))) {
+        per_week.entry(date_of(note).format("%G-W%V").to_string()).or_default().push(id.clone());
+    }
+
+    Report {
+        kind: "report",
+        period: period.map(|p| p.to_string()),
+        timezone: timezone.to_string(),
+        notes: in_period.len(),
+        notes_per_day,
+        top_posters,
+        top_reacted_notes,
+        busiest_hours: (0..).zip(per_hour).map(|(hour, notes)| HourCount { hour, notes }).collect(),
+        new_participants_per_week: per_week.into_iter().map(|(week, mut users)| {
+            users.sort_by(|a, b| a.0.cmp(&b.0));
+            WeekParticipants { week, users }
+        }).collect(),
+        notes_per_language: per_language(&in_period),
+    }
+}
+
+/// 言語の推定されたノートが1つも無ければ空。多い順に並べる
+fn per_language(notes: &[&Note]) -> Vec<LanguageCount> {
+    if notes.iter().all(|x| x.detected_language.is_none()) {
+        return vec![]
+    }
+
+    let mut per_language: BTreeMap<&str, (usize, HashSet<&UserId>)> = BTreeMap::new();
+    for note in notes {
+        let (count, users) = per_language.entry(note.detected_language.as_deref().unwrap_or("und")).or_default();
+        *count += 1;
+        users.insert(&note.user.id);
+    }
+    let mut counts: Vec<_> = per_language.into_iter()
+        .map(|(language, (notes, users))| LanguageCount { language: language.to_owned(), notes, users: users.len() })
+        .collect();
+    counts.sort_by_key(|x| std::cmp::Reverse(x.notes));
+
+    counts
+}
+
+/// 表で読めるようにしたもの
+pub fn to_markdown(report: &Report) -> String {
+    let user = |id: &UserId, username: Option<&String>| username.map_or_else(|| id.0.clone(), |x| format!("@{x}"));
+    let mut md = String::new();
+    let _ = writeln!(md, "# Channel report{}\n", report.period.as_ref().map_or_else(String::new, |p| format!(" {p}")));
+    let _ = writeln!(md, "{} notes, timezone {}\n", report.notes, report.timezone);
+
+    md.push_str("## Notes per day\n\n| Date | Notes |\n| --- | ---: |\n");
+    for day in &report.notes_per_day {
+        let _ = writeln!(md, "| {} | {} |", day.date, day.notes);
+    }
+    md.push_str("\n## Top posters\n\n| User | Notes |\n| --- | ---: |\n");
+    for poster in &report.top_posters {
+        let _ = writeln!(md, "| {} | {} |", user(&poster.user_id, poster.username.as_ref()), poster.notes);
+    }
+    md.push_str("\n## Top reacted notes\n\n| Note | User | Reactions |\n| --- | --- | ---: |\n");
+    for note in &report.top_reacted_notes {
+        let username = report.top_posters.iter().find(|x| x.user_id == note.user_id).and_then(|x| x.username.as_ref());
+        let _ = writeln!(md, "| {} | {} | {} |", note.note_id.0, user(&note.user_id, username), note.reactions);
+    }
+    md.push_str("\n## Busiest hours\n\n| Hour | Notes |\n| ---: | ---: |\n");
+    for hour in &report.busiest_hours {
+        let _ = writeln!(md, "| {:02}:00 | {} |", hour.hour, hour.notes);
+    }
+    md.push_str("\n## New participants per week\n\n| Week | New participants |\n| --- | ---: |\n");
+    for week in &report.new_participants_per_week {
+        let _ = writeln!(md, "| {} | {} |", week.week, week.users.len());
+    }
+    if !report.notes_per_language.is_empty() {
+        md.push_str("\n## Notes per language\n\n| Language | Notes | Users |\n| --- | ---: | ---: |\n");
+        for language in &report.notes_per_language {
+            let _ = writeln!(md, "| {} | {} | {} |", language.language, language.notes, language.users);
+        }
+    }
+
+    md
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use chrono::FixedOffset;
+    use serde_json::json;
+
+    use crate::model::{Note, UserId};
+    use crate::report::{build, to_markdown, Period};
+    use crate::testing::note_json;
+    use crate::timezone::Timezone;
+
+    fn note(id: &str, user: &str, created_at: &str, reactions: usize) -> Note {
+        let mut note = note_json(id, created_at);
+        note["user"] = json!({ "id": user });
+        note["reactions"] = if reactions == 0 { json!({}) } else { json!({ "👍": reactions }) };
+        serde_json::from_value(note).unwrap()
+    }
+
+    #[test]
+    fn aggregates_a_month_in_the_local_timezone() {
+        let notes = [
+            // 日本時間では11月1日
+            note("n1", "u1", "2024-10-31T15:30:00Z", 3),
+            note("n2", "u1", "2024-11-02T01:00:00Z", 0),
+            note("n3", "u2", "2024-11-05T03:00:00Z", 5),
+            // 10月からいるので、新しい参加者ではない
+            note("n0", "u3", "2024-10-01T00:00:00Z", 1),
+            note("n4", "u3", "2024-11-30T00:00:00Z", 0),
+        ];
+        let users = HashMap::from([(UserId("u1".to_owned()), "alice".to_owned())]);
+        let tokyo = Timezone::Fixed(FixedOffset::east_opt(9 * 3600).unwrap());
+
+        let report = build(&notes, &users, Some("2024-11".parse().unwrap()), &tokyo, "2024-12-01T00:00:00Z".parse().unwrap());
+        let value = serde_json::to_value(&report).unwrap();
+
+        assert_eq!(value["notes"], 4);
+        assert_eq!(value["notes_per_day"].as_array().unwrap().len(), 30);
+        assert_eq!(value["notes_per_day"][0], json!({ "date": "2024-11-01", "notes": 1 }));
+        assert_eq!(value["top_posters"][0], json!({ "user_id": "u1", "username": "alice", "notes": 2 }));
+        assert_eq!(value["top_reacted_notes"][0]["note_id"], "n3");
+        assert_eq!(value["busiest_hours"][0], json!({ "hour": 0, "notes": 1 }));
+        assert_eq!(value["new_participants_per_week"], json!([
+            { "week": "2024-W44", "users": ["u1"] },
+            { "week": "2024-W45", "users": ["u2"] },
+        ]));
+
+        let md = to_markdown(&report);
+        assert!(md.starts_with("# Channel report 2024-11\n"));
+        assert!(md.contains("| @alice | 2 |"));
+    }
+
+    #[test]
+    fn future_notes_are_counted_at_the_time_of_the_report() {
+        let notes = [note("n1", "u1", "2024-11-01T00:00:00Z", 0), note("n2", "u1", "2106-02-07T06:28:16Z", 0)];
+
+        let report = build(&notes, &HashMap::new(), None, &Timezone::default(), "2024-11-03T12:00:00Z".parse().unwrap());
+        let value = serde_json::to_value(&report).unwrap();
+
+        assert_eq!(value["notes_per_day"].as_array().unwrap().len(), 3);
+        assert_eq!(value["notes_per_day"][2], json!({ "date": "2024-11-03", "notes": 1 }));
+        assert_eq!(value["busiest_hours"][0], json!({ "hour": 0, "notes": 1 }));
+    }
+
+    #[test]
+    fn detected_languages_are_broken_down_only_when_present() {
+        let mut notes = [note("n1", "u1", "2024-11-01T00:00:00Z", 0), note("n2", "u2", "2024-11-02T00:00:00Z", 0), note("n3", "u1", "2024-11-03T00:00:00Z", 0)];
+        let build = |notes: &[Note]| build(notes, &HashMap::new(), None, &Timezone::default(), "2024-12-01T00:00:00Z".parse().unwrap());
+        assert!(serde_json::to_value(build(&notes)).unwrap().get("notes_per_language").is_none());
+
+        notes[0].detected_language = Some("ja".to_owned());
+        notes[1].detected_language = Some("ja".to_owned());
+        let report = build(&notes);
+
+        assert_eq!(serde_json::to_value(&report).unwrap()["notes_per_language"], json!([
+            { "language": "ja", "notes": 2, "users": 2 },
+            { "language": "und", "notes": 1, "users": 1 },
+        ]));
+        assert!(to_markdown(&report).contains("| ja | 2 | 2 |\n| und | 1 | 1 |\n"));
+    }
+
+    #[test]
+    fn rejects_malformed_periods() {
+        assert_eq!("2024-11".parse::<Period>().unwrap().to_string(), "2024-11");
+        assert!("2024-13".parse::<Period>().is_err());
+        assert!("november".parse::<Period>().is_err());
+    }
+}
@@ -0,0 +1,622 @@
+//! `schema`。書き出す記録の形を、JSON Schema (2020-12)かTypeScriptの型として書き出す。
+//!
+//! 形は型から導かずにここへ書き下している。モデルや記録を変えたらここも変える。
+//! テストで実際の`archive`の出力をこのスキーマで確かめるので、食い違えば気づける。
+
+use std::collections::BTreeSet;
+use std::fmt::Write as _;
+
+use clap::ValueEnum;
+use serde_json::{json, Map, Value};
+
+use crate::status::Outcome;
+
+/// `schema --record`
+#[derive(Eq, PartialEq, Copy, Clone, Debug, ValueEnum)]
+pub enum RecordKind {
+    /// 出力の先頭に書く、形式の記録
+    Meta,
+    Account,
+    /// 取得したアカウントとチャンネルの関係
+    Relationship,
+    Channel,
+    List,
+    /// 1ページ分のノートの配列
+    Page,
+    /// `--emit-page-markers`で、ページの前に書くリクエスト
+    PageMarker,
+    /// `--canonical`のときの、1つのノート
+    Note,
+    PinnedNote,
+    Replies,
+    Summary,
+    DryRun,
+    Gap,
+    /// `--fetch-replies-to-archived`で、返信を取れなかった親
+    ReplyGap,
+    /// `fetch-notes`では`note_id`だけを持つ
+    Tombstone,
+    NoteError,
+    Warning,
+    Log,
+    Status,
+    /// `fetch-user`の1行
+    User,
+    /// `fetch-user`で、消えていたユーザー
+    UserTombstone,
+    Follow,
+    Hidden,
+    Renote,
+    Reaction,
+    /// `snapshot-featured`の1行
+    FeaturedSnapshot,
+    /// `estimate-files`の1行
+    FileEstimate,
+    /// `explore`の、チャンネルごとの1行
+    NoteDensity,
+}
+
+impl RecordKind {
+    const ALL: [Self; 28] = [
+        Self::Meta, Self::Account, Self::Relationship, Self::Channel, Self::List, Self::Page, Self::PageMarker, Self::Note, Self::PinnedNote, Self::Replies,
+        Self::Summary, Self::DryRun, Self::Gap, Self::ReplyGap, Self::Tombstone, Self::NoteError, Self::Warning, Self::Log, Self::Status, Self::User,
+        Self::UserTombstone, Self::Follow, Self::Hidden, Self::Renote, Self::Reaction, Self::FeaturedSnapshot, Self::FileEstimate,
+        Self::NoteDensity,
+    ];
+
+    /// `$defs`での名前。TypeScriptの型の名前にもする
+    const fn title(self) -> &'static str {
+        match self {
+            Self::Meta => "MetaRecord",
+            Self::Account => "AccountRecord",
+            Self::Relationship => "RelationshipRecord",
+            Self::Channel => "ChannelRecord",
+            Self::List => "ListRecord",
+            Self::Page => "Page",
+            Self::PageMarker => "PageMarkerRecord",
+            Self::Note => "NoteRecord",
+            Self::PinnedNote => "PinnedNoteRecord",
+            Self::Replies => "RepliesRecord",
+            Self::Summary => "SummaryRecord",
+            Self::DryRun => "DryRunRecord",
+            Self::Gap => "GapRecord",
+            Self::ReplyGap => "ReplyGapRecord",
+            Self::Tombstone => "TombstoneRecord",
+            Self::NoteError => "NoteErrorRecord",
+            Self::Warning => "WarningRecord",
+            Self::Log => "LogRecord",
+            Self::Status => "StatusRecord",
+            Self::User => "User",
+            Self::UserTombstone => "UserTombstoneRecord",
+            Self::Follow => "FollowRecord",
+            Self::Hidden => "HiddenRecord",
+            Self::Renote => "RenoteRecord",
+            Self::Reaction => "ReactionRecord",
+            Self::FeaturedSnapshot => "FeaturedSnapshotRecord",
+            Self::FileEstimate => "FileEstimateRecord",
+            Self::NoteDensity => "NoteDensityRecord",
+        }
+    }
+
+    fn schema(self) -> Value {
+        match self {
+            Self::Meta => meta(),
+            Self::Account => record("account", &[("account", reference("Account"))], &["account"]),
+            Self::Relationship => record("relationship", &[
+                ("channel_id", string()),
+                ("account_id", string()),
+                ("following", nullable(boolean())),
+                ("owner", nullable(boolean())),
+            ], &["channel_id", "account_id", "following", "owner"]),
+            Self::Channel => record("channel", &[("channel", reference("Channel"))], &["channel"]),
+            Self::List => record("list", &[("list", reference("List"))], &["list"]),
+            Self::Page => json!({ "type": "array", "items": reference("Note") }),
+            Self::PageMarker => page_marker(),
+            Self::Note => record("note", &[("note", reference("Note"))], &["note"]),
+            // チャンネルかユーザーのどちらかがピン留めしたもの
+            Self::PinnedNote => record("pinned-note", &[("channel_id", string()), ("user_id", string()), ("note", reference("Note"))], &["note"]),
+            Self::Replies => record("replies", &[("parent_id", string()), ("notes", Self::Page.schema())], &["parent_id", "notes"]),
+            Self::Summary => record("summary", &[
+                ("outcome", json!({ "enum": ["ok", "failed", "channel-became-unavailable"] })),
+                ("channel_id", string()),
+                ("list_id", string()),
+                ("notes", count()),
+                ("pages", count()),
+                ("replies", count()),
+                ("tombstones", count()),
+                ("channel_notes_count_before", nullable(count())),
+                ("channel_notes_count_after", nullable(count())),
+                ("notes_count_delta", nullable(json!({ "type": "integer" }))),
+                ("empty_reason", nullable(json!({ "enum": ["no-new-notes", "channel-empty", "unknown"] }))),
+                // `channel-became-unavailable`なら、Misskeyのエラーコード
+                ("error_code", string()),
+                ("error", string()),
+            ], &["outcome"]),
+            Self::DryRun => record("dry-run", &[
+                ("channel_id", string()),
+                ("list_id", string()),
+                ("notes", count()),
+                ("oldest", nullable(date_time())),
+                ("channel_notes_count", nullable(count())),
+                ("estimated_seconds", nullable(count())),
+                ("estimated_requests", nullable(count())),
+            ], &["notes", "oldest", "channel_notes_count", "estimated_seconds", "estimated_requests"]),
+            Self::Gap => record("gap", &[
+                ("direction", json!({ "enum": ["backward", "forward"] })),
+                ("channel_id", string()),
+                ("list_id", string()),
+                ("until_id", nullable(string())),
+                ("since_id", nullable(string())),
+                ("cursor", nullable(reference("Cursor"))),
+                ("limit", json!({ "type": "integer", "minimum": 1 })),
+                ("error", string()),
+            ], &["direction", "until_id", "since_id", "cursor", "limit", "error"]),
+            Self::ReplyGap => record("reply-gap", &[
+                ("parent_id", string()),
+                ("until_id", nullable(string())),
+                ("error", string()),
+            ], &["parent_id", "until_id", "error"]),
+            Self::Tombstone => record("tombstone", &[
+                ("note_id", string()),
+                ("channel_id", string()),
+                ("confirmed", boolean()),
+                ("checked_at", nullable(date_time())),
+                ("last_known", reference("Note")),
+                ("error", string()),
+            ], &["note_id"]),
+            Self::NoteError => record("note-error", &[("note_id", string()), ("error", string())], &["note_id", "error"]),
+            // 場面ごとに手がかりを添えるので、`message`の他は決めない
+            Self::Warning => open_record("warning"),
+            Self::Log => open_record("log"),
+            Self::Status => record("status", &[
+                ("outcome", json!({ "enum": Outcome::ALL.map(Outcome::name) })),
+                ("exit_code", json!({ "enum": Outcome::ALL.map(Outcome::code).into_iter().collect::<BTreeSet<_>>() })),
+                ("gaps", count()),
+                ("error", nullable(string())),
+            ], &["outcome", "exit_code", "gaps", "error"]),
+            Self::User => object(&[
+                ("id", string()),
+                ("name", nullable(string())),
+                ("username", string()),
+                ("isBot", boolean()),
+                ("isCat", boolean()),
+                ("avatarUrl", url()),
+                ("notesCount", count()),
+                ("pinnedNoteIds", json!({ "type": "array", "items": string() })),
+                ("isSuspended", boolean()),
+                ("isLocked", boolean()),
+                ("isDeleted", boolean()),
+            ], &["id", "name", "username", "isBot", "isCat", "avatarUrl", "notesCount"]),
+            Self::UserTombstone => record("user-tombstone", &[("user_id", string()), ("checked_at", date_time())], &["user_id", "checked_at"]),
+            Self::Follow => record("follow", &[("follower", string()), ("followee", string()), ("created_at", date_time())], &["follower", "followee", "created_at"]),
+            Self::Hidden => record("hidden", &[("user_id", string()), ("relation", json!({ "enum": ["followers", "following"] }))], &["user_id", "relation"]),
+            Self::Renote => record("renote", &[("note_id", string()), ("user_id", string()), ("renoted_at", date_time())], &["note_id", "user_id", "renoted_at"]),
+            Self::Reaction => record("reaction", &[
+                ("note_id", string()),
+                ("user_id", string()),
+                ("reaction", string()),
+                ("created_at", date_time()),
+            ], &["note_id", "user_id", "reaction", "created_at"]),
+            Self::FeaturedSnapshot => featured_snapshot(),
+            Self::FileEstimate => file_estimate(),
+            Self::NoteDensity => note_density(),
+        }
+    }
+}
+
+fn page_marker() -> Value {
+    record("page", &[
+        ("params", object(&[
+            ("channelId", string()),
+            ("listId", string()),
+            ("limit", json!({ "type": "integer", "minimum": 1 })),
+            ("sinceId", string()),
+            ("untilId", string()),
+            ("sinceDate", count()),
+            ("untilDate", count()),
+            ("attempt", json!({ "type": "integer", "minimum": 1 })),
+        ], &["limit", "attempt"])),
+        ("returned", count()),
+        ("new", count()),
+    ], &["params", "returned", "new"])
+}
+
+fn meta() -> Value {
+    record("meta", &[
+        ("timestamp_format", json!({ "enum": ["rfc3339", "epoch-ms", "epoch-s"] })),
+        ("timezone", string()),
+        ("run_id", string()),
+        ("archived_at", date_time()),
+        ("possibly_incomplete", json!({ "const": true })),
+        ("field_map", json!({ "type": "object", "additionalProperties": false, "properties": {
+            "note": { "type": "object", "additionalProperties": string() },
+            "user": { "type": "object", "additionalProperties": string() },
+        } })),
+    ], &["timestamp_format", "timezone"])
+}
+
+fn featured_snapshot() -> Value {
+    record("featured-snapshot", &[
+        ("taken_at", date_time()),
+        ("channels", json!({ "type": "array", "items": object(&[("id", string()), ("name", string())], &["id", "name"]) })),
+        ("channels_unavailable", nullable(string())),
+        ("trending_tags", json!({ "type": "array", "items": object(&[("tag", string()), ("users_count", count())], &["tag", "users_count"]) })),
+        ("trending_tags_unavailable", nullable(string())),
+    ], &["taken_at", "channels", "channels_unavailable", "trending_tags", "trending_tags_unavailable"])
+}
+
+fn file_estimate() -> Value {
+    record("file-estimate", &[
+        ("files", count()),
+        // `approximate`なら、`size`の無いファイルをHEADで確かめた平均で埋めたもの
+        ("bytes", count()),
+        ("known_bytes", count()),
+        ("approximate", boolean()),
+        ("without_size", count()),
+        ("probed", count()),
+        ("by_type", json!({ "type": "object", "additionalProperties": object(&[("files", count()), ("bytes", count())], &["files", "bytes"]) })),
+        ("largest", json!({ "type": "array", "items": object(&[
+            ("note_id", string()),
+            ("id", string()),
+            ("name", nullable(string())),
+            ("type", nullable(string())),
+            ("size", nullable(count())),
+            ("url", nullable(url_reference())),
+        ], &["note_id", "id", "name", "type", "size", "url"]) })),
+    ], &["files", "bytes", "known_bytes", "approximate", "without_size", "probed", "by_type", "largest"])
+}
+
+fn note_density() -> Value {
+    record("note-density", &[
+        ("channel_id", string()),
+        ("granularity", json!({ "enum": ["day", "month", "year"] })),
+        ("buckets", json!({ "type": "array", "items": object(&[
+            ("bucket", string()),
+            ("since", date_time()),
+            ("until", date_time()),
+            ("notes", count()),
+            // `false`なら、埋まったページから延ばして見積もったところがある
+            ("exact", boolean()),
+        ], &["bucket", "since", "until", "notes", "exact"]) })),
+    ], &["channel_id", "granularity", "buckets"])
+}
+
+/// `schema --format`
+#[derive(Eq, PartialEq, Copy, Clone, Debug, ValueEnum)]
+pub enum SchemaFormat {
+    JsonSchema,
+    Typescript,
+}
+
+/// 記録の中に現れる、記録ではない型
+fn shared() -> [(&'static str, Value); 8] {
+    [
+        ("Timestamp", json!({
+            "description": "--timestamp-format: a string for rfc3339, milliseconds or seconds since the UNIX epoch otherwise",
+            "type": ["string", "integer"],
+        })),
+        ("Note", object(&[
+            ("id", string()),
+            ("createdAt", reference("Timestamp")),
+            ("user", reference("NoteUser")),
+            ("text", nullable(string())),
+            ("cw", nullable(string())),
+            ("replyId", nullable(string())),
+            ("renoteId", nullable(string())),
+            ("renoteCount", count()),
+            ("repliesCount", count()),
+            ("reactions", json!({ "type": "object", "additionalProperties": { "type": "integer", "minimum": 1 } })),
+            ("files", json!({ "type": "array", "items": reference("DriveFile") })),
+            ("other_reactions_count", count()),
+            ("myReaction", string()),
+            ("channel_id", string()),
+            ("channel", object(&[("id", string()), ("name", string())], &["id", "name"])),
+            ("local_url", url()),
+            ("translation", object(&[("lang", string()), ("text", string())], &["lang", "text"])),
+            ("refreshed_at", date_time()),
+            ("detected_language", string()),
+            ("hidden_author", json!({ "enum": ["muted", "blocked"] })),
+            ("content_hash", string()),
+            // `--canonical`でなければ、書き出すときに添える
+            ("archived_at", date_time()),
+            ("run_id", string()),
+            ("extra", json!({ "type": "object" })),
+        ], &["id", "createdAt", "user", "text", "cw", "replyId", "renoteId", "renoteCount", "repliesCount", "reactions"])),
+        // `--inline-user-detail`なら`username`なども書く
+        ("NoteUser", object(&[
+            ("id", string()),
+            ("local_url", url()),
+            ("username", nullable(string())),
+            ("name", nullable(string())),
+            ("avatarUrl", nullable(url())),
+            ("host", nullable(string())),
+        ], &["id"])),
+        ("DriveFile", object(&[
+            ("id", string()),
+            ("name", nullable(string())),
+            ("type", nullable(string())),
+            ("size", nullable(count())),
+            ("url", nullable(url_reference())),
+        ], &["id", "name", "type", "size", "url"])),
+        ("Account", object(&[("id", string()), ("username", string())], &["id", "username"])),
+        ("Cursor", object(&[("id", string()), ("created_at", date_time())], &["id", "created_at"])),
+        ("Channel", object(&[
+            ("id", string()),
+            ("name", string()),
+            ("notesCount", nullable(count())),
+            ("description", nullable(string())),
+            ("bannerUrl", nullable(url())),
+            ("pinnedNoteIds", json!({ "type": "array", "items": string() })),
+        ], &["id", "name", "notesCount", "description", "bannerUrl", "pinnedNoteIds"])),
+        ("List", object(&[
+            ("id", string()),
+            ("name", string()),
+            ("user_ids", json!({ "type": "array", "items": string() })),
+        ], &["id", "name", "user_ids"])),
+    ]
+}
+
+fn string() -> Value {
+    json!({ "type": "string" })
+}
+
+fn boolean() -> Value {
+    json!({ "type": "boolean" })
+}
+
+fn count() -> Value {
+    json!({ "type": "integer", "minimum": 0 })
+}
+
+fn url() -> Value {
+    json!({ "type": "string", "format": "uri" })
+}
+
+/// 相対URLも許す
+fn url_reference() -> Value {
+    json!({ "type": "string", "format": "uri-reference" })
+}
+
+/// `--timestamp-format`に関わらず、RFC 3339で書く日時
+fn date_time() -> Value {
+    json!({ "type": "string", "format": "date-time" })
+}
+
+fn reference(name: &str) -> Value {
+    json!({ "$ref": format!("#/$defs/{name}") })
+}
+
+fn nullable(mut schema: Value) -> Value {
+    if let Some(kind) = schema["type"].as_str() {
+        schema["type"] = json!([kind, "null"]);
+        return schema
+    }
+
+    json!({ "anyOf": [schema, { "type": "null" }] })
+}
+
+/// 知らないフィールドは許さない
+fn object(properties: &[(&str, Value)], required: &[&str]) -> Value {
+    let properties: Map<String, Value> = properties.iter().map(|(name, schema)| ((*name).to_owned(), schema.clone())).collect();
+
+    json!({ "type": "object", "properties": properties, "required": required, "additionalProperties": false })
+}
+
+/// `{"kind": kind}`を持つ記録
+fn record(kind: &str, properties: &[(&str, Value)], required: &[&str]) -> Value {
+    let properties: Vec<_> = std::iter::once(("kind", json!({ "const": kind }))).chain(properties.iter().cloned()).collect();
+    let required: Vec<_> = std::iter::once("kind").chain(required.iter().copied()).collect();
+
+    object(&properties, &required)
+}
+
+fn open_record(kind: &str) -> Value {
+    let mut schema = record(kind, &[("message", string())], &["message"]);
+    schema["additionalProperties"] = true.into();
+
+    schema
+}
+
+fn definitions() -> Map<String, Value> {
+    shared().into_iter()
+        .map(|(name, schema)| (name.to_owned(), schema))
+        .chain(RecordKind::ALL.map(|x| (x.title().to_owned(), x.schema())))
+        .collect()
+}
+
+/// `$defs`の`name`の、一番上のフィールドの名前。`--field-map`を確かめるのに使う
+pub fn field_names(name: &str) -> BTreeSet<String> {
+    definitions().get(name).and_then(|x| x["properties"].as_object()).map(|x| x.keys().cloned().collect()).unwrap_or_default()
+}
+
+/// `record`が無ければ、出力のどの行にも当てはまる文書にする。
+pub fn document(record: Option<RecordKind>) -> Value {
+    let mut document = json!({
+        "$schema": "https://json-schema.org/draft/2020-12/schema",
+        "title": record.map_or("misskey-channel-archiver record", RecordKind::title),
+    });
+    match record {
+        Some(record) => document["$ref"] = reference(record.title())["$ref"].clone(),
+        None => document["oneOf"] = RecordKind::ALL.map(|x| reference(x.title())).into(),
+    }
+    document["$defs"] = definitions().into();
+
+    document
+}
+
+/// `record`が無ければ、全ての型と、それらをまとめた`Record`を書く。
+pub fn typescript(record: Option<RecordKind>) -> String {
+    let mut ts = String::new();
+    for (name, schema) in &definitions() {
+        let _ = writeln!(ts, "export type {name} = {};", ts_type(schema));
+    }
+    let records = record.map_or_else(|| RecordKind::ALL.map(RecordKind::title).join(" | "), |x| x.title().to_owned());
+    let _ = writeln!(ts, "export type Record = {records};");
+
+    ts
+}
+
+fn ts_type(schema: &Value) -> String {
+    if let Some(target) = schema["$ref"].as_str() {
+        return target.trim_start_matches("#/$defs/").to_owned()
+    }
+    if let Some(value) = schema.get("const") {
+        return value.to_string()
+    }
+    if let Some(values) = schema["enum"].as_array() {
+        return values.iter().map(Value::to_string).collect::<Vec<_>>().join(" | ")
+    }
+    if let Some(variants) = schema["anyOf"].as_array() {
+        return variants.iter().map(ts_type).collect::<Vec<_>>().join(" | ")
+    }
+
+    match &schema["type"] {
+        Value::Array(kinds) => kinds.iter().map(|kind| ts_primitive(kind.as_str().unwrap_or_default(), schema)).collect::<Vec<_>>().join(" | "),
+        Value::String(kind) => ts_primitive(kind, schema),
+        _ => "unknown".to_owned(),
+    }
+}
+
+fn ts_primitive(kind: &str, schema: &Value) -> String {
+    match kind {
+        "string" => "string".to_owned(),
+        "integer" | "number" => "number".to_owned(),
+        "boolean" => "boolean".to_owned(),
+        "null" => "null".to_owned(),
+        "array" => {
+            let items = ts_type(&schema["items"]);
+            if items.contains(' ') { format!("({items})[]") } else { format!("{items}[]") }
+        }
+        "object" => {
+            let required: Vec<_> = schema["required"].as_array().into_iter().flatten().filter_map(Value::as_str).collect();
+            let mut fields: Vec<_> = schema["properties"].as_object().into_iter().flatten()
+                .map(|(name, x)| format!("{name}{}: {}", if required.contains(&name.as_str()) { "" } else { "?" }, ts_type(x)))
+                .collect();
+            match &schema["additionalProperties"] {
+                Value::Bool(false) => {}
+                Value::Object(_) => fields.push(format!("[key: string]: {}", ts_type(&schema["additionalProperties"]))),
+                _ => fields.push("[key: string]: unknown".to_owned()),
+            }
+            format!("{{ {} }}", fields.join("; "))
+        }
+        _ => "unknown".to_owned(),
+    }
+}
+
+/// テストで出力を確かめるための、このモジュールが書く範囲のJSON Schemaだけを解釈する検証
+#[cfg(test)]
+pub fn validate(document: &Value, schema: &Value, value: &Value, path: &str) -> Result<(), String> {
+    if let Some(target) = schema["$ref"].as_str() {
+        let name = target.trim_start_matches("#/$defs/");
+        return validate(document, &document["$defs"][name], value, path)
+    }
+    if let Some(expected) = schema.get("const").filter(|x| *x != value) {
+        return Err(format!("{path}: expected {expected}, got {value}"))
+    }
+    if let Some(values) = schema["enum"].as_array().filter(|x| !x.contains(value)) {
+        return Err(format!("{path}: {value} is not one of {}", Value::from(values.clone())))
+    }
+    for key in ["anyOf", "oneOf"] {
+        if let Some(variants) = schema[key].as_array() {
+            let errors: Vec<_> = variants.iter().filter_map(|x| validate(document, x, value, path).err()).collect();
+            if errors.len() == variants.len() {
+                return Err(format!("{path}: matches none of {key}: {}", errors.join("; ")))
+            }
+        }
+    }
+
+    let kinds: Vec<_> = match &schema["type"] {
+        Value::String(kind) => vec![kind.as_str()],
+        Value::Array(kinds) => kinds.iter().filter_map(Value::as_str).collect(),
+        _ => return Ok(()),
+    };
+    let matches = |kind: &str| match kind {
+        "string" => value.is_string(),
+        "integer" => value.is_i64() || value.is_u64(),
+        "number" => value.is_number(),
+        "boolean" => value.is_boolean(),
+        "null" => value.is_null(),
+        "array" => value.is_array(),
+        "object" => value.is_object(),
+        _ => false,
+    };
+    if !kinds.iter().any(|x| matches(x)) {
+        return Err(format!("{path}: expected {}, got {value}", kinds.join(" or ")))
+    }
+    if let Some(minimum) = schema["minimum"].as_i64().filter(|x| value.as_i64().is_some_and(|v| v < *x)) {
+        return Err(format!("{path}: {value} is less than {minimum}"))
+    }
+
+    if let Value::Array(items) = value {
+        for (i, item) in items.iter().enumerate() {
+            validate(document, &schema["items"], item, &format!("{path}[{i}]"))?;
+        }
+    }
+    if let Value::Object(fields) = value {
+        for name in schema["required"].as_array().into_iter().flatten().filter_map(Value::as_str) {
+            if !fields.contains_key(name) {
+                return Err(format!("{path}: missing {name}"))
+            }
+        }
+        for (name, field) in fields {
+            let path = format!("{path}.{name}");
+            match (schema["properties"].get(name), &schema["additionalProperties"]) {
+                (Some(property), _) => validate(document, property, field, &path)?,
+                (None, Value::Bool(false)) => return Err(format!("{path}: not in the schema")),
+                (None, Value::Object(_)) => validate(document, &schema["additionalProperties"], field, &path)?,
+                (None, _) => {}
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use crate::model::Note;
+    use crate::schema::{document, typescript, validate, RecordKind};
+    use crate::testing::note_json;
+    use crate::timestamp::{Formatted, TimestampFormat};
+
+    #[test]
+    fn every_field_of_a_note_is_described() {
+        let mut value = note_json("n1", "2024-01-01T00:00:00.000Z");
+        value["user"] = json!({ "id": "u1", "username": "alice", "name": null, "avatarUrl": null, "host": null });
+        value["channelId"] = json!("ch");
+        value["channel"] = json!({ "id": "ch", "name": "general" });
+        value["myReaction"] = json!("👍");
+        value["files"] = json!([{ "id": "f1", "name": "cat.png", "type": "image/png", "size": 1024, "url": "https://misskey.example/files/f1", "md5": "00" }]);
+        value["translation"] = json!({ "lang": "en", "text": "hello" });
+        value["refreshed_at"] = json!("2024-01-02T00:00:00Z");
+        value["content_hash"] = json!("sha256:00");
+        value["extra"] = json!({ "visibility": "home" });
+        let mut note: Note = serde_json::from_value(value).unwrap();
+        note.other_reactions_count = Some(3);
+        note.fill_urls(&"misskey.example".parse().unwrap());
+        note.user.inline_detail();
+        let schema = document(Some(RecordKind::Note));
+
+        for format in [TimestampFormat::Rfc3339, TimestampFormat::EpochMs] {
+            let record = json!({ "kind": "note", "note": Formatted(&note, format) });
+            validate(&schema, &schema, &record, "$").unwrap();
+        }
+        let mut unknown = json!({ "kind": "note", "note": Formatted(&note, TimestampFormat::Rfc3339) });
+        unknown["note"]["visibility"] = json!("home");
+        assert_eq!(validate(&schema, &schema, &unknown, "$").unwrap_err(), "$.note.visibility: not in the schema");
+    }
+
+    #[test]
+    fn typescript_follows_the_schema() {
+        let ts = typescript(None);
+
+        assert!(ts.contains("export type Page = Note[];\n"), "{ts}");
+        assert!(ts.contains("export type RenoteRecord = { kind: \"renote\"; note_id: string; renoted_at: string; user_id: string };\n"), "{ts}");
+        assert!(ts.contains("reactions: { [key: string]: number }; refreshed_at?: string;"), "{ts}");
+        assert!(ts.contains("export type LogRecord = { kind: \"log\"; message: string; [key: string]: unknown };\n"), "{ts}");
+        assert!(ts.ends_with(" | RenoteRecord | ReactionRecord | FeaturedSnapshotRecord | FileEstimateRecord | NoteDensityRecord;\n"));
+        assert_eq!(typescript(Some(RecordKind::User)).lines().last(), Some("export type Record = User;"));
+    }
+}
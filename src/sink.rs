@@ -0,0 +1,380 @@
+//! `--sink`。記録を1行ずつ受け取る書き出し先。標準出力、ファイル、HTTPの3つがある。
+
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use std::fs::OpenOptions;
+use std::io::{self, IoSlice, Write};
+use std::num::NonZeroUsize;
+use std::path::PathBuf;
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use clap::ValueEnum;
+use reqwest::header::HeaderMap;
+use url::Url;
+
+use crate::api::ExtraHeader;
+use crate::i18n::{msg_in, Lang};
+use crate::log::info;
+use crate::output::RecordFile;
+
+/// 諦めてスピルファイルに書くまでに送る回数
+pub const ATTEMPTS: u32 = 3;
+
+/// 送り終わるのを待たずに溜めておけるバッチの数
+const QUEUE: usize = 4;
+
+/// `--sink`
+#[derive(Eq, PartialEq, Copy, Clone, Debug, ValueEnum)]
+pub enum SinkKind {
+    Stdout,
+    /// `--output`のファイル
+    File,
+    /// `--sink-url`へPOSTする
+    Http,
+    /// `--s3-bucket`へアップロードする
+    #[cfg(feature = "s3")]
+    S3,
+}
+
+/// 手元ではなく、ネットワークの先に書き出すときの設定
+#[derive(Clone)]
+pub enum RemoteSink {
+    Http(HttpSinkOptions),
+    #[cfg(feature = "s3")]
+    S3(crate::s3::S3SinkOptions),
+}
+
+pub trait OutputSink: Send {
+    /// `record`は改行を含まない1行のJSON
+    fn write_record(&mut self, record: &str) -> io::Result<()>;
+
+    fn flush(&mut self) -> io::Result<()>;
+
+    /// 全て書き終えたときに呼ぶ。ファイルに書いていたなら、その名前を返す。
+    fn finish(self: Box<Self>) -> io::Result<Option<PathBuf>>;
+}
+
+/// 標準出力のようなパイプ。1つの記録は改行と共に`write_vectored`で渡し、記録を写し直さない。
+/// 読む側が先に閉じたら、[`PipeClosed`]で終わる。
+pub struct StreamSink<W>(pub W);
+
+impl<W: Write + Send> OutputSink for StreamSink<W> {
+    fn write_record(&mut self, record: &str) -> io::Result<()> {
+        let mut line = [IoSlice::new(record.as_bytes()), IoSlice::new(b"\n")];
+        write_all_vectored(&mut self.0, &mut line).map_err(closed)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush().map_err(closed)
+    }
+
+    fn finish(mut self: Box<Self>) -> io::Result<Option<PathBuf>> {
+        OutputSink::flush(&mut *self).map(|()| None)
+    }
+}
+
+/// 全て書き終えるまで`write_vectored`を繰り返す。
+fn write_all_vectored(writer: &mut impl Write, mut bufs: &mut [IoSlice<'_>]) -> io::Result<()> {
+    while !bufs.is_empty() {
+        match writer.write_vectored(bufs) {
+            Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+            Ok(n) => IoSlice::advance_slices(&mut bufs, n),
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok(())
+}
+
+/// 閉じたパイプへの書き込みなら、[`PipeClosed`]にする。
+fn closed(e: io::Error) -> io::Error {
+    if e.kind() == io::ErrorKind::BrokenPipe {
+        return io::Error::new(io::ErrorKind::BrokenPipe, PipeClosed)
+    }
+
+    e
+}
+
+/// `e`が、標準出力を読む側が先に閉じたことによるものか
+pub fn is_pipe_closed(e: &io::Error) -> bool {
+    e.get_ref().is_some_and(<dyn Error + Send + Sync>::is::<PipeClosed>)
+}
+
+impl OutputSink for RecordFile {
+    fn write_record(&mut self, record: &str) -> io::Result<()> {
+        writeln!(self, "{record}")
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Write::flush(self)
+    }
+
+    fn finish(self: Box<Self>) -> io::Result<Option<PathBuf>> {
+        Self::finish(*self).map(Some)
+    }
+}
+
+#[derive(Eq, PartialEq, Clone, Debug)]
+pub struct HttpSinkOptions {
+    pub url: Url,
+    pub headers: Vec<ExtraHeader>,
+    /// 1回のPOSTに含める記録の数の上限
+    pub batch: NonZeroUsize,
+    /// 送れなかった記録を書き足すファイル
+    pub spill: PathBuf,
+    /// 失敗したとき、次に送るまでの最初の待ち時間。送るたびに倍にする
+    pub backoff: Duration,
+}
+
+/// 記録をJSONの配列にまとめてPOSTする。送るのは別のスレッドで行い、書き出す側を待たせない。
+/// 何度送っても失敗したバッチはスピルファイルに残し、[`SinkFailed`]で終わる。
+pub struct HttpSink {
+    batch: Vec<String>,
+    size: NonZeroUsize,
+    sender: Option<SyncSender<Vec<String>>>,
+    worker: Option<JoinHandle<io::Result<Delivery>>>,
+    spill: PathBuf,
+}
+
+#[derive(Default)]
+struct Delivery {
+    spilled: usize,
+    last_error: Option<String>,
+}
+
+impl HttpSink {
+    pub fn new(options: &HttpSinkOptions) -> Self {
+        let (sender, receiver) = mpsc::sync_channel(QUEUE);
+        let worker_options = options.clone();
+        let worker = thread::spawn(move || deliver(&worker_options, &receiver));
+
+        Self {
+            batch: vec![],
+            size: options.batch,
+            sender: Some(sender),
+            worker: Some(worker),
+            spill: options.spill.clone(),
+        }
+    }
+
+    fn send_batch(&mut self) -> io::Result<()> {
+        if self.batch.is_empty() {
+            return Ok(())
+        }
+
+        let batch = std::mem::take(&mut self.batch);
+        self.sender.as_ref().expect("only taken by finish").send(batch)
+            .map_err(|_| io::Error::other("the HTTP sink stopped unexpectedly"))
+    }
+}
+
+impl OutputSink for HttpSink {
+    fn write_record(&mut self, record: &str) -> io::Result<()> {
+        self.batch.push(record.to_owned());
+        if self.batch.len() >= self.size.get() {
+            self.send_batch()?;
+        }
+
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.send_batch()
+    }
+
+    fn finish(mut self: Box<Self>) -> io::Result<Option<PathBuf>> {
+        self.send_batch()?;
+        drop(self.sender.take());
+        let delivery = self.worker.take().expect("only taken here").join()
+            .map_err(|_| io::Error::other("the HTTP sink panicked"))??;
+
+        match delivery {
+            Delivery { spilled: 0, .. } => Ok(None),
+            Delivery { spilled, last_error } => Err(io::Error::other(SinkFailed {
+                spilled,
+                spill: self.spill.clone(),
+                error: last_error.unwrap_or_default(),
+            })),
+        }
+    }
+}
+
+/// 受け取ったバッチを順に送る。送れなかったものはスピルファイルに書き足す。
+fn deliver(options: &HttpSinkOptions, batches: &Receiver<Vec<String>>) -> io::Result<Delivery> {
+    let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build()?;
+    let headers: HeaderMap = options.headers.iter().map(|h| (h.name.clone(), h.value.clone())).collect();
+    let client = reqwest::Client::builder().use_rustls_tls().default_headers(headers).build()
+        .map_err(io::Error::other)?;
+    let mut delivery = Delivery::default();
+
+    for batch in batches {
+        let body = format!("[{}]", batch.join(","));
+        let mut backoff = options.backoff;
+        let mut result = Err(String::new());
+        for attempt in 1..=ATTEMPTS {
+            result = runtime.block_on(post(&client, &options.url, body.clone()));
+            let Err(e) = &result else {
+                break
+            };
+            if attempt < ATTEMPTS {
+                info!("sink: {e}; retrying in {}s", backoff.as_secs_f64());
+                thread::sleep(backoff);
+                backoff *= 2;
+            }
+        }
+
+        if let Err(e) = result {
+            info!("sink: {e}; writing {} record(s) to {}", batch.len(), options.spill.display());
+            let mut spill = OpenOptions::new().create(true).append(true).open(&options.spill)?;
+            spill.write_all((batch.join("\n") + "\n").as_bytes())?;
+            delivery.spilled += batch.len();
+            delivery.last_error = Some(e);
+        }
+    }
+
+    Ok(delivery)
+}
+
+async fn post(client: &reqwest::Client, url: &Url, body: String) -> Result<(), String> {
+    let response = client.post(url.clone())
+        .header(reqwest::header::CONTENT_TYPE, "application/json")
+        .body(body)
+        .send()
+        .await
+        .map_err(|e| format!("POST {url} failed: {e}"))?;
+
+    if response.status().is_success() {
+        Ok(())
+    } else {
+        Err(format!("POST {url} failed with {}", response.status()))
+    }
+}
+
+/// 一部の記録を送れず、スピルファイルに残した
+#[derive(Debug)]
+pub struct SinkFailed {
+    pub spilled: usize,
+    pub spill: PathBuf,
+    pub error: String,
+}
+
+impl Display for SinkFailed {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} record(s) could not be sent and were written to {} ({})", self.spilled, self.spill.display(), self.error)
+    }
+}
+
+impl Error for SinkFailed {}
+
+/// 標準出力を読む側が、全て読む前に閉じた
+#[derive(Debug)]
+pub struct PipeClosed;
+
+impl Display for PipeClosed {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&msg_in(Lang::En, "pipe-closed", &[]))
+    }
+}
+
+impl Error for PipeClosed {}
+
+#[cfg(test)]
+mod tests {
+    use std::error::Error;
+    use std::fs;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::num::NonZeroUsize;
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::Duration;
+
+    use crate::sink::{is_pipe_closed, HttpSink, HttpSinkOptions, OutputSink, SinkFailed, StreamSink};
+    use crate::status::Outcome;
+    use crate::testing::capture_dir;
+
+    fn options(url: &str, spill: &str) -> HttpSinkOptions {
+        HttpSinkOptions {
+            url: url.parse().unwrap(),
+            headers: vec!["X-Ingest-Key: secret".parse().unwrap()],
+            batch: NonZeroUsize::new(2).unwrap(),
+            spill: capture_dir(spill, &[]).join("spill.jsonl"),
+            backoff: Duration::ZERO,
+        }
+    }
+
+    /// 1つの接続につき1つのリクエストを読み、200を返す。ヘッダーと本文を送り返す。
+    fn serve(listener: TcpListener, requests: usize) -> mpsc::Receiver<(String, String)> {
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || {
+            for stream in listener.incoming().take(requests) {
+                let mut reader = BufReader::new(stream.unwrap());
+                let mut head = String::new();
+                while !head.ends_with("\r\n\r\n") {
+                    reader.read_line(&mut head).unwrap();
+                }
+                let length = head.lines()
+                    .find_map(|x| x.to_ascii_lowercase().strip_prefix("content-length: ").map(str::to_owned))
+                    .map_or(0, |x| x.parse().unwrap());
+                let mut body = vec![0; length];
+                reader.read_exact(&mut body).unwrap();
+                reader.get_mut().write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\nConnection: close\r\n\r\n").unwrap();
+                sender.send((head, String::from_utf8(body).unwrap())).unwrap();
+            }
+        });
+
+        receiver
+    }
+
+    #[test]
+    fn posts_records_in_batches() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/batch", listener.local_addr().unwrap());
+        let received = serve(listener, 2);
+
+        let mut sink = Box::new(HttpSink::new(&options(&url, "sink-http")));
+        for record in [r#"{"kind":"log"}"#, "[1]", "[2]"] {
+            sink.write_record(record).unwrap();
+        }
+        assert_eq!(sink.finish().unwrap(), None);
+
+        let (head, body) = received.recv().unwrap();
+        assert!(head.starts_with("POST /batch "));
+        assert!(head.to_ascii_lowercase().contains("x-ingest-key: secret"));
+        assert_eq!(body, r#"[{"kind":"log"},[1]]"#);
+        assert_eq!(received.recv().unwrap().1, "[[2]]");
+    }
+
+    #[test]
+    fn unreachable_sink_spills_every_record() {
+        let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let options = options(&format!("http://127.0.0.1:{port}/batch"), "sink-spill");
+
+        let mut sink = Box::new(HttpSink::new(&options));
+        sink.write_record("[1]").unwrap();
+        let e = sink.finish().unwrap_err();
+
+        assert!(e.get_ref().is_some_and(<dyn Error + Send + Sync>::is::<SinkFailed>));
+        assert_eq!(fs::read_to_string(&options.spill).unwrap(), "[1]\n");
+    }
+
+    #[test]
+    fn closed_pipe_ends_with_its_own_outcome() {
+        let (mut reader, writer) = std::io::pipe().unwrap();
+        let mut sink = StreamSink(writer);
+        sink.write_record(r#"{"kind":"meta"}"#).unwrap();
+        let mut line = [0; 16];
+        reader.read_exact(&mut line).unwrap();
+        assert_eq!(&line, b"{\"kind\":\"meta\"}\n");
+
+        drop(reader);
+        let e = sink.write_record("[]").unwrap_err();
+
+        assert!(is_pipe_closed(&e));
+        assert_eq!(Outcome::classify(&e), Outcome::PipeClosed);
+        assert_eq!(Outcome::PipeClosed.code(), 8);
+    }
+}
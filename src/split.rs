@@ -0,0 +1,273 @@
+//! `--split-by`で、ノートを複数のファイルに振り分ける。
+//!
+//! 振り分けはページ単位ではなくノート単位で行う。ページの境目で日付が前後しても、正しいファイルに入る。
+
+use std::collections::HashSet;
+use std::fmt::{Display, Formatter};
+use std::fs;
+use std::io::{self, Write};
+use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use chrono::{DateTime, Utc};
+
+use crate::clock;
+use crate::field_map::{Renamed, Renames};
+use crate::model::Note;
+use crate::output::{temporary_path, OutputOptions, RecordFile};
+use crate::provenance::{Run, Stamped};
+use crate::timestamp::{Formatted, TimestampFormat};
+use crate::timezone::Timezone;
+
+#[derive(Eq, PartialEq, Copy, Clone, Debug)]
+pub enum SplitBy {
+    Day,
+    Month,
+    /// n件ごとに次のファイルへ移る
+    Count(NonZeroUsize),
+}
+
+impl FromStr for SplitBy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "day" => Ok(Self::Day),
+            "month" => Ok(Self::Month),
+            _ => s.strip_prefix("count:")
+                .and_then(|n| n.parse().ok())
+                .map(Self::Count)
+                .ok_or_else(|| format!("expected day, month or count:<n>, got {s}")),
+        }
+    }
+}
+
+impl Display for SplitBy {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Day => f.write_str("day"),
+            Self::Month => f.write_str("month"),
+            Self::Count(n) => write!(f, "count:{n}"),
+        }
+    }
+}
+
+/// `base`が`archive.jsonl`なら、`archive-2024-11.jsonl`のような名前のファイルに書き出す。
+pub struct SplitWriter {
+    base: PathBuf,
+    by: SplitBy,
+    /// 日付で振り分けるときに使う
+    timezone: Timezone,
+    /// 今開いているファイルと、その区分
+    current: Option<(String, RecordFile)>,
+    /// この実行で作ったファイルの区分。2回目以降は追記する。
+    created: HashSet<String>,
+    written: usize,
+    atomic: bool,
+    timestamp_format: TimestampFormat,
+    /// 各ファイルの先頭に書く記録
+    meta: serde_json::Value,
+    /// ノートに添える実行
+    run: Option<Run>,
+    renames: Renames,
+}
+
+impl SplitWriter {
+    pub fn new(base: &Path, by: SplitBy, options: &OutputOptions) -> Self {
+        Self {
+            base: base.to_path_buf(),
+            by,
+            timezone: options.timezone.clone(),
+            current: None,
+            created: HashSet::new(),
+            written: 0,
+            atomic: options.atomic,
+            timestamp_format: options.timestamp_format,
+            meta: options.meta(),
+            run: options.stamping(),
+            renames: options.field_map.note.clone(),
+        }
+    }
+
+    /// 1ページ分のノートを、区分が同じものごとに1行の配列として書き出す。
+    pub fn write_page(&mut self, notes: &[Note]) -> io::Result<()> {
+        let (mut start, fetched_at) = (0, Utc::now());
+        while start < notes.len() {
+            let bucket = self.bucket_of(&notes[start], 0, fetched_at);
+            let end = (start + 1..notes.len())
+                .find(|&i| self.bucket_of(&notes[i], i - start, fetched_at) != bucket)
+                .unwrap_or(notes.len());
+
+            let (format, stamp, renames) = (self.timestamp_format, self.run.map(Run::stamp), self.renames.clone());
+            let out = self.switch_to(bucket)?;
+            serde_json::to_writer(&mut *out, &Renamed(Stamped(Formatted(&notes[start..end], format), stamp), &renames))?;
+            writeln!(out)?;
+
+            self.written += end - start;
+            start = end;
+        }
+
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        match &mut self.current {
+            Some((_, out)) => out.flush(),
+            None => Ok(()),
+        }
+    }
+
+    /// このチャンネルで作った全てのファイルの名前を、一時ファイルから本来のものに変える。
+    /// 作ったファイルの名前を返す。
+    pub fn finish(mut self) -> io::Result<Vec<PathBuf>> {
+        self.flush()?;
+        drop(self.current.take());
+        let mut files: Vec<_> = self.created.iter().map(|bucket| self.path_of(bucket)).collect();
+        files.sort();
+        if self.atomic {
+            for path in &files {
+                fs::rename(temporary_path(path), path)?;
+            }
+        }
+
+        Ok(files)
+    }
+
+    /// `offset`は、まだ書いていないノートのうち何件目か。`fetched_at`より先の日付のノートは、`fetched_at`の区分に入れる
+    fn bucket_of(&self, note: &Note, offset: usize, fetched_at: DateTime<Utc>) -> String {
+        let created_at = clock::effective(note.created_at, fetched_at);
+        match self.by {
+            SplitBy::Day => self.timezone.to_local(created_at).format("%Y-%m-%d").to_string(),
+            SplitBy::Month => self.timezone.to_local(created_at).format("%Y-%m").to_string(),
+            SplitBy::Count(n) => format!("{:05}", (self.written + offset) / n.get()),
+        }
+    }
+
+    fn path_of(&self, bucket: &str) -> PathBuf {
+        let stem = self.base.file_stem().unwrap_or_default().to_string_lossy();
+        let name = self.base.extension().map_or_else(
+            || format!("{stem}-{bucket}"),
+            |ext| format!("{stem}-{bucket}.{}", ext.to_string_lossy()),
+        );
+
+        self.base.with_file_name(name)
+    }
+
+    fn switch_to(&mut self, bucket: String) -> io::Result<&mut RecordFile> {
+        if self.current.as_ref().is_some_and(|(b, _)| *b == bucket) {
+            return Ok(&mut self.current.as_mut().expect("checked above").1);
+        }
+
+        self.flush()?;
+        let path = self.path_of(&bucket);
+        let out = if self.created.insert(bucket.clone()) {
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            let mut out = RecordFile::create(&path, self.atomic)?;
+            let mut meta = self.meta.clone();
+            meta["split_by"] = self.by.to_string().into();
+            meta["bucket"] = bucket.clone().into();
+            writeln!(out, "{meta}")?;
+            out
+        } else {
+            RecordFile::append(&path, self.atomic)?
+        };
+
+        Ok(&mut self.current.insert((bucket, out)).1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::num::NonZeroUsize;
+
+    use chrono::Utc;
+
+    use crate::chunk;
+    use crate::field_map::FieldMap;
+    use crate::model::Note;
+    use crate::split::{SplitBy, SplitWriter};
+    use crate::output::{Layout, OutputOptions};
+    use crate::testing::{capture_dir, note_json, RUN};
+    use crate::timestamp::TimestampFormat;
+    use crate::timezone::Timezone;
+
+    fn options(timezone: Timezone, atomic: bool) -> OutputOptions {
+        OutputOptions { split_by: None, timezone, atomic, timestamp_format: TimestampFormat::Rfc3339, layout: Layout::Lines, chunk_size: chunk::DEFAULT_CHUNK_SIZE, sink: None, canonical: false, possibly_incomplete: vec![], run: RUN, field_map: FieldMap::EMPTY, detect_language: false, append: false, append_index: None }
+    }
+
+    fn note(id: &str, created_at: &str) -> Note {
+        serde_json::from_value(note_json(id, created_at)).unwrap()
+    }
+
+    #[test]
+    fn parses_split_by() {
+        assert_eq!("day".parse(), Ok(SplitBy::Day));
+        assert_eq!("count:100".parse(), Ok(SplitBy::Count(NonZeroUsize::new(100).unwrap())));
+        assert!("count:0".parse::<SplitBy>().is_err());
+    }
+
+    #[test]
+    fn notes_out_of_order_land_in_their_month() {
+        let dir = capture_dir("split-month", &[]);
+        let mut writer = SplitWriter::new(&dir.join("nested/archive.jsonl"), SplitBy::Month, &options(Timezone::default(), true));
+
+        writer.write_page(&[note("c", "2024-12-01T00:00:00.000Z"), note("b", "2024-11-30T00:00:00.000Z")]).unwrap();
+        writer.write_page(&[note("d", "2024-12-02T00:00:00.000Z"), note("a", "2024-11-01T00:00:00.000Z")]).unwrap();
+        writer.finish().unwrap();
+
+        let november = fs::read_to_string(dir.join("nested/archive-2024-11.jsonl")).unwrap();
+        let december = fs::read_to_string(dir.join("nested/archive-2024-12.jsonl")).unwrap();
+        assert_eq!(november.lines().count(), 3);
+        assert_eq!(november.matches(r#""kind":"meta""#).count(), 1);
+        assert!(november.contains(r#""id":"a""#) && november.contains(r#""id":"b""#));
+        assert!(december.contains(r#""id":"c""#) && december.contains(r#""id":"d""#));
+    }
+
+    #[test]
+    fn day_boundary_follows_timezone() {
+        let dir = capture_dir("split-timezone", &[]);
+        let mut writer = SplitWriter::new(&dir.join("archive.jsonl"), SplitBy::Day, &options("+09:00".parse().unwrap(), false));
+
+        writer.write_page(&[note("late", "2024-10-31T15:00:00.000Z"), note("evening", "2024-10-31T14:59:59.000Z")]).unwrap();
+        writer.finish().unwrap();
+
+        let november = fs::read_to_string(dir.join("archive-2024-11-01.jsonl")).unwrap();
+        let october = fs::read_to_string(dir.join("archive-2024-10-31.jsonl")).unwrap();
+        assert!(november.contains(r#""id":"late""#) && november.contains(r#""timezone":"+09:00""#));
+        assert!(october.contains(r#""id":"evening""#));
+        assert!(november.contains(r#""createdAt":"2024-10-31T15:00:00Z""#));
+    }
+
+    #[test]
+    fn future_notes_land_in_the_bucket_of_the_fetch_time() {
+        let dir = capture_dir("split-future", &[]);
+        let mut writer = SplitWriter::new(&dir.join("archive.jsonl"), SplitBy::Month, &options(Timezone::default(), false));
+
+        writer.write_page(&[note("future", "2106-02-07T06:28:16.000Z")]).unwrap();
+        let files = writer.finish().unwrap();
+
+        let month = Utc::now().format("%Y-%m").to_string();
+        assert_eq!(files, [dir.join(format!("archive-{month}.jsonl"))]);
+        assert!(fs::read_to_string(&files[0]).unwrap().contains(r#""createdAt":"2106-02-07T06:28:16Z""#));
+    }
+
+    #[test]
+    fn count_rotates_within_a_page() {
+        let dir = capture_dir("split-count", &[]);
+        let mut writer = SplitWriter::new(&dir.join("archive.jsonl"), SplitBy::Count(NonZeroUsize::new(2).unwrap()), &options(Timezone::default(), false));
+
+        writer.write_page(&[
+            note("a", "2024-01-03T00:00:00.000Z"),
+            note("b", "2024-01-02T00:00:00.000Z"),
+            note("c", "2024-01-01T00:00:00.000Z"),
+        ]).unwrap();
+        writer.flush().unwrap();
+
+        assert!(fs::read_to_string(dir.join("archive-00000.jsonl")).unwrap().contains(r#""id":"b""#));
+        assert!(fs::read_to_string(dir.join("archive-00001.jsonl")).unwrap().contains(r#""id":"c""#));
+    }
+}
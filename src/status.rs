@@ -0,0 +1,324 @@
+//! 終了コードと、最後に書き出す`status`の記録。systemdやワークフローエンジンから結果を判断できるようにする。
+
+use std::error::Error;
+use std::io::{self, Write};
+use std::process::ExitCode;
+
+use clap::error::ContextKind;
+
+use crate::api::{ApiError, Challenged, ProxyError};
+use crate::archive::{ChannelUnavailable, ChannelsFailed, Incomplete, NotesFailed};
+use crate::i18n::{self, msg, Lang, Message};
+use crate::log;
+use crate::metrics::{BudgetExhausted, FailThreshold, TimeBudgetExhausted};
+use crate::preflight::{InvalidRange, MissingPermissions};
+use crate::sink::{self, SinkFailed};
+use crate::usage::UsageError;
+
+/// `--help`に載せる、終了コードの一覧
+pub const EXIT_CODES: &str = "\
+Exit codes:
+  0   success
+  1   failure not covered below
+  2   completed with gaps: some channels or notes failed or fell short of --max-missing-notes, the others were archived;
+      also when an endpoint failed more often than --fail-threshold even after retries, or a channel was deleted or archived mid-run
+  3   authentication error: the token is missing, invalid or lacks a permission
+  4   rate limit exhausted
+  5   network failure or the server was unavailable, including error pages from a proxy in front of it
+  6   stopped at --max-requests or --max-duration
+  7   --sink http or --sink s3 could not deliver some records; they were kept in --sink-spill
+  8   the reader of stdout closed the pipe; the status and a {\"kind\": \"gap\"} record for backfill go to stderr instead
+  64  usage error, including --after not being older than --before; with --log-format json, stderr gets one {\"kind\": \"usage-error\"} object
+
+archive, archive-list, backfill, fetch-notes, refresh, fetch-user, fetch-followers, fetch-following, fetch-reactions, fetch-renotes, snapshot-featured, estimate-files, explore, verify-manifest, verify-notes and user-activity always print a final {\"kind\": \"status\"} record to stdout.
+The other subcommands print it only on failure.";
+
+#[derive(Eq, PartialEq, Copy, Clone, Debug)]
+pub enum Outcome {
+    Success,
+    Failed,
+    CompletedWithGaps,
+    Auth,
+    RateLimited,
+    Network,
+    BudgetExhausted,
+    TimeBudgetExhausted,
+    Sink,
+    PipeClosed,
+    Usage,
+}
+
+impl Outcome {
+    pub const ALL: [Self; 11] = [
+        Self::Success, Self::Failed, Self::CompletedWithGaps, Self::Auth, Self::RateLimited,
+        Self::Network, Self::BudgetExhausted, Self::TimeBudgetExhausted, Self::Sink, Self::PipeClosed, Self::Usage,
+    ];
+
+    pub const fn code(self) -> u8 {
+        match self {
+            Self::Success => 0,
+            Self::Failed => 1,
+            Self::CompletedWithGaps => 2,
+            Self::Auth => 3,
+            Self::RateLimited => 4,
+            Self::Network => 5,
+            Self::BudgetExhausted | Self::TimeBudgetExhausted => 6,
+            Self::Sink => 7,
+            Self::PipeClosed => 8,
+            Self::Usage => 64,
+        }
+    }
+
+    pub const fn name(self) -> &'static str {
+        match self {
+            Self::Success => "success",
+            Self::Failed => "failed",
+            Self::CompletedWithGaps => "completed-with-gaps",
+            Self::Auth => "auth-error",
+            Self::RateLimited => "rate-limited",
+            Self::Network => "network-error",
+            Self::BudgetExhausted => "budget-exhausted",
+            Self::TimeBudgetExhausted => "time-budget-exhausted",
+            Self::Sink => "sink-failed",
+            Self::PipeClosed => "pipe-closed",
+            Self::Usage => "usage-error",
+        }
+    }
+
+    pub fn classify(e: &(dyn Error + Send + Sync + 'static)) -> Self {
+        if let Some(e) = e.downcast_ref::<ChannelsFailed>() {
+            // 全て失敗したなら、その理由で分ける
+            return if e.failed < e.total { Self::CompletedWithGaps } else { Self::classify(&*e.last) }
+        }
+
+        if let Some(e) = e.downcast_ref::<NotesFailed>() {
+            return if e.failed < e.total { Self::CompletedWithGaps } else { Self::classify(&*e.last) }
+        }
+
+        if e.is::<Incomplete>() || e.is::<FailThreshold>() || e.is::<ChannelUnavailable>() {
+            return Self::CompletedWithGaps
+        }
+
+        if e.is::<BudgetExhausted>() {
+            return Self::BudgetExhausted
+        }
+
+        if e.is::<TimeBudgetExhausted>() {
+            return Self::TimeBudgetExhausted
+        }
+
+        if e.downcast_ref::<io::Error>().and_then(io::Error::get_ref).is_some_and(<dyn Error + Send + Sync>::is::<SinkFailed>) {
+            return Self::Sink
+        }
+
+        if e.downcast_ref::<io::Error>().is_some_and(sink::is_pipe_closed) {
+            return Self::PipeClosed
+        }
+
+        if e.is::<clap::Error>() || e.is::<UsageError>() || e.is::<InvalidRange>() {
+            return Self::Usage
+        }
+
+        if e.is::<MissingPermissions>() {
+            return Self::Auth
+        }
+
+        if let Some(e) = e.downcast_ref::<ApiError>() {
+            return match (e.status, e.code.as_str()) {
+                (_, "PERMISSION_DENIED" | "CREDENTIAL_REQUIRED" | "AUTHENTICATION_FAILED") | (401 | 403, _) => Self::Auth,
+                (_, "RATE_LIMIT_EXCEEDED") | (429, _) => Self::RateLimited,
+                (500.., _) => Self::Network,
+                _ => Self::Failed,
+            }
+        }
+
+        if e.is::<reqwest::Error>() || e.is::<ProxyError>() {
+            return Self::Network
+        }
+
+        Self::Failed
+    }
+}
+
+/// チャンネル単位で欠けた数
+fn gaps(e: &(dyn Error + Send + Sync + 'static)) -> usize {
+    e.downcast_ref::<ChannelsFailed>().map(|e| e.failed)
+        .or_else(|| e.downcast_ref::<NotesFailed>().map(|e| e.failed))
+        .unwrap_or(0)
+}
+
+/// 結果の分類、欠けたチャンネルの数と、エラーの1行目
+pub fn summarize(result: &Result<(), Box<dyn Error + Send + Sync>>) -> (Outcome, usize, Option<String>) {
+    match result {
+        Ok(()) => (Outcome::Success, 0, None),
+        Err(e) => (Outcome::classify(&**e), gaps(&**e), e.to_string().lines().next().map(str::to_owned)),
+    }
+}
+
+pub fn record(result: &Result<(), Box<dyn Error + Send + Sync>>) -> (Outcome, serde_json::Value) {
+    let (outcome, gaps, error) = summarize(result);
+
+    (outcome, serde_json::json!({
+        "kind": "status",
+        "outcome": outcome.name(),
+        "exit_code": outcome.code(),
+        "gaps": gaps,
+        "error": error,
+    }))
+}
+
+/// `--log-format json`で標準エラー出力に書く、エラーの記録
+fn error_record(e: &(dyn Error + Send + Sync + 'static)) -> serde_json::Value {
+    if let Some(e) = e.downcast_ref::<UsageError>() {
+        return e.to_json()
+    }
+    if let Some(e) = e.downcast_ref::<clap::Error>() {
+        // clapの文はバージョンで変わるので、`rule`で見分けられるようにする
+        let rendered = e.render().to_string();
+        let message = rendered.lines().next().unwrap_or_default();
+        let arguments: Vec<_> = e.get(ContextKind::InvalidArg).into_iter().map(ToString::to_string).collect();
+        return serde_json::json!({
+            "kind": "usage-error",
+            "rule": "parse",
+            "arguments": arguments,
+            "message": message.strip_prefix("error: ").unwrap_or(message),
+            "exit_code": Outcome::Usage.code(),
+        })
+    }
+    let outcome = Outcome::classify(e);
+
+    serde_json::json!({ "kind": "error", "outcome": outcome.name(), "exit_code": outcome.code(), "message": e.to_string() })
+}
+
+/// 人が読むための、`lang`での説明。表に無いエラーは英語のまま
+fn describe(e: &(dyn Error + Send + Sync + 'static), lang: Lang) -> String {
+    let message = if let Some(e) = e.downcast_ref::<UsageError>() {
+        e.message.clone()
+    } else if let Some(e) = e.downcast_ref::<ChannelsFailed>() {
+        e.message()
+    } else if let Some(e) = e.downcast_ref::<NotesFailed>() {
+        e.message()
+    } else if let Some(e) = e.downcast_ref::<Incomplete>() {
+        e.message()
+    } else if let Some(e) = e.downcast_ref::<ChannelUnavailable>() {
+        e.message()
+    } else if let Some(e) = e.downcast_ref::<BudgetExhausted>() {
+        e.message()
+    } else if let Some(e) = e.downcast_ref::<TimeBudgetExhausted>() {
+        e.message()
+    } else if let Some(e) = e.downcast_ref::<FailThreshold>() {
+        e.message()
+    } else if let Some(e) = e.downcast_ref::<InvalidRange>() {
+        e.message()
+    } else if let Some(e) = e.downcast_ref::<Challenged>() {
+        e.message()
+    } else if e.is::<sink::PipeClosed>() {
+        Message::new("pipe-closed", &[])
+    } else {
+        return e.to_string()
+    };
+
+    message.render(lang)
+}
+
+/// エラーを表示し、`status`の記録を書き出して、終了コードを返す。
+pub fn finish(result: &Result<(), Box<dyn Error + Send + Sync>>, always: bool) -> ExitCode {
+    if let Err(e) = result {
+        match e.downcast_ref::<clap::Error>() {
+            _ if log::json() => eprintln!("{}", error_record(&**e)),
+            Some(e) => { let _ = e.print(); }
+            None => eprintln!("{}", msg("error", &[("message", &describe(&**e, i18n::current()))])),
+        }
+    }
+
+    let (outcome, record) = record(result);
+    // 標準出力が閉じられていれば、読む人のいる標準エラー出力に書く
+    if (always || outcome != Outcome::Success) && (outcome == Outcome::PipeClosed || writeln!(io::stdout(), "{record}").is_err()) {
+        eprintln!("{record}");
+    }
+
+    ExitCode::from(outcome.code())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::error::Error;
+    use std::io;
+    use std::num::NonZeroU64;
+    use std::time::Duration;
+
+    use crate::api::ApiError;
+    use crate::archive::ChannelsFailed;
+    use crate::metrics::{BudgetExhausted, TimeBudgetExhausted};
+    use clap::Parser;
+
+    use crate::cli::Cli;
+    use crate::sink::SinkFailed;
+    use crate::i18n::Lang;
+    use crate::status::{describe, error_record, record, Outcome};
+    use crate::usage;
+
+    fn api_error(status: u16, code: &str) -> Box<dyn Error + Send + Sync> {
+        Box::new(ApiError { endpoint: "i".to_owned(), status, code: code.to_owned(), message: String::new() })
+    }
+
+    #[test]
+    fn classifies_errors() {
+        assert_eq!(Outcome::classify(&*api_error(403, "PERMISSION_DENIED")), Outcome::Auth);
+        assert_eq!(Outcome::classify(&*api_error(429, "RATE_LIMIT_EXCEEDED")), Outcome::RateLimited);
+        assert_eq!(Outcome::classify(&*api_error(504, "")), Outcome::Network);
+        assert_eq!(Outcome::classify(&*Box::<dyn Error + Send + Sync>::from("broken")), Outcome::Failed);
+        assert_eq!(Outcome::classify(&BudgetExhausted { limit: NonZeroU64::MIN }), Outcome::BudgetExhausted);
+        let timed_out = TimeBudgetExhausted { limit: Duration::from_mins(50) };
+        assert_eq!((Outcome::classify(&timed_out), Outcome::TimeBudgetExhausted.code()), (Outcome::TimeBudgetExhausted, 6));
+        let spilled = SinkFailed { spilled: 1, spill: "spill.jsonl".into(), error: String::new() };
+        assert_eq!(Outcome::classify(&io::Error::other(spilled)), Outcome::Sink);
+    }
+
+    #[test]
+    fn partial_failure_reports_gaps() {
+        let partial: Result<(), Box<dyn Error + Send + Sync>> = Err(Box::new(ChannelsFailed { failed: 1, total: 3, last: api_error(504, "") }));
+        let (outcome, status) = record(&partial);
+        assert_eq!(outcome, Outcome::CompletedWithGaps);
+        assert_eq!(status["exit_code"], 2);
+        assert_eq!(status["gaps"], 1);
+        assert_eq!(status["error"], "1 of 3 channel(s) failed");
+
+        let all: Result<(), Box<dyn Error + Send + Sync>> = Err(Box::new(ChannelsFailed { failed: 2, total: 2, last: api_error(401, "AUTHENTICATION_FAILED") }));
+        assert_eq!(record(&all).0, Outcome::Auth);
+    }
+
+    #[test]
+    fn parse_errors_are_usage_errors_in_json() {
+        let e: Box<dyn Error + Send + Sync> = Cli::try_parse_from(["misskey-channel-archiver", "archive", "--cool-down", "0"]).err().unwrap().into();
+
+        let record = error_record(&*e);
+
+        assert_eq!(Outcome::classify(&*e), Outcome::Usage);
+        assert_eq!(record["kind"], "usage-error");
+        assert_eq!(record["rule"], "parse");
+        assert_eq!(record["exit_code"], 64);
+        assert!(record["arguments"][0].as_str().unwrap().starts_with("--cool-down"));
+        assert!(!record["message"].as_str().unwrap().starts_with("error:"));
+    }
+
+    #[test]
+    fn errors_are_described_in_either_language_but_recorded_in_english() {
+        let usage: Box<dyn Error + Send + Sync> = Box::new(usage::check(&Cli::try_parse_from(["misskey-channel-archiver", "fetch-user", "--token", "t", "--user", "a"]).unwrap()).unwrap_err());
+        let partial: Box<dyn Error + Send + Sync> = Box::new(ChannelsFailed { failed: 1, total: 3, last: api_error(504, "") });
+        let budget: Box<dyn Error + Send + Sync> = Box::new(BudgetExhausted { limit: NonZeroU64::new(500).unwrap() });
+        let cases = [
+            (&usage, "--host is required", "--hostが必要です"),
+            (&partial, "1 of 3 channel(s) failed", "3個のうち1個のチャンネルで失敗しました"),
+            (&budget, "reached --max-requests 500; stopped before sending more", "--max-requests 500に達したため、それ以上送らずに止めました"),
+        ];
+
+        for (e, en, ja) in cases {
+            assert_eq!(describe(&**e, Lang::En), en);
+            assert_eq!(describe(&**e, Lang::Ja), ja);
+            assert_eq!(error_record(&**e)["message"], en);
+        }
+        // 表に無いエラーは、そのまま書く
+        assert_eq!(describe(&*api_error(504, ""), Lang::Ja), "i failed with 504");
+    }
+}
@@ -0,0 +1,96 @@
+//! テストで共有する、キャプチャを使った偽のサーバー。
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+use std::fs;
+use std::path::PathBuf;
+
+use serde_json::{json, Value};
+
+use crate::api::ResponseHeaders;
+use crate::capture::Exchange;
+use crate::provenance::{Run, RunId};
+
+/// 実行ごとに変わらない[`Run`]
+pub const RUN: Run = Run { id: RunId([0; 16]), started_at: chrono::DateTime::from_timestamp_nanos(0) };
+
+pub fn exchange(endpoint: &str, request: Value, response: &Value) -> Exchange {
+    Exchange {
+        endpoint: endpoint.to_owned(),
+        request,
+        status: 200,
+        response: response.to_string(),
+        headers: ResponseHeaders::default(),
+    }
+}
+
+/// `exchanges`を`ReplayClient`が読める形で一時ディレクトリに書き出す。
+pub fn capture_dir(name: &str, exchanges: &[Exchange]) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("misskey-channel-archiver-{}-{name}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    for (i, e) in exchanges.iter().enumerate() {
+        fs::write(dir.join(format!("{i:05}-{}.json", e.endpoint.replace('/', "_"))), serde_json::to_string(e).unwrap()).unwrap();
+    }
+
+    dir
+}
+
+pub fn note_json(id: &str, created_at: &str) -> Value {
+    json!({
+        "id": id, "createdAt": created_at, "user": { "id": "u1" },
+        "text": "hello", "cw": null, "replyId": null, "renoteId": null,
+        "renoteCount": 0, "repliesCount": 0, "reactions": { ":blobcat@.:": 2, "👍": 1 },
+    })
+}
+
+pub fn me() -> Exchange {
+    exchange("i", json!({}), &json!({ "id": "me", "username": "archiver" }))
+}
+
+pub fn channel(id: &str) -> Exchange {
+    exchange("channels/show", json!({ "channelId": id }), &json!({ "id": id, "name": "test", "notesCount": 1, "isFollowing": true }))
+}
+
+/// 遡る前に、`channel`の新しいノートが見えるかを確かめるリクエスト
+pub fn probe(id: &str) -> Exchange {
+    exchange("channels/timeline", json!({ "channelId": id, "limit": 100 }), &json!([note_json("probe", "2024-01-01T00:00:00.000Z")]))
+}
+
+/// 確保しているバイト数を、スレッドごとに数える。他のテストが並んで動いても混ざらない
+struct CountingAllocator;
+
+thread_local! {
+    static ALLOCATED: Cell<usize> = const { Cell::new(0) };
+    static PEAK: Cell<usize> = const { Cell::new(0) };
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+#[allow(unsafe_code)]
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let _ = ALLOCATED.try_with(|x| {
+            x.set(x.get() + layout.size());
+            let _ = PEAK.try_with(|peak| peak.set(peak.get().max(x.get())));
+        });
+        // SAFETY: `layout`をそのまま渡す
+        unsafe { System.alloc(layout) }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let _ = ALLOCATED.try_with(|x| x.set(x.get().saturating_sub(layout.size())));
+        // SAFETY: `ptr`は同じ`layout`で`alloc`したもの
+        unsafe { System.dealloc(ptr, layout) }
+    }
+}
+
+/// `f`の間に、呼ぶ前よりも多く確保していたバイト数の最大
+pub fn peak_allocation<T>(f: impl FnOnce() -> T) -> (T, usize) {
+    let base = ALLOCATED.with(Cell::get);
+    PEAK.with(|x| x.set(base));
+    let result = f();
+
+    (result, PEAK.with(Cell::get) - base)
+}
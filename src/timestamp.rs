@@ -0,0 +1,93 @@
+//! 書き出すときの日時の形式。APIから読むときは常にRFC 3339。
+
+use chrono::{DateTime, Utc};
+use clap::ValueEnum;
+use serde::{Serialize, Serializer};
+use serde::ser::Error as _;
+
+use crate::model::Note;
+
+#[derive(Eq, PartialEq, Copy, Clone, Debug, Default, ValueEnum)]
+pub enum TimestampFormat {
+    /// `2024-11-01T00:00:00Z`
+    #[default]
+    Rfc3339,
+    /// UNIX時間のミリ秒
+    EpochMs,
+    /// UNIX時間の秒
+    EpochS,
+}
+
+impl TimestampFormat {
+    pub const fn name(self) -> &'static str {
+        match self {
+            Self::Rfc3339 => "rfc3339",
+            Self::EpochMs => "epoch-ms",
+            Self::EpochS => "epoch-s",
+        }
+    }
+
+    pub fn format(self, at: DateTime<Utc>) -> serde_json::Value {
+        match self {
+            Self::Rfc3339 => at.to_rfc3339_opts(chrono::SecondsFormat::AutoSi, true).into(),
+            Self::EpochMs => at.timestamp_millis().into(),
+            Self::EpochS => at.timestamp().into(),
+        }
+    }
+}
+
+/// `createdAt`を指定した形式で書き出すための包み
+pub struct Formatted<'a, T: ?Sized>(pub &'a T, pub TimestampFormat);
+
+impl Serialize for Formatted<'_, Note> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut value = serde_json::to_value(self.0).map_err(S::Error::custom)?;
+        value["createdAt"] = self.1.format(self.0.created_at);
+
+        value.serialize(serializer)
+    }
+}
+
+impl Serialize for Formatted<'_, [Note]> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(self.0.iter().map(|note| Formatted(note, self.1)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{DateTime, Utc};
+    use clap::ValueEnum;
+
+    use crate::model::Note;
+    use crate::testing::note_json;
+    use crate::timestamp::{Formatted, TimestampFormat};
+
+    /// 読む側がするように、書き出した値を読み戻す
+    fn parse(format: TimestampFormat, value: &serde_json::Value) -> Option<DateTime<Utc>> {
+        match format {
+            TimestampFormat::Rfc3339 => value.as_str()?.parse().ok(),
+            TimestampFormat::EpochMs => DateTime::from_timestamp_millis(value.as_i64()?),
+            TimestampFormat::EpochS => DateTime::from_timestamp(value.as_i64()?, 0),
+        }
+    }
+
+    #[test]
+    fn every_format_round_trips() {
+        let at: DateTime<Utc> = "2024-10-31T15:00:00.123Z".parse().unwrap();
+        for format in TimestampFormat::value_variants() {
+            let parsed = parse(*format, &format.format(at)).unwrap();
+            let expected = if *format == TimestampFormat::EpochS { "2024-10-31T15:00:00Z".parse().unwrap() } else { at };
+            assert_eq!(parsed, expected, "{}", format.name());
+        }
+    }
+
+    #[test]
+    fn only_created_at_changes() {
+        let note: Note = serde_json::from_value(note_json("n1", "2024-01-01T00:00:00.000Z")).unwrap();
+        let value = serde_json::to_value(Formatted(&note, TimestampFormat::EpochMs)).unwrap();
+
+        assert_eq!(value["createdAt"], 1_704_067_200_000_i64);
+        assert_eq!(value["id"], "n1");
+    }
+}
@@ -0,0 +1,429 @@
+//! `--timezone`で指定するタイムゾーン。
+//!
+//! IANAのタイムゾーン名は、OSのtzdata(`$TZDIR`か`/usr/share/zoneinfo`)にある`TZif`ファイルを読んで解決する。
+//! 最後の遷移より後は、v2以降のファイルの末尾にあるPOSIXのTZの規則に従い、規則が無ければ最後のオフセットが続くとみなす。
+//! 遷移を数年分しか持たない"slim"のtzdataでも、その先の夏時間を求められる。
+
+use std::fmt::{Display, Formatter};
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use chrono::{DateTime, Datelike, FixedOffset, NaiveDate, NaiveTime, Utc};
+
+#[derive(Eq, PartialEq, Clone, Debug)]
+pub enum Timezone {
+    Fixed(FixedOffset),
+    Zone {
+        name: String,
+        /// 遷移する時刻(UNIX時間)と、その後のUTCからのオフセット(秒)
+        transitions: Vec<(i64, i32)>,
+        /// 最初の遷移より前のオフセット
+        initial: i32,
+        /// 最後の遷移より後の規則
+        rule: Option<PosixRule>,
+    },
+}
+
+/// POSIXのTZの、`CET-1CEST,M3.5.0,M10.5.0/3`のような規則。オフセットはUTCから東への秒
+#[derive(Eq, PartialEq, Clone, Debug)]
+pub struct PosixRule {
+    std: i32,
+    dst: Option<Dst>,
+}
+
+#[derive(Eq, PartialEq, Clone, Debug)]
+struct Dst {
+    offset: i32,
+    /// 始まる日と、その日の標準時での時刻(秒)
+    start: (RuleDate, i32),
+    /// 終わる日と、その日の夏時間での時刻(秒)
+    end: (RuleDate, i32),
+}
+
+#[derive(Eq, PartialEq, Copy, Clone, Debug)]
+enum RuleDate {
+    /// `Mm.w.d`。`week`が5なら最後の週、`weekday`は日曜が0
+    Month { month: u32, week: u32, weekday: u32 },
+    /// `Jn`。2月29日を数えない1から365
+    Julian(u32),
+    /// `n`。2月29日も数える0から365
+    Day(u32),
+}
+
+impl RuleDate {
+    fn in_year(self, year: i32) -> Option<NaiveDate> {
+        match self {
+            Self::Month { month, week, weekday } => {
+                let first = NaiveDate::from_ymd_opt(year, month, 1)?;
+                let mut day = 1 + (weekday + 7 - first.weekday().num_days_from_sunday()) % 7 + (week - 1) * 7;
+                // 5週目が無い月は、最後の週
+                while day > 28 && NaiveDate::from_ymd_opt(year, month, day).is_none() {
+                    day -= 7;
+                }
+                NaiveDate::from_ymd_opt(year, month, day)
+            }
+            Self::Julian(n) => {
+                let leap = NaiveDate::from_ymd_opt(year, 2, 29).is_some();
+                NaiveDate::from_yo_opt(year, n + u32::from(leap && n >= 60))
+            }
+            Self::Day(n) => NaiveDate::from_yo_opt(year, n + 1),
+        }
+    }
+}
+
+impl PosixRule {
+    fn offset_at(&self, at: DateTime<Utc>) -> i32 {
+        let Some(dst) = &self.dst else {
+            return self.std
+        };
+        let change = |(date, time): (RuleDate, i32), before: i32| {
+            let midnight = date.in_year(at.year())?.and_time(NaiveTime::MIN).and_utc().timestamp();
+            Some(midnight + i64::from(time) - i64::from(before))
+        };
+        let (Some(start), Some(end)) = (change(dst.start, self.std), change(dst.end, dst.offset)) else {
+            return self.std
+        };
+        let timestamp = at.timestamp();
+        // 南半球では、年をまたいで夏時間が続く
+        let in_dst = if start < end { start <= timestamp && timestamp < end } else { timestamp < end || start <= timestamp };
+
+        if in_dst { dst.offset } else { self.std }
+    }
+}
+
+impl FromStr for PosixRule {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut rest = s;
+        skip_name(&mut rest).ok_or(())?;
+        // POSIXでは西が正
+        let std = -parse_time(&mut rest).ok_or(())?;
+        if rest.is_empty() {
+            return Ok(Self { std, dst: None })
+        }
+        skip_name(&mut rest).ok_or(())?;
+        let offset = if rest.starts_with(',') { std + 3600 } else { -parse_time(&mut rest).ok_or(())? };
+        let (start, end) = rest.strip_prefix(',').and_then(|x| x.split_once(',')).ok_or(())?;
+
+        Ok(Self { std, dst: Some(Dst { offset, start: parse_change(start).ok_or(())?, end: parse_change(end).ok_or(())? }) })
+    }
+}
+
+/// `CET`や`<+09>`を読み飛ばす
+fn skip_name(s: &mut &str) -> Option<()> {
+    let len = if let Some(quoted) = s.strip_prefix('<') {
+        quoted.find('>')? + 2
+    } else {
+        s.find(|c: char| !c.is_ascii_alphabetic()).unwrap_or(s.len())
+    };
+    if len < 3 {
+        return None
+    }
+    *s = &s[len..];
+
+    Some(())
+}
+
+/// `[+-]hh[:mm[:ss]]`を秒で読む
+fn parse_time(s: &mut &str) -> Option<i32> {
+    let sign = if let Some(rest) = s.strip_prefix('-') {
+        *s = rest;
+        -1
+    } else {
+        *s = s.strip_prefix('+').unwrap_or(s);
+        1
+    };
+    let mut seconds = 0;
+    for (i, unit) in [3600, 60, 1].into_iter().enumerate() {
+        if i > 0 {
+            let Some(rest) = s.strip_prefix(':') else {
+                break
+            };
+            *s = rest;
+        }
+        let len = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+        if len == 0 || len > 3 {
+            return None
+        }
+        seconds += s[..len].parse::<i32>().ok()? * unit;
+        *s = &s[len..];
+    }
+
+    Some(sign * seconds)
+}
+
+/// `M3.5.0/3`のような、日と時刻
+fn parse_change(s: &str) -> Option<(RuleDate, i32)> {
+    let (date, time) = match s.split_once('/') {
+        Some((date, mut time)) => {
+            let seconds = parse_time(&mut time)?;
+            if !time.is_empty() {
+                return None
+            }
+            (date, seconds)
+        }
+        None => (s, 2 * 3600),
+    };
+    let date = if let Some(fields) = date.strip_prefix('M') {
+        let fields: Vec<u32> = fields.split('.').map(str::parse).collect::<Result<_, _>>().ok()?;
+        let [month @ 1..=12, week @ 1..=5, weekday @ 0..=6] = fields[..] else {
+            return None
+        };
+        RuleDate::Month { month, week, weekday }
+    } else if let Some(n) = date.strip_prefix('J') {
+        RuleDate::Julian(n.parse().ok().filter(|x| (1..=365).contains(x))?)
+    } else {
+        RuleDate::Day(date.parse().ok().filter(|x| *x <= 365)?)
+    };
+
+    Some((date, time))
+}
+
+impl Default for Timezone {
+    fn default() -> Self {
+        Self::Fixed(FixedOffset::east_opt(0).expect("zero is in range"))
+    }
+}
+
+impl Timezone {
+    pub fn offset_at(&self, at: DateTime<Utc>) -> FixedOffset {
+        match self {
+            Self::Fixed(offset) => *offset,
+            Self::Zone { transitions, initial, rule, .. } => {
+                let timestamp = at.timestamp();
+                let seconds = match (transitions.partition_point(|(t, _)| *t <= timestamp), rule) {
+                    (i, Some(rule)) if i == transitions.len() => rule.offset_at(at),
+                    (0, _) => *initial,
+                    (i, _) => transitions[i - 1].1,
+                };
+
+                FixedOffset::east_opt(seconds).unwrap_or_else(|| FixedOffset::east_opt(0).expect("zero is in range"))
+            }
+        }
+    }
+
+    /// 保存する`created_at`はUTCのまま、区分などを求めるときだけ使う。
+    pub fn to_local(&self, at: DateTime<Utc>) -> DateTime<FixedOffset> {
+        at.with_timezone(&self.offset_at(at))
+    }
+}
+
+impl FromStr for Timezone {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "UTC" || s == "Z" {
+            return Ok(Self::default())
+        }
+
+        if let Some(offset) = parse_offset(s) {
+            return Ok(Self::Fixed(offset))
+        }
+
+        let valid_name = !s.is_empty()
+            && !s.starts_with('/')
+            && s.split('/').all(|part| part != ".." && part != ".")
+            && s.chars().all(|c| c.is_ascii_alphanumeric() || "/_+-".contains(c));
+        if !valid_name {
+            return Err(format!("invalid timezone {s}"))
+        }
+
+        let dir = std::env::var_os("TZDIR").map_or_else(|| PathBuf::from("/usr/share/zoneinfo"), PathBuf::from);
+        let data = std::fs::read(dir.join(s)).map_err(|e| format!("unknown timezone {s}: {e}"))?;
+        let Tzif { transitions, initial, rule } = parse_tzif(&data).ok_or_else(|| format!("unknown timezone {s}: not a TZif file"))?;
+
+        Ok(Self::Zone { name: s.to_owned(), transitions, initial, rule })
+    }
+}
+
+impl Display for Timezone {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Fixed(offset) => write!(f, "{offset}"),
+            Self::Zone { name, .. } => f.write_str(name),
+        }
+    }
+}
+
+/// `+09:00`や`-0530`
+fn parse_offset(s: &str) -> Option<FixedOffset> {
+    let sign = match s.as_bytes().first()? {
+        b'+' => 1,
+        b'-' => -1,
+        _ => return None,
+    };
+    let digits: String = s[1..].chars().filter(|&c| c != ':').collect();
+    if digits.len() != 4 || !digits.chars().all(|c| c.is_ascii_digit()) {
+        return None
+    }
+    let hours: i32 = digits[..2].parse().ok()?;
+    let minutes: i32 = digits[2..].parse().ok()?;
+
+    FixedOffset::east_opt(sign * (hours * 3600 + minutes * 60))
+}
+
+fn take<'a>(data: &mut &'a [u8], n: usize) -> Option<&'a [u8]> {
+    let (head, rest) = (data.get(..n)?, data.get(n..)?);
+    *data = rest;
+
+    Some(head)
+}
+
+fn be_u32(bytes: &[u8]) -> usize {
+    u32::from_be_bytes(bytes.try_into().expect("4 bytes")) as usize
+}
+
+/// `TZif`から読んだもの
+struct Tzif {
+    transitions: Vec<(i64, i32)>,
+    initial: i32,
+    rule: Option<PosixRule>,
+}
+
+/// ヘッダーの数から、続くデータの大きさを求める。壊れたファイルで溢れれば[`None`]
+fn block_size(time_size: usize, [is_ut, is_std, leap, time, types, chars]: [usize; 6]) -> Option<usize> {
+    time.checked_mul(time_size + 1)?
+        .checked_add(types.checked_mul(6)?)?
+        .checked_add(chars)?
+        .checked_add(leap.checked_mul(time_size + 4)?)?
+        .checked_add(is_std)?
+        .checked_add(is_ut)
+}
+
+/// RFC 8536の`TZif`から、遷移とオフセット、v2以降なら末尾の規則を読む。
+fn parse_tzif(mut data: &[u8]) -> Option<Tzif> {
+    let mut time_size = 4;
+    loop {
+        let header = take(&mut data, 44)?;
+        if &header[..4] != b"TZif" {
+            return None
+        }
+        let version = header[4];
+        let counts: Vec<usize> = header[20..].chunks(4).map(be_u32).collect();
+        let counts: [usize; 6] = counts.try_into().ok()?;
+        let [_, _, _, time, types, _] = counts;
+
+        // v2以降は64bitの時刻で同じ内容がもう一度続くので、そちらを読む
+        if time_size == 4 && version >= b'2' {
+            take(&mut data, block_size(time_size, counts)?)?;
+            time_size = 8;
+            continue
+        }
+
+        let times = take(&mut data, time.checked_mul(time_size)?)?;
+        let indices = take(&mut data, time)?;
+        let infos = take(&mut data, types.checked_mul(6)?)?;
+        let offset_of = |index: usize| -> Option<i32> {
+            let info = infos.get(index * 6..index * 6 + 4)?;
+            Some(i32::from_be_bytes(info.try_into().ok()?))
+        };
+
+        let transitions = times.chunks(time_size).zip(indices).map(|(t, &index)| {
+            let t = if time_size == 8 {
+                i64::from_be_bytes(t.try_into().ok()?)
+            } else {
+                i64::from(i32::from_be_bytes(t.try_into().ok()?))
+            };
+            Some((t, offset_of(usize::from(index))?))
+        }).collect::<Option<_>>()?;
+
+        // 残りのデータの後に、`\n`で囲んで規則が続く。空なら規則は無い
+        let rule = if time_size == 8 {
+            take(&mut data, block_size(time_size, counts)? - time * (time_size + 1) - types * 6)?;
+            let footer = std::str::from_utf8(data).ok()?.strip_prefix('\n')?;
+            let footer = &footer[..footer.find('\n')?];
+            if footer.is_empty() { None } else { Some(footer.parse().ok()?) }
+        } else {
+            None
+        };
+
+        return Some(Tzif { transitions, initial: offset_of(0)?, rule })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{DateTime, FixedOffset, Utc};
+
+    use crate::timezone::{parse_tzif, Timezone, Tzif};
+
+    fn at(s: &str) -> DateTime<Utc> {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn parses_fixed_offsets() {
+        assert_eq!("+09:00".parse(), Ok(Timezone::Fixed(FixedOffset::east_opt(9 * 3600).unwrap())));
+        assert_eq!("-0530".parse(), Ok(Timezone::Fixed(FixedOffset::west_opt(5 * 3600 + 1800).unwrap())));
+        assert_eq!("UTC".parse(), Ok(Timezone::default()));
+    }
+
+    #[test]
+    fn invalid_zone_names_are_rejected() {
+        assert!("Not/AZone".parse::<Timezone>().is_err());
+        assert!("../../etc/passwd".parse::<Timezone>().is_err());
+    }
+
+    #[test]
+    fn reads_tzdata_including_daylight_saving() {
+        let tokyo: Timezone = "Asia/Tokyo".parse().unwrap();
+        assert_eq!(tokyo.to_local(at("2024-10-31T15:00:00Z")).to_rfc3339(), "2024-11-01T00:00:00+09:00");
+
+        let berlin: Timezone = "Europe/Berlin".parse().unwrap();
+        assert_eq!(berlin.offset_at(at("2024-03-30T12:00:00Z")).local_minus_utc(), 3600);
+        assert_eq!(berlin.offset_at(at("2024-03-31T01:00:00Z")).local_minus_utc(), 7200);
+    }
+
+    /// 2010年の遷移を1つだけ持ち、後は`footer`の規則に従うv2の`TZif`
+    fn slim(footer: &str) -> Vec<u8> {
+        let header = |time_count: u32| {
+            let mut header = b"TZif2".to_vec();
+            header.resize(20, 0);
+            for count in [0, 0, 0, time_count, 2, 9] {
+                header.extend(count.to_be_bytes());
+            }
+            header
+        };
+        let types = [&3600_i32.to_be_bytes()[..], &[0, 0], &7200_i32.to_be_bytes(), &[1, 4], b"CET\0CEST\0"].concat();
+
+        let mut data = header(0);
+        data.extend(&types);
+        data.extend(header(1));
+        data.extend(1_262_304_000_i64.to_be_bytes());
+        data.push(0);
+        data.extend(&types);
+        data.extend(format!("\n{footer}\n").bytes());
+
+        data
+    }
+
+    #[test]
+    fn posix_rules_apply_after_the_last_transition() {
+        let Tzif { transitions, initial, rule } = parse_tzif(&slim("CET-1CEST,M3.5.0,M10.5.0/3")).unwrap();
+        assert_eq!(transitions.len(), 1);
+        let berlin = Timezone::Zone { name: "Europe/Berlin".to_owned(), transitions, initial, rule };
+        let offset = |s| berlin.offset_at(at(s)).local_minus_utc();
+        assert_eq!([offset("2024-03-31T00:59:59Z"), offset("2024-03-31T01:00:00Z")], [3600, 7200]);
+        assert_eq!([offset("2024-10-27T00:59:59Z"), offset("2024-10-27T01:00:00Z")], [7200, 3600]);
+        assert_eq!(offset("2031-07-01T00:00:00Z"), 7200);
+
+        // 年をまたいで夏時間が続く
+        let Tzif { transitions, initial, rule } = parse_tzif(&slim("<+1030>-10:30<+11>-11,M10.1.0,M4.1.0")).unwrap();
+        let lord_howe = Timezone::Zone { name: "Australia/Lord_Howe".to_owned(), transitions, initial, rule };
+        assert_eq!(lord_howe.offset_at(at("2025-01-01T00:00:00Z")).local_minus_utc(), 11 * 3600);
+        assert_eq!(lord_howe.offset_at(at("2025-06-01T00:00:00Z")).local_minus_utc(), 10 * 3600 + 1800);
+
+        assert!(parse_tzif(&slim("CET-1CEST,M13.5.0,M10.5.0")).is_none());
+    }
+
+    #[test]
+    fn huge_counts_are_rejected() {
+        let mut data = b"TZif2".to_vec();
+        data.resize(20, 0);
+        for count in [u32::MAX; 6] {
+            data.extend(count.to_be_bytes());
+        }
+        assert!(parse_tzif(&data).is_none());
+    }
+}
@@ -0,0 +1,72 @@
+//! `--translate`。サーバーの翻訳機能でノートを訳し、元の本文の隣に残す。
+
+use std::error::Error;
+use std::io::Write;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use crate::api::{is_transient, ApiClient, ApiError, NoteTranslateCommand};
+use crate::model::{Note, Translation};
+use crate::output::Destination;
+use crate::pacer::Pacer;
+
+/// 全てのチャンネルで共有する。
+#[derive(Clone)]
+pub struct Translator {
+    lang: String,
+    /// サーバーで翻訳が使えないと分かったら立てる
+    unavailable: Arc<AtomicBool>,
+}
+
+impl Translator {
+    pub fn new(lang: String) -> Self {
+        Self { lang, unavailable: Arc::new(AtomicBool::new(false)) }
+    }
+
+    /// 本文のあるノートに`translation`を埋める。1つのノートにつき1回リクエストを送る。
+    /// 翻訳が使えないサーバーなら、警告を1度だけ書いてそれ以降は送らない。
+    pub async fn translate(
+        &self,
+        client: &impl ApiClient,
+        pacer: &Pacer,
+        out: &mut Destination,
+        notes: &mut [Note],
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        for note in notes.iter_mut().filter(|x| x.text.as_ref().is_some_and(|t| !t.0.trim().is_empty())) {
+            if self.unavailable.load(Ordering::Relaxed) {
+                return Ok(())
+            }
+
+            pacer.wait().await;
+            let result = NoteTranslateCommand { note_id: note.id.clone(), target_lang: self.lang.clone() }.send(client).await;
+            let e = match result {
+                Ok(translated) => {
+                    note.translation = translated.map(|x| Translation { lang: self.lang.clone(), text: x.text });
+                    continue
+                }
+                Err(e) => e,
+            };
+
+            let Some(api) = e.downcast_ref::<ApiError>().filter(|x| x.code != "RATE_LIMIT_EXCEEDED" && !is_transient(&*e)) else {
+                return Err(e)
+            };
+            if api.code == "UNAVAILABLE" {
+                if !self.unavailable.swap(true, Ordering::Relaxed) {
+                    writeln!(out, "{}", serde_json::json!({
+                        "kind": "warning",
+                        "message": "translation is not configured on this server; --translate is disabled for the rest of the run",
+                    }))?;
+                }
+                return Ok(())
+            }
+            writeln!(out, "{}", serde_json::json!({
+                "kind": "warning",
+                "note_id": note.id,
+                "created_at": note.created_at,
+                "message": format!("could not translate the note: {e}"),
+            }))?;
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,258 @@
+//! `--output-layout tree`。1つのノートを1つの整形したJSONファイルに書き、gitで差分を見やすくする。
+//!
+//! ```text
+//! dir/meta.json
+//! dir/notes/<yyyy>/<mm>/<note id>.json
+//! dir/users/<user id>.json
+//! ```
+//!
+//! `fetch-user --output-layout per-user`では、ユーザーをIDの先頭2文字で分けて書く。
+//!
+//! ```text
+//! dir/index.json
+//! dir/<first 2 chars of user id>/<user id>.json
+//! ```
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::field_map::{Renamed, Renames};
+use crate::filename::sanitize_filename;
+use crate::model::{DetailedUser, Note};
+use crate::output::OutputOptions;
+use crate::timestamp::{Formatted, TimestampFormat};
+use crate::timezone::Timezone;
+
+pub struct TreeWriter {
+    dir: PathBuf,
+    timezone: Timezone,
+    timestamp_format: TimestampFormat,
+    renames: Renames,
+    atomic: bool,
+    /// 書き換えなかったものも含む
+    files: Vec<PathBuf>,
+    /// 中身が変わって書き換えた数
+    changed: usize,
+}
+
+impl TreeWriter {
+    pub fn new(dir: &Path, options: &OutputOptions) -> io::Result<Self> {
+        let mut writer = Self {
+            dir: dir.to_path_buf(),
+            timezone: options.timezone.clone(),
+            timestamp_format: options.timestamp_format,
+            renames: options.field_map.note.clone(),
+            atomic: options.atomic,
+            files: vec![],
+            changed: 0,
+        };
+        writer.write_json(dir.join("meta.json"), &options.meta())?;
+
+        Ok(writer)
+    }
+
+    pub fn write_page(&mut self, notes: &[Note]) -> io::Result<()> {
+        for note in notes {
+            let local = self.timezone.to_local(note.created_at);
+            let path = self.dir.join("notes")
+                .join(local.format("%Y").to_string())
+                .join(local.format("%m").to_string())
+                .join(file_name(&note.id.0));
+            let value = serde_json::to_value(Renamed(Formatted(note, self.timestamp_format), &self.renames))?;
+            self.write_json(path, &value)?;
+            self.write_json(self.dir.join("users").join(file_name(&note.user.id.0)), &note.user)?;
+        }
+
+        Ok(())
+    }
+
+    fn write_json(&mut self, path: PathBuf, value: &impl Serialize) -> io::Result<()> {
+        if write_if_changed(&path, value, self.atomic)? {
+            self.changed += 1;
+        }
+        if !self.files.contains(&path) {
+            self.files.push(path);
+        }
+
+        Ok(())
+    }
+
+    pub fn finish(self) -> Vec<PathBuf> {
+        self.files
+    }
+}
+
+/// `index.json`の1行
+#[derive(Serialize, Deserialize)]
+struct IndexEntry {
+    id: String,
+    fetched_at: DateTime<Utc>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Index {
+    users: Vec<IndexEntry>,
+}
+
+/// `fetch-user --output-layout per-user`。前の実行で書いたユーザーも`index.json`に残す。
+pub struct UserTree {
+    dir: PathBuf,
+    fetched_at: BTreeMap<String, DateTime<Utc>>,
+    /// 中身が変わって書き換えた数
+    changed: usize,
+}
+
+impl UserTree {
+    /// `index.json`が無いか壊れていれば、空から始める。
+    pub fn open(dir: &Path) -> io::Result<Self> {
+        let fetched_at = match fs::read(dir.join("index.json")) {
+            Ok(data) => serde_json::from_slice::<Index>(&data)
+                .map(|x| x.users.into_iter().map(|e| (e.id, e.fetched_at)).collect())
+                .unwrap_or_default(),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => return Err(e),
+        };
+
+        Ok(Self { dir: dir.to_path_buf(), fetched_at, changed: 0 })
+    }
+
+    pub fn write_user(&mut self, user: &DetailedUser, renames: &Renames, fetched_at: DateTime<Utc>) -> io::Result<()> {
+        let name = sanitize_filename(&user.id.0, "_");
+        let shard: String = name.chars().take(2).collect();
+        // 他のプロセスが同じユーザーを書いていても混ざらないよう、常に置き換える
+        if write_if_changed(&self.dir.join(shard).join(format!("{name}.json")), &Renamed(user, renames), true)? {
+            self.changed += 1;
+        }
+        self.fetched_at.insert(user.id.0.clone(), fetched_at);
+
+        Ok(())
+    }
+
+    pub fn finish(self) -> io::Result<()> {
+        let index = Index { users: self.fetched_at.into_iter().map(|(id, fetched_at)| IndexEntry { id, fetched_at }).collect() };
+        write_if_changed(&self.dir.join("index.json"), &index, true).map(|_| ())
+    }
+}
+
+/// 整形したJSONを書く。中身が同じなら書き換えず、更新日時も変えない。書き換えたら`true`。
+/// `atomic`なら、プロセスごとの一時ファイルに書いてから置き換えるので、読む側が書きかけを見ることはない。
+pub fn write_if_changed(path: &Path, value: &impl Serialize, atomic: bool) -> io::Result<bool> {
+    let mut content = serde_json::to_string_pretty(value)?;
+    content.push('\n');
+
+    if fs::read(path).ok().as_deref() == Some(content.as_bytes()) {
+        return Ok(false)
+    }
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    if atomic {
+        let mut temporary = path.as_os_str().to_owned();
+        temporary.push(format!(".{}.tmp", std::process::id()));
+        fs::write(&temporary, &content)?;
+        fs::rename(&temporary, path)?;
+    } else {
+        fs::write(path, &content)?;
+    }
+
+    Ok(true)
+}
+
+/// IDをファイル名にする。
+fn file_name(id: &str) -> String {
+    format!("{}.json", sanitize_filename(id, "_"))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use chrono::{FixedOffset, TimeDelta};
+    use serde_json::json;
+
+    use crate::model::{DetailedUser, Note};
+    use crate::chunk;
+    use crate::output::{Layout, OutputOptions};
+    use crate::field_map::{FieldMap, Renames};
+    use crate::testing::{capture_dir, note_json, RUN};
+    use crate::timestamp::TimestampFormat;
+    use crate::timezone::Timezone;
+    use crate::tree::{file_name, TreeWriter, UserTree};
+
+    fn options() -> OutputOptions {
+        OutputOptions {
+            split_by: None,
+            timezone: Timezone::Fixed(FixedOffset::east_opt(9 * 3600).unwrap()),
+            atomic: true,
+            timestamp_format: TimestampFormat::Rfc3339,
+            layout: Layout::Tree,
+            chunk_size: chunk::DEFAULT_CHUNK_SIZE,
+            sink: None,
+            canonical: false,
+            possibly_incomplete: vec![],
+            run: RUN,
+            field_map: FieldMap::EMPTY,
+            detect_language: false,
+            append: false,
+            append_index: None,
+        }
+    }
+
+    #[test]
+    fn unchanged_files_are_not_rewritten() {
+        let dir = capture_dir("tree", &[]);
+        let note: Note = serde_json::from_value(note_json("n1", "2024-01-31T15:00:00.000Z")).unwrap();
+
+        let mut first = TreeWriter::new(&dir, &options()).unwrap();
+        first.write_page(std::slice::from_ref(&note)).unwrap();
+        assert_eq!(first.changed, 3);
+        assert_eq!(first.finish().len(), 3);
+
+        // 日付はタイムゾーンで決まる
+        let path = dir.join("notes/2024/02/n1.json");
+        assert!(fs::read_to_string(&path).unwrap().starts_with("{\n  \"createdAt\""));
+        assert!(dir.join("users/u1.json").exists());
+
+        let mut second = TreeWriter::new(&dir, &options()).unwrap();
+        second.write_page(std::slice::from_ref(&note)).unwrap();
+        assert_eq!(second.changed, 0);
+    }
+
+    #[test]
+    fn users_are_sharded_by_id_and_indexed() {
+        let dir = capture_dir("user-tree", &[]);
+        let user: DetailedUser = serde_json::from_value(json!({
+            "id": "9abc", "name": null, "username": "alice", "isBot": false, "isCat": false,
+            "avatarUrl": "https://misskey.example/avatar.webp", "notesCount": 0,
+        })).unwrap();
+        let at = "2024-01-01T00:00:00Z".parse().unwrap();
+
+        let mut first = UserTree::open(&dir).unwrap();
+        first.write_user(&user, &Renames::default(), at).unwrap();
+        assert_eq!(first.changed, 1);
+        first.finish().unwrap();
+        assert!(dir.join("9a/9abc.json").exists());
+
+        let mut second = UserTree::open(&dir).unwrap();
+        second.write_user(&user, &Renames::default(), at + TimeDelta::days(1)).unwrap();
+        assert_eq!(second.changed, 0);
+        second.finish().unwrap();
+
+        let index: serde_json::Value = serde_json::from_str(&fs::read_to_string(dir.join("index.json")).unwrap()).unwrap();
+        assert_eq!(index, json!({ "users": [{ "id": "9abc", "fetched_at": "2024-01-02T00:00:00Z" }] }));
+    }
+
+    #[test]
+    fn ids_cannot_escape_the_directory() {
+        assert_eq!(file_name("9xyz"), "9xyz.json");
+        assert_eq!(file_name("../../etc/passwd"), "_.._etc_passwd.json");
+        assert_eq!(file_name("CON"), "_CON.json");
+        assert_eq!(file_name(""), "_.json");
+        assert_eq!(file_name(&"a".repeat(300)).len(), 64 + ".json".len());
+    }
+}
@@ -0,0 +1,254 @@
+//! clapが引数を読んだ後の、引数の組み合わせの確かめ。
+//!
+//! 他の引数と関わる決まりは全てここの[`RULES`]に置く。新しい引数に決まりがあれば、ここに足す。
+//! 破ったときは終了コード64で終わり、`--log-format json`なら`rule`の名前で見分けられる1つのJSONを書く。
+
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+
+use serde_json::Value;
+
+use crate::cli::{Cli, Command, GlobalArgs, TimelineArgs};
+use crate::i18n::{Lang, Message};
+use crate::mfm::EmojiStyle;
+use crate::output::{Layout, UserLayout};
+use crate::status::Outcome;
+
+/// 引数の組み合わせが決まりに合わない
+#[derive(Debug)]
+pub struct UsageError {
+    /// 破った決まりの名前。バージョンをまたいで変えない
+    pub rule: &'static str,
+    /// 決まりに関わる引数
+    pub arguments: &'static [&'static str],
+    /// キーは`rule`と同じか、`sink-output`なら`--sink`ごとのもの
+    pub message: Message,
+}
+
+impl Display for UsageError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.message.render(Lang::En))
+    }
+}
+
+impl Error for UsageError {}
+
+impl UsageError {
+    /// `--log-format json`で標準エラー出力に書く形
+    pub fn to_json(&self) -> Value {
+        serde_json::json!({
+            "kind": "usage-error",
+            "rule": self.rule,
+            "arguments": self.arguments,
+            "message": self.message.render(Lang::En),
+            "exit_code": Outcome::Usage.code(),
+        })
+    }
+}
+
+/// 引数の組み合わせの決まり
+struct Rule {
+    name: &'static str,
+    arguments: &'static [&'static str],
+    /// 合わなければ、その説明を返す
+    check: fn(&Cli) -> Option<Message>,
+}
+
+const RULES: [Rule; 11] = [
+    Rule {
+        name: "credentials-required",
+        arguments: &["--token", "--token-file", "--token-env"],
+        check: |cli| (needs_token(&cli.cmd) && cli.global.replay.is_none() && !has_token(&cli.global))
+            .then(|| Message::new("credentials-required", &[])),
+    },
+    Rule {
+        name: "host-required",
+        arguments: &["--host"],
+        check: |cli| (needs_host(&cli.cmd) && cli.global.replay.is_none() && cli.global.host.is_none())
+            .then(|| Message::new("host-required", &[])),
+    },
+    Rule {
+        name: "export-host-required",
+        arguments: &["--host"],
+        check: |cli| (matches!(cli.cmd, Command::Export { .. }) && cli.global.host.is_none())
+            .then(|| Message::new("export-host-required", &[])),
+    },
+    Rule {
+        name: "sink-output",
+        arguments: &["--sink", "--sink-url", "--output"],
+        check: |cli| cli.global.sink_conflict().map(|key| Message::new(key, &[])),
+    },
+    Rule {
+        name: "layout-requires-output",
+        arguments: &["--output-layout", "--output"],
+        check: |cli| {
+            let layout = match &cli.cmd {
+                Command::Archive { timeline, .. } | Command::ArchiveList { timeline, .. } | Command::Backfill { timeline, .. } => directory(timeline),
+                Command::Export { output_layout: Layout::Tree, .. } => Some("tree"),
+                Command::FetchUser { output_layout: UserLayout::PerUser, .. } => Some("per-user"),
+                _ => None,
+            };
+            layout.filter(|_| cli.global.output.is_none()).map(|x| Message::new("layout-requires-output", &[("layout", &x)]))
+        },
+    },
+    Rule {
+        name: "emoji-dir-requires-image",
+        arguments: &["--emoji-dir", "--emoji-style"],
+        check: |cli| match &cli.cmd {
+            Command::Export { text, .. } if text.emoji_dir.is_some() && text.emoji_style != EmojiStyle::Image => {
+                Some(Message::new("emoji-dir-requires-image", &[]))
+            }
+            _ => None,
+        },
+    },
+    Rule {
+        name: "export-layout",
+        arguments: &["--output-layout"],
+        check: |cli| matches!(cli.cmd, Command::Export { output_layout: Layout::Chunked, .. })
+            .then(|| Message::new("export-layout", &[])),
+    },
+    Rule {
+        name: "reaction-range",
+        arguments: &["--since", "--until"],
+        check: |cli| match &cli.cmd {
+            Command::FetchReactions { since: Some(since), until: Some(until), .. } if since >= until => {
+                Some(Message::new("reaction-range", &[("since", since), ("until", until)]))
+            }
+            _ => None,
+        },
+    },
+    Rule {
+        name: "pacer-state-requires-host",
+        arguments: &["--pacer-state", "--host"],
+        check: |cli| (cli.global.pacer_state.is_some() && cli.global.host.is_none())
+            .then(|| Message::new("pacer-state-requires-host", &[])),
+    },
+    Rule {
+        name: "pinned-notes-layout",
+        arguments: &["--with-pinned-notes", "--output-layout"],
+        check: |cli| {
+            let layout = match &cli.cmd {
+                Command::FetchUser { with_pinned_notes: true, output_layout: UserLayout::PerUser, .. } => Some("per-user"),
+                Command::FetchUser { with_pinned_notes: true, output_layout: UserLayout::Map, .. } => Some("map"),
+                _ => None,
+            };
+            layout.map(|x| Message::new("pinned-notes-layout", &[("layout", &x)]))
+        },
+    },
+    Rule {
+        name: "append-requires-map",
+        arguments: &["--append", "--output-layout", "--output"],
+        check: |cli| match &cli.cmd {
+            Command::FetchUser { append: true, output_layout, .. } if *output_layout != UserLayout::Map || cli.global.output.is_none() => {
+                Some(Message::new("append-requires-map", &[]))
+            }
+            _ => None,
+        },
+    },
+];
+
+/// 全ての決まりを確かめ、最初に破ったものを返す。
+pub fn check(cli: &Cli) -> Result<(), UsageError> {
+    for rule in &RULES {
+        if let Some(message) = (rule.check)(cli) {
+            return Err(UsageError { rule: rule.name, arguments: rule.arguments, message })
+        }
+    }
+
+    Ok(())
+}
+
+/// APIを呼ぶのでトークンが要るもの
+const fn needs_token(cmd: &Command) -> bool {
+    matches!(cmd,
+        Command::Archive { .. } | Command::ArchiveList { .. } | Command::Backfill { .. } | Command::FetchNotes { .. } | Command::Refresh { .. }
+        | Command::FetchUser { .. } | Command::FetchFollowers { .. } | Command::FetchFollowing { .. } | Command::FetchReactions { .. }
+        | Command::FetchRenotes { .. } | Command::EstimateFiles { input: None, .. } | Command::Explore { .. }
+    )
+}
+
+/// 接続するのでホストが要るもの
+const fn needs_host(cmd: &Command) -> bool {
+    #[cfg(feature = "keyring")]
+    if let Command::Auth { action: Some(_), .. } = cmd {
+        return false
+    }
+
+    needs_token(cmd) || matches!(cmd, Command::Auth { .. } | Command::SnapshotFeatured)
+}
+
+const fn has_token(global: &GlobalArgs) -> bool {
+    #[cfg(feature = "keyring")]
+    if global.token_keyring.is_some() {
+        return true
+    }
+
+    global.token.is_some() || global.token_file.is_some() || global.token_env.is_some()
+}
+
+/// ディレクトリに書き出す`--output-layout`。`--dry-run`では書き出さないので要らない
+const fn directory(timeline: &TimelineArgs) -> Option<&'static str> {
+    match timeline.output_layout {
+        _ if timeline.dry_run => None,
+        Layout::Tree => Some("tree"),
+        Layout::Chunked => Some("chunked"),
+        Layout::Lines => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+    use serde_json::json;
+
+    use crate::cli::Cli;
+    use crate::usage::{check, RULES};
+
+    fn cli(args: &[&str]) -> Cli {
+        Cli::try_parse_from(std::iter::once(&"misskey-channel-archiver").chain(args)).unwrap()
+    }
+
+    #[test]
+    fn every_rule_reports_in_both_forms() {
+        let cases: [(&[&str], &str, &str); 11] = [
+            (&["archive", "--host", "misskey.example", "--channel-id", "c"], "credentials-required", "one of --token, --token-file or --token-env is required"),
+            (&["fetch-user", "--token", "t", "--user", "a"], "host-required", "--host is required"),
+            (&["export", "--input", "a.jsonl", "--format", "activitystreams"], "export-host-required", "export needs --host to build the ids of the objects"),
+            (&["archive", "--host", "misskey.example", "--token", "t", "--channel-id", "c", "--sink", "file"], "sink-output", "--sink file requires --output"),
+            (&["fetch-user", "--host", "misskey.example", "--token", "t", "--user", "a", "--output-layout", "per-user"], "layout-requires-output", "--output-layout per-user requires --output to name the directory"),
+            (&["export", "--host", "misskey.example", "--input", "a.jsonl", "--format", "activitystreams", "--emoji-dir", "emoji"], "emoji-dir-requires-image", "--emoji-dir requires --emoji-style image"),
+            (&["export", "--host", "misskey.example", "--input", "a.jsonl", "--format", "activitystreams", "--output-layout", "chunked", "--output", "out"], "export-layout", "export does not support --output-layout chunked; use lines or tree"),
+            (
+                &["fetch-reactions", "--host", "misskey.example", "--token", "t", "--note-id", "n", "--since", "2024-02-01T00:00:00Z", "--until", "2024-01-01T00:00:00Z"],
+                "reaction-range",
+                "--since (2024-02-01 00:00:00 UTC) must be earlier than --until (2024-01-01 00:00:00 UTC)",
+            ),
+            (&["report", "--input", "a.jsonl", "--pacer-state", "pacer.json"], "pacer-state-requires-host", "--pacer-state requires --host; the state is kept per host"),
+            (
+                &["fetch-user", "--host", "misskey.example", "--token", "t", "--user", "a", "--output-layout", "per-user", "--output", "users", "--with-pinned-notes"],
+                "pinned-notes-layout",
+                "--with-pinned-notes cannot be used with --output-layout per-user; pinned notes are written as lines",
+            ),
+            (
+                &["fetch-user", "--host", "misskey.example", "--token", "t", "--user", "a", "--append", "--output", "users.json"],
+                "append-requires-map",
+                "--append rewrites the file named by --output, so it requires --output-layout map and --output",
+            ),
+        ];
+        assert_eq!(cases.map(|x| x.1), RULES.map(|x| x.name));
+
+        for (args, rule, message) in cases {
+            let e = check(&cli(args)).unwrap_err();
+            assert_eq!((e.rule, e.to_string().as_str()), (rule, message));
+            let arguments = RULES.iter().find(|x| x.name == rule).unwrap().arguments;
+            assert_eq!(e.to_json(), json!({ "kind": "usage-error", "rule": rule, "arguments": arguments, "message": message, "exit_code": 64 }));
+        }
+    }
+
+    #[test]
+    fn replay_needs_neither_host_nor_token() {
+        assert!(check(&cli(&["archive", "--replay", "captured", "--channel-id", "c"])).is_ok());
+        assert!(check(&cli(&["archive", "--host", "misskey.example", "--token-env", "TOKEN", "--channel-id", "c", "--output-layout", "tree"])).is_err());
+        assert!(check(&cli(&["archive", "--host", "misskey.example", "--token-env", "TOKEN", "--channel-id", "c", "--output-layout", "chunked", "--dry-run"])).is_ok());
+    }
+}
@@ -0,0 +1,121 @@
+//! `--user-cache`。取得したユーザーを手元に残し、次の実行で使い回す。
+
+use std::collections::HashMap;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::log::info;
+use crate::model::{DetailedUser, UserId};
+use crate::output::RecordFile;
+
+const VERSION: u32 = 1;
+
+#[derive(Serialize, Deserialize)]
+struct Entry {
+    fetched_at: DateTime<Utc>,
+    user: DetailedUser,
+}
+
+#[derive(Serialize, Deserialize)]
+struct CacheFile {
+    version: u32,
+    users: Vec<Entry>,
+}
+
+pub struct UserCache {
+    path: PathBuf,
+    ttl: Duration,
+    entries: HashMap<UserId, Entry>,
+}
+
+impl UserCache {
+    /// 無ければ空から始める。読めなければ作り直す。
+    pub fn open(path: &Path, ttl: Duration) -> io::Result<Self> {
+        let entries = match std::fs::read(path) {
+            Ok(data) => match serde_json::from_slice::<CacheFile>(&data) {
+                Ok(file) if file.version == VERSION => file.users.into_iter().map(|e| (e.user.id.clone(), e)).collect(),
+                _ => {
+                    info!("user cache {} is corrupted; rebuilding it", path.display());
+                    HashMap::new()
+                }
+            },
+            Err(e) if e.kind() == io::ErrorKind::NotFound => HashMap::new(),
+            Err(e) => return Err(e),
+        };
+
+        Ok(Self { path: path.to_path_buf(), ttl, entries })
+    }
+
+    /// `ttl`より新しいものだけを、取得した日時と共に返す。
+    pub fn get(&self, id: &UserId, now: DateTime<Utc>) -> Option<(&DetailedUser, DateTime<Utc>)> {
+        let entry = self.entries.get(id)?;
+        let age = (now - entry.fetched_at).to_std().unwrap_or_default();
+
+        (age < self.ttl).then_some((&entry.user, entry.fetched_at))
+    }
+
+    pub fn insert(&mut self, user: DetailedUser, now: DateTime<Utc>) {
+        self.entries.insert(user.id.clone(), Entry { fetched_at: now, user });
+    }
+
+    /// 書き込み中に止まっても前の内容が残るよう、一時ファイルを経由する。
+    pub fn save(self) -> io::Result<()> {
+        let mut users: Vec<_> = self.entries.into_values().collect();
+        users.sort_by(|a, b| a.user.id.0.cmp(&b.user.id.0));
+
+        let mut file = RecordFile::create(&self.path, true)?;
+        writeln!(file, "{}", serde_json::to_string(&CacheFile { version: VERSION, users })?)?;
+        file.finish().map(drop)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::time::Duration;
+
+    use chrono::{DateTime, TimeDelta, Utc};
+    use serde_json::json;
+
+    use crate::model::{DetailedUser, UserId};
+    use crate::user_cache::UserCache;
+
+    fn user(id: &str) -> DetailedUser {
+        serde_json::from_value(json!({
+            "id": id, "name": null, "username": id, "isBot": false, "isCat": true,
+            "avatarUrl": "https://misskey.example/avatar.webp", "notesCount": 3,
+        })).unwrap()
+    }
+
+    #[test]
+    fn entries_expire_after_ttl_and_survive_a_reopen() {
+        let path = std::env::temp_dir().join(format!("misskey-channel-archiver-{}-user-cache.json", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let ttl = Duration::from_hours(1);
+        let now: DateTime<Utc> = "2024-01-01T00:00:00Z".parse().unwrap();
+
+        let mut cache = UserCache::open(&path, ttl).unwrap();
+        cache.insert(user("u1"), now);
+        cache.save().unwrap();
+
+        let cache = UserCache::open(&path, ttl).unwrap();
+        assert_eq!(cache.get(&UserId("u1".to_owned()), now + TimeDelta::minutes(59)).map(|(u, _)| u.mention.as_str()), Some("u1"));
+        assert!(cache.get(&UserId("u1".to_owned()), now + TimeDelta::minutes(61)).is_none());
+        assert!(cache.get(&UserId("u2".to_owned()), now).is_none());
+    }
+
+    #[test]
+    fn corrupted_cache_is_rebuilt() {
+        let path = std::env::temp_dir().join(format!("misskey-channel-archiver-{}-user-cache-broken.json", std::process::id()));
+        fs::write(&path, r#"{"version":1,"users":[{"#).unwrap();
+
+        let cache = UserCache::open(&path, Duration::from_hours(1)).unwrap();
+        cache.save().unwrap();
+
+        assert_eq!(fs::read_to_string(&path).unwrap().trim(), r#"{"version":1,"users":[]}"#);
+    }
+}
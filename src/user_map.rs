@@ -0,0 +1,91 @@
+//! `fetch-user --output-layout map`。取得したユーザーをIDで引けるよう、1つのJSONオブジェクトにまとめる。
+//!
+//! `--append`なら、前の実行で書いたファイルを読み込み、取得したユーザーで置き換えて書き直す。
+
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fs::File;
+use std::io::{self, BufReader, Write};
+use std::path::Path;
+
+use serde_json::Value;
+
+use crate::field_map::{Renamed, Renames};
+use crate::model::DetailedUser;
+
+#[derive(Default)]
+pub struct UserMap {
+    /// IDの順に書くので、同じユーザーからは同じファイルになる
+    users: BTreeMap<String, Value>,
+}
+
+impl UserMap {
+    /// `existing`があれば、その中身から始める。大きなファイルでも1つの文字列には読み込まず、少しずつ読む。
+    pub fn open(existing: Option<&Path>) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        let Some(path) = existing else {
+            return Ok(Self::default())
+        };
+        let file = match File::open(path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Self::default()),
+            Err(e) => return Err(format!("failed to open {}: {e}", path.display()).into()),
+        };
+        let users = serde_json::from_reader(BufReader::new(file)).map_err(|e| format!("failed to read {}: {e}", path.display()))?;
+
+        Ok(Self { users })
+    }
+
+    pub fn insert(&mut self, user: &DetailedUser, renames: &Renames) -> serde_json::Result<()> {
+        self.users.insert(user.id.0.clone(), serde_json::to_value(Renamed(user, renames))?);
+
+        Ok(())
+    }
+
+    pub fn write(&self, out: &mut (impl Write + ?Sized)) -> io::Result<()> {
+        serde_json::to_writer(&mut *out, &self.users)?;
+        writeln!(out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use serde_json::json;
+
+    use crate::field_map::Renames;
+    use crate::model::DetailedUser;
+    use crate::testing::capture_dir;
+    use crate::user_map::UserMap;
+
+    fn user(id: &str, notes_count: usize) -> DetailedUser {
+        serde_json::from_value(json!({
+            "id": id, "name": null, "username": id, "isBot": false, "isCat": false,
+            "avatarUrl": "https://misskey.example/avatar.webp", "notesCount": notes_count,
+        })).unwrap()
+    }
+
+    #[test]
+    fn appending_updates_the_existing_map_in_id_order() {
+        let dir = capture_dir("user-map", &[]);
+        let path = dir.join("users.json");
+        assert!(UserMap::open(Some(&path)).unwrap().users.is_empty());
+        // 前の実行で書いたもの。知らないフィールドもそのまま残す
+        fs::write(&path, json!({ "u2": { "id": "u2", "notesCount": 1, "fields": [] }, "u3": { "id": "u3", "notesCount": 1 } }).to_string()).unwrap();
+
+        let mut map = UserMap::open(Some(&path)).unwrap();
+        map.insert(&user("u3", 5), &Renames::default()).unwrap();
+        map.insert(&user("u1", 2), &Renames::default()).unwrap();
+        let mut out = vec![];
+        map.write(&mut out).unwrap();
+
+        let out = String::from_utf8(out).unwrap();
+        let written: serde_json::Value = serde_json::from_str(&out).unwrap();
+        assert_eq!(written.as_object().unwrap().keys().collect::<Vec<_>>(), ["u1", "u2", "u3"]);
+        assert_eq!((&written["u2"]["fields"], &written["u3"]["notesCount"]), (&json!([]), &json!(5)));
+        assert!(out.ends_with("}\n") && out.find("\"u1\"") < out.find("\"u2\""));
+
+        fs::write(&path, "{\"u1\": ").unwrap();
+        assert!(UserMap::open(Some(&path)).is_err());
+    }
+}